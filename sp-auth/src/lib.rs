@@ -1,4 +1,4 @@
-use base64::decode;
+use base64::{Engine, engine::general_purpose::STANDARD};
 use serde_json::{Value, to_vec, from_slice};
 use sha3::Sha3_256;
 use hmac::{Hmac, Mac, crypto_mac::{NewMac, MacError}};
@@ -20,6 +20,13 @@ pub fn create_auth_token(auth_token_key: &[u8], payload: &Value) -> Result<Vec<u
     Ok(code_bytes.to_vec())
 }
 
+/// Creates auth token checked by verify_auth_token, hash and payload are base64 encoded and joined with ".".
+pub fn create_auth_token_string(auth_token_key: &[u8], payload: &Value) -> Result<String, serde_json::Error> {
+    let hash = create_auth_token(auth_token_key, payload)?;
+
+    Ok(STANDARD.encode(hash) + "." + &STANDARD.encode(to_vec(payload)?))
+}
+
 pub fn verify_auth_token(auth_token_key: &[u8], auth_token: &str) -> Result<Value, Error> {
     let split: Vec<&str> = auth_token.split(".").collect();
 
@@ -27,8 +34,8 @@ pub fn verify_auth_token(auth_token_key: &[u8], auth_token: &str) -> Result<Valu
         return Err(Error::IncorrectSplitLen);
     }
 
-    let hash = decode(split[0])?;
-    let payload = decode(split[1])?;
+    let hash = STANDARD.decode(split[0])?;
+    let payload = STANDARD.decode(split[1])?;
 
     //To verify the message:
     let mut mac = HmacSha::new_from_slice(auth_token_key).expect("HMAC can take key of any size");
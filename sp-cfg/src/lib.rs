@@ -1,10 +1,50 @@
+use std::convert::TryFrom;
+use std::fmt;
 use std::io::BufReader;
 use std::io::prelude::*;
-use serde_derive::Deserialize;
+use std::str::FromStr;
+use std::time::Duration;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sp_dto::Key;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ServerConfig {
-    pub host: String
+    pub host: String,
+    /// Persistent log settings, log is disabled if not set
+    pub log: Option<LogConfig>,
+    /// Secret keys for hashing client addrs on the server, random keys are used if not set
+    pub hash_keys: Option<(u64, u64)>,
+    /// Settings of namespaces, server log settings are used for default namespace if it has no own log
+    pub namespaces: Option<Vec<NamespaceConfig>>,
+    /// Max frame payload size offered to clients on handshake, max size allowed by protocol is used if not set
+    pub max_frame_payload_size: Option<u16>,
+    /// Interval of pings sent to clients in milliseconds, heartbeats are disabled if not set
+    pub heartbeat_interval: Option<u64>,
+    /// Clients which don't answer pings or write frames for this time in milliseconds are disconnected, 3 heartbeat intervals are used if not set
+    pub heartbeat_timeout: Option<u64>,
+    /// CRC32C checksums of frames are offered to clients on handshake, enabled if not set. Can be disabled on trusted local links
+    pub frame_checksums: Option<bool>
+}
+
+/// Namespace isolates clients from each other: subscribes, routing, retained events and logs are not shared between namespaces.
+#[derive(Debug, Deserialize, Clone)]
+pub struct NamespaceConfig {
+    pub name: String,
+    /// Access keys accepted from clients, any access key is accepted if not set
+    pub access_keys: Option<Vec<String>>,
+    /// Persistent log settings, path must not be shared with other namespaces
+    pub log: Option<LogConfig>
+}
+
+/// Events sent with listed keys are appended to on-disk log, consumers read them from stored offsets.
+#[derive(Debug, Deserialize, Clone)]
+pub struct LogConfig {
+    /// Directory for log segments and consumer offsets
+    pub path: String,
+    /// Max size of log segment file in bytes, new segment is started after it is reached
+    pub segment_size: Option<u64>,
+    pub keys: Vec<Key>
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -36,4 +76,412 @@ pub fn get_config_from_arg() -> ServerConfig {
 
      toml::from_str(&config)
         .expect("failed to deserialize config")
-}
\ No newline at end of file
+}
+
+/// Settings of streaming platform client. Client entry points read them from json config, other values of the config are left for the service.
+/// Config is loaded with from_value, from_toml, from_file or from_env, or built with new and with_* methods, loaded config is validated.
+#[derive(Debug, Serialize, Clone, Default, PartialEq)]
+pub struct ClientConfig {
+    /// Network addr of the server in host:port format, required with tcp transport
+    pub host: Option<String>,
+    /// Address of the client, required if config is not requested from config service
+    pub addr: Option<String>,
+    /// Access key sent to the server on authorization, required with tcp transport
+    pub access_key: Option<String>,
+    /// Server namespace of the client, default namespace is used if not set
+    pub namespace: Option<String>,
+    /// Network addr of config service host, client config is requested from config service if set
+    pub cfg_host: Option<String>,
+    /// Domain of client config in config service, required with cfg_host
+    pub cfg_domain: Option<String>,
+    /// Token for client config in config service, required with cfg_host
+    pub cfg_token: Option<String>,
+    /// Max payload size of written frames up to 64 KiB, receivers must be able to read frames of this size
+    pub max_frame_payload_size: Option<u16>,
+    /// Enables lz4 compression of sent messages, disabled if not set
+    pub compression: Option<bool>,
+    /// Messages with payload and attachments data smaller than this in bytes are not compressed, 1 KiB is used if not set
+    pub compression_threshold: Option<u64>,
+    /// Interval of pings sent to the server in milliseconds, heartbeats are disabled if not set
+    pub heartbeat_interval: Option<u64>,
+    /// Connection is closed if nothing is received from the server for this time in milliseconds, 3 heartbeat intervals are used if not set
+    pub heartbeat_timeout: Option<u64>,
+    /// CRC32C checksums of frames are offered to the server on handshake and used if it agrees, enabled if not set
+    pub frame_checksums: Option<bool>,
+    /// First delay between attempts to connect to the server in milliseconds, 100 is used if not set
+    pub reconnect_min_delay: Option<u64>,
+    /// Max delay between attempts to connect to the server in milliseconds, 10000 is used if not set.
+    /// Client waits for the server on start with the same delays
+    pub reconnect_max_delay: Option<u64>,
+    /// Rpcs waiting for response when connection is lost are sent again after reconnect, they fail with connection lost error if not set
+    pub rpc_retry: Option<bool>,
+    /// Time to wait for rpc responses in milliseconds, default rpc timeout is used if not set. It can be changed for single call with MagicBall with_rpc_timeout
+    pub rpc_timeout: Option<u64>,
+    /// Max amount of rpcs waiting for response, default limit is used if not set
+    pub max_pending_rpcs: Option<u64>,
+    /// Max amount of events and rpcs processed at once, not limited if not set
+    pub max_running_handlers: Option<u64>,
+    /// Max amount of events and rpcs with the same key processed at once, not limited if not set
+    pub max_running_handlers_per_key: Option<u64>,
+    /// Max amount of events and rpcs waiting for their turn over max_running_handlers, max_running_handlers_per_key or ordered_handlers,
+    /// rpcs over it fail with Busy error and events are dropped. Not limited if not set
+    pub max_queued_handlers: Option<u64>,
+    /// Events and rpcs with the same key are processed one at a time in order of receiving, disabled if not set
+    pub ordered_handlers: Option<bool>
+}
+
+impl ClientConfig {
+    pub fn new(addr: &str) -> ClientConfig {
+        ClientConfig {
+            addr: Some(addr.to_owned()),
+            ..Default::default()
+        }
+    }
+    pub fn with_host(mut self, host: &str) -> ClientConfig {
+        self.host = Some(host.to_owned());
+        self
+    }
+    pub fn with_access_key(mut self, access_key: &str) -> ClientConfig {
+        self.access_key = Some(access_key.to_owned());
+        self
+    }
+    pub fn with_namespace(mut self, namespace: &str) -> ClientConfig {
+        self.namespace = Some(namespace.to_owned());
+        self
+    }
+    /// Client config is requested from config service with provided domain and token, the rest of settings are taken from received config.
+    pub fn with_cfg(mut self, cfg_host: &str, cfg_domain: &str, cfg_token: &str) -> ClientConfig {
+        self.cfg_host = Some(cfg_host.to_owned());
+        self.cfg_domain = Some(cfg_domain.to_owned());
+        self.cfg_token = Some(cfg_token.to_owned());
+        self
+    }
+    pub fn with_max_frame_payload_size(mut self, max_frame_payload_size: u16) -> ClientConfig {
+        self.max_frame_payload_size = Some(max_frame_payload_size);
+        self
+    }
+    /// Enables compression of messages not smaller than threshold, default threshold is used if it is not set.
+    pub fn with_compression(mut self, compression_threshold: Option<u64>) -> ClientConfig {
+        self.compression = Some(true);
+        self.compression_threshold = compression_threshold;
+        self
+    }
+    pub fn with_heartbeat(mut self, interval: Duration, timeout: Option<Duration>) -> ClientConfig {
+        self.heartbeat_interval = Some(interval.as_millis() as u64);
+        self.heartbeat_timeout = timeout.map(|timeout| timeout.as_millis() as u64);
+        self
+    }
+    pub fn with_frame_checksums(mut self, frame_checksums: bool) -> ClientConfig {
+        self.frame_checksums = Some(frame_checksums);
+        self
+    }
+    pub fn with_reconnect_delays(mut self, min_delay: Duration, max_delay: Duration) -> ClientConfig {
+        self.reconnect_min_delay = Some(min_delay.as_millis() as u64);
+        self.reconnect_max_delay = Some(max_delay.as_millis() as u64);
+        self
+    }
+    pub fn with_rpc_retry(mut self, rpc_retry: bool) -> ClientConfig {
+        self.rpc_retry = Some(rpc_retry);
+        self
+    }
+    pub fn with_rpc_timeout(mut self, rpc_timeout: Duration) -> ClientConfig {
+        self.rpc_timeout = Some(rpc_timeout.as_millis() as u64);
+        self
+    }
+    pub fn with_max_pending_rpcs(mut self, max_pending_rpcs: u64) -> ClientConfig {
+        self.max_pending_rpcs = Some(max_pending_rpcs);
+        self
+    }
+    /// Limits amount of handlers processed at once and waiting for their turn, queue is not limited if max_queued is not set.
+    pub fn with_max_running_handlers(mut self, max_running: u64, max_queued: Option<u64>) -> ClientConfig {
+        self.max_running_handlers = Some(max_running);
+        self.max_queued_handlers = max_queued;
+        self
+    }
+    pub fn with_max_running_handlers_per_key(mut self, max_running: u64) -> ClientConfig {
+        self.max_running_handlers_per_key = Some(max_running);
+        self
+    }
+    pub fn with_max_queued_handlers(mut self, max_queued: u64) -> ClientConfig {
+        self.max_queued_handlers = Some(max_queued);
+        self
+    }
+    pub fn with_ordered_handlers(mut self, ordered_handlers: bool) -> ClientConfig {
+        self.ordered_handlers = Some(ordered_handlers);
+        self
+    }
+    /// Reads settings from json config, values not related to client are ignored.
+    pub fn from_value(config: &Value) -> Result<ClientConfig, ConfigError> {
+        if !config.is_object() {
+            return Err(ConfigError::Invalid("config", format!("expected object, got {}", config)));
+        }
+
+        let client_config = ClientConfig {
+            host: get_string(config, "host")?,
+            addr: get_string(config, "addr")?,
+            access_key: get_string(config, "access_key")?,
+            namespace: get_string(config, "namespace")?,
+            cfg_host: get_string(config, "cfg_host")?,
+            cfg_domain: get_string(config, "cfg_domain")?,
+            cfg_token: get_string(config, "cfg_token")?,
+            max_frame_payload_size: match get_u64(config, "max_frame_payload_size")? {
+                Some(max_frame_payload_size) => Some(u16::try_from(max_frame_payload_size).map_err(|_| ConfigError::Invalid("max_frame_payload_size", format!("expected size up to {}, got {}", u16::MAX, max_frame_payload_size)))?),
+                None => None
+            },
+            compression: get_bool(config, "compression")?,
+            compression_threshold: get_u64(config, "compression_threshold")?,
+            heartbeat_interval: get_u64(config, "heartbeat_interval")?,
+            heartbeat_timeout: get_u64(config, "heartbeat_timeout")?,
+            frame_checksums: get_bool(config, "frame_checksums")?,
+            reconnect_min_delay: get_u64(config, "reconnect_min_delay")?,
+            reconnect_max_delay: get_u64(config, "reconnect_max_delay")?,
+            rpc_retry: get_bool(config, "rpc_retry")?,
+            rpc_timeout: get_u64(config, "rpc_timeout")?,
+            max_pending_rpcs: get_u64(config, "max_pending_rpcs")?,
+            max_running_handlers: get_u64(config, "max_running_handlers")?,
+            max_running_handlers_per_key: get_u64(config, "max_running_handlers_per_key")?,
+            max_queued_handlers: get_u64(config, "max_queued_handlers")?,
+            ordered_handlers: get_bool(config, "ordered_handlers")?
+        };
+
+        client_config.validate()?;
+
+        Ok(client_config)
+    }
+    /// Reads settings from TOML document with the same keys as json config.
+    pub fn from_toml(config: &str) -> Result<ClientConfig, ConfigError> {
+        let config: toml::Value = toml::from_str(config).map_err(ConfigError::Toml)?;
+
+        ClientConfig::from_value(&serde_json::to_value(config).map_err(ConfigError::Json)?)
+    }
+    pub fn from_file(path: &str) -> Result<ClientConfig, ConfigError> {
+        ClientConfig::from_toml(&std::fs::read_to_string(path).map_err(ConfigError::Io)?)
+    }
+    /// Reads settings from environment variables named as upper case keys with prefix, for example SP_HOST and SP_RPC_TIMEOUT for "SP_" prefix.
+    pub fn from_env(prefix: &str) -> Result<ClientConfig, ConfigError> {
+        let client_config = ClientConfig {
+            host: get_env(prefix, "host"),
+            addr: get_env(prefix, "addr"),
+            access_key: get_env(prefix, "access_key"),
+            namespace: get_env(prefix, "namespace"),
+            cfg_host: get_env(prefix, "cfg_host"),
+            cfg_domain: get_env(prefix, "cfg_domain"),
+            cfg_token: get_env(prefix, "cfg_token"),
+            max_frame_payload_size: parse_env(prefix, "max_frame_payload_size")?,
+            compression: parse_env(prefix, "compression")?,
+            compression_threshold: parse_env(prefix, "compression_threshold")?,
+            heartbeat_interval: parse_env(prefix, "heartbeat_interval")?,
+            heartbeat_timeout: parse_env(prefix, "heartbeat_timeout")?,
+            frame_checksums: parse_env(prefix, "frame_checksums")?,
+            reconnect_min_delay: parse_env(prefix, "reconnect_min_delay")?,
+            reconnect_max_delay: parse_env(prefix, "reconnect_max_delay")?,
+            rpc_retry: parse_env(prefix, "rpc_retry")?,
+            rpc_timeout: parse_env(prefix, "rpc_timeout")?,
+            max_pending_rpcs: parse_env(prefix, "max_pending_rpcs")?,
+            max_running_handlers: parse_env(prefix, "max_running_handlers")?,
+            max_running_handlers_per_key: parse_env(prefix, "max_running_handlers_per_key")?,
+            max_queued_handlers: parse_env(prefix, "max_queued_handlers")?,
+            ordered_handlers: parse_env(prefix, "ordered_handlers")?
+        };
+
+        client_config.validate()?;
+
+        Ok(client_config)
+    }
+    /// Json config for client entry points, service specific values of provided config are kept and passed to handlers.
+    pub fn to_value_with(&self, service_config: Value) -> Result<Value, ConfigError> {
+        let mut config = match service_config {
+            Value::Object(config) => config,
+            Value::Null => Map::new(),
+            service_config => return Err(ConfigError::Invalid("service config", format!("expected object, got {}", service_config)))
+        };
+
+        if let Value::Object(client_config) = serde_json::to_value(self).map_err(ConfigError::Json)? {
+            for (key, value) in client_config {
+                if !value.is_null() {
+                    config.insert(key, value);
+                }
+            }
+        }
+
+        Ok(Value::Object(config))
+    }
+    /// Host, domain and token of config service if client config is requested from it.
+    pub fn get_cfg(&self) -> Result<Option<(String, String, String)>, ConfigError> {
+        match &self.cfg_host {
+            Some(cfg_host) => Ok(Some((cfg_host.clone(), get_required(&self.cfg_domain, "cfg_domain")?.to_owned(), get_required(&self.cfg_token, "cfg_token")?.to_owned()))),
+            None => Ok(None)
+        }
+    }
+    pub fn get_addr(&self) -> Result<&str, ConfigError> {
+        get_required(&self.addr, "addr")
+    }
+    pub fn get_host(&self) -> Result<&str, ConfigError> {
+        get_required(&self.host, "host")
+    }
+    pub fn get_access_key(&self) -> Result<&str, ConfigError> {
+        get_required(&self.access_key, "access_key")
+    }
+    /// Checks that settings can be used together. Host and access key are not required here, they are not needed with loopback transport.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.get_cfg()?.is_none() && self.get_addr()?.is_empty() {
+            return Err(ConfigError::Invalid("addr", "addr must not be empty".to_owned()));
+        }
+
+        if let Some(host) = &self.host {
+            match host.rsplit_once(':') {
+                Some((name, port)) if !name.is_empty() && port.parse::<u16>().is_ok() => {}
+                _ => return Err(ConfigError::Invalid("host", format!("expected host:port, got {}", host)))
+            }
+        }
+
+        if self.max_frame_payload_size == Some(0) {
+            return Err(ConfigError::Invalid("max_frame_payload_size", "size must not be 0".to_owned()));
+        }
+
+        match (self.heartbeat_interval, self.heartbeat_timeout) {
+            (Some(0), _) => return Err(ConfigError::Invalid("heartbeat_interval", "interval must not be 0".to_owned())),
+            (Some(interval), Some(timeout)) if timeout <= interval => return Err(ConfigError::Invalid("heartbeat_timeout", format!("timeout {} must be greater than heartbeat interval {}", timeout, interval))),
+            (None, Some(_)) => return Err(ConfigError::Invalid("heartbeat_timeout", "timeout is set without heartbeat_interval".to_owned())),
+            _ => {}
+        }
+
+        if let (Some(min_delay), Some(max_delay)) = (self.reconnect_min_delay, self.reconnect_max_delay) {
+            if min_delay > max_delay {
+                return Err(ConfigError::Invalid("reconnect_min_delay", format!("min delay {} is greater than reconnect_max_delay {}", min_delay, max_delay)));
+            }
+        }
+
+        if self.rpc_timeout == Some(0) {
+            return Err(ConfigError::Invalid("rpc_timeout", "timeout must not be 0".to_owned()));
+        }
+
+        if self.max_running_handlers == Some(0) {
+            return Err(ConfigError::Invalid("max_running_handlers", "limit must not be 0".to_owned()));
+        }
+
+        if self.max_running_handlers_per_key == Some(0) {
+            return Err(ConfigError::Invalid("max_running_handlers_per_key", "limit must not be 0".to_owned()));
+        }
+
+        if self.max_queued_handlers.is_some() && self.max_running_handlers.is_none() && self.max_running_handlers_per_key.is_none() && !self.ordered_handlers.unwrap_or_default() {
+            return Err(ConfigError::Invalid("max_queued_handlers", "queue is limited without limits of running handlers".to_owned()));
+        }
+
+        if self.max_running_handlers_per_key.is_some() && self.ordered_handlers.unwrap_or_default() {
+            return Err(ConfigError::Invalid("max_running_handlers_per_key", "limit is set with ordered_handlers, they are processed one at a time per key".to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
+    /// Required value is not set
+    Missing(&'static str),
+    /// Value is set, but can't be used, reason is passed
+    Invalid(&'static str, String)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "failed to read config, {}", e),
+            ConfigError::Toml(e) => write!(f, "failed to parse config, {}", e),
+            ConfigError::Json(e) => write!(f, "failed to convert config, {}", e),
+            ConfigError::Missing(name) => write!(f, "\"{}\" is not set", name),
+            ConfigError::Invalid(name, reason) => write!(f, "\"{}\" is not valid, {}", name, reason)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn get_required<'a>(value: &'a Option<String>, name: &'static str) -> Result<&'a str, ConfigError> {
+    match value {
+        Some(value) => Ok(value),
+        None => Err(ConfigError::Missing(name))
+    }
+}
+
+fn get_string(config: &Value, name: &'static str) -> Result<Option<String>, ConfigError> {
+    match &config[name] {
+        Value::Null => Ok(None),
+        Value::String(value) => Ok(Some(value.clone())),
+        value => Err(ConfigError::Invalid(name, format!("expected string, got {}", value)))
+    }
+}
+
+fn get_u64(config: &Value, name: &'static str) -> Result<Option<u64>, ConfigError> {
+    match &config[name] {
+        Value::Null => Ok(None),
+        value => value.as_u64().map(Some).ok_or_else(|| ConfigError::Invalid(name, format!("expected non-negative integer, got {}", value)))
+    }
+}
+
+fn get_bool(config: &Value, name: &'static str) -> Result<Option<bool>, ConfigError> {
+    match &config[name] {
+        Value::Null => Ok(None),
+        value => value.as_bool().map(Some).ok_or_else(|| ConfigError::Invalid(name, format!("expected boolean, got {}", value)))
+    }
+}
+
+fn get_env(prefix: &str, name: &str) -> Option<String> {
+    std::env::var(format!("{}{}", prefix, name.to_uppercase())).ok()
+}
+
+fn parse_env<T>(prefix: &str, name: &'static str) -> Result<Option<T>, ConfigError> where T: FromStr, T::Err: fmt::Display {
+    match get_env(prefix, name) {
+        Some(value) => value.parse().map(Some).map_err(|e| ConfigError::Invalid(name, format!("failed to parse {}, {}", value, e))),
+        None => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use serde_json::json;
+    use super::{ClientConfig, ConfigError};
+
+    #[test]
+    fn client_config() {
+        let config = ClientConfig::new("Client")
+            .with_host("127.0.0.1:11001")
+            .with_access_key("")
+            .with_rpc_timeout(Duration::from_secs(5));
+        let value = config.to_value_with(json!({ "service_value": 1 })).expect("Failed to get config");
+
+        assert_eq!(value["service_value"], json!(1));
+        assert_eq!(value["rpc_timeout"], json!(5000));
+        assert_eq!(ClientConfig::from_value(&value).expect("Failed to read config"), config);
+
+        let toml_config = ClientConfig::from_toml(r#"
+            addr = "Client"
+            host = "127.0.0.1:11001"
+            access_key = ""
+            rpc_timeout = 5000
+        "#).expect("Failed to read config");
+
+        assert_eq!(toml_config, config);
+    }
+
+    #[test]
+    fn invalid_client_config() {
+        assert!(matches!(ClientConfig::from_value(&json!({ "host": "127.0.0.1:11001" })), Err(ConfigError::Missing("addr"))));
+        assert!(matches!(ClientConfig::from_value(&json!({ "cfg_host": "127.0.0.1:11002", "cfg_domain": "Cfg" })), Err(ConfigError::Missing("cfg_token"))));
+        assert!(matches!(ClientConfig::from_value(&json!({ "addr": "Client", "host": "127.0.0.1" })), Err(ConfigError::Invalid("host", _))));
+        assert!(matches!(ClientConfig::from_value(&json!({ "addr": "Client", "rpc_timeout": "5s" })), Err(ConfigError::Invalid("rpc_timeout", _))));
+        assert!(matches!(ClientConfig::from_value(&json!({ "addr": "Client", "heartbeat_interval": 1000, "heartbeat_timeout": 500 })), Err(ConfigError::Invalid("heartbeat_timeout", _))));
+        assert!(matches!(ClientConfig::from_value(&json!({ "addr": "Client", "max_frame_payload_size": 70000 })), Err(ConfigError::Invalid("max_frame_payload_size", _))));
+        assert!(matches!(ClientConfig::from_value(&json!({ "addr": "Client", "max_queued_handlers": 10 })), Err(ConfigError::Invalid("max_queued_handlers", _))));
+        assert!(ClientConfig::from_value(&json!({ "addr": "Client", "max_queued_handlers": 10, "ordered_handlers": true })).is_ok());
+
+        let e = ClientConfig::from_value(&json!({ "addr": "Client", "reconnect_min_delay": 1000, "reconnect_max_delay": 100 })).expect_err("Invalid config is read");
+
+        assert_eq!(e.to_string(), "\"reconnect_min_delay\" is not valid, min delay 1000 is greater than reconnect_max_delay 100");
+    }
+}
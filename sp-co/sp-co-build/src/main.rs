@@ -1,8 +1,11 @@
 use std::{collections::HashMap, process::ExitStatus};
-use std::io::Read;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, SeekFrom};
+use std::time::UNIX_EPOCH;
 use serde_json::{json, Value, from_value, to_vec};
 use log::*;
-use streaming_platform::{MAX_FRAME_PAYLOAD_SIZE, tokio::{self, fs::File, io::AsyncReadExt}, client, MagicBall, sp_dto::{Key, MsgMeta, Message, Response, resp}};
+use streaming_platform::{tokio::{self, fs::File, io::AsyncSeekExt}, client, MagicBall, sp_dto::{Key, MsgMeta, Message, Response, Transfer, bytes::Bytes, resp, uuid::Uuid}};
 use sp_build_core::{pack, DeployConfig, DeployUnitConfig, TargetFile, RunConfig, RunUnit};
 
 mod flow;
@@ -32,7 +35,7 @@ pub async fn process_rpc(config: Value, mut mb: MagicBall, msg: Message<Value>,
 
                 let deploy_config: DeployConfig = from_value(msg.payload).expect("Failed to deserialize build config");
 
-				mb.start_event_stream(Key::new("DeployStream", "Deploy", "Deploy"), json!({})).await.unwrap();
+				let mut stream = mb.start_event_stream(Key::new("DeployStream", "Deploy", "Deploy"), json!({})).await.unwrap();
 
                 let mut build_success = false;
 
@@ -55,7 +58,7 @@ pub async fn process_rpc(config: Value, mut mb: MagicBall, msg: Message<Value>,
                     payload.push(0x0D);
                     payload.push(0x0A);
 
-                    mb.send_frame(&payload, payload.len()).unwrap();
+                    stream.write_payload(Bytes::from(payload)).await.unwrap();
 
                     let mut handle = match build_config.args {
                         Some(args) => {
@@ -86,7 +89,7 @@ pub async fn process_rpc(config: Value, mut mb: MagicBall, msg: Message<Value>,
 
                         match n {
                             0 => break,
-                            _ => mb.send_frame(&buf[..n], n).unwrap()
+                            _ => stream.write_payload(Bytes::copy_from_slice(&buf[..n])).await.unwrap()
                         }
                     }
                 
@@ -101,7 +104,7 @@ pub async fn process_rpc(config: Value, mut mb: MagicBall, msg: Message<Value>,
                     payload.push(0x0D);
                     payload.push(0x0A);
 
-                    mb.send_frame(&payload, payload.len()).unwrap();
+                    stream.write_payload(Bytes::from(payload)).await.unwrap();
 
                     let mut build_result_msg;
 
@@ -122,7 +125,7 @@ pub async fn process_rpc(config: Value, mut mb: MagicBall, msg: Message<Value>,
                     payload.push(0x0D);
                     payload.push(0x0A);
 
-                    mb.send_frame(&payload, payload.len()).unwrap();
+                    stream.write_payload(Bytes::from(payload)).await.unwrap();
                 }
 
                 match build_success {
@@ -145,7 +148,7 @@ pub async fn process_rpc(config: Value, mut mb: MagicBall, msg: Message<Value>,
                                 payload.push(0x0D);
                                 payload.push(0x0A);
 
-                                mb.send_frame(&payload, payload.len()).unwrap();
+                                stream.write_payload(Bytes::from(payload)).await.unwrap();
                             }
                             Err(e) => {
                                 pack_result_msg = format!("Pack result is Err, {:?}", e);
@@ -159,14 +162,14 @@ pub async fn process_rpc(config: Value, mut mb: MagicBall, msg: Message<Value>,
                         payload.push(0x0D);
                         payload.push(0x0A);
 
-                        mb.send_frame(&payload, payload.len()).unwrap();
+                        stream.write_payload(Bytes::from(payload)).await.unwrap();
                     }
                     false => {
                         
                     }
                 }
 
-                mb.complete_stream().unwrap();
+                stream.finish().unwrap();
             });
 
             json!({
@@ -181,38 +184,41 @@ pub async fn process_rpc(config: Value, mut mb: MagicBall, msg: Message<Value>,
 pub async fn startup(initial_config: Value, target_config: Value, mut mb: MagicBall, startup_data: Option<Value>, _: ()) {
 }
 
-async fn deploy_unit(mut mb: MagicBall, path: &str, deploy_unit_name: &str, run_config: Option<RunConfig>) -> Result<Message<Value>, Error> {
+/// Transfer id is derived from the deploy unit file, so deploy retried after failure resumes sending of the same file.
+fn get_transfer_id(deploy_unit_name: &str, size: u64, modified: u128) -> Uuid {
+    let mut hasher = DefaultHasher::new();
+
+    deploy_unit_name.hash(&mut hasher);
+    modified.hash(&mut hasher);
+
+    Uuid::from_u128((hasher.finish() as u128) << 64 | size as u128)
+}
+
+async fn deploy_unit(mb: MagicBall, path: &str, deploy_unit_name: &str, run_config: Option<RunConfig>) -> Result<Message<Value>, Error> {
     info!("Opening file {}", path);
 
     let mut file = File::open(&path).await?;
-    let size = file.metadata().await?.len();
+    let metadata = file.metadata().await?;
+    let size = metadata.len();
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let mut mb = mb.with_transfer(Transfer::new(get_transfer_id(deploy_unit_name, size, modified), size));
 
-    let correlation_id = mb.start_rpc_stream(Key::new("DeployUnit", "Deploy", "Deploy"), json!({
+    let mut stream = mb.start_rpc_stream(Key::new("DeployUnit", "Deploy", "Deploy"), json!({
         "deploy_unit_name": deploy_unit_name,
         "run_config": run_config
     })).await?;
 
-    match size {
-        0 => {
-        }
-        _ => {
-            let mut buf = [0; MAX_FRAME_PAYLOAD_SIZE];
-
-            loop {
-                match file.read(&mut buf).await? {
-                    0 => {
-                        mb.complete_attachment()?;
-                        break;
-                    }
-                    n => {                
-                        mb.send_frame(&buf, n)?;
-                    }
-                }
-            }
-        }
+    // pod acknowledges size of the unit already received by previous attempts
+    let offset = stream.wait_transfer_offset().await?;
+
+    if offset > 0 {
+        info!("Resuming deploy unit {} from offset {}", deploy_unit_name, offset);
+        file.seek(SeekFrom::Start(offset)).await?;
     }
 
-    Ok(mb.complete_rpc_stream(correlation_id).await?)
+    stream.write_attachment(file).await?;
+
+    Ok(stream.finish_rpc().await?)
 }
 
 pub fn main() {
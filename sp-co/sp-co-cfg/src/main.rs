@@ -144,7 +144,9 @@ pub fn main() {
     let config = json!({
         "host": "127.0.0.1:11002",
         "addr": "Cfg",
-        "access_key": ""
+        "access_key": "",
+        "max_running_handlers": 16,
+        "max_queued_handlers": 1000
     });
  
     client::start_full_message(config, process_event, process_rpc, startup, None, dc);
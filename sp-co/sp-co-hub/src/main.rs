@@ -5,7 +5,14 @@ fn main() {
     env_logger::init();
     
     let config = ServerConfig {
-        host: "127.0.0.1:11002".to_owned()
+        host: "127.0.0.1:11002".to_owned(),
+        log: None,
+        hash_keys: None,
+        namespaces: None,
+        max_frame_payload_size: None,
+        heartbeat_interval: None,
+        heartbeat_timeout: None,
+        frame_checksums: None
     };
 
     let mut event_subscribes = HashMap::new();
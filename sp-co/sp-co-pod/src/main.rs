@@ -4,7 +4,7 @@ use serde_json::{json, Value, from_slice, to_vec, to_string, from_str, from_valu
 use log::*;
 use tokio::{io::AsyncWriteExt, fs::File, sync::mpsc::{UnboundedSender, UnboundedReceiver}};
 use sysinfo::{ProcessExt, SystemExt};
-use streaming_platform::{ClientMsg, Frame, FrameType, MAX_FRAME_PAYLOAD_SIZE, MagicBall, ProcessError, RestreamMsg, StreamLayout, client, sp_cfg, sp_dto::{MsgMeta, MsgType, rpc_response_dto2_sizes, Participator, RpcResult}, tokio::{self, io::AsyncReadExt}};
+use streaming_platform::{ClientMsg, Frame, FrameType, MagicBall, ProcessError, RestreamMsg, StreamLayout, TransferFile, client, sp_cfg, sp_dto::{MsgMeta, MsgType, rpc_response_dto2_sizes, Participator, RpcResult}, tokio};
 use sp_build_core::{unpack, RunConfig};
 
 struct FileStreamLayout {
@@ -13,6 +13,8 @@ struct FileStreamLayout {
     payload: Option<Value>,
     download_payload: Option<Value>,    
     file: Option<File>,
+    /// File of deploy unit sent as resumable transfer
+    transfer_file: Option<TransferFile>,
     rpc_result: RpcResult
 }
 
@@ -111,6 +113,7 @@ async fn process_client_msg(mb: &mut MagicBall, stream_layouts: &mut HashMap<u64
 										payload: None,
 										download_payload: None,
 										file: None,
+										transfer_file: None,
 										rpc_result: RpcResult::Ok
 									});
 								}
@@ -146,6 +149,7 @@ async fn process_client_msg(mb: &mut MagicBall, stream_layouts: &mut HashMap<u64
 										payload: None,
 										download_payload: None,
 										file: None,
+										transfer_file: None,
 										rpc_result: RpcResult::Ok
 									};
 
@@ -190,8 +194,23 @@ async fn process_client_msg(mb: &mut MagicBall, stream_layouts: &mut HashMap<u64
 											let payload: Value = from_slice(&stream_layout.layout.payload)?;
                                             info!("{:#?}", payload);
 											let path = String::new() + payload["deploy_unit_name"].as_str().ok_or(Error::None)?;
-                                            info!("Creating file {}", path);
-											stream_layout.file = Some(File::create(path).await?);
+
+											match msg_meta.transfer {
+												Some(transfer) => {
+													let transfer_file = TransferFile::open(&path, transfer).await?;
+
+													info!("Opened file {} at offset {}", path, transfer_file.get_offset());
+
+													// sender continues from acknowledged offset
+													mb.ack_transfer(&msg_meta.tx, frame.stream_id, transfer_file.get_offset())?;
+													stream_layout.transfer_file = Some(transfer_file);
+												}
+												None => {
+													info!("Creating file {}", path);
+													stream_layout.file = Some(File::create(path).await?);
+												}
+											}
+
 											stream_layout.payload = Some(payload);
 										}
 										_ => {}
@@ -208,8 +227,7 @@ async fn process_client_msg(mb: &mut MagicBall, stream_layouts: &mut HashMap<u64
                                 Some(payload) => {
                                     let stream_layout = stream_layouts.get_mut(&frame.stream_id).ok_or(ProcessError::StreamLayoutNotFound)?;
 
-                                    let file = stream_layout.file.as_mut().ok_or(Error::None).unwrap();
-							        file.write_all(&payload[..frame.payload_size as usize]).await?;
+                                    write_deploy_unit(mb, stream_layout, frame.stream_id, &payload[..frame.payload_size as usize]).await?;
                                 }
                                 None => {}                                
                             }													
@@ -220,13 +238,22 @@ async fn process_client_msg(mb: &mut MagicBall, stream_layouts: &mut HashMap<u64
 
                             match frame.payload {
                                 Some(payload) => {
-                                    let file = stream_layout.file.as_mut().ok_or(Error::None)?;
-							        file.write_all(&payload[..frame.payload_size as usize]).await?;
+                                    write_deploy_unit(mb, stream_layout, frame.stream_id, &payload[..frame.payload_size as usize]).await?;
                                 }
                                 None => {}                                
                             }
 
 							stream_layout.file = None;
+
+							if let Some(transfer_file) = stream_layout.transfer_file.take() {
+								if !transfer_file.is_complete() {
+									stream_layouts.remove(&frame.stream_id);
+
+									return Err(Error::CustomError(format!("Deploy unit transfer is incomplete, offset {}", transfer_file.get_offset())));
+								}
+
+								transfer_file.complete().await?;
+							}
 						}
 						FrameType::End => {
                             info!("Stream end frame");	
@@ -327,6 +354,26 @@ async fn process_client_msg(mb: &mut MagicBall, stream_layouts: &mut HashMap<u64
 								}
 							}
 						}
+						FrameType::Credit | FrameType::TransferAck | FrameType::Ping | FrameType::Pong | FrameType::RpcCancel => {}
+						FrameType::Cancel => {
+							warn!("Stream {} cancelled, {:?}", frame.stream_id, frame.get_cancel_reason());
+
+							if let Some(stream_layout) = stream_layouts.remove(&frame.stream_id) {
+								// partially written deploy unit is removed
+								if let (Some(file), Some(payload)) = (stream_layout.file, stream_layout.payload) {
+									drop(file);
+
+									if let Some(deploy_unit_name) = payload["deploy_unit_name"].as_str() {
+										tokio::fs::remove_file(deploy_unit_name).await?;
+									}
+								}
+
+								// persisted part of deploy unit sent as transfer is kept for resume
+								if let Some(transfer_file) = stream_layout.transfer_file {
+									info!("Deploy unit transfer {} kept at offset {}", transfer_file.get_transfer().id, transfer_file.get_offset());
+								}
+							}
+						}
 					}
 
 				}
@@ -343,35 +390,33 @@ async fn process_client_msg(mb: &mut MagicBall, stream_layouts: &mut HashMap<u64
     Ok(())
 }
 
+/// Deploy unit sent as resumable transfer is written with persisted progress, persisted offsets are acknowledged to the sender.
+async fn write_deploy_unit(mb: &MagicBall, stream_layout: &mut FileStreamLayout, stream_id: u64, data: &[u8]) -> Result<(), Error> {
+    match (stream_layout.transfer_file.as_mut(), stream_layout.msg_meta.as_ref()) {
+        (Some(transfer_file), Some(msg_meta)) => {
+            if let Some(offset) = transfer_file.write(data).await? {
+                mb.ack_transfer(&msg_meta.tx, stream_id, offset)?;
+            }
+        }
+        _ => stream_layout.file.as_mut().ok_or(Error::None)?.write_all(data).await?
+    }
+
+    Ok(())
+}
+
 async fn send_file(mut mb: MagicBall, msg_meta: MsgMeta, path: std::path::PathBuf, file_name: String) -> Result<(), Error> {
-    let mut file = File::open(&path).await?;
+    let file = File::open(&path).await?;
     let size = file.metadata().await?.len();
 
-    mb.start_rpc_stream_response(msg_meta, json!({
+    let mut stream = mb.start_rpc_stream_response(msg_meta, json!({
         "file_name": file_name
     })).await?;
 
-    match size {
-        0 => {
-        }
-        _ => {
-            let mut buf = [0; MAX_FRAME_PAYLOAD_SIZE];
-
-            loop {
-                match file.read(&mut buf).await? {
-                    0 => {
-                        mb.complete_attachment()?;
-                        break;
-                    }
-                    n => {                
-                        mb.send_frame(&buf, n)?;
-                    }
-                }
-            }
-        }
+    if size > 0 {
+        stream.write_attachment(file).await?;
     }
 
-    mb.complete_stream()?;
+    stream.finish()?;
 
     Ok(())
 }
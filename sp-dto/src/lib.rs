@@ -122,6 +122,11 @@ pub fn resp_full<T>(payload: T, attachments: Vec<(String, u64)>, attachments_dat
     Ok(Response::Full(payload, attachments, attachments_data))
 }
 
+/// Helper function for rpc error responses, typed errors are converted to error envelope with their codes.
+pub fn resp_err<T, E>(error: E) -> Result<Response<T>, Box<dyn std::error::Error>> where E: Into<RemoteError> {
+    Err(Box::new(error.into()))
+}
+
 pub fn resp_raw(payload: Vec<u8>) -> Result<ResponseRaw, Box<dyn std::error::Error>> {
     Ok(ResponseRaw::Simple(payload))
 }
@@ -130,6 +135,77 @@ pub fn resp_raw_full(payload: Vec<u8>, attachments: Vec<(String, u64)>, attachme
     Ok(ResponseRaw::Full(payload, attachments, attachments_data))
 }
 
+/// Code of rpc error.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Handler failed with error which is not mapped to a code
+    Internal,
+    BadRequest,
+    Unauthorized,
+    NotFound,
+    /// No subscriber for the rpc key, set by the server
+    NoRoute,
+    /// Response is not received in time, set by the server
+    Timeout,
+    /// Handler limits of the service are reached, request can be sent again later
+    Busy,
+    /// Application specific code
+    Custom(u16)
+}
+
+/// Error envelope of failed rpc, it is sent as payload of rpc response with RpcResult::Err.
+/// Handlers return it (or errors converted to it) to set error code, other handler errors are sent with Internal code.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RemoteError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default)]
+    pub details: Option<Value>
+}
+
+impl RemoteError {
+    pub fn new<M>(code: ErrorCode, message: M) -> RemoteError where M: Into<String> {
+        RemoteError {
+            code,
+            message: message.into(),
+            details: None
+        }
+    }
+    pub fn with_details(mut self, details: Value) -> RemoteError {
+        self.details = Some(details);
+        self
+    }
+    /// Converts error returned by rpc handler, RemoteError is kept as is.
+    pub fn from_error(e: &(dyn std::error::Error + 'static)) -> RemoteError {
+        match e.downcast_ref::<RemoteError>() {
+            Some(remote_error) => remote_error.clone(),
+            None => RemoteError::new(ErrorCode::Internal, e.to_string())
+        }
+    }
+    /// Reads error envelope from payload of error response. Payloads without envelope ({"err": message} sent by older peers) get Internal code.
+    pub fn from_payload(codec: Codec, payload: &[u8]) -> RemoteError {
+        if let Ok(remote_error) = decode::<RemoteError>(codec, payload) {
+            return remote_error;
+        }
+
+        match decode::<Value>(codec, payload) {
+            Ok(payload) => match payload["err"].as_str() {
+                Some(message) => RemoteError::new(ErrorCode::Internal, message),
+                None => RemoteError::new(ErrorCode::Internal, "Error response without error envelope").with_details(payload)
+            },
+            Err(e) => RemoteError::new(ErrorCode::Internal, format!("Failed to decode error response, {}", e))
+        }
+    }
+}
+
+impl std::fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
 /// Enum used for returning from processing rpc functions in raw mode
 #[derive(Debug, Serialize, Clone)]
 pub enum ResponseRaw {
@@ -268,6 +344,20 @@ impl Key {
     }
 }
 
+/// Correlation id, message data, msg meta size, payload size and attachments sizes of created message
+pub type SizedDto = (Uuid, Vec<u8>, u64, u64, Vec<u64>);
+
+/// Optional message meta fields for dto functions with options, default options give the same meta as functions without them.
+#[derive(Debug, Clone, Default)]
+pub struct MsgOptions {
+    pub auth_token: Option<String>,
+    pub auth_data: Option<Value>,
+    /// Deadline for message processing, unix time in milliseconds
+    pub deadline: Option<u64>,
+    /// Codec of payload
+    pub codec: Codec
+}
+
 /// Message meta data. Message passing protocol is build around this structure.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MsgMeta {
@@ -288,7 +378,25 @@ pub struct MsgMeta {
     /// Authorization data.
     pub auth_data: Option<Value>,
     /// Attachments to message
-	pub attachments: Vec<AttachmentMeta>
+	pub attachments: Vec<AttachmentMeta>,
+    /// Deadline for message processing, unix time in milliseconds. Expired messages are dropped by server and receivers.
+    #[serde(default)]
+    pub deadline: Option<u64>,
+    /// Retained event, server keeps last retained event for the key and delivers it to clients connected later. Retained event with empty payload clears kept event.
+    #[serde(default)]
+    pub retain: bool,
+    /// Compression of payload and attachments data. When set, payload_size and attachment sizes are sizes of compressed data.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Codec of payload, msg meta itself is always encoded with JSON.
+    #[serde(default)]
+    pub codec: Codec,
+    /// Initial credit window of flow controlled stream, in frames. Receiver grants more credits to the sender as it consumes stream frames.
+    #[serde(default)]
+    pub credits: Option<u32>,
+    /// Resumable transfer carried by stream attachment data. Receiver acknowledges persisted offset of the transfer, sender resumes from it.
+    #[serde(default)]
+    pub transfer: Option<Transfer>
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -313,12 +421,45 @@ impl MsgType {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Lz4
+}
+
+/// Payload encoding
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Codec {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+    Bincode
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum RpcResult {
     Ok,
     Err
 }
 
+/// Resumable transfer of stream attachment data. Data of the stream starts at the offset acknowledged by the receiver for this transfer.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Transfer {
+    /// Id of transferred data, sender keeps it across retries so receiver finds persisted progress
+    pub id: Uuid,
+    /// Total size of transferred data
+    pub size: u64
+}
+
+impl Transfer {
+    pub fn new(id: Uuid, size: u64) -> Transfer {
+        Transfer {
+            id,
+            size
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AttachmentMeta {
 	pub name: String,
@@ -476,7 +617,13 @@ pub fn event_dto<T>(tx: String, key: Key, payload: T, route: Route, auth_token:
         payload_size: payload.len() as u64,
         auth_token,
         auth_data,
-		attachments: vec![]
+		attachments: vec![],
+        deadline: None,
+        retain: false,
+        compression: None,
+        codec: Codec::Json,
+        credits: None,
+        transfer: None
     };
 
     let mut msg_meta = serde_json::to_vec(&msg_meta)?;    
@@ -492,7 +639,16 @@ pub fn event_dto<T>(tx: String, key: Key, payload: T, route: Route, auth_token:
 }
 
 pub fn event_dto_with_sizes<T>(tx: String, key: Key, payload: T, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Uuid, Vec<u8>, u64, u64, Vec<u64>), Error> where T: Debug, T: serde::Serialize {
-    let mut payload = serde_json::to_vec(&payload)?;
+    event_dto_with_options(tx, key, payload, route, MsgOptions {
+        auth_token,
+        auth_data,
+        ..MsgOptions::default()
+    })
+}
+
+/// Same as event_dto_with_sizes, optional meta fields are taken from options.
+pub fn event_dto_with_options<T>(tx: String, key: Key, payload: T, route: Route, options: MsgOptions) -> Result<SizedDto, Error> where T: Debug, T: serde::Serialize {
+    let mut payload = encode(options.codec, &payload)?;
     let correlation_id = Uuid::new_v4();
     let msg_meta = MsgMeta {
         tx,        
@@ -501,9 +657,15 @@ pub fn event_dto_with_sizes<T>(tx: String, key: Key, payload: T, route: Route, a
         correlation_id,
         route,
         payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: vec![]
+        auth_token: options.auth_token,
+        auth_data: options.auth_data,
+		attachments: vec![],
+        deadline: options.deadline,
+        retain: false,
+        compression: None,
+        codec: options.codec,
+        credits: None,
+        transfer: None
     };
     let payload_size = msg_meta.payload_size;
     let attachments_sizes = msg_meta.attachments_sizes();
@@ -528,7 +690,13 @@ pub fn rpc_response_dto<T>(tx: String, key: Key, correlation_id: Uuid, payload:
         payload_size: payload.len() as u64,
         auth_token,
         auth_data,
-		attachments: vec![]
+		attachments: vec![],
+        deadline: None,
+        retain: false,
+        compression: None,
+        codec: Codec::Json,
+        credits: None,
+        transfer: None
     };        
 
     let mut msg_meta = serde_json::to_vec(&msg_meta)?;    
@@ -556,7 +724,13 @@ pub fn rpc_dto<T>(tx: String, key: Key, payload: T, route: Route, auth_token: Op
         payload_size: payload.len() as u64,
         auth_token,
         auth_data,
-		attachments: vec![]
+		attachments: vec![],
+        deadline: None,
+        retain: false,
+        compression: None,
+        codec: Codec::Json,
+        credits: None,
+        transfer: None
     };
     
     let mut msg_meta = serde_json::to_vec(&msg_meta)?;
@@ -572,7 +746,16 @@ pub fn rpc_dto<T>(tx: String, key: Key, payload: T, route: Route, auth_token: Op
 }
 
 pub fn rpc_dto_with_sizes<T>(tx: String, key: Key, payload: T, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Uuid, Vec<u8>, u64, u64, Vec<u64>), Error> where T: Debug, T: serde::Serialize {
-    let mut payload = serde_json::to_vec(&payload)?;
+    rpc_dto_with_options(tx, key, payload, route, MsgOptions {
+        auth_token,
+        auth_data,
+        ..MsgOptions::default()
+    })
+}
+
+/// Same as rpc_dto_with_sizes, optional meta fields are taken from options.
+pub fn rpc_dto_with_options<T>(tx: String, key: Key, payload: T, route: Route, options: MsgOptions) -> Result<SizedDto, Error> where T: Debug, T: serde::Serialize {
+    let mut payload = encode(options.codec, &payload)?;
     let correlation_id = Uuid::new_v4();
     let msg_meta = MsgMeta {
         tx,        
@@ -581,9 +764,15 @@ pub fn rpc_dto_with_sizes<T>(tx: String, key: Key, payload: T, route: Route, aut
         correlation_id,
         route,
         payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: vec![]
+        auth_token: options.auth_token,
+        auth_data: options.auth_data,
+		attachments: vec![],
+        deadline: options.deadline,
+        retain: false,
+        compression: None,
+        codec: options.codec,
+        credits: None,
+        transfer: None
     };
     let payload_size = msg_meta.payload_size;
     let attachments_sizes = msg_meta.attachments_sizes();
@@ -619,7 +808,13 @@ pub fn rpc_dto_with_attachments<T>(tx: String, key: Key, payload: T, attachments
         payload_size: payload.len() as u64,
         auth_token,
         auth_data,
-		attachments: attachments_meta
+		attachments: attachments_meta,
+        deadline: None,
+        retain: false,
+        compression: None,
+        codec: Codec::Json,
+        credits: None,
+        transfer: None
     };
 
     let mut msg_meta = serde_json::to_vec(&msg_meta)?;    
@@ -635,6 +830,37 @@ pub fn rpc_dto_with_attachments<T>(tx: String, key: Key, payload: T, attachments
     Ok((correlation_id, buf))
 }
 
+/// Creates retained event with already encoded payload, server keeps it for the key. Empty payload clears kept event.
+pub fn retained_event_dto_with_options(tx: String, key: Key, mut payload: Vec<u8>, route: Route, options: MsgOptions) -> Result<SizedDto, Error> {
+    let correlation_id = Uuid::new_v4();
+    let msg_meta = MsgMeta {
+        tx,        
+        key,
+        msg_type: MsgType::Event,
+        correlation_id,
+        route,
+        payload_size: payload.len() as u64,
+        auth_token: options.auth_token,
+        auth_data: options.auth_data,
+		attachments: vec![],
+        deadline: options.deadline,
+        retain: true,
+        compression: None,
+        codec: options.codec,
+        credits: None,
+        transfer: None
+    };
+    let payload_size = msg_meta.payload_size;
+    let attachments_sizes = msg_meta.attachments_sizes();
+    let mut msg_meta = serde_json::to_vec(&msg_meta)?;    
+    let msg_meta_size = msg_meta.len() as u64;
+    let mut buf = vec![];
+    buf.put_u32(msg_meta.len() as u32);
+    buf.append(&mut msg_meta);
+    buf.append(&mut payload);    
+    Ok((correlation_id, buf, msg_meta_size, payload_size, attachments_sizes))
+}
+
 pub fn event_dto2(tx: String, key: Key, mut payload: Vec<u8>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Uuid, Vec<u8>), Error> {        
     let correlation_id = Uuid::new_v4();
     
@@ -647,7 +873,13 @@ pub fn event_dto2(tx: String, key: Key, mut payload: Vec<u8>, route: Route, auth
         payload_size: payload.len() as u64,
         auth_token,
         auth_data,
-		attachments: vec![]
+		attachments: vec![],
+        deadline: None,
+        retain: false,
+        compression: None,
+        codec: Codec::Json,
+        credits: None,
+        transfer: None
     };
 
     let mut msg_meta = serde_json::to_vec(&msg_meta)?;        
@@ -662,39 +894,38 @@ pub fn event_dto2(tx: String, key: Key, mut payload: Vec<u8>, route: Route, auth
     Ok((correlation_id, buf))
 }
 
-pub fn rpc_response_dto_sizes<T>(tx: String, key: Key, correlation_id: Uuid, payload: T, attachments: Vec<(String, u64)>, mut attachments_data: Vec<u8>, result: RpcResult, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Vec<u8>, u64, u64, Vec<u64>), Error> where T: Debug, T: serde::Serialize {
-    let mut payload = serde_json::to_vec(&payload)?;
-    let mut attachments_meta = vec![];
-    for (attachment_name,attachment_size) in attachments {
-        attachments_meta.push(AttachmentMeta {
-            name: attachment_name,
-            size: attachment_size
-        });                
-    }
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcResponse(result),
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
+pub fn rpc_response_dto_sizes<T>(tx: String, key: Key, correlation_id: Uuid, payload: T, attachments: Vec<(String, u64)>, attachments_data: Vec<u8>, result: RpcResult, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Vec<u8>, u64, u64, Vec<u64>), Error> where T: Debug, T: serde::Serialize {
+    rpc_response_dto_with_options(tx, key, correlation_id, Response::Full(payload, attachments, attachments_data), result, route, MsgOptions {
         auth_token,
         auth_data,
-		attachments: attachments_meta
+        ..MsgOptions::default()
+    })
+}
+
+/// Creates rpc response, payload is encoded with codec from options. Responses usually use codec of the request.
+pub fn rpc_response_dto_with_options<T>(tx: String, key: Key, correlation_id: Uuid, response: Response<T>, result: RpcResult, route: Route, options: MsgOptions) -> Result<(Vec<u8>, u64, u64, Vec<u64>), Error> where T: Debug, T: serde::Serialize {
+    let response = match response {
+        Response::Simple(payload) => ResponseRaw::Simple(encode(options.codec, &payload)?),
+        Response::Full(payload, attachments, attachments_data) => ResponseRaw::Full(encode(options.codec, &payload)?, attachments, attachments_data)
     };
-    let payload_size = msg_meta.payload_size;
-    let attachments_sizes = msg_meta.attachments_sizes();
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;
-    let msg_meta_size = msg_meta.len() as u64;     
-    let mut buf = vec![];
-    buf.put_u32(msg_meta.len() as u32);
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
-    buf.append(&mut attachments_data);    
-    Ok((buf, msg_meta_size, payload_size, attachments_sizes))
+
+    rpc_response_dto2_with_options(tx, key, correlation_id, response, result, route, options)
 }
 
-pub fn rpc_response_dto2_sizes(tx: String, key: Key, correlation_id: Uuid, mut payload: Vec<u8>, attachments: Vec<(String, u64)>, mut attachments_data: Vec<u8>, result: RpcResult, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Vec<u8>, u64, u64, Vec<u64>), Error> {
+pub fn rpc_response_dto2_sizes(tx: String, key: Key, correlation_id: Uuid, payload: Vec<u8>, attachments: Vec<(String, u64)>, attachments_data: Vec<u8>, result: RpcResult, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Vec<u8>, u64, u64, Vec<u64>), Error> {
+    rpc_response_dto2_with_options(tx, key, correlation_id, ResponseRaw::Full(payload, attachments, attachments_data), result, route, MsgOptions {
+        auth_token,
+        auth_data,
+        ..MsgOptions::default()
+    })
+}
+
+/// Creates rpc response with payload already encoded by codec from options.
+pub fn rpc_response_dto2_with_options(tx: String, key: Key, correlation_id: Uuid, response: ResponseRaw, result: RpcResult, route: Route, options: MsgOptions) -> Result<(Vec<u8>, u64, u64, Vec<u64>), Error> {
+    let (mut payload, attachments, mut attachments_data) = match response {
+        ResponseRaw::Simple(payload) => (payload, vec![], vec![]),
+        ResponseRaw::Full(payload, attachments, attachments_data) => (payload, attachments, attachments_data)
+    };
     let mut attachments_meta = vec![];
     for (attachment_name,attachment_size) in attachments {
         attachments_meta.push(AttachmentMeta {
@@ -709,9 +940,15 @@ pub fn rpc_response_dto2_sizes(tx: String, key: Key, correlation_id: Uuid, mut p
         correlation_id,
         route,
         payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: attachments_meta
+        auth_token: options.auth_token,
+        auth_data: options.auth_data,
+		attachments: attachments_meta,
+        deadline: None,
+        retain: false,
+        compression: None,
+        codec: options.codec,
+        credits: None,
+        transfer: None
     };
     let payload_size = msg_meta.payload_size;
     let attachments_sizes = msg_meta.attachments_sizes();
@@ -737,7 +974,13 @@ pub fn rpc_dto2(tx: String, key: Key, mut payload: Vec<u8>, route: Route, auth_t
         payload_size: payload.len() as u64,
         auth_token,
         auth_data,
-		attachments: vec![]
+		attachments: vec![],
+        deadline: None,
+        retain: false,
+        compression: None,
+        codec: Codec::Json,
+        credits: None,
+        transfer: None
     };
 
     let mut msg_meta = serde_json::to_vec(&msg_meta)?;
@@ -774,7 +1017,13 @@ pub fn rpc_dto_with_attachments2(tx: String, key: Key, mut payload: Vec<u8>, att
         payload_size: payload.len() as u64,
         auth_token,
         auth_data,
-		attachments: attachments_meta
+		attachments: attachments_meta,
+        deadline: None,
+        retain: false,
+        compression: None,
+        codec: Codec::Json,
+        credits: None,
+        transfer: None
     };
 
     let mut msg_meta = serde_json::to_vec(&msg_meta)?;    
@@ -805,7 +1054,7 @@ pub fn get_msg<T>(data: &[u8]) -> Result<Message<T>, Error> where T: Debug, T: s
     let msg_meta = serde_json::from_slice::<MsgMeta>(&data[4..msg_meta_offset as usize])?;
 
     let payload_offset = msg_meta_offset + msg_meta.payload_size as usize;
-    let payload = serde_json::from_slice::<T>(&data[msg_meta_offset..payload_offset])?;
+    let payload = decode::<T>(msg_meta.codec, &data[msg_meta_offset..payload_offset])?;
 
     Ok(match len > payload_offset as u32 {
         true => Message {
@@ -830,7 +1079,7 @@ pub fn get_msg_meta_and_payload<T>(data: &[u8]) -> Result<(MsgMeta, T), Error> w
 
     let payload_offset = msg_meta_offset + msg_meta.payload_size as usize;
 
-    let payload = serde_json::from_slice::<T>(&data[msg_meta_offset..payload_offset])?;    
+    let payload = decode::<T>(msg_meta.codec, &data[msg_meta_offset..payload_offset])?;    
 
     Ok((msg_meta, payload))
 }
@@ -842,7 +1091,31 @@ pub fn get_payload<T>(msg_meta: &MsgMeta, data: &[u8]) -> Result<T, Error> where
 
     let payload_offset = msg_meta_offset + msg_meta.payload_size as usize;
 
-    let payload = serde_json::from_slice::<T>(&data[msg_meta_offset..payload_offset])?;    
+    let payload = decode::<T>(msg_meta.codec, &data[msg_meta_offset..payload_offset])?;    
 
     Ok(payload)
-}
\ No newline at end of file
+}
+
+/// Encodes value with provided codec. Codec errors are returned as serde_json errors, so dto functions keep their error type.
+pub fn encode<T>(codec: Codec, value: &T) -> Result<Vec<u8>, Error> where T: serde::Serialize {
+    match codec {
+        Codec::Json => serde_json::to_vec(value),
+        Codec::MessagePack => rmp_serde::to_vec_named(value).map_err(<Error as serde::ser::Error>::custom),
+        Codec::Cbor => serde_cbor::to_vec(value).map_err(<Error as serde::ser::Error>::custom),
+        Codec::Bincode => bincode::serialize(value).map_err(<Error as serde::ser::Error>::custom)
+    }
+}
+
+/// Decodes value encoded with provided codec. Bincode payloads are decoded only to types with known structure.
+pub fn decode<T>(codec: Codec, data: &[u8]) -> Result<T, Error> where for<'de> T: serde::Deserialize<'de> {
+    match codec {
+        Codec::Json => serde_json::from_slice(data),
+        Codec::MessagePack => rmp_serde::from_slice(data).map_err(<Error as serde::de::Error>::custom),
+        Codec::Cbor => serde_cbor::from_slice(data).map_err(<Error as serde::de::Error>::custom),
+        Codec::Bincode => bincode::deserialize(data).map_err(|e| match *e {
+            // bincode is not self-describing, so it can't be decoded to types like serde_json::Value
+            bincode::ErrorKind::DeserializeAnyNotSupported => <Error as serde::de::Error>::custom("Bincode payload can't be decoded to self-describing type (like serde_json::Value), use other codec"),
+            e => <Error as serde::de::Error>::custom(e)
+        })
+    }
+}
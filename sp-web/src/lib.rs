@@ -523,6 +523,20 @@ async fn process_client_msg(mb: &mut MagicBall, stream_layouts: &mut HashMap<u64
 								}
 							}
 						}
+						FrameType::Credit | FrameType::TransferAck | FrameType::Ping | FrameType::Pong | FrameType::RpcCancel => {}
+						FrameType::Cancel => {
+							warn!("Stream {} cancelled, {:?}", frame.stream_id, frame.get_cancel_reason());
+
+							if let Some(stream_layout) = stream_layouts.remove(&frame.stream_id) {
+								for (_, (_, completion_tx)) in stream_layout.txs {
+									if let Some(completion_tx) = completion_tx {
+										if completion_tx.send(StreamCompletion::Err).is_err() {
+											error!("Failed to send stream completion")
+										}
+									}
+								}
+							}
+						}
 					}
 
 				}
@@ -8,7 +8,7 @@ use yew::agent::HandlerId;
 use yew::format::Nothing;
 use yew_services::console::ConsoleService;
 use yew_services::fetch::{self, FetchService, FetchTask};
-use sp_dto::{Key, Subscribes, Participator, MsgType, uuid::Uuid, MsgMeta, Route, RouteSpec, CmpSpec, rpc_dto, get_msg};
+use sp_dto::{Key, Subscribes, Participator, MsgType, uuid::Uuid, MsgMeta, Codec, Route, RouteSpec, CmpSpec, rpc_dto, get_msg};
 
 pub struct Worker {
     link: AgentLink<Worker>,
@@ -430,7 +430,13 @@ impl Hub {
                 payload_size: 0,
                 auth_token: self.cfg.auth_token.clone(),
                 auth_data: self.cfg.auth_data.clone(),
-                attachments: vec![]
+                attachments: vec![],
+                deadline: None,
+                retain: false,
+                compression: None,
+                codec: Codec::Json,
+                credits: None,
+                transfer: None
             }, 
             payload
         ));
@@ -451,7 +457,13 @@ impl Hub {
                 payload_size: 0,
                 auth_token: self.cfg.auth_token.clone(),
                 auth_data: self.cfg.auth_data.clone(),
-                attachments: vec![]
+                attachments: vec![],
+                deadline: None,
+                retain: false,
+                compression: None,
+                codec: Codec::Json,
+                credits: None,
+                transfer: None
             },
             payload
         ));
@@ -470,7 +482,13 @@ impl Hub {
                 payload_size: 0,
                 auth_token: self.cfg.auth_token.clone(),
                 auth_data: self.cfg.auth_data.clone(),
-                attachments: vec![]
+                attachments: vec![],
+                deadline: msg_meta.deadline,
+                retain: msg_meta.retain,
+                compression: None,
+                codec: Codec::Json,
+                credits: None,
+                transfer: None
             },
             payload
         ));
@@ -1,32 +1,35 @@
-use std::collections::HashMap;
-use serde_json::{json, Value, from_value};
-use streaming_platform::{client, MagicBall, sp_dto::{MsgMeta, Message, Response, resp}};
-
-pub async fn process_event(config: HashMap<String, String>, mut mb: MagicBall, msg: Message<Value>, _: ()) -> Result<(), Box<dyn std::error::Error>>  {
-    println!("{:#?}", msg);
-    
-    Ok(())
-}
+use std::sync::Arc;
+use serde_json::{json, Value};
+use streaming_platform::{client, MagicBall, Service, sp_dto::{Message, Response, resp}};
 
-pub async fn process_rpc(config: HashMap<String, String>, mut mb: MagicBall, msg: Message<Value>, _: ()) -> Result<Response<Value>, Box<dyn std::error::Error>> {    
-    println!("{:#?}", msg);
+struct Client2;
 
-    resp(json!({
-        "data": "hi"
-    }))
-}
+impl Service for Client2 {
+    type Payload = Value;
+
+    async fn on_event(&self, _: MagicBall, msg: Message<Value>) -> Result<(), Box<dyn std::error::Error>> {
+        println!("{:#?}", msg);
 
-pub async fn startup(config: HashMap<String, String>, mut mb: MagicBall, startup_data: Option<Value>, _: ()) {
+        Ok(())
+    }
+    async fn on_rpc(&self, _: MagicBall, msg: Message<Value>) -> Result<Response<Value>, Box<dyn std::error::Error>> {
+        println!("{:#?}", msg);
+
+        resp(json!({
+            "data": "hi"
+        }))
+    }
 }
 
-pub fn main() {
+#[tokio::main]
+pub async fn main() {
     env_logger::init();
 
-    let mut config = HashMap::new();
+    let config = json!({
+        "addr": "Client2",
+        "host": "127.0.0.1:11001",
+        "access_key": ""
+    });
 
-    config.insert("addr".to_owned(), "Client2".to_owned());
-    config.insert("host".to_owned(), "127.0.0.1:11001".to_owned());
-    config.insert("access_key".to_owned(), "".to_owned());
- 
-    client::start_full_message(config, process_event, process_rpc, startup, None, ());
- }
\ No newline at end of file
+    client::run(Arc::new(Client2), config).await;
+}
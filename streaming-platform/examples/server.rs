@@ -10,7 +10,14 @@ pub fn main() {
     env_logger::init();
 
     let config = ServerConfig {
-        host: "127.0.0.1:11001".to_owned()
+        host: "127.0.0.1:11001".to_owned(),
+        log: None,
+        hash_keys: None,
+        namespaces: None,
+        max_frame_payload_size: None,
+        heartbeat_interval: None,
+        heartbeat_timeout: None,
+        frame_checksums: None
     };
     
     let mut event_subscribes = HashMap::new();
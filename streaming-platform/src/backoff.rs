@@ -0,0 +1,61 @@
+use std::time::Duration;
+use rand::random;
+
+/// Delay before the first reconnect attempt, if other delay is not set in config.
+pub const DEFAULT_RECONNECT_MIN_DELAY_MS: u64 = 100;
+/// Max delay between reconnect attempts, if other delay is not set in config.
+pub const DEFAULT_RECONNECT_MAX_DELAY_MS: u64 = 10000;
+
+/// Exponential backoff of reconnect attempts. Delay is doubled after every attempt up to max delay,
+/// random part of up to half of it is subtracted, so clients disconnected at once don't reconnect at once.
+pub(crate) struct Backoff {
+    min_delay: Duration,
+    max_delay: Duration,
+    delay: Duration
+}
+
+impl Backoff {
+    pub fn new(min_delay: Duration, max_delay: Duration) -> Backoff {
+        let min_delay = min_delay.max(Duration::from_millis(1));
+
+        Backoff {
+            min_delay,
+            max_delay: max_delay.max(min_delay),
+            delay: min_delay
+        }
+    }
+    pub fn get_max_delay(&self) -> Duration {
+        self.max_delay
+    }
+    /// Returns delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+
+        self.delay = (self.delay * 2).min(self.max_delay);
+
+        delay - delay.mul_f64(random::<f64>() / 2.0)
+    }
+    pub fn reset(&mut self) {
+        self.delay = self.min_delay;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::Backoff;
+
+    #[test]
+    fn backoff_delays() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay()).collect();
+
+        for (delay, max) in delays.iter().zip([100, 200, 400, 800, 1000, 1000]) {
+            assert!(*delay <= Duration::from_millis(max) && *delay >= Duration::from_millis(max / 2), "{:?} is not in range of {} ms", delay, max);
+        }
+
+        backoff.reset();
+
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+}
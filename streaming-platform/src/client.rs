@@ -1,17 +1,39 @@
-use std::{collections::HashMap, hash::Hash};
+use std::collections::HashMap;
 use std::future::Future;
 use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use log::*;
 use tokio::{io::AsyncWriteExt, runtime::Runtime};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
-use tokio::sync::{mpsc::{self, UnboundedSender, UnboundedReceiver}};
-use serde_json::{json, Value, from_slice, to_vec};
+use tokio::sync::{mpsc::{self, UnboundedSender, UnboundedReceiver}, oneshot};
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+use futures::SinkExt;
+use serde_json::{json, Value, from_slice};
 use sp_dto::*;
+use sp_cfg::{ClientConfig, ConfigError};
 use crate::proto::*;
+use crate::codec::FrameCodec;
+use crate::loopback::Loopback;
+use crate::backoff::{Backoff, DEFAULT_RECONNECT_MIN_DELAY_MS, DEFAULT_RECONNECT_MAX_DELAY_MS};
+use crate::service::Service;
+use crate::limits::HandlerLimits;
+
+/// Transport used by client for reaching the server.
+#[derive(Clone)]
+pub enum Transport {
+    /// TCP connection, server network addr is taken from "host" config value
+    Tcp,
+    /// In-process server, no network connection is made
+    Loopback(Box<Loopback>)
+}
 
 /// Starts a stream based client based on provided config. Creates new runtime and blocks.
 /// Config must have "addr" key, this will be used as address for endpoint, and "host" key - network addr for the server (in host:port format)
 /// Config must have "access_key" key, this will be send for optional authorization, more information about this feature will be provided later.
+/// Other client settings are described in sp_cfg::ClientConfig, received frames are passed to process_stream as described in stream_mode.
 /// process_stream is used for stream of incoming data processing.
 /// startup is executed on the start of this function.
 /// restream_rx can be used for restreaming data somewhere else, for example returning data for incoming web request
@@ -28,7 +50,9 @@ where
 }
 
 /// Starts a message based client based on provided config. Creates new runtime and blocks.
+/// Handlers keeping state are better implemented as Service and run with run.
 /// Config must have "addr" key, this will be used as address for endpoint, and "host" key - network addr for the server (in host:port format)
+/// Other client settings are described in sp_cfg::ClientConfig.
 /// process_event is used for processing incoming message, which are marked as events via message msg_type.
 /// process_rpc is used for processing incoming message, which are marked as rpc request via message msg_type.
 /// startup is executed on the start of this function.
@@ -46,10 +70,15 @@ where
 }
 
 /// Future for stream based client based on provided config.
+/// Client settings are read from config as sp_cfg::ClientConfig (it can be built and converted with to_value_with), client is not started and error is logged if they are not valid.
+/// The whole config is passed to startup and handlers, so it can carry service specific values too.
 /// "addr" value will be used as address for endpoint, "host" value - network addr for the server (in host:port format)
 /// "access_key" value will be send for optional authorization, more information about this feature will be provided later.
-/// process_event is used for processing incoming message, which are marked as events via message msg_type.
-/// process_rpc is used for processing incoming message, which are marked as rpc request via message msg_type.
+/// Received frames are passed to process_stream as is, compressed messages (with compression set in msg meta) can be restored with decompress_message after collecting.
+/// Flow controlled streams (with credits set in msg meta) are not granted credits automatically, process_stream should grant them with MagicBall grant_credits as frames are consumed.
+/// Cancel frame ends aborted stream instead of end frame, its reason is available with Frame get_cancel_reason, data collected for the stream should be dropped.
+/// Rpc cancel frame is received when requester doesn't wait for response to rpc processed by the client anymore, its correlation id is available with Frame get_rpc_cancel_correlation_id.
+/// process_stream is used for stream of incoming data processing.
 /// startup is executed on the start of this function.
 /// restream_rx can be used for restreaming data somewhere else, for example returning data for incoming web request
 /// dependency is w/e clonable dependency needed when processing data.
@@ -59,12 +88,27 @@ where
     T: Future<Output = ()> + Send,
     R: Future<Output = ()> + Send,
     D: Clone + Send + Sync
+{
+    stream_mode_with_transport(Transport::Tcp, config, process_stream, startup, startup_data, restream_tx, restream_rx, dependency).await
+}
+
+/// Same as stream_mode, but server is reached with provided transport. With loopback transport "host" and "access_key" config values are not needed.
+pub async fn stream_mode_with_transport<T, R, D>(transport: Transport, config: Value, process_stream: ProcessStream<T, D>, startup: Startup<R, D>, startup_data: Option<Value>, restream_tx: Option<UnboundedSender<RestreamMsg>>, restream_rx: Option<UnboundedReceiver<RestreamMsg>>, dependency: D)
+where 
+    T: Future<Output = ()> + Send + 'static,
+    R: Future<Output = ()> + Send + 'static,
+    D: Clone + Send + Sync + 'static
 {
 	let initial_config = config.clone();
-    let target_config = match config["cfg_host"].as_str() {
-        Some(cfg_host) => {
-			let cfg_domain = config["cfg_domain"].as_str().expect("cfg_domain not passed");
-            let cfg_token = config["cfg_token"].as_str().expect("cfg_token not passed");
+    let cfg = match ClientConfig::from_value(&config).and_then(|client_config| client_config.get_cfg()) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("Invalid client config, {}", e);
+            return;
+        }
+    };
+    let target_config = match cfg {
+        Some((cfg_host, cfg_domain, cfg_token)) => {
             let (cfg_tx, mut cfg_rx) = mpsc::unbounded_channel();
 			let (rpc_inbound_tx, mut rpc_inbound_rx) = mpsc::unbounded_channel();
 			let (write_tx, write_rx) = mpsc::unbounded_channel();
@@ -72,7 +116,7 @@ where
 			let rpc_completion_tx = rpc_inbound_tx.clone();
 			let completion_tx = write_tx.clone();
 
-            tokio::spawn(cfg_mode(cfg_host.to_owned(), cfg_domain.to_owned(), cfg_token.to_owned(), rpc_inbound_tx, rpc_inbound_rx, write_tx, write_rx, cfg_tx));
+            tokio::spawn(cfg_mode(transport.clone(), cfg_host, cfg_domain, cfg_token, rpc_inbound_tx, rpc_inbound_rx, write_tx, write_rx, cfg_tx));
 
             let res = cfg_rx.recv().await.expect("Failed to get config");
 			
@@ -91,9 +135,14 @@ where
         None => config
     };
 
-    let host = target_config["host"].as_str().expect("Failed to get host from config").to_owned();
-    let addr = target_config["addr"].as_str().expect("Failed to get addr from config").to_owned();
-    let access_key = target_config["access_key"].as_str().expect("Failed to get access key from config").to_owned();
+    let (client_config, connect_settings) = match get_client_config(&transport, &target_config) {
+        Ok(res) => res,
+        Err(e) => {
+            error!("Invalid client config, {}", e);
+            return;
+        }
+    };
+    let addr = connect_settings.addr.clone();
 
     let (read_tx, read_rx) = mpsc::unbounded_channel();
     let (write_tx, write_rx) = mpsc::unbounded_channel();
@@ -104,7 +153,10 @@ where
         let mut rpcs = HashMap::new();        
 
         loop {
-            let msg = rpc_inbound_rx.recv().await.expect("rpc inbound msg receive failed");
+            let msg = match rpc_inbound_rx.recv().await {
+                Some(msg) => msg,
+                None => break
+            };
 
             match msg {
                 RpcMsg::AddRpc(correlation_id, rpc_tx) => {
@@ -112,6 +164,11 @@ where
 
                     info!("stream_mode: add rpc ok, correlation_id {}", correlation_id);
                 }                
+                RpcMsg::RemoveRpc(correlation_id) => {
+                    rpcs.remove(&correlation_id);
+
+                    debug!("stream_mode: remove rpc ok, correlation_id {}", correlation_id);
+                }
                 RpcMsg::RpcDataRequest(correlation_id) => {
                     match rpcs.remove(&correlation_id) {
                         Some(rpc_tx) => {
@@ -131,18 +188,29 @@ where
 		info!("Rpc loop completed");
     });
 
-    let mb = MagicBall::new(addr.to_owned(), write_tx, rpc_inbound_tx);
+    let mut mb = MagicBall::new(addr.to_owned(), write_tx, rpc_inbound_tx);
+
+    set_client_settings(&mut mb, &client_config);
+
+    let mb2 = mb.clone();
+
     tokio::spawn(process_stream(target_config.clone(), mb.clone(), read_rx, restream_tx, restream_rx, dependency.clone()));
     tokio::spawn(startup(initial_config, target_config, mb, startup_data, dependency));
-    connect_stream_future(CompleteCondition::Never, host, addr.to_owned(), access_key.to_owned(), read_tx, write_rx).await;
+    match transport {
+        Transport::Tcp => connect_future(ReadMode::Stream(CompleteCondition::Never), connect_settings, mb2, read_tx, write_rx).await,
+        Transport::Loopback(loopback) => connect_stream_loopback(*loopback, CompleteCondition::Never, addr, mb2, read_tx, write_rx).await
+    }
 }
 
 /// Future for message based client based on provided config.
+/// Client settings are read from config as sp_cfg::ClientConfig (it can be built and converted with to_value_with), client is not started and error is logged if they are not valid.
+/// The whole config is passed to startup and handlers, so it can carry service specific values too.
 /// "addr" value will be used as address for endpoint, "host" value - network addr for the server (in host:port format)
 /// "access_key" value will be send for optional authorization, more information about this feature will be provided later.
-/// process_stream is used for stream of incoming data processing.
+/// process_event is used for processing incoming message, which are marked as events via message msg_type.
+/// process_rpc is used for processing incoming message, which are marked as rpc request via message msg_type.
+/// process_rpc is stopped when requester doesn't wait for the response anymore (rpc future is dropped or timed out).
 /// startup is executed on the start of this function.
-/// restream_rx can be used for restreaming data somewhere else, for example returning data for incoming web request
 /// dependency is w/e clonable dependency needed when processing data.
 /// The protocol message format is in sp-dto crate.
 pub async fn full_message_mode<P: 'static, T: 'static, Q: 'static, R: 'static, D: 'static>(config: Value, process_event: ProcessEvent<T, P, D>, process_rpc: ProcessRpc<Q, P, D>, startup: Startup<R, D>, startup_data: Option<Value>, dependency: D)
@@ -152,12 +220,29 @@ where
     R: Future<Output = ()> + Send,
     P: serde::Serialize, for<'de> P: serde::Deserialize<'de> + Send,
     D: Clone + Send + Sync
+{
+    full_message_mode_with_transport(Transport::Tcp, config, process_event, process_rpc, startup, startup_data, dependency).await
+}
+
+/// Same as full_message_mode, but server is reached with provided transport. With loopback transport "host" and "access_key" config values are not needed.
+pub async fn full_message_mode_with_transport<P, T, Q, R, D>(transport: Transport, config: Value, process_event: ProcessEvent<T, P, D>, process_rpc: ProcessRpc<Q, P, D>, startup: Startup<R, D>, startup_data: Option<Value>, dependency: D)
+where 
+    T: Future<Output = Result<(), Box<dyn Error>>> + Send + 'static,
+    Q: Future<Output = Result<Response<P>, Box<dyn Error>>> + Send + 'static,
+    R: Future<Output = ()> + Send + 'static,
+    P: serde::Serialize + 'static, for<'de> P: serde::Deserialize<'de> + Send,
+    D: Clone + Send + Sync + 'static
 {
 	let initial_config = config.clone();
-    let target_config = match config["cfg_host"].as_str() {
-        Some(cfg_host) => {
-			let cfg_domain = config["cfg_domain"].as_str().expect("cfg_domain not passed");
-            let cfg_token = config["cfg_token"].as_str().expect("cfg_token not passed");
+    let cfg = match ClientConfig::from_value(&config).and_then(|client_config| client_config.get_cfg()) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("Invalid client config, {}", e);
+            return;
+        }
+    };
+    let target_config = match cfg {
+        Some((cfg_host, cfg_domain, cfg_token)) => {
             let (cfg_tx, mut cfg_rx) = mpsc::unbounded_channel();
 			let (rpc_inbound_tx, mut rpc_inbound_rx) = mpsc::unbounded_channel();
 			let (write_tx, write_rx) = mpsc::unbounded_channel();			
@@ -165,7 +250,7 @@ where
             let rpc_completion_tx = rpc_inbound_tx.clone();
 			let completion_tx = write_tx.clone();
 
-            tokio::spawn(cfg_mode(cfg_host.to_owned(), cfg_domain.to_owned(), cfg_token.to_owned(), rpc_inbound_tx, rpc_inbound_rx, write_tx, write_rx, cfg_tx));
+            tokio::spawn(cfg_mode(transport.clone(), cfg_host, cfg_domain, cfg_token, rpc_inbound_tx, rpc_inbound_rx, write_tx, write_rx, cfg_tx));
 
             let res = cfg_rx.recv().await.expect("Failed to get config");
 			
@@ -184,19 +269,21 @@ where
         None => config
     };
 
-    let host = target_config["host"].as_str().expect("Failed to get host from config").to_owned();
-    let addr = target_config["addr"].as_str().expect("Failed to get addr from config");
-    let access_key = target_config["access_key"].as_str().expect("Failed to get access key from config");
+    let (client_config, connect_settings) = match get_client_config(&transport, &target_config) {
+        Ok(res) => res,
+        Err(e) => {
+            error!("Invalid client config, {}", e);
+            return;
+        }
+    };
+    let addr = connect_settings.addr.clone();
 
     let (read_tx, mut read_rx) = mpsc::unbounded_channel();
     let (write_tx, write_rx) = mpsc::unbounded_channel();
     let (rpc_inbound_tx, mut rpc_inbound_rx) = mpsc::unbounded_channel();
     let (rpc_outbound_tx, mut rpc_outbound_rx) = mpsc::unbounded_channel();
 
-    let addr = addr.to_owned();
-    let addr2 = addr.to_owned();
-    let addr3 = addr.to_owned();
-    let access_key = access_key.to_owned();
+    let addr2 = addr.clone();
 
     let rpc_inbound_tx2 = rpc_inbound_tx.clone();
 
@@ -204,7 +291,10 @@ where
         let mut rpcs = HashMap::new();        
 
         loop {
-            let msg = rpc_inbound_rx.recv().await.expect("Rpc inbound msg receive failed");
+            let msg = match rpc_inbound_rx.recv().await {
+                Some(msg) => msg,
+                None => break
+            };
 
             match msg {
                 RpcMsg::AddRpc(correlation_id, rpc_tx) => {
@@ -212,6 +302,11 @@ where
 
                     info!("full_message_mode: add rpc ok, correlation_id {}", correlation_id);
                 }                
+                RpcMsg::RemoveRpc(correlation_id) => {
+                    rpcs.remove(&correlation_id);
+
+                    debug!("full_message_mode: remove rpc ok, correlation_id {}", correlation_id);
+                }
                 RpcMsg::RpcDataRequest(correlation_id) => {
                     match rpcs.remove(&correlation_id) {
                         Some(rpc_tx) => {
@@ -221,7 +316,14 @@ where
                             }
                             //info!("send rpc response ok {}", correlation_id);
                         }
-                        None => error!("full_message_mode: not found rpc data for removal, correlation_id {}", correlation_id)
+                        None => {
+                            error!("full_message_mode: not found rpc data for removal, correlation_id {}", correlation_id);
+
+                            match rpc_outbound_tx.send(RpcMsg::RpcDataNotFound(correlation_id)) {
+                                Ok(()) => {}
+                                Err(_) => panic!("full_message_mode: rpc outbound tx send failed on rpc data request")
+                            }
+                        }
                     }
                 }
 				RpcMsg::Complete => break,
@@ -232,11 +334,19 @@ where
 		info!("Rpc loop completed");
     });
 
-    tokio::spawn(async move {
-        let mb = MagicBall::new(addr2, write_tx, rpc_inbound_tx);        
+    let mut mb = MagicBall::new(addr2, write_tx, rpc_inbound_tx);        
+
+    set_client_settings(&mut mb, &client_config);
 
+    let mb2 = mb.clone();
+
+    tokio::spawn(async move {
         tokio::spawn(startup(initial_config, target_config.clone(), mb.clone(), startup_data, dependency.clone()));
 
+        // rpc handlers being processed, they are stopped by cancel senders
+        let mut running_rpcs: HashMap<uuid::Uuid, oneshot::Sender<()>> = HashMap::new();
+        let mut limits = HandlerLimits::new(&client_config);
+
         loop {                        
             let msg = match read_rx.recv().await {
                 Some(msg) => msg,
@@ -256,53 +366,127 @@ where
 
                             debug!("Client got event {}", msg_meta.display());
 
+                            if deadline_expired(msg_meta.deadline) {
+                                warn!("Client {} skipped event with expired deadline {}", mb.addr, msg_meta.display());
+                                continue;
+                            }
+
+                            mb.deadline = msg_meta.deadline;
+
+                            let turn = match limits.get_turn(&msg_meta.key) {
+                                Some(turn) => turn,
+                                None => {
+                                    warn!("Client {} dropped event, handler queue is full, {}", mb.addr, msg_meta.display());
+                                    continue;
+                                }
+                            };
+
                             tokio::spawn(async move {
                                 let key = msg_meta.key.clone();
-                                let payload: P = from_slice(&payload).expect("Failed to deserialize event payload");                                
-                                if let Err(e) = process_event(config, mb.clone(), Message {meta: msg_meta, payload, attachments_data}, dependency).await {
-                                    error!("Process event error {}, {:?}, {:?}", mb.addr.clone(), key, e);
+                                let deadline = msg_meta.deadline;
+                                let payload: P = match decode(msg_meta.codec, &payload) {
+                                    Ok(payload) => payload,
+                                    Err(e) => {
+                                        error!("Client {} failed to decode event payload, {}, {}", mb.addr, msg_meta.display(), e);
+                                        return;
+                                    }
+                                };
+                                match run_until_deadline(deadline, turn.run(process_event(config, mb.clone(), Message {meta: msg_meta, payload, attachments_data}, dependency))).await {
+                                    Ok(Ok(())) => debug!("Client {} process_event succeeded", mb.addr),
+                                    Ok(Err(e)) => error!("Process event error {}, {:?}, {:?}", mb.addr.clone(), key, e),
+                                    Err(_) => warn!("Process event cancelled {}, {:?}, deadline expired", mb.addr, key)
                                 }
-                                debug!("Client {} process_event succeeded", mb.addr);
                             });                            
                         }
                         MsgType::RpcRequest => {                        
                             debug!("Client got rpc request {}", msg_meta.display());
 
+                            if deadline_expired(msg_meta.deadline) {
+                                warn!("Client {} skipped rpc request with expired deadline {}", mb.addr, msg_meta.display());
+                                continue;
+                            }
+
+                            mb.deadline = msg_meta.deadline;
+
+                            let (cancel_tx, cancel_rx) = oneshot::channel();
+
+                            running_rpcs.retain(|_, cancel_tx| !cancel_tx.is_closed());
+                            running_rpcs.insert(msg_meta.correlation_id, cancel_tx);
+
+                            let turn = limits.get_turn(&msg_meta.key);
+
                             tokio::spawn(async move {
                                 let mut route = msg_meta.route.clone();
                                 let correlation_id = msg_meta.correlation_id;
                                 let key = msg_meta.key.clone();
-                                let payload: P = from_slice(&payload).expect("failed to deserialize rpc request payload");
+                                let deadline = msg_meta.deadline;
+                                let codec = msg_meta.codec;
 								let source_hash = get_addr_hash(&msg_meta.tx);
 
-                                let (payload, attachments, attachments_data, rpc_result) = match process_rpc(config.clone(), mb.clone(), Message {meta: msg_meta, payload, attachments_data}, dependency).await {
-                                    Ok(res) => {
-                                        debug!("Client {} process_rpc succeeded", mb.addr);
-                                        let (res, attachments, attachments_data) = match res {
-                                            Response::Simple(payload) => (payload, vec![], vec![]),
-                                            Response::Full(payload, attachments, attachments_data) => (payload, attachments, attachments_data)
-                                        };
-                                        (to_vec(&res).expect("Failed to serialize rpc process result"), attachments, attachments_data, RpcResult::Ok)
+                                let res = match (decode::<P>(codec, &payload), turn) {
+                                    (Ok(payload), Some(turn)) => tokio::select! {
+                                        res = run_until_deadline(deadline, turn.run(process_rpc(config.clone(), mb.clone(), Message {meta: msg_meta, payload, attachments_data}, dependency))) => match res {
+                                            Ok(Ok(res)) => {
+                                                debug!("Client {} process_rpc succeeded", mb.addr);
+                                                Ok(res)
+                                            }
+                                            Ok(Err(e)) =>  {
+                                                error!("Process rpc error {}, {:?}, {:?}", mb.addr.clone(), key, e);
+                                                Err(RemoteError::from_error(e.as_ref()))
+                                            }
+                                            Err(_) => {
+                                                // nobody waits for the response after deadline
+                                                warn!("Process rpc cancelled {}, {:?}, deadline expired", mb.addr, key);
+                                                return;
+                                            }
+                                        },
+                                        Ok(()) = cancel_rx => {
+                                            warn!("Process rpc cancelled {}, {:?}, requester doesn't wait for response", mb.addr, key);
+                                            return;
+                                        }
+                                    },
+                                    (Ok(_), None) => {
+                                        warn!("Client {} rejected rpc, handler queue is full, {}", mb.addr, msg_meta.display());
+                                        Err(RemoteError::new(ErrorCode::Busy, "Handler queue is full"))
                                     }
-                                    Err(e) =>  {
-                                        error!("Process rpc error {}, {:?}, {:?}", mb.addr.clone(), key, e);
-                                        (to_vec(&json!({ "err": e.to_string() })).expect("failed to serialize rpc process error result"), vec![], vec![], RpcResult::Err)
+                                    (Err(e), _) => {
+                                        error!("Client {} failed to decode rpc request payload, {}, {}", mb.addr, msg_meta.display(), e);
+                                        Err(RemoteError::new(ErrorCode::BadRequest, format!("Failed to decode rpc request payload, {}", e)))
                                     }
-                                };                                
+                                };
+
+                                let (payload, attachments, attachments_data, rpc_result, codec) = match encode_rpc_result(codec, res) {
+                                    Ok(res) => res,
+                                    Err(e) => {
+                                        error!("Client {} failed to encode rpc error, {:?}, {}", mb.addr, key, e);
+                                        return;
+                                    }
+                                };
 
                                 route.points.push(Participator::Service(mb.addr.clone()));
 
                                 let key_hash = get_key_hash(&key);                                
 
-                                let (res, msg_meta_size, payload_size, attachments_sizes) = rpc_response_dto2_sizes(mb.addr.clone(),  key, correlation_id, payload, attachments, attachments_data, rpc_result, route, None, None).expect("failed to create rpc reply");
+                                let msg_type = MsgType::RpcResponse(rpc_result.clone()).get_u8();
+                                let (res, msg_meta_size, payload_size, attachments_sizes) = match rpc_response_dto2_with_options(mb.addr.clone(), key.clone(), correlation_id, ResponseRaw::Full(payload, attachments, attachments_data), rpc_result, route, MsgOptions {
+                                    codec,
+                                    ..MsgOptions::default()
+                                }) {
+                                    Ok(res) => res,
+                                    Err(e) => {
+                                        error!("Client {} failed to create rpc response, {:?}, {}", mb.addr, key, e);
+                                        return;
+                                    }
+                                };
 
                                 debug!("Client {} attempt to write rpc response", mb.addr);
 
                                 let stream_id = mb.get_stream_id();
 
-                                mb.write_full_message(MsgType::RpcResponse(RpcResult::Ok).get_u8(), key_hash, stream_id, source_hash, res, msg_meta_size, payload_size, attachments_sizes, true).await.expect("Failed to write rpc response");
-                             
-                                debug!("Client {} write rpc response succeded", mb.addr);
+                                match mb.write_full_message(msg_type, key_hash, stream_id, source_hash, res, msg_meta_size, payload_size, attachments_sizes, true).await {
+                                    Ok(()) => debug!("Client {} write rpc response succeded", mb.addr),
+                                    Err(e) => error!("Client {} failed to write rpc response, {:?}, {:?}", mb.addr, key, e)
+                                }
                             });                            
                         }
                         MsgType::RpcResponse(_) => {           
@@ -314,7 +498,13 @@ where
                                 }
                                 Err(_) => panic!("Rpc inbound tx2 msg send failed on rpc response")
                             }
-                            let msg = rpc_outbound_rx.recv().await.expect("Rpc outbound msg receive failed");                            
+                            let msg = match rpc_outbound_rx.recv().await {
+                                Some(msg) => msg,
+                                None => {
+                                    error!("Client {} rpc loop is completed, rpc response dropped, {}", mb.addr, msg_meta.display());
+                                    break;
+                                }
+                            };
 
                             match msg {
                                 RpcMsg::RpcDataResponse(received_correlation_id, rpc_tx) => {
@@ -330,20 +520,149 @@ where
                                         false => error!("Received_correlation_id not equals correlation_id: {}, {}", received_correlation_id, msg_meta.correlation_id)
                                     }
                                 }
+                                RpcMsg::RpcDataNotFound(_) => warn!("Client {} dropped rpc response, requester is not waiting for it, {}", mb.addr, msg_meta.display()),
                                 _ => error!("Client handler: wrong RpcMsg")
                             }                                
                         }
                     }
                 }
+                ClientMsg::RpcCancelled(_, correlation_id) => {
+                    if let Some(cancel_tx) = running_rpcs.remove(&correlation_id) {
+                        let _ = cancel_tx.send(());
+                    }
+                }
+                ClientMsg::Cancelled(_, msg_meta, reason) => {
+                    if let MsgType::RpcResponse(_) = msg_meta.msg_type {
+                        warn!("Rpc response cancelled, {:?}, {}", reason, msg_meta.display());
+
+                        // waiting rpc fails when its response sender is dropped
+                        match rpc_inbound_tx2.send(RpcMsg::RpcDataRequest(msg_meta.correlation_id)) {
+                            Ok(()) => {}
+                            Err(_) => panic!("Rpc inbound tx2 msg send failed on rpc response cancellation")
+                        }
+
+                        if rpc_outbound_rx.recv().await.is_none() {
+                            error!("Client {} rpc loop is completed", mb.addr);
+                            break;
+                        }
+                    }
+                }
                 _ => {}
             }
         }    
     });
 
-    connect_full_message_future(&host, addr3, access_key, read_tx, write_rx).await;
+    match transport {
+        Transport::Tcp => connect_future(ReadMode::FullMessage, connect_settings, mb2, read_tx, write_rx).await,
+        Transport::Loopback(loopback) => connect_full_message_loopback(*loopback, addr, mb2, read_tx, write_rx).await
+    }
+}
+
+/// Runs message based client for the service, config values are the same as for full_message_mode.
+/// Received events and rpcs are passed to service on_event and on_rpc, service on_stop is called when client is completed or interrupted with ctrl-c.
+pub async fn run<S: Service>(service: Arc<S>, config: Value) {
+    run_with_transport(Transport::Tcp, service, config).await
+}
+
+/// Same as run, but server is reached with provided transport.
+pub async fn run_with_transport<S: Service>(transport: Transport, service: Arc<S>, config: Value) {
+    run_until_stopped(service.clone(), full_message_mode_with_transport(transport, config, process_service_event::<S>, process_service_rpc::<S>, service_startup::<S>, None, service)).await
+}
+
+/// Runs stream based client for the service, config values are the same as for stream_mode.
+/// Received frames are passed to service on_stream, service on_stop is called when client is completed or interrupted with ctrl-c.
+pub async fn run_stream<S: Service>(service: Arc<S>, config: Value) {
+    run_stream_with_transport(Transport::Tcp, service, config).await
+}
+
+/// Same as run_stream, but server is reached with provided transport.
+pub async fn run_stream_with_transport<S: Service>(transport: Transport, service: Arc<S>, config: Value) {
+    run_until_stopped(service.clone(), stream_mode_with_transport(transport, config, process_service_stream::<S>, service_startup::<S>, None, None, None, service)).await
+}
+
+async fn run_until_stopped<S: Service>(service: Arc<S>, client: impl Future<Output = ()>) {
+    tokio::select! {
+        _ = client => info!("Client completed"),
+        _ = tokio::signal::ctrl_c() => info!("Client interrupted")
+    }
+
+    service.on_stop().await;
+}
+
+async fn process_service_event<S: Service>(_: Value, mb: MagicBall, msg: Message<S::Payload>, service: Arc<S>) -> Result<(), Box<dyn Error>> {
+    service.on_event(mb, msg).await
 }
 
-async fn auth(addr: String, access_key: String, tcp_stream: &mut TcpStream) -> Result<(), ProcessError> {
+async fn process_service_rpc<S: Service>(_: Value, mb: MagicBall, msg: Message<S::Payload>, service: Arc<S>) -> Result<Response<S::Payload>, Box<dyn Error>> {
+    service.on_rpc(mb, msg).await
+}
+
+async fn process_service_stream<S: Service>(_: Value, mb: MagicBall, rx: UnboundedReceiver<ClientMsg>, _: Option<UnboundedSender<RestreamMsg>>, _: Option<UnboundedReceiver<RestreamMsg>>, service: Arc<S>) {
+    service.on_stream(mb, rx).await
+}
+
+async fn service_startup<S: Service>(_: Value, config: Value, mb: MagicBall, _: Option<Value>, service: Arc<S>) {
+    service.on_start(config, mb).await
+}
+
+/// Sends pings to the server with heartbeat interval, if it is set. Pings are skipped while client is disconnected and stopped when write channel is closed.
+fn start_heartbeat(mb: &MagicBall) {
+    if let Some(interval) = mb.heartbeat.get_interval() {
+        let mb = mb.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+
+                if !mb.is_connected() {
+                    continue;
+                }
+
+                if mb.send_ping().is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Runs future until deadline, ProcessError::DeadlineExpired is returned if deadline is reached first.
+async fn run_until_deadline<F>(deadline: Option<u64>, future: F) -> Result<F::Output, ProcessError> where F: Future {
+    match get_time_left(deadline) {
+        Some(time_left) => timeout(time_left, future).await.map_err(|_| ProcessError::DeadlineExpired),
+        None => Ok(future.await)
+    }
+}
+
+/// Payload, attachments, attachments data, result and payload codec of rpc response
+type RpcReply = (Vec<u8>, Vec<(String, u64)>, Vec<u8>, RpcResult, Codec);
+
+/// Encodes rpc handler result as response payload, result which can't be encoded is replied with Internal error.
+/// Errors are encoded with JSON, so requesters are able to read them whatever codec is used for payloads.
+fn encode_rpc_result<P>(codec: Codec, res: Result<Response<P>, RemoteError>) -> Result<RpcReply, serde_json::Error> where P: serde::Serialize {
+    let error = match res {
+        Ok(res) => {
+            let (payload, attachments, attachments_data) = match res {
+                Response::Simple(payload) => (payload, vec![], vec![]),
+                Response::Full(payload, attachments, attachments_data) => (payload, attachments, attachments_data)
+            };
+
+            match encode(codec, &payload) {
+                Ok(payload) => return Ok((payload, attachments, attachments_data, RpcResult::Ok, codec)),
+                Err(e) => {
+                    error!("Failed to encode rpc result, {}", e);
+                    RemoteError::new(ErrorCode::Internal, format!("Failed to encode rpc result, {}", e))
+                }
+            }
+        }
+        Err(error) => error
+    };
+
+    Ok((encode(Codec::Json, &error)?, vec![], vec![], RpcResult::Err, Codec::Json))
+}
+
+/// Sends authorization message, connection role tells the server if it carries frames written by the client ("write") or frames sent to the client ("read").
+async fn auth<S>(addr: String, access_key: String, namespace: Option<String>, connection: &str, frames: &mut Framed<S, FrameCodec>) -> Result<(), ProcessError> where S: AsyncRead + AsyncWrite + Unpin {
     let route = Route {
         source: Participator::Service(addr.clone()),
         spec: RouteSpec::Simple,
@@ -351,206 +670,439 @@ async fn auth(addr: String, access_key: String, tcp_stream: &mut TcpStream) -> R
     };  
 
     let (correlation_id, dto, msg_meta_size, payload_size, attachments_size) = rpc_dto_with_sizes(addr.clone(), Key::simple("Auth"), json!({
-        "access_key": access_key
+        "access_key": access_key,
+        "namespace": namespace,
+        "connection": connection
     }), route, None, None).expect("Failed to create auth dto");
 
-    write_to_tcp_stream(tcp_stream, 0, 0, get_stream_id_onetime(&addr), get_addr_hash(&addr), dto, msg_meta_size, payload_size, attachments_size, true).await
+    let sizes = MsgSizes {
+        msg_meta: msg_meta_size,
+        payload: payload_size,
+        attachments: attachments_size
+    };
+
+    for frame in get_message_frames(0, 0, get_stream_id_onetime(&addr), get_addr_hash(&addr), bytes::Bytes::from(dto), sizes, DEFAULT_FRAME_PAYLOAD_SIZE) {
+        frames.send(frame).await?;
+    }
+
+    Ok(())
+}
+
+/// Exchanges handshakes with the server, agreed frame size and capabilities are applied to write settings.
+/// Frame codec of the connection is returned, it writes and checks frame checksums if they are agreed.
+async fn handshake_stream<S>(mut stream: S, write_settings: &WriteSettings) -> Result<Framed<S, FrameCodec>, ProcessError> where S: AsyncRead + AsyncWrite + Unpin {
+    let local = Handshake::new(write_settings.get_requested_frame_payload_size() as u16).with_capabilities(write_settings.get_requested_capabilities());
+    let agreed = handshake(&mut stream, &local).await?;
+
+    debug!("Handshake completed, protocol version {}, capabilities {:?}, max frame payload size {}", agreed.version, agreed.capabilities, agreed.max_frame_payload_size);
+
+    write_settings.set_agreed(&agreed);
+
+    // frames of other clients are routed as they are written, so read frames are limited only by protocol max payload size
+    Ok(Framed::new(stream, FrameCodec::new(agreed.capabilities.contains(Capabilities::CHECKSUM), MAX_NEGOTIATED_FRAME_PAYLOAD_SIZE)))
+}
+
+/// Network settings of the client connection to the server.
+struct ConnectSettings {
+    host: String,
+    addr: String,
+    access_key: String,
+    namespace: Option<String>,
+    /// Delays between attempts to connect to the server
+    backoff: Backoff
+}
+
+/// Reads client settings from config passed to entry point, "host" and "access_key" values are required with tcp transport only.
+fn get_client_config(transport: &Transport, config: &Value) -> Result<(ClientConfig, ConnectSettings), ConfigError> {
+    let client_config = ClientConfig::from_value(config)?;
+    let (host, access_key) = match transport {
+        Transport::Tcp => (client_config.get_host()?.to_owned(), client_config.get_access_key()?.to_owned()),
+        Transport::Loopback(_) => (String::new(), String::new())
+    };
+    let connect_settings = ConnectSettings {
+        host,
+        addr: client_config.get_addr()?.to_owned(),
+        access_key,
+        namespace: client_config.namespace.clone(),
+        backoff: get_backoff(&client_config)
+    };
+
+    Ok((client_config, connect_settings))
 }
 
+/// Applies client settings to MagicBall before the client is connected.
+fn set_client_settings(mb: &mut MagicBall, config: &ClientConfig) {
+    if let Some(max_frame_payload_size) = config.max_frame_payload_size {
+        mb.set_max_frame_payload_size(max_frame_payload_size as usize);
+    }
+
+    if config.compression.unwrap_or_default() {
+        mb.set_compression_threshold(Some(config.compression_threshold.map(|threshold| threshold as usize).unwrap_or(DEFAULT_COMPRESSION_THRESHOLD)));
+    }
 
-async fn connect_stream_future(complete_condition: CompleteCondition, host: String, addr: String, access_key: String, read_tx: UnboundedSender<ClientMsg>, write_rx: UnboundedReceiver<WriteMsg>) {
-    let mut write_stream = TcpStream::connect(host.clone()).await.expect("Connection to host failed");
-    auth(addr.clone(), access_key.clone(), &mut write_stream).await.expect("Write stream authorization failed");
+    if let Some(heartbeat_interval) = config.heartbeat_interval {
+        mb.set_heartbeat(Duration::from_millis(heartbeat_interval), config.heartbeat_timeout.map(Duration::from_millis));
+    }
 
-    let mut read_stream = TcpStream::connect(host.clone()).await.expect("Connection to host failed");
-    auth(addr.clone(), access_key, &mut read_stream).await.expect("Read stream authorization failed");
+    if let Some(frame_checksums) = config.frame_checksums {
+        mb.set_frame_checksums(frame_checksums);
+    }
 
-    info!("Connected in stream mode to {} as {}", host, addr);
+    if config.rpc_retry.unwrap_or_default() {
+        mb.rpc_retry_policy = RpcRetryPolicy::Retry;
+    }
+
+    if let Some(rpc_timeout) = config.rpc_timeout {
+        mb.rpc_timeout = Some(Duration::from_millis(rpc_timeout));
+    }
+
+    if let Some(max_pending_rpcs) = config.max_pending_rpcs {
+        mb.set_max_pending_rpcs(max_pending_rpcs);
+    }
+}
 
-    let res = process_stream_mode(complete_condition, write_stream, read_stream, read_tx, write_rx).await;
+fn get_backoff(config: &ClientConfig) -> Backoff {
+    let min_delay = config.reconnect_min_delay.unwrap_or(DEFAULT_RECONNECT_MIN_DELAY_MS);
+    let max_delay = config.reconnect_max_delay.unwrap_or(DEFAULT_RECONNECT_MAX_DELAY_MS);
 
-    info!("Connections closed, {:?}", res);
+    Backoff::new(Duration::from_millis(min_delay), Duration::from_millis(max_delay))
 }
 
-async fn connect_full_message_future(host: &str, addr: String, access_key: String, read_tx: UnboundedSender<ClientMsg>, write_rx: UnboundedReceiver<WriteMsg>) {    
-    let mut write_stream = TcpStream::connect(host).await.expect("Connection to host failed");
-    auth(addr.clone(), access_key.clone(), &mut write_stream).await.expect("Write stream authorization failed");
+/// Opens write and read connections to the server, both are authorized with client addr.
+/// Server subscriptions of the addr are applied to the connection on authorization, so they are restored after reconnect too.
+async fn connect(settings: &ConnectSettings, write_settings: &WriteSettings) -> Result<(Framed<TcpStream, FrameCodec>, Framed<TcpStream, FrameCodec>), ProcessError> {
+    let write_stream = TcpStream::connect(&settings.host).await?;
+    let mut write_frames = handshake_stream(write_stream, write_settings).await?;
+    auth(settings.addr.clone(), settings.access_key.clone(), settings.namespace.clone(), "write", &mut write_frames).await?;
 
-    let mut read_stream = TcpStream::connect(host).await.expect("Connection to host failed");
-    auth(addr.clone(), access_key, &mut read_stream).await.expect("Read stream authorization failed");
+    let read_stream = TcpStream::connect(&settings.host).await?;
+    let mut read_frames = handshake_stream(read_stream, write_settings).await?;
+    auth(settings.addr.clone(), settings.access_key.clone(), settings.namespace.clone(), "read", &mut read_frames).await?;
 
-    info!("Connected in full message mode to {} as {}", host, addr);
+    Ok((write_frames, read_frames))
+}
 
-    let res = process_full_message_mode(write_stream, read_stream, read_tx, write_rx).await;
+/// Connection is not reestablished if it is completed or the client is gone.
+fn is_reconnect_needed(res: &Result<(), ProcessError>) -> bool {
+    !matches!(res, Ok(()) | Err(ProcessError::WriteChannelDropped) | Err(ProcessError::ReadChannelDropped))
+}
 
-    info!("{:?}", res);
+/// Handling of frames read by client connection.
+enum ReadMode {
+    /// Frames are passed to read channel as is
+    Stream(CompleteCondition),
+    /// Frames are collected to messages
+    FullMessage
 }
 
-async fn process_stream_mode(complete_condition: CompleteCondition, mut write_tcp_stream: TcpStream, mut read_tcp_stream: TcpStream, read_tx: UnboundedSender<ClientMsg>, write_rx: UnboundedReceiver<WriteMsg>) -> Result<(), ProcessError> {
-    //let (auth_msg_meta, auth_payload, auth_attachments) = read_full(&mut socket_read).await?;
-    //let auth_payload: Value = from_slice(&auth_payload)?;    
+/// Connects to the server and reconnects with backoff until connection is completed. Frames written while client is disconnected are kept in write channel
+/// and written to the next connection, rpcs waiting for response on lost connection are failed or retried according to MagicBall rpc retry policy.
+async fn connect_future(read_mode: ReadMode, mut settings: ConnectSettings, mb: MagicBall, read_tx: UnboundedSender<ClientMsg>, mut write_rx: UnboundedReceiver<WriteMsg>) {
+    start_heartbeat(&mb);
 
-    //info!("auth {:?}", auth_msg_meta);
-    //info!("auth {:?}", auth_payload);
+    loop {
+        let (write_frames, read_frames) = match connect(&settings, &mb.write_settings).await {
+            Ok(frames) => frames,
+            Err(e) => {
+                let delay = settings.backoff.next_delay();
 
-    tokio::spawn(async move {
-        match write_loop(write_rx, &mut write_tcp_stream).await {
-			Ok(()) => info!("Write loop ended"),
-			Err(e) => error!("Write loop ended with error, {:?}", e)
-		}        
-    });	
-
-    let mut state = State::new();
-
-	match complete_condition {
-		CompleteCondition::Never => {
-			loop {
-				match state.read_frame() {
-					ReadFrameResult::NotEnoughBytesForFrame => {
-						state.read_from_tcp_stream(&mut read_tcp_stream).await?;
-					}
-					ReadFrameResult::NextStep => {}
-					ReadFrameResult::Frame(frame) => {
-						debug!("Stream frame read, frame type {}, msg type {}, stream id {}", frame.frame_type, frame.msg_type, frame.stream_id);
-		
-						let frame_type = frame.frame_type;
-		
-						match read_tx.send(ClientMsg::Frame(frame)) {
-							Ok(()) => {}
-							Err(_) => {                        
-								panic!("Client message send with read_tx in stream mode failed");
-							}
-						}							
-					}
-				}
-			}
+                warn!("Connection to {} failed, next attempt in {:?}, {:?}", settings.host, delay, e);
+
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+        };
+
+        info!("Connected to {} as {}", settings.host, settings.addr);
+
+        let connected_at = Instant::now();
+
+        mb.write_settings.set_connected(true);
+
+        let res = match &read_mode {
+            ReadMode::Stream(complete_condition) => process_stream_mode(complete_condition, write_frames, read_frames, &mb, &read_tx, &mut write_rx).await,
+            ReadMode::FullMessage => process_full_message_mode(write_frames, read_frames, &mb, &read_tx, &mut write_rx).await
+        };
+
+        mb.write_settings.set_connected(false);
+
+        if !is_reconnect_needed(&res) {
+            info!("Connections closed, {:?}", res);
+            break;
+        }
+
+        // connections closed right after connect (for example, on rejected authorization) are retried with growing delays
+        if connected_at.elapsed() >= settings.backoff.get_max_delay() {
+            settings.backoff.reset();
+        }
+
+        let delay = settings.backoff.next_delay();
+
+        warn!("Connection to {} lost, reconnecting in {:?}, {:?}", settings.host, delay, res);
+
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn connect_stream_loopback(loopback: Loopback, complete_condition: CompleteCondition, addr: String, mb: MagicBall, read_tx: UnboundedSender<ClientMsg>, write_rx: UnboundedReceiver<WriteMsg>) {
+    // in-process server has the same protocol version, so no handshake is needed
+    mb.write_settings.set_agreed(&Handshake::new(MAX_NEGOTIATED_FRAME_PAYLOAD_SIZE as u16).with_capabilities(Capabilities::SUPPORTED.difference(Capabilities::CHECKSUM)));
+
+    let mut client_rx = loopback.connect(addr.clone(), write_rx).expect("Loopback connection failed");
+
+    info!("Connected in stream mode to loopback as {}", addr);
+
+    mb.write_settings.set_connected(true);
+
+    while let Some(WriteMsg::Frame(frame)) = client_rx.recv().await {
+        debug!("Loopback stream frame read, frame type {}, msg type {}, stream id {}", frame.frame_type, frame.msg_type, frame.stream_id);
+
+        let frame_type = frame.frame_type;
+
+        match mb.process_connection_frame(&frame) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => error!("Connection frame processing failed, client addr {}, {:?}", addr, e)
+        }
+
+        if read_tx.send(ClientMsg::Frame(frame)).is_err() {
+            warn!("Read channel dropped, client addr {}", addr);
+            break;
+        }
+
+        match complete_condition {
+            CompleteCondition::OnStreamEnd if frame_type == FrameType::End as u8 || frame_type == FrameType::Cancel as u8 => break,
+            _ => {}
+        }
+    }
+
+    mb.write_settings.set_connected(false);
+
+    info!("Loopback connection closed, client addr {}", addr);
+}
+
+async fn connect_full_message_loopback(loopback: Loopback, addr: String, mb: MagicBall, read_tx: UnboundedSender<ClientMsg>, write_rx: UnboundedReceiver<WriteMsg>) {
+    mb.write_settings.set_agreed(&Handshake::new(MAX_NEGOTIATED_FRAME_PAYLOAD_SIZE as u16).with_capabilities(Capabilities::SUPPORTED.difference(Capabilities::CHECKSUM)));
+
+    let mut client_rx = loopback.connect(addr.clone(), write_rx).expect("Loopback connection failed");
+    let mut stream_layouts = HashMap::new();
+    let mut stream_credits = HashMap::new();
+
+    info!("Connected in full message mode to loopback as {}", addr);
+
+    mb.write_settings.set_connected(true);
+
+    while let Some(WriteMsg::Frame(frame)) = client_rx.recv().await {
+        debug!("Loopback full message frame read, frame type {}, msg type {}, stream id {}", frame.frame_type, frame.msg_type, frame.stream_id);
+
+        if let Err(e) = process_full_message_frame(frame, &mut stream_layouts, &mut stream_credits, &mb, &read_tx) {
+            error!("Loopback connection frame processing failed, client addr {}, {:?}", addr, e);
+            break;
+        }
+    }
+
+    mb.write_settings.set_connected(false);
+
+    info!("Loopback connection closed, client addr {}", addr);
+}
+
+/// Writes frames from write channel and passes read frames to read_tx until one of the connections is closed.
+/// Write loop is stopped with read loop, frames left in write channel are written to the next connection.
+async fn process_stream_mode<S>(complete_condition: &CompleteCondition, mut write_frames: Framed<S, FrameCodec>, mut read_frames: Framed<S, FrameCodec>, mb: &MagicBall, read_tx: &UnboundedSender<ClientMsg>, write_rx: &mut UnboundedReceiver<WriteMsg>) -> Result<(), ProcessError> where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    tokio::select! {
+        res = write_loop(write_rx, &mut write_frames) => res,
+        res = read_stream_mode(complete_condition, &mut read_frames, mb, read_tx) => res
+    }
+}
+
+async fn read_stream_mode<S>(complete_condition: &CompleteCondition, read_frames: &mut Framed<S, FrameCodec>, mb: &MagicBall, read_tx: &UnboundedSender<ClientMsg>) -> Result<(), ProcessError> where S: AsyncRead + AsyncWrite + Unpin {
+	loop {
+		let frame = read_frame(read_frames, mb.heartbeat.get_timeout()).await?;
+
+		debug!("Stream frame read, frame type {}, msg type {}, stream id {}", frame.frame_type, frame.msg_type, frame.stream_id);
+
+		let frame_type = frame.frame_type;
+
+		if mb.process_connection_frame(&frame)? {
+			continue;
 		}
-		CompleteCondition::OnStreamEnd => {
-			loop {
-				match state.read_frame() {
-					ReadFrameResult::NotEnoughBytesForFrame => {
-						state.read_from_tcp_stream(&mut read_tcp_stream).await?;
-					}
-					ReadFrameResult::NextStep => {}
-					ReadFrameResult::Frame(frame) => {
-						debug!("Stream frame read, frame type {}, msg type {}, stream id {}", frame.frame_type, frame.msg_type, frame.stream_id);
-		
-						let frame_type = frame.frame_type;
-		
-						match read_tx.send(ClientMsg::Frame(frame)) {
-							Ok(()) => {}
-							Err(_) => {                        
-								panic!("Client message send with read_tx in stream mode failed");
-							}
-						}
-		
-						match frame_type {
-							6 => break,								//
-							_ => {}
-						}
-					}
-				}
-			}
 
-			info!("Read loop completed");
+		read_tx.send(ClientMsg::Frame(frame)).map_err(|_| ProcessError::ReadChannelDropped)?;
+
+		match complete_condition {
+			CompleteCondition::OnStreamEnd if frame_type == FrameType::End as u8 || frame_type == FrameType::Cancel as u8 => break,
+			_ => {}
 		}
 	}
 
+	info!("Read loop completed");
+
 	Ok(())
 }
 
-async fn process_full_message_mode(mut write_tcp_stream: TcpStream, mut read_tcp_stream: TcpStream, read_tx: UnboundedSender<ClientMsg>, write_rx: UnboundedReceiver<WriteMsg>) -> Result<(), ProcessError> {    
-    //let (auth_msg_meta, auth_payload, auth_attachments) = read_full(&mut socket_read).await?;
-    //let auth_payload: Value = from_slice(&auth_payload)?;    
+/// Same as process_stream_mode, but frames are collected to messages. Messages collected partially are dropped with the connection.
+async fn process_full_message_mode<S>(mut write_frames: Framed<S, FrameCodec>, mut read_frames: Framed<S, FrameCodec>, mb: &MagicBall, read_tx: &UnboundedSender<ClientMsg>, write_rx: &mut UnboundedReceiver<WriteMsg>) -> Result<(), ProcessError> where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    tokio::select! {
+        res = write_loop(write_rx, &mut write_frames) => res,
+        res = read_full_message_mode(&mut read_frames, mb, read_tx) => res
+    }
+}
 
-    //info!("auth {:?}", auth_msg_meta);
-    //info!("auth {:?}", auth_payload);
-            
+async fn read_full_message_mode<S>(read_frames: &mut Framed<S, FrameCodec>, mb: &MagicBall, read_tx: &UnboundedSender<ClientMsg>) -> Result<(), ProcessError> where S: AsyncRead + AsyncWrite + Unpin {
     let mut stream_layouts: HashMap<u64, StreamLayout> = HashMap::new();
-    let mut state = State::new();
-
-    tokio::spawn(async move {
-        let res = write_loop(write_rx, &mut write_tcp_stream).await;
-        error!("{:?}", res);
-    });
+    let mut stream_credits = HashMap::new();
 
 	loop {
-		match state.read_frame() {
-			ReadFrameResult::NotEnoughBytesForFrame => {
-				state.read_from_tcp_stream(&mut read_tcp_stream).await?;
+		let frame = read_frame(read_frames, mb.heartbeat.get_timeout()).await?;
+
+		debug!("Full message stream frame read, frame type {}, msg type {}, stream id {}", frame.frame_type, frame.msg_type, frame.stream_id);
+
+		process_full_message_frame(frame, &mut stream_layouts, &mut stream_credits, mb, read_tx)?;
+	}
+}
+
+/// Credits consumed by flow controlled stream being collected, credits are granted back to the sender when half of the window is consumed.
+struct StreamCredits {
+    tx: String,
+    window: u32,
+    consumed: u32
+}
+
+/// Collects frames to full messages, completed messages are decompressed and passed to read_tx.
+/// Credits are granted for flow controlled streams as their frames are collected, received credit frames are applied to streams written by the client.
+/// Transfers are acknowledged from zero offset, collected data is not kept after failure.
+fn process_full_message_frame(frame: Frame, stream_layouts: &mut HashMap<u64, StreamLayout>, stream_credits: &mut HashMap<u64, StreamCredits>, mb: &MagicBall, read_tx: &UnboundedSender<ClientMsg>) -> Result<(), ProcessError> {
+	match frame.get_frame_type() {
+		Ok(frame_type) => {
+			// msg meta of streams started before reconnect is not received, their frames are dropped
+			match frame_type {
+				FrameType::Payload | FrameType::PayloadEnd | FrameType::Attachment | FrameType::AttachmentEnd | FrameType::End if !stream_layouts.contains_key(&frame.stream_id) => {
+					warn!("Frame of unknown stream {} dropped, frame type {}", frame.stream_id, frame.frame_type);
+					return Ok(());
+				}
+				_ => {}
 			}
-			ReadFrameResult::NextStep => {}
-			ReadFrameResult::Frame(frame) => {
-				debug!("Full message stream frame read, frame type {}, msg type {}, stream id {}", frame.frame_type, frame.msg_type, frame.stream_id);
-
-				match frame.get_frame_type() {
-					Ok(frame_type) => {
-						match frame_type {
-							FrameType::MsgMeta | FrameType::MsgMetaEnd => {
-								match stream_layouts.get_mut(&frame.stream_id) {
-									Some(stream_layout) => {
-                                        match frame.payload {
-                                            Some(payload) => {
-                                                stream_layout.msg_meta.extend_from_slice(&payload[..frame.payload_size as usize]);
-                                            }
-                                            None => {}
-                                        }
-									}
-									None => {
-										stream_layouts.insert(frame.stream_id, StreamLayout {
-											id: frame.stream_id,
-											msg_meta: match frame.payload {
-                                                Some(payload) => payload[..frame.payload_size as usize].to_vec(),
-                                                None => vec![]
-                                            },
-											payload: vec![],
-											attachments_data: vec![]
-										});
-									}
-								}
-							}
-							FrameType::Payload | FrameType::PayloadEnd => {
-                                match frame.payload {
-                                    Some(payload) => {
-                                        let stream_layout = stream_layouts.get_mut(&frame.stream_id).ok_or(ProcessError::StreamLayoutNotFound)?;
-								        stream_layout.payload.extend_from_slice(&payload[..frame.payload_size as usize]);
-                                    }
-                                    None => {}
-                                }
-							}
-							FrameType::Attachment | FrameType::AttachmentEnd => {
-                                match frame.payload {
-                                    Some(payload) => {
-                                        let stream_layout = stream_layouts.get_mut(&frame.stream_id).ok_or(ProcessError::StreamLayoutNotFound)?;
-								        stream_layout.attachments_data.extend_from_slice(&payload[..frame.payload_size as usize]);
-                                    }
-                                    None => {}
+
+			match frame_type {
+				FrameType::MsgMeta | FrameType::MsgMetaEnd => {
+					match stream_layouts.get_mut(&frame.stream_id) {
+						Some(stream_layout) => {
+                            match frame.payload {
+                                Some(payload) => {
+                                    stream_layout.msg_meta.extend_from_slice(&payload[..frame.payload_size as usize]);
                                 }
-							}
-							FrameType::End => {
-								let stream_layout = stream_layouts.remove(&frame.stream_id).ok_or(ProcessError::StreamLayoutNotFound)?;
-
-								let msg_meta = from_slice(&stream_layout.msg_meta)?;
-		
-								match read_tx.send(ClientMsg::Message(frame.stream_id, msg_meta, stream_layout.payload, match stream_layout.attachments_data.is_empty() {
-                                    true => Some(stream_layout.attachments_data),
-                                    false => None
-                                })) {
-									Ok(()) => {}
-									Err(_) => {
-										panic!("Client message send with read_tx in full message mode failed")
-									}
-								}
-							}
+                                None => {}
+                            }
+						}
+						None => {
+							stream_layouts.insert(frame.stream_id, StreamLayout {
+								id: frame.stream_id,
+								msg_meta: match frame.payload {
+                                    Some(payload) => payload[..frame.payload_size as usize].to_vec(),
+                                    None => vec![]
+                                },
+								payload: vec![],
+								attachments_data: vec![]
+							});
 						}
 					}
-					Err(e) => {
-						error!("Get frame type failed in read: {:?}", e);
+
+					if let FrameType::MsgMetaEnd = frame_type {
+						let stream_layout = stream_layouts.get(&frame.stream_id).ok_or(ProcessError::StreamLayoutNotFound)?;
+						let msg_meta: MsgMeta = from_slice(&stream_layout.msg_meta)?;
+
+						// collected messages are not persisted, so transfer is always sent from the start
+						if msg_meta.transfer.is_some() {
+							mb.ack_transfer(&msg_meta.tx, frame.stream_id, 0)?;
+						}
+
+						if let Some(window) = msg_meta.credits {
+							stream_credits.insert(frame.stream_id, StreamCredits {
+								tx: msg_meta.tx,
+								window,
+								consumed: 0
+							});
+						}
 					}
-				}				
+				}
+				FrameType::Payload | FrameType::PayloadEnd => {
+                    match frame.payload {
+                        Some(payload) => {
+                            let stream_layout = stream_layouts.get_mut(&frame.stream_id).ok_or(ProcessError::StreamLayoutNotFound)?;
+					        stream_layout.payload.extend_from_slice(&payload[..frame.payload_size as usize]);
+                        }
+                        None => {}
+                    }
+				}
+				FrameType::Attachment | FrameType::AttachmentEnd => {
+                    match frame.payload {
+                        Some(payload) => {
+                            let stream_layout = stream_layouts.get_mut(&frame.stream_id).ok_or(ProcessError::StreamLayoutNotFound)?;
+					        stream_layout.attachments_data.extend_from_slice(&payload[..frame.payload_size as usize]);
+                        }
+                        None => {}
+                    }
+
+                    if let Some(credits) = stream_credits.get_mut(&frame.stream_id) {
+                        credits.consumed += 1;
+
+                        if credits.consumed >= (credits.window / 2).max(1) {
+                            mb.grant_credits(&credits.tx, frame.stream_id, credits.consumed)?;
+                            credits.consumed = 0;
+                        }
+                    }
+				}
+				FrameType::End => {
+					stream_credits.remove(&frame.stream_id);
+
+					let stream_layout = stream_layouts.remove(&frame.stream_id).ok_or(ProcessError::StreamLayoutNotFound)?;
+
+					let mut msg_meta = from_slice(&stream_layout.msg_meta)?;
+
+                    let (payload, attachments_data) = match decompress_message(&mut msg_meta, stream_layout.payload, match stream_layout.attachments_data.is_empty() {
+                        true => None,
+                        false => Some(stream_layout.attachments_data)
+                    }) {
+                        Ok(res) => res,
+                        Err(e) => {
+                            error!("Message decompression failed, {}, {:?}", msg_meta.display(), e);
+                            return Ok(());
+                        }
+                    };
+
+					read_tx.send(ClientMsg::Message(frame.stream_id, msg_meta, payload, attachments_data)).map_err(|_| ProcessError::ReadChannelDropped)?;
+				}
+				FrameType::Credit | FrameType::TransferAck | FrameType::Ping | FrameType::Pong => {
+					mb.process_connection_frame(&frame)?;
+				}
+				FrameType::Cancel => {
+					stream_credits.remove(&frame.stream_id);
+
+					let reason = frame.get_cancel_reason().unwrap_or(CancelReason::SenderFailed);
+
+					match stream_layouts.remove(&frame.stream_id).map(|stream_layout| from_slice::<MsgMeta>(&stream_layout.msg_meta)) {
+						Some(Ok(msg_meta)) => {
+							warn!("Stream {} cancelled, {:?}, {}", frame.stream_id, reason, msg_meta.display());
+
+							read_tx.send(ClientMsg::Cancelled(frame.stream_id, msg_meta, reason)).map_err(|_| ProcessError::ReadChannelDropped)?;
+						}
+						_ => warn!("Stream {} cancelled, {:?}", frame.stream_id, reason)
+					}
+				}
+				FrameType::RpcCancel => {
+					match frame.get_rpc_cancel_correlation_id() {
+						Some(correlation_id) => read_tx.send(ClientMsg::RpcCancelled(frame.stream_id, correlation_id)).map_err(|_| ProcessError::ReadChannelDropped)?,
+						None => warn!("Malformed rpc cancel frame for stream {}", frame.stream_id)
+					}
+				}
 			}
 		}
-	}  
+		Err(e) => {
+			error!("Get frame type failed in read: {:?}", e);
+		}
+	}
+
+	Ok(())
 }
 
 struct CfgStreamLayout {
@@ -559,7 +1111,7 @@ struct CfgStreamLayout {
     payload: Option<Value>
 }
 
-pub async fn cfg_mode(cfg_host: String, cfg_domain: String, cfg_token: String, rpc_inbound_tx: UnboundedSender<RpcMsg>, mut rpc_inbound_rx: UnboundedReceiver<RpcMsg>, write_tx: UnboundedSender<WriteMsg>, write_rx: UnboundedReceiver<WriteMsg>, result_tx: UnboundedSender<Value>) {
+pub async fn cfg_mode(transport: Transport, cfg_host: String, cfg_domain: String, cfg_token: String, rpc_inbound_tx: UnboundedSender<RpcMsg>, mut rpc_inbound_rx: UnboundedReceiver<RpcMsg>, write_tx: UnboundedSender<WriteMsg>, write_rx: UnboundedReceiver<WriteMsg>, result_tx: UnboundedSender<Value>) {
     let (read_tx, read_rx) = mpsc::unbounded_channel();        
     let (rpc_outbound_tx, mut _rpc_outbound_rx) = mpsc::unbounded_channel();
 
@@ -567,7 +1119,10 @@ pub async fn cfg_mode(cfg_host: String, cfg_domain: String, cfg_token: String, r
         let mut rpcs = HashMap::new();        
 
         loop {
-            let msg = rpc_inbound_rx.recv().await.expect("Rpc inbound msg receive failed");
+            let msg = match rpc_inbound_rx.recv().await {
+                Some(msg) => msg,
+                None => break
+            };
 
             match msg {
                 RpcMsg::AddRpc(correlation_id, rpc_tx) => {
@@ -575,6 +1130,11 @@ pub async fn cfg_mode(cfg_host: String, cfg_domain: String, cfg_token: String, r
 
                     info!("cfg_mode: add rpc ok, correlation_id {}", correlation_id);
                 }                
+                RpcMsg::RemoveRpc(correlation_id) => {
+                    rpcs.remove(&correlation_id);
+
+                    debug!("cfg_mode: remove rpc ok, correlation_id {}", correlation_id);
+                }
                 RpcMsg::RpcDataRequest(correlation_id) => {
                     match rpcs.remove(&correlation_id) {
                         Some(rpc_tx) => {
@@ -598,6 +1158,7 @@ pub async fn cfg_mode(cfg_host: String, cfg_domain: String, cfg_token: String, r
     let access_key = "";
 
     let mut mb = MagicBall::new(addr.clone(), write_tx, rpc_inbound_tx);
+    let mb2 = mb.clone();
 
     tokio::spawn(process_cfg_stream(mb.clone(), read_rx, result_tx));
 
@@ -609,14 +1170,33 @@ pub async fn cfg_mode(cfg_host: String, cfg_domain: String, cfg_token: String, r
         Err(e) => panic!("Failed to send cfg rpc, {:?}", e)
     }
 
-    connect_stream_future(CompleteCondition::OnStreamEnd, cfg_host.to_owned(), addr, access_key.to_owned(), read_tx, write_rx).await;
+    match transport {
+        Transport::Tcp => {
+            let connect_settings = ConnectSettings {
+                host: cfg_host,
+                addr,
+                access_key: access_key.to_owned(),
+                namespace: None,
+                backoff: Backoff::new(Duration::from_millis(DEFAULT_RECONNECT_MIN_DELAY_MS), Duration::from_millis(DEFAULT_RECONNECT_MAX_DELAY_MS))
+            };
+
+            connect_future(ReadMode::Stream(CompleteCondition::OnStreamEnd), connect_settings, mb2, read_tx, write_rx).await
+        }
+        Transport::Loopback(loopback) => connect_stream_loopback(*loopback, CompleteCondition::OnStreamEnd, addr, mb2, read_tx, write_rx).await
+    }
 }
 
 pub async fn process_cfg_stream(mut mb: MagicBall, mut rx: UnboundedReceiver<ClientMsg>, mut result_tx: UnboundedSender<Value>) {
     let mut stream_layouts = HashMap::new();
 
     loop {        
-        let client_msg = rx.recv().await.expect("Connection issues acquired");
+        let client_msg = match rx.recv().await {
+            Some(client_msg) => client_msg,
+            None => {
+                error!("Cfg stream closed before config is received");
+                break;
+            }
+        };
         let stream_id = client_msg.get_stream_id();
         match process_client_msg(&mut mb, &mut stream_layouts, client_msg, &mut result_tx).await {
             Ok(completed) => {
@@ -781,6 +1361,12 @@ async fn process_client_msg(mb: &mut MagicBall, stream_layouts: &mut HashMap<u64
 						FrameType::AttachmentEnd => {
                             info!("Attachment end frame");
 						}
+						FrameType::Credit | FrameType::TransferAck | FrameType::Ping | FrameType::Pong | FrameType::RpcCancel => {}
+						FrameType::Cancel => {
+							stream_layouts.remove(&frame.stream_id);
+
+							return Err(ProcessError::StreamCancelled(frame.get_cancel_reason().unwrap_or(CancelReason::SenderFailed)));
+						}
 						FrameType::End => {
                             info!("Stream end frame");	
 							match stream_layouts.remove(&frame.stream_id) {
@@ -821,4 +1407,192 @@ async fn process_client_msg(mb: &mut MagicBall, stream_layouts: &mut HashMap<u64
 	}
 
     Ok(false)
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use tokio::net::TcpStream;
+    use tokio::runtime::Runtime;
+    use tokio::sync::{mpsc::{self, UnboundedReceiver, UnboundedSender}, oneshot};
+    use sp_cfg::{ClientConfig, ServerConfig};
+    use sp_dto::{Key, Message, Response, Subscribes, resp};
+    use crate::{MagicBall, ProcessError, server};
+
+    const HOST: &str = "127.0.0.1:21911";
+    const SILENT_PEER_HOST: &str = "127.0.0.1:21912";
+
+    /// Runs server on its own runtime, so all its connections are closed when it is stopped with returned sender.
+    fn start_server(host: &str) -> oneshot::Sender<()> {
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let config = ServerConfig {
+            host: host.to_owned(),
+            log: None,
+            hash_keys: None,
+            namespaces: None,
+            max_frame_payload_size: None,
+            heartbeat_interval: None,
+            heartbeat_timeout: None,
+            frame_checksums: None
+        };
+        let mut rpc_subscribes = HashMap::new();
+
+        rpc_subscribes.insert(Key::simple("Rpc"), vec!["Service".to_owned()]);
+        rpc_subscribes.insert(Key::simple("SlowRpc"), vec!["Service".to_owned()]);
+
+        std::thread::spawn(move || {
+            let rt = Runtime::new().expect("Failed to create runtime");
+
+            rt.block_on(async move {
+                tokio::select! {
+                    res = server::start_future(config, Subscribes::ByKey(HashMap::new(), rpc_subscribes)) => panic!("Server stopped, {:?}", res),
+                    _ = stop_rx => {}
+                }
+            });
+        });
+
+        stop_tx
+    }
+
+    async fn process_event(_: Value, _: MagicBall, _: Message<Value>, _: UnboundedSender<Value>) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    async fn process_rpc(_: Value, _: MagicBall, msg: Message<Value>, _: UnboundedSender<Value>) -> Result<Response<Value>, Box<dyn std::error::Error>> {
+        if msg.meta.key.action == "SlowRpc" {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        resp(msg.payload)
+    }
+
+    async fn startup(_: Value, _: Value, _: MagicBall, _: Option<Value>, _: UnboundedSender<Value>) {
+    }
+
+    /// Calls until the service is connected and answers.
+    async fn call(mb: &MagicBall, payload: Value) -> Value {
+        loop {
+            match mb.with_timeout(Duration::from_millis(500)).rpc::<_, Value>(Key::simple("Rpc"), payload.clone()).await {
+                Ok(msg) => return msg.payload,
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await
+            }
+        }
+    }
+
+    async fn startup_caller(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        tx.send(call(&mb, json!({ "n": 1 })).await).expect("Failed to send result");
+
+        // server is restarted while the request is processed
+        let res = mb.rpc::<_, Value>(Key::simple("SlowRpc"), json!({ "n": 2 })).await;
+
+        tx.send(json!({ "lost": matches!(res, Err(ProcessError::ConnectionLost)) })).expect("Failed to send result");
+        tx.send(call(&mb, json!({ "n": 3 })).await).expect("Failed to send result");
+    }
+
+    async fn startup_retrying_caller(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        call(&mb, json!({ "n": 1 })).await;
+
+        let res = mb.rpc::<_, Value>(Key::simple("SlowRpc"), json!({ "n": 2 })).await.expect("Retried rpc failed");
+
+        tx.send(json!({ "retried": res.payload })).expect("Failed to send result");
+    }
+
+    async fn startup_single_caller(_: Value, _: Value, mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        tx.send(call(&mb, json!({ "n": 1 })).await).expect("Failed to send result");
+    }
+
+    async fn recv(rx: &mut UnboundedReceiver<Value>) -> Value {
+        tokio::time::timeout(Duration::from_secs(10), rx.recv()).await.expect("Result is not received in time").expect("Result channel closed")
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let config = json!({
+            "host": HOST,
+            "access_key": "",
+            "reconnect_min_delay": 20,
+            "reconnect_max_delay": 200
+        });
+        let mut service_config = config.clone();
+        let mut caller_config = config.clone();
+        let mut retrying_caller_config = config;
+
+        service_config["addr"] = json!("Service");
+        caller_config["addr"] = json!("Caller");
+        retrying_caller_config["addr"] = json!("RetryingCaller");
+        retrying_caller_config["rpc_retry"] = json!(true);
+
+        // clients wait for the server on start
+        tokio::spawn(super::full_message_mode(service_config, process_event, process_rpc, startup, None, tx.clone()));
+        tokio::spawn(super::full_message_mode(caller_config, process_event, process_rpc, startup_caller, None, tx.clone()));
+        tokio::spawn(super::full_message_mode(retrying_caller_config, process_event, process_rpc, startup_retrying_caller, None, tx));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let stop_tx = start_server(HOST);
+
+        assert_eq!(recv(&mut rx).await, json!({ "n": 1 }));
+
+        // slow rpcs are started, they are in flight when server is restarted
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        drop(stop_tx);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let _stop_tx = start_server(HOST);
+        let mut results = vec![];
+
+        for _ in 0..3 {
+            results.push(recv(&mut rx).await);
+        }
+
+        assert!(results.contains(&json!({ "lost": true })));
+        assert!(results.contains(&json!({ "n": 3 })));
+        assert!(results.contains(&json!({ "retried": { "n": 2 } })));
+    }
+
+    #[tokio::test]
+    async fn silent_peer() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let _stop_tx = start_server(SILENT_PEER_HOST);
+
+        // peer connects before clients and doesn't send handshake
+        let _silent_peer = loop {
+            match TcpStream::connect(SILENT_PEER_HOST).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await
+            }
+        };
+
+        let config = json!({
+            "host": SILENT_PEER_HOST,
+            "access_key": ""
+        });
+        let mut service_config = config.clone();
+        let mut caller_config = config;
+
+        service_config["addr"] = json!("Service");
+        caller_config["addr"] = json!("Caller");
+
+        tokio::spawn(super::full_message_mode(service_config, process_event, process_rpc, startup, None, tx.clone()));
+        tokio::spawn(super::full_message_mode(caller_config, process_event, process_rpc, startup_single_caller, None, tx));
+
+        assert_eq!(recv(&mut rx).await, json!({ "n": 1 }));
+    }
+
+    #[tokio::test]
+    async fn invalid_config() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let config = ClientConfig::new("Client")
+            .with_host(HOST)
+            .to_value_with(json!({ "service_value": 1 }))
+            .expect("Failed to get config");
+
+        assert_eq!(config["service_value"], json!(1));
+
+        // access key is missing, client is not started
+        tokio::time::timeout(Duration::from_secs(1), super::full_message_mode(config, process_event, process_rpc, startup, None, tx)).await.expect("Client with invalid config is started");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,168 @@
+use byteorder::ByteOrder;
+use tokio_util::codec::{Decoder, Encoder};
+use sp_dto::bytes::{BufMut, BytesMut};
+use crate::proto::{Frame, ProcessError, FRAME_HEADER_SIZE, MAX_FRAME_PAYLOAD_SIZE, get_frame_checksum};
+
+/// Codec of protocol frames, it is used with tokio_util Framed over any AsyncRead + AsyncWrite stream (TCP, TLS, Unix sockets, in-memory duplex).
+/// Frame payloads are split from the read buffer without copying. If checksums are enabled, they are written to frame signature
+/// and checked on read, corrupted frame fails the stream with ProcessError::FrameChecksumMismatch because position of the next frame can't be trusted.
+/// Frames with payload bigger than max payload size (agreed on handshake) fail the stream with ProcessError::FramePayloadSizeExceeded.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    checksum: bool,
+    max_payload_size: usize
+}
+
+impl FrameCodec {
+    /// Frames up to MAX_FRAME_PAYLOAD_SIZE are always accepted, all peers are able to write them before handshake.
+    pub fn new(checksum: bool, max_payload_size: usize) -> FrameCodec {
+        FrameCodec {
+            checksum,
+            max_payload_size: max_payload_size.max(MAX_FRAME_PAYLOAD_SIZE)
+        }
+    }
+    /// Enables checksums, it is set when checksums are agreed on handshake.
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.checksum = checksum;
+    }
+    pub fn has_checksum(&self) -> bool {
+        self.checksum
+    }
+    pub fn get_max_payload_size(&self) -> usize {
+        self.max_payload_size
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = ProcessError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, ProcessError> {
+        if src.len() < FRAME_HEADER_SIZE {
+            src.reserve(FRAME_HEADER_SIZE - src.len());
+            return Ok(None);
+        }
+
+        let payload_size = byteorder::BigEndian::read_u16(&src[1..3]);
+
+        if payload_size as usize > self.max_payload_size {
+            return Err(ProcessError::FramePayloadSizeExceeded);
+        }
+
+        let frame_size = FRAME_HEADER_SIZE + payload_size as usize;
+
+        if src.len() < frame_size {
+            src.reserve(frame_size - src.len());
+            return Ok(None);
+        }
+
+        let mut frame_buf = src.split_to(frame_size);
+        let frame_signature = byteorder::BigEndian::read_u64(&frame_buf[20..28]);
+
+        if self.checksum && get_frame_checksum(&frame_buf[..FRAME_HEADER_SIZE], &frame_buf[FRAME_HEADER_SIZE..]) as u64 != frame_signature {
+            return Err(ProcessError::FrameChecksumMismatch);
+        }
+
+        let payload = match payload_size {
+            0 => None,
+            _ => Some(frame_buf.split_off(FRAME_HEADER_SIZE).freeze())
+        };
+
+        Ok(Some(Frame {
+            frame_type: frame_buf[0],
+            payload_size,
+            msg_type: frame_buf[3],
+            key_hash: byteorder::BigEndian::read_u64(&frame_buf[4..12]),
+            stream_id: byteorder::BigEndian::read_u64(&frame_buf[12..20]),
+            frame_signature,
+            source_hash: byteorder::BigEndian::read_u64(&frame_buf[28..36]),
+            payload
+        }))
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = ProcessError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), ProcessError> {
+        let mut header = [0; FRAME_HEADER_SIZE];
+
+        header[0] = frame.frame_type;
+        byteorder::BigEndian::write_u16(&mut header[1..3], frame.payload_size);
+        header[3] = frame.msg_type;
+        byteorder::BigEndian::write_u64(&mut header[4..12], frame.key_hash);
+        byteorder::BigEndian::write_u64(&mut header[12..20], frame.stream_id);
+        byteorder::BigEndian::write_u64(&mut header[20..28], frame.frame_signature);
+        byteorder::BigEndian::write_u64(&mut header[28..36], frame.source_hash);
+
+        let payload = match &frame.payload {
+            Some(payload) => &payload[..frame.payload_size as usize],
+            None => &[]
+        };
+
+        if self.checksum {
+            let checksum = get_frame_checksum(&header, payload);
+
+            byteorder::BigEndian::write_u64(&mut header[20..28], checksum as u64);
+        }
+
+        dst.reserve(FRAME_HEADER_SIZE + payload.len());
+        dst.put_slice(&header);
+        dst.put_slice(payload);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+    use sp_dto::bytes::Bytes;
+    use crate::proto::{Frame, FrameType, ProcessError};
+    use super::FrameCodec;
+
+    #[tokio::test]
+    async fn frames_over_duplex() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut writer = FramedWrite::new(client, FrameCodec::new(true, 1000));
+        let mut reader = FramedRead::new(server, FrameCodec::new(true, 1000));
+        let payload = Bytes::from(vec![7; 1000]);
+
+        tokio::spawn(async move {
+            writer.send(Frame::new(FrameType::Payload as u8, 1000, 0, 1, 2, 3, Some(payload))).await.expect("Failed to write frame");
+            writer.send(Frame::new(FrameType::End as u8, 0, 0, 1, 2, 3, None)).await.expect("Failed to write frame");
+            // frame without checksum looks like corrupted one for the reader
+            writer.encoder_mut().set_checksum(false);
+            writer.send(Frame::new(FrameType::End as u8, 0, 0, 1, 2, 3, None)).await.expect("Failed to write frame");
+        });
+
+        let frame = reader.next().await.expect("Stream ended").expect("Failed to read frame");
+
+        assert_eq!((frame.stream_id, frame.source_hash), (2, 3));
+        assert_eq!(frame.payload.as_deref(), Some(&[7; 1000][..]));
+
+        let frame = reader.next().await.expect("Stream ended").expect("Failed to read frame");
+
+        assert!(matches!(frame.get_frame_type(), Ok(FrameType::End)));
+        assert!(frame.payload.is_none());
+        assert!(matches!(reader.next().await, Some(Err(ProcessError::FrameChecksumMismatch))));
+    }
+
+    #[tokio::test]
+    async fn oversized_frame() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut writer = FramedWrite::new(client, FrameCodec::new(false, 4096));
+        let mut reader = FramedRead::new(server, FrameCodec::new(false, 2048));
+
+        tokio::spawn(async move {
+            writer.send(Frame::new(FrameType::Payload as u8, 2048, 0, 1, 2, 3, Some(Bytes::from(vec![7; 2048])))).await.expect("Failed to write frame");
+            writer.send(Frame::new(FrameType::Payload as u8, 2049, 0, 1, 2, 3, Some(Bytes::from(vec![7; 2049])))).await.expect("Failed to write frame");
+        });
+
+        assert_eq!(reader.next().await.expect("Stream ended").expect("Failed to read frame").payload_size, 2048);
+
+        // frame is rejected by its header, payload bigger than agreed is not buffered
+        assert!(matches!(reader.next().await, Some(Err(ProcessError::FramePayloadSizeExceeded))));
+    }
+}
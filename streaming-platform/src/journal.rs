@@ -0,0 +1,534 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use log::*;
+use serde_derive::{Serialize, Deserialize};
+use serde_json::{json, from_slice, to_vec, Value};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use sp_dto::bytes::{Buf, BufMut};
+use sp_dto::{Key, MsgMeta, RpcResult, RemoteError, ErrorCode, decode};
+use sp_cfg::LogConfig;
+use crate::proto::*;
+
+/// Addr used by server as sender of journal rpc responses.
+pub const JOURNAL_ADDR: &str = "Journal";
+/// Default max size of log segment file.
+pub const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+/// Max amount of records returned by single read.
+pub const MAX_READ_COUNT: u64 = 1000;
+
+const CONSUMERS_FILE_NAME: &str = "consumers.json";
+const KEY_FILE_NAME: &str = "key.json";
+const SEGMENT_EXTENSION: &str = "log";
+
+/// Journal rpc response payload, attachments and attachments data
+type RpcOutput = (Value, Vec<(String, u64)>, Vec<u8>);
+
+/// Key of server rpc for reading log records.
+pub fn get_read_key() -> Key {
+    Key::new("Read", "Journal", "Server")
+}
+
+/// Key of server rpc for storing consumer offset.
+pub fn get_commit_key() -> Key {
+    Key::new("Commit", "Journal", "Server")
+}
+
+/// Key of server rpc for getting consumer and log offsets.
+pub fn get_offsets_key() -> Key {
+    Key::new("Offsets", "Journal", "Server")
+}
+
+/// Single log record, message attachments are not kept in log.
+#[derive(Debug)]
+pub struct LogRecord<T> {
+    pub offset: u64,
+    pub msg: sp_dto::Message<T>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogOffsets {
+    /// Offset stored by consumer, reading starts from here
+    pub committed: u64,
+    /// Offset of next appended record
+    pub next: u64
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ReadRequest {
+    pub key: Key,
+    pub offset: Option<u64>,
+    pub max_count: u64
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ReadResponse {
+    pub records: Vec<ReadRecord>,
+    pub next_offset: u64
+}
+
+/// Record meta, record payload is passed as attachment with the same index.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ReadRecord {
+    pub offset: u64,
+    pub msg_meta: MsgMeta
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CommitRequest {
+    pub key: Key,
+    pub offset: u64
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct OffsetsRequest {
+    pub key: Key
+}
+
+pub(crate) enum JournalMsg {
+    /// Appends message data to the log of the key
+    Append(Key, Vec<u8>),
+    /// Processes journal rpc request, requester addr hash and message data are passed
+    Rpc(u64, Vec<u8>)
+}
+
+/// Handle for the journal task, used by routers.
+#[derive(Clone)]
+pub(crate) struct Journal {
+    tx: UnboundedSender<JournalMsg>,
+    keys: HashSet<Key>,
+    rpc_keys: HashSet<Key>
+}
+
+impl Journal {
+    /// Restores logs from disk and starts journal task. Must be called inside tokio runtime.
+    pub fn start(config: LogConfig, server_tx: UnboundedSender<ServerMsg>) -> Result<Journal, ProcessError> {
+        let path = PathBuf::from(&config.path);
+        let segment_size = config.segment_size.unwrap_or(SEGMENT_SIZE);
+        let mut topics = HashMap::new();
+        let mut dirs = HashMap::new();
+
+        std::fs::create_dir_all(&path)?;
+
+        for key in config.keys {
+            // log directories are named by key hash, so colliding keys can't be logged together
+            let key_hash = get_key_hash(&key);
+
+            match dirs.get(&key_hash) {
+                Some(other_key) if *other_key != key => return Err(ProcessError::Custom(format!("Log keys {:?} and {:?} have the same hash", other_key, key))),
+                _ => {
+                    dirs.insert(key_hash, key.clone());
+                }
+            }
+
+            let topic = Topic::open(path.join(key_hash.to_string()), &key)?;
+
+            info!("Log for key {:?} restored, next offset {}", key, topic.next_offset);
+
+            topics.insert(key, topic);
+        }
+
+        let keys = topics.keys().cloned().collect();
+        let rpc_keys = [get_read_key(), get_commit_key(), get_offsets_key()].iter().cloned().collect();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(journal_loop(rx, topics, segment_size, server_tx));
+
+        Ok(Journal {
+            tx,
+            keys,
+            rpc_keys
+        })
+    }
+    pub fn is_logged(&self, key: &Key) -> bool {
+        self.keys.contains(key)
+    }
+    pub fn is_rpc(&self, key: &Key) -> bool {
+        self.rpc_keys.contains(key)
+    }
+    pub fn send(&self, msg: JournalMsg) -> Result<(), ProcessError> {
+        self.tx.send(msg).map_err(|_| ProcessError::SendJournalMsgError)
+    }
+}
+
+/// Log of single key. Records are kept in segment files named by offset of first record, each record is u32 length followed by message data.
+struct Topic {
+    path: PathBuf,
+    segments: Vec<u64>,
+    segment_bytes: u64,
+    next_offset: u64,
+    consumers: HashMap<String, u64>
+}
+
+impl Topic {
+    fn open(path: PathBuf, key: &Key) -> Result<Topic, ProcessError> {
+        std::fs::create_dir_all(&path)?;
+        std::fs::write(path.join(KEY_FILE_NAME), to_vec(key)?)?;
+
+        let mut segments = vec![];
+
+        for entry in std::fs::read_dir(&path)? {
+            let entry_path = entry?.path();
+
+            if entry_path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+
+            match entry_path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok()) {
+                Some(base_offset) => segments.push(base_offset),
+                None => warn!("Unexpected file in log directory: {:?}", entry_path)
+            }
+        }
+
+        segments.sort_unstable();
+
+        let (segment_bytes, next_offset) = match segments.last() {
+            Some(base_offset) => {
+                let data = std::fs::read(get_segment_path(&path, *base_offset))?;
+                let count = get_records(&data)?.len() as u64;
+
+                (data.len() as u64, base_offset + count)
+            }
+            None => (0, 0)
+        };
+
+        let consumers = match std::fs::read(path.join(CONSUMERS_FILE_NAME)) {
+            Ok(data) => from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into())
+        };
+
+        Ok(Topic {
+            path,
+            segments,
+            segment_bytes,
+            next_offset,
+            consumers
+        })
+    }
+    async fn append(&mut self, data: &[u8], segment_size: u64) -> Result<u64, ProcessError> {
+        if self.segments.is_empty() || self.segment_bytes >= segment_size {
+            self.segments.push(self.next_offset);
+            self.segment_bytes = 0;
+        }
+
+        let base_offset = self.segments[self.segments.len() - 1];
+        let mut file = OpenOptions::new().create(true).append(true).open(get_segment_path(&self.path, base_offset)).await?;
+        let mut buf = vec![];
+
+        buf.put_u32(data.len() as u32);
+        buf.extend_from_slice(data);
+
+        file.write_all(&buf).await?;
+        file.flush().await?;
+
+        let offset = self.next_offset;
+
+        self.segment_bytes += buf.len() as u64;
+        self.next_offset += 1;
+
+        Ok(offset)
+    }
+    async fn read(&self, offset: u64, max_count: u64) -> Result<Vec<(u64, Vec<u8>)>, ProcessError> {
+        let mut res = vec![];
+
+        for (i, base_offset) in self.segments.iter().enumerate() {
+            let segment_end = match self.segments.get(i + 1) {
+                Some(next_base_offset) => *next_base_offset,
+                None => self.next_offset
+            };
+
+            if segment_end <= offset {
+                continue;
+            }
+
+            let data = fs::read(get_segment_path(&self.path, *base_offset)).await?;
+
+            for (record_offset, record) in (*base_offset..).zip(get_records(&data)?) {
+                if record_offset < offset {
+                    continue;
+                }
+
+                if res.len() as u64 >= max_count {
+                    return Ok(res);
+                }
+
+                res.push((record_offset, record.to_vec()));
+            }
+        }
+
+        Ok(res)
+    }
+    async fn commit(&mut self, consumer: String, offset: u64) -> Result<(), ProcessError> {
+        self.consumers.insert(consumer, offset);
+
+        fs::write(self.path.join(CONSUMERS_FILE_NAME), to_vec(&self.consumers)?).await?;
+
+        Ok(())
+    }
+    fn get_offsets(&self, consumer: &str) -> LogOffsets {
+        LogOffsets {
+            committed: self.consumers.get(consumer).cloned().unwrap_or(0),
+            next: self.next_offset
+        }
+    }
+}
+
+fn get_segment_path(path: &Path, base_offset: u64) -> PathBuf {
+    path.join(format!("{:020}.{}", base_offset, SEGMENT_EXTENSION))
+}
+
+fn get_records(data: &[u8]) -> Result<Vec<&[u8]>, ProcessError> {
+    let mut res = vec![];
+    let mut position = 0;
+
+    while position < data.len() {
+        if data.len() - position < LEN_BUF_SIZE {
+            return Err(ProcessError::Custom(format!("Log segment is corrupted at position {}", position)));
+        }
+
+        let len = Cursor::new(&data[position..]).get_u32() as usize;
+        let start = position + LEN_BUF_SIZE;
+
+        if data.len() - start < len {
+            return Err(ProcessError::Custom(format!("Log segment is corrupted at position {}", position)));
+        }
+
+        res.push(&data[start..start + len]);
+
+        position = start + len;
+    }
+
+    Ok(res)
+}
+
+/// Splits message data to msg meta and the rest of the data (payload with attachments).
+fn get_msg_meta_and_rest(data: &[u8]) -> Result<(MsgMeta, &[u8]), ProcessError> {
+    if data.len() < LEN_BUF_SIZE {
+        return Err(ProcessError::Custom("Message data is too short".to_owned()));
+    }
+
+    let len = Cursor::new(data).get_u32() as usize;
+
+    if data.len() - LEN_BUF_SIZE < len {
+        return Err(ProcessError::Custom("Message data is inconsistent with msg meta length".to_owned()));
+    }
+
+    let msg_meta = from_slice(&data[LEN_BUF_SIZE..LEN_BUF_SIZE + len])?;
+
+    Ok((msg_meta, &data[LEN_BUF_SIZE + len..]))
+}
+
+/// Returns payload from the rest of message data, payload size is taken from msg meta.
+fn get_payload<'a>(msg_meta: &MsgMeta, rest: &'a [u8]) -> Result<&'a [u8], ProcessError> {
+    match rest.get(..msg_meta.payload_size as usize) {
+        Some(payload) => Ok(payload),
+        None => Err(ProcessError::Custom(format!("Message data is inconsistent with payload size, {}", msg_meta.display())))
+    }
+}
+
+async fn journal_loop(mut rx: UnboundedReceiver<JournalMsg>, mut topics: HashMap<Key, Topic>, segment_size: u64, server_tx: UnboundedSender<ServerMsg>) {
+    while let Some(msg) = rx.recv().await {
+        match msg {
+            JournalMsg::Append(key, data) => {
+                match topics.get_mut(&key) {
+                    Some(topic) => {
+                        match topic.append(&data, segment_size).await {
+                            Ok(offset) => debug!("Log record appended, key {:?}, offset {}", key, offset),
+                            Err(e) => error!("Log append failed, key {:?}, {:?}", key, e)
+                        }
+                    }
+                    None => error!("No log for key {:?}", key)
+                }
+            }
+            JournalMsg::Rpc(addr_hash, data) => {
+                if let Err(e) = process_rpc(&mut topics, addr_hash, &data, &server_tx).await {
+                    error!("Journal rpc processing failed, {:?}", e);
+                }
+            }
+        }
+    }
+
+    info!("Journal loop completed");
+}
+
+async fn process_rpc(topics: &mut HashMap<Key, Topic>, addr_hash: u64, data: &[u8], server_tx: &UnboundedSender<ServerMsg>) -> Result<(), ProcessError> {
+    let (mut msg_meta, rest) = get_msg_meta_and_rest(data)?;
+    let payload = get_payload(&msg_meta, rest)?.to_vec();
+    let (payload, _) = decompress_message(&mut msg_meta, payload, None)?;
+
+    debug!("Journal rpc request {}", msg_meta.display());
+
+    let res = match msg_meta.key.action.as_ref() {
+        "Read" => read(topics, &msg_meta, &payload).await,
+        "Commit" => commit(topics, &msg_meta, &payload).await,
+        "Offsets" => get_offsets(topics, &msg_meta, &payload),
+        _ => Err(ProcessError::Custom(format!("Unknown journal rpc {:?}", msg_meta.key)))
+    };
+
+    let (payload, attachments, attachments_data, rpc_result) = match res {
+        Ok((payload, attachments, attachments_data)) => (payload, attachments, attachments_data, RpcResult::Ok),
+        Err(e) => {
+            warn!("Journal rpc error, {}, {:?}", msg_meta.display(), e);
+            (serde_json::to_value(RemoteError::new(ErrorCode::Internal, format!("{:?}", e)))?, vec![], vec![], RpcResult::Err)
+        }
+    };
+
+    for frame in get_rpc_response_frames(JOURNAL_ADDR, &msg_meta, payload, attachments, attachments_data, rpc_result)? {
+        server_tx.send(ServerMsg::Send(addr_hash, frame))?;
+    }
+
+    Ok(())
+}
+
+fn get_topic<'a>(topics: &'a mut HashMap<Key, Topic>, key: &Key) -> Result<&'a mut Topic, ProcessError> {
+    topics.get_mut(key).ok_or_else(|| ProcessError::Custom(format!("No log for key {:?}", key)))
+}
+
+async fn read(topics: &mut HashMap<Key, Topic>, msg_meta: &MsgMeta, payload: &[u8]) -> Result<RpcOutput, ProcessError> {
+    let request: ReadRequest = decode(msg_meta.codec, payload)?;
+    let topic = get_topic(topics, &request.key)?;
+    let offset = match request.offset {
+        Some(offset) => offset,
+        None => topic.get_offsets(&msg_meta.tx).committed
+    };
+
+    let mut records = vec![];
+    let mut attachments = vec![];
+    let mut attachments_data = vec![];
+    let mut next_offset = offset;
+
+    for (record_offset, data) in topic.read(offset, request.max_count.min(MAX_READ_COUNT)).await? {
+        let (record_msg_meta, rest) = get_msg_meta_and_rest(&data)?;
+        let record_payload = get_payload(&record_msg_meta, rest)?;
+
+        attachments.push((record_offset.to_string(), record_payload.len() as u64));
+        attachments_data.extend_from_slice(record_payload);
+        records.push(ReadRecord {
+            offset: record_offset,
+            msg_meta: record_msg_meta
+        });
+
+        next_offset = record_offset + 1;
+    }
+
+    let response = ReadResponse {
+        records,
+        next_offset
+    };
+
+    Ok((serde_json::to_value(response)?, attachments, attachments_data))
+}
+
+async fn commit(topics: &mut HashMap<Key, Topic>, msg_meta: &MsgMeta, payload: &[u8]) -> Result<RpcOutput, ProcessError> {
+    let request: CommitRequest = decode(msg_meta.codec, payload)?;
+    let topic = get_topic(topics, &request.key)?;
+
+    topic.commit(msg_meta.tx.clone(), request.offset).await?;
+
+    Ok((json!({}), vec![], vec![]))
+}
+
+fn get_offsets(topics: &mut HashMap<Key, Topic>, msg_meta: &MsgMeta, payload: &[u8]) -> Result<RpcOutput, ProcessError> {
+    let request: OffsetsRequest = decode(msg_meta.codec, payload)?;
+    let topic = get_topic(topics, &request.key)?;
+
+    Ok((serde_json::to_value(topic.get_offsets(&msg_meta.tx))?, vec![], vec![]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc::UnboundedSender;
+    use sp_cfg::LogConfig;
+    use sp_dto::{Key, Participator, Route, RouteSpec, Subscribes, event_dto2, uuid::Uuid};
+    use crate::MagicBall;
+    use crate::loopback::Loopback;
+    use crate::loopback::fixture::Fixture;
+    use super::{Topic, get_msg_meta_and_rest, get_payload};
+
+    #[tokio::test]
+    async fn topic_reopen() {
+        let path = std::env::temp_dir().join(format!("sp-topic-{}", Uuid::new_v4()));
+        let key = Key::simple("Deploy");
+        let mut topic = Topic::open(path.clone(), &key).expect("Failed to open topic");
+
+        for n in 0..3u8 {
+            topic.append(&[n; 8], 10).await.expect("Failed to append record");
+        }
+
+        topic.commit("Consumer".to_owned(), 1).await.expect("Failed to commit offset");
+
+        let topic = Topic::open(path.clone(), &key).expect("Failed to reopen topic");
+        let records = topic.read(1, 10).await.expect("Failed to read records");
+
+        let _ = std::fs::remove_dir_all(path);
+
+        assert_eq!(topic.segments, vec![0, 1, 2]);
+        assert_eq!(topic.next_offset, 3);
+        assert_eq!(topic.get_offsets("Consumer").committed, 1);
+        assert_eq!(records, vec![(1, vec![1; 8]), (2, vec![2; 8])]);
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let route = Route {
+            source: Participator::Service("Publisher".to_owned()),
+            spec: RouteSpec::Simple,
+            points: vec![]
+        };
+        let (_, mut data) = event_dto2("Publisher".to_owned(), Key::simple("Deploy"), vec![1; 8], route, None, None).expect("Failed to create event");
+
+        data.truncate(data.len() - 4);
+
+        let (msg_meta, rest) = get_msg_meta_and_rest(&data).expect("Failed to get msg meta");
+
+        assert!(get_payload(&msg_meta, rest).is_err());
+    }
+
+    async fn startup_log_consumer(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        for n in 0..3 {
+            mb.send_event(Key::simple("Deploy"), json!({ "n": n })).await.expect("Failed to send event");
+        }
+
+        // journal handles appends and log rpcs of the client in order, so all events are read
+        let records = mb.read_log::<Value>(Key::simple("Deploy"), None, 10).await.expect("Failed to read log");
+
+        mb.commit_log_offset(Key::simple("Deploy"), 2).await.expect("Failed to commit offset");
+
+        let records_after_commit = mb.read_log::<Value>(Key::simple("Deploy"), None, 10).await.expect("Failed to read log");
+        let offsets = mb.get_log_offsets(Key::simple("Deploy")).await.expect("Failed to get offsets");
+
+        tx.send(json!({
+            "records": records.into_iter().map(|record| record.msg.payload).collect::<Vec<_>>(),
+            "records_after_commit": records_after_commit.into_iter().map(|record| (record.offset, record.msg.payload)).collect::<Vec<_>>(),
+            "offsets": [offsets.committed, offsets.next]
+        })).expect("Failed to send log result");
+    }
+
+    #[tokio::test]
+    async fn log_read_commit() {
+        let path = std::env::temp_dir().join(format!("sp-log-{}", Uuid::new_v4()));
+        let log_config = LogConfig {
+            path: path.to_string_lossy().to_string(),
+            segment_size: Some(100),
+            keys: vec![Key::simple("Deploy")]
+        };
+        let mut fixture = Fixture::with_loopback(Loopback::start_with_log(Subscribes::ByKey(HashMap::new(), HashMap::new()), log_config).expect("Failed to start loopback"));
+
+        fixture.spawn_client(json!({ "addr": "Consumer" }), startup_log_consumer);
+
+        let payload = fixture.recv().await;
+
+        let _ = std::fs::remove_dir_all(path);
+
+        assert_eq!(payload, json!({
+            "records": [{ "n": 0 }, { "n": 1 }, { "n": 2 }],
+            "records_after_commit": [[2, { "n": 2 }]],
+            "offsets": [2, 3]
+        }));
+    }
+}
@@ -3,8 +3,25 @@ pub use futures;
 pub use tokio;
 pub use sp_dto;
 pub use sp_cfg;
-pub use proto::{LEN_BUF_SIZE, MAX_FRAME_PAYLOAD_SIZE, MAX_FRAME_SIZE, ClientMsg, StreamLayout, StreamCompletion, ProcessStream, ProcessEvent, ProcessRpc, Startup, MagicBall, ProcessError, RestreamMsg, FrameType, Frame};
+pub use proto::{LEN_BUF_SIZE, MAX_FRAME_PAYLOAD_SIZE, MAX_NEGOTIATED_FRAME_PAYLOAD_SIZE, DEFAULT_FRAME_PAYLOAD_SIZE, MAX_FRAME_SIZE, DEFAULT_COMPRESSION_THRESHOLD, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, Capabilities, Handshake, ClientMsg, StreamLayout, StreamCompletion, ProcessStream, ProcessEvent, ProcessRpc, Startup, MagicBall, StreamWriter, ProcessError, RpcRetryPolicy, RpcStats, DEFAULT_MAX_PENDING_RPCS, RestreamMsg, FrameType, Frame, CancelReason, decompress_message};
+pub use codec::FrameCodec;
+pub use service::Service;
+pub use router::{KeyRouter, KeyPattern, ANY_KEY_PART};
+pub use middleware::{Layer, Interceptor, LogLayer, Logged, MetricsLayer, Metered, HandlerMetrics, CatchPanicLayer, CatchPanic};
+#[cfg(feature = "auth")]
+pub use middleware::{AuthLayer, Authorized};
+pub use backoff::{DEFAULT_RECONNECT_MIN_DELAY_MS, DEFAULT_RECONNECT_MAX_DELAY_MS};
+pub use transfer::{TransferFile, DEFAULT_TRANSFER_SYNC_SIZE};
 
 mod proto;
+mod codec;
+mod backoff;
+mod service;
+mod router;
+mod middleware;
+mod limits;
+mod transfer;
 pub mod server;
-pub mod client;
\ No newline at end of file
+pub mod client;
+pub mod loopback;
+pub mod journal;
\ No newline at end of file
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, oneshot::{self, error::TryRecvError}};
+use sp_cfg::ClientConfig;
+use sp_dto::Key;
+
+/// Limits of events and rpcs processed by message based client at once. Handlers over running limits wait for their turn,
+/// handlers over queue limit are not started. With ordered handlers messages with the same key are processed one at a time in order of receiving.
+pub(crate) struct HandlerLimits {
+    running: Option<Arc<Semaphore>>,
+    max_running_per_key: Option<usize>,
+    /// Handlers which are running or waiting for their turn
+    accepted: Arc<AtomicUsize>,
+    /// Max amount of accepted handlers, it is set if queue is limited with max_running_handlers
+    max_accepted: Option<usize>,
+    /// Handlers waiting for handlers with the same key, it is set if queue is limited without max_running_handlers
+    key_queue: Option<Arc<Mutex<KeyQueue>>>,
+    ordered: bool,
+    running_by_key: HashMap<Key, Arc<Semaphore>>,
+    /// Completion of the last handler started for the key, it is waited by the next one if handlers are ordered
+    last_by_key: HashMap<Key, oneshot::Receiver<()>>
+}
+
+impl HandlerLimits {
+    pub fn new(config: &ClientConfig) -> HandlerLimits {
+        let max_running = config.max_running_handlers.map(|max_running| max_running as usize);
+
+        HandlerLimits {
+            running: max_running.map(|max_running| Arc::new(Semaphore::new(max_running))),
+            max_running_per_key: config.max_running_handlers_per_key.map(|max_running| max_running as usize),
+            accepted: Arc::new(AtomicUsize::new(0)),
+            max_accepted: match (max_running, config.max_queued_handlers) {
+                (Some(max_running), Some(max_queued)) => Some(max_running.saturating_add(max_queued as usize)),
+                _ => None
+            },
+            key_queue: match (max_running, config.max_queued_handlers) {
+                (None, Some(max_queued)) => Some(Arc::new(Mutex::new(KeyQueue::new(config, max_queued as usize)))),
+                _ => None
+            },
+            ordered: config.ordered_handlers.unwrap_or_default(),
+            running_by_key: HashMap::new(),
+            last_by_key: HashMap::new()
+        }
+    }
+    /// Takes turn for handler of message with the key, None is returned if queue is full.
+    pub fn get_turn(&mut self, key: &Key) -> Option<HandlerTurn> {
+        if let Some(max_accepted) = self.max_accepted {
+            if self.accepted.load(Ordering::Relaxed) >= max_accepted {
+                return None;
+            }
+        }
+
+        if let Some(key_queue) = &self.key_queue {
+            if !key_queue.lock().expect("Key queue lock is poisoned").accept(key) {
+                return None;
+            }
+        }
+
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+
+        let mut turn = HandlerTurn {
+            key: key.clone(),
+            key_queue: self.key_queue.clone(),
+            running: self.running.clone(),
+            running_by_key: None,
+            previous: None,
+            _completion_tx: None,
+            accepted: self.accepted.clone()
+        };
+
+        if self.ordered {
+            let (completion_tx, completion_rx) = oneshot::channel();
+
+            self.last_by_key.retain(|_, completion_rx| matches!(completion_rx.try_recv(), Err(TryRecvError::Empty)));
+
+            turn.previous = self.last_by_key.insert(key.clone(), completion_rx);
+            turn._completion_tx = Some(completion_tx);
+        } else if let Some(max_running_per_key) = self.max_running_per_key {
+            self.running_by_key.retain(|_, running| Arc::strong_count(running) > 1);
+
+            turn.running_by_key = Some(self.running_by_key.entry(key.clone()).or_insert_with(|| Arc::new(Semaphore::new(max_running_per_key))).clone());
+        }
+
+        Some(turn)
+    }
+}
+
+/// Handlers accepted for each key, handlers over running limit of the key are waiting in the queue.
+struct KeyQueue {
+    max_running_per_key: usize,
+    max_waiting: usize,
+    accepted_by_key: HashMap<Key, usize>,
+    waiting: usize
+}
+
+impl KeyQueue {
+    fn new(config: &ClientConfig, max_waiting: usize) -> KeyQueue {
+        let max_running_per_key = match config.ordered_handlers {
+            Some(true) => 1,
+            _ => config.max_running_handlers_per_key.map(|max_running| max_running as usize).unwrap_or(usize::MAX)
+        };
+
+        KeyQueue {
+            max_running_per_key,
+            max_waiting,
+            accepted_by_key: HashMap::new(),
+            waiting: 0
+        }
+    }
+    /// Accepts handler of message with the key, false is returned if it has to wait and queue is full.
+    fn accept(&mut self, key: &Key) -> bool {
+        let accepted = self.accepted_by_key.get(key).copied().unwrap_or_default();
+
+        if accepted >= self.max_running_per_key {
+            if self.waiting >= self.max_waiting {
+                return false;
+            }
+
+            self.waiting += 1;
+        }
+
+        self.accepted_by_key.insert(key.clone(), accepted + 1);
+
+        true
+    }
+    fn release(&mut self, key: &Key) {
+        let accepted = self.accepted_by_key.get(key).copied().unwrap_or_default();
+
+        if accepted > self.max_running_per_key {
+            self.waiting -= 1;
+        }
+
+        if accepted > 1 {
+            self.accepted_by_key.insert(key.clone(), accepted - 1);
+        } else {
+            self.accepted_by_key.remove(key);
+        }
+    }
+}
+
+/// Place of handler in limits, it is released when handler is completed or dropped.
+pub(crate) struct HandlerTurn {
+    key: Key,
+    key_queue: Option<Arc<Mutex<KeyQueue>>>,
+    running: Option<Arc<Semaphore>>,
+    running_by_key: Option<Arc<Semaphore>>,
+    previous: Option<oneshot::Receiver<()>>,
+    /// Dropped with the turn, so the next handler with the same key can start
+    _completion_tx: Option<oneshot::Sender<()>>,
+    accepted: Arc<AtomicUsize>
+}
+
+impl HandlerTurn {
+    /// Waits for the turn and runs handler.
+    pub async fn run<F: Future>(mut self, handler: F) -> F::Output {
+        if let Some(previous) = self.previous.take() {
+            let _ = previous.await;
+        }
+
+        let _key_permit = acquire(&self.running_by_key).await;
+        let _permit = acquire(&self.running).await;
+
+        handler.await
+    }
+}
+
+impl Drop for HandlerTurn {
+    fn drop(&mut self) {
+        self.accepted.fetch_sub(1, Ordering::Relaxed);
+
+        if let Some(key_queue) = &self.key_queue {
+            key_queue.lock().expect("Key queue lock is poisoned").release(&self.key);
+        }
+    }
+}
+
+async fn acquire(semaphore: &Option<Arc<Semaphore>>) -> Option<OwnedSemaphorePermit> {
+    match semaphore {
+        // semaphores are never closed
+        Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+        None => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc::{self, UnboundedSender};
+    use sp_cfg::ClientConfig;
+    use sp_dto::{ErrorCode, Key, Message, resp};
+    use crate::{KeyRouter, MagicBall, ProcessError};
+    use crate::loopback::fixture::Fixture;
+    use super::HandlerLimits;
+
+    #[test]
+    fn key_queue() {
+        let config = ClientConfig {
+            max_running_handlers_per_key: Some(1),
+            max_queued_handlers: Some(1),
+            ..ClientConfig::new("Client")
+        };
+        let mut limits = HandlerLimits::new(&config);
+        let running = limits.get_turn(&Key::simple("First"));
+        // waits for the running handler and fills the queue
+        let waiting = limits.get_turn(&Key::simple("First"));
+
+        assert!(running.is_some());
+        assert!(waiting.is_some());
+        assert!(limits.get_turn(&Key::simple("First")).is_none());
+        // handlers with other keys don't wait
+        assert!(limits.get_turn(&Key::simple("Second")).is_some());
+
+        drop(running);
+
+        assert!(limits.get_turn(&Key::simple("First")).is_some());
+    }
+
+    async fn startup_limits_caller(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        for n in 0..3 {
+            mb.send_event(Key::simple("OrderedEvent"), json!(n)).await.expect("Failed to send event");
+        }
+
+        // events are completed before rpcs are sent
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let rpcs = (0..4).map(|_| {
+            let mut mb = mb.clone();
+
+            async move { mb.rpc::<_, Value>(Key::simple("SlowRpc"), json!({})).await }
+        });
+        let results = futures::future::join_all(rpcs).await;
+
+        tx.send(json!({
+            "completed": results.iter().filter(|res| res.is_ok()).count(),
+            "busy": results.iter().filter(|res| matches!(res, Err(ProcessError::Rpc(error)) if error.code == ErrorCode::Busy)).count()
+        })).expect("Failed to send rpc result");
+    }
+
+    #[tokio::test]
+    async fn handler_limits() {
+        let mut fixture = Fixture::start(&[Key::simple("OrderedEvent")], &[Key::simple("SlowRpc")]);
+        let (handler_tx, mut handler_rx) = mpsc::unbounded_channel();
+        let router = KeyRouter::new()
+            .with_event(Key::simple("OrderedEvent"), move |_, msg: Message<u64>| {
+                let handler_tx = handler_tx.clone();
+
+                async move {
+                    // later events are faster, they are completed in order only if handlers are ordered
+                    tokio::time::sleep(Duration::from_millis((3 - msg.payload) * 50)).await;
+                    handler_tx.send(json!(msg.payload))?;
+
+                    Ok(())
+                }
+            })
+            .with_rpc(Key::simple("SlowRpc"), |_, _: Message<Value>| async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+
+                resp(json!({}))
+            });
+        let config = json!({
+            "addr": "Service",
+            "max_running_handlers": 2,
+            "max_queued_handlers": 1,
+            "ordered_handlers": true
+        });
+
+        fixture.run_service(Arc::new(router), config).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_limits_caller);
+
+        let mut events = vec![];
+
+        for _ in 0..3 {
+            events.push(tokio::time::timeout(Duration::from_secs(2), handler_rx.recv()).await.expect("Timeout").expect("Channel closed"));
+        }
+
+        assert_eq!(events, vec![json!(0), json!(1), json!(2)]);
+
+        // 2 rpcs are running and 1 is queued
+        assert_eq!(fixture.recv().await, json!({
+            "completed": 3,
+            "busy": 1
+        }));
+    }
+}
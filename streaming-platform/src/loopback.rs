@@ -0,0 +1,811 @@
+use log::*;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use sp_dto::Subscribes;
+use sp_cfg::{LogConfig, NamespaceConfig};
+use crate::proto::*;
+use crate::server::{DEFAULT_NAMESPACE, Namespace, Router};
+
+/// In-process server. Clients connected with it exchange frames over channels instead of TCP connections, routing is the same as for the TCP server.
+/// Useful for tests and for embedding server and services in one process.
+#[derive(Clone)]
+pub struct Loopback {
+    namespace: Namespace
+}
+
+impl Loopback {
+    /// Starts in-process server with provided subscribes, addrs are hashed with random keys. Must be called inside tokio runtime.
+    /// All loopback clients are connected to single default namespace.
+    pub fn start(subscribes: Subscribes) -> Result<Loopback, ProcessError> {
+        Loopback::start_namespace(subscribes, None)
+    }
+    /// Same as start, events with keys from log config are appended to persistent log.
+    pub fn start_with_log(subscribes: Subscribes, log_config: LogConfig) -> Result<Loopback, ProcessError> {
+        Loopback::start_namespace(subscribes, Some(log_config))
+    }
+    fn start_namespace(subscribes: Subscribes, log: Option<LogConfig>) -> Result<Loopback, ProcessError> {
+        let config = NamespaceConfig {
+            name: DEFAULT_NAMESPACE.to_owned(),
+            access_keys: None,
+            log
+        };
+        let namespace = Namespace::start(config, subscribes, None, None)?;
+
+        info!("Loopback started");
+
+        Ok(Loopback {
+            namespace
+        })
+    }
+    /// Registers client with addr. Frames written by client are taken from write_rx and routed,
+    /// frames routed to the client are available via returned receiver.
+    pub fn connect(&self, addr: String, mut write_rx: UnboundedReceiver<WriteMsg>) -> Result<UnboundedReceiver<WriteMsg>, ProcessError> {
+        let (client_tx, client_rx) = mpsc::unbounded_channel();
+        let addr_hash = self.namespace.get_addr_hash(&addr);
+
+        self.namespace.server_tx.send(ServerMsg::AddClient(addr.clone(), None, client_tx))?;
+
+        info!("Loopback client connected as {}", addr);
+
+        let server_tx = self.namespace.server_tx.clone();
+        let mut router = Router::new(&addr, &self.namespace);
+
+        tokio::spawn(async move {
+            while let Some(WriteMsg::Frame(frame)) = write_rx.recv().await {
+                debug!("Loopback frame read, frame type {}, msg type {}, stream id {}", frame.frame_type, frame.msg_type, frame.stream_id);
+
+                if let Err(e) = router.route(frame, &server_tx) {
+                    error!("Loopback frame routing failed, client addr {}, {:?}", addr, e);
+                }
+            }
+
+            router.cancel_streams(CancelReason::SenderDisconnected, &server_tx);
+
+            let _ = server_tx.send(ServerMsg::RemoveClient(addr_hash));
+
+            info!("Loopback client {} disconnected", addr);
+        });
+
+        Ok(client_rx)
+    }
+}
+
+/// Shared setup of loopback tests: clients are spawned on one loopback and report results over the fixture channel.
+#[cfg(test)]
+pub(crate) mod fixture {
+    use std::error::Error;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+    use sp_dto::{ErrorCode, Key, Message, RemoteError, Response, Subscribes, resp, resp_err};
+    use crate::client::{self, Transport};
+    use crate::{ClientMsg, MagicBall, ProcessEvent, ProcessRpc, ProcessStream, Service, Startup};
+    use super::Loopback;
+
+    /// Addr of the client subscribed to keys passed to Fixture::start, tests spawn services with it
+    const SERVICE_ADDR: &str = "Service";
+    const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub struct Fixture {
+        pub loopback: Loopback,
+        /// Sender of results passed to clients as dependency
+        pub tx: UnboundedSender<Value>,
+        rx: UnboundedReceiver<Value>
+    }
+
+    impl Fixture {
+        /// Starts loopback where event and rpc keys are subscribed by SERVICE_ADDR client.
+        pub fn start(event_keys: &[Key], rpc_keys: &[Key]) -> Fixture {
+            let subscribes = |keys: &[Key]| keys.iter().map(|key| (key.clone(), vec![SERVICE_ADDR.to_owned()])).collect();
+
+            Fixture::with_loopback(Loopback::start(Subscribes::ByKey(subscribes(event_keys), subscribes(rpc_keys))).expect("Failed to start loopback"))
+        }
+        pub fn with_loopback(loopback: Loopback) -> Fixture {
+            let _ = env_logger::try_init();
+            let (tx, rx) = mpsc::unbounded_channel();
+
+            Fixture {
+                loopback,
+                tx,
+                rx
+            }
+        }
+        /// Spawns message based client and waits until it is connected, so messages sent after it are not dropped for missing subscriber.
+        pub async fn spawn_service<P, T, Q>(&mut self, config: Value, process_event: ProcessEvent<T, P, UnboundedSender<Value>>, process_rpc: ProcessRpc<Q, P, UnboundedSender<Value>>)
+        where
+            T: Future<Output = Result<(), Box<dyn Error>>> + Send + 'static,
+            Q: Future<Output = Result<Response<P>, Box<dyn Error>>> + Send + 'static,
+            P: serde::Serialize + 'static, for<'de> P: serde::Deserialize<'de> + Send
+        {
+            tokio::spawn(client::full_message_mode_with_transport(Transport::Loopback(Box::new(self.loopback.clone())), config, process_event, process_rpc, startup_ready, None, self.tx.clone()));
+
+            self.wait_ready().await;
+        }
+        /// Same as spawn_service for stream based client.
+        pub async fn spawn_stream_service<T>(&mut self, config: Value, process_stream: ProcessStream<T, UnboundedSender<Value>>) where T: Future<Output = ()> + Send + 'static {
+            tokio::spawn(client::stream_mode_with_transport(Transport::Loopback(Box::new(self.loopback.clone())), config, process_stream, startup_ready, None, None, None, self.tx.clone()));
+
+            self.wait_ready().await;
+        }
+        /// Same as spawn_service for client running the service.
+        pub async fn run_service<S: Service>(&mut self, service: Arc<S>, config: Value) {
+            let service = Ready {
+                inner: service,
+                tx: self.tx.clone()
+            };
+
+            tokio::spawn(client::run_with_transport(Transport::Loopback(Box::new(self.loopback.clone())), Arc::new(service), config));
+
+            self.wait_ready().await;
+        }
+        /// Spawns message based client without waiting for it, usually the caller which sends results from startup.
+        pub fn spawn_client<R>(&self, config: Value, startup: Startup<R, UnboundedSender<Value>>) where R: Future<Output = ()> + Send + 'static {
+            tokio::spawn(client::full_message_mode_with_transport(Transport::Loopback(Box::new(self.loopback.clone())), config, process_event, process_rpc, startup, None, self.tx.clone()));
+        }
+        pub async fn recv(&mut self) -> Value {
+            tokio::time::timeout(RECV_TIMEOUT, self.rx.recv()).await.expect("Timeout").expect("Channel closed")
+        }
+        pub async fn recv_n(&mut self, n: usize) -> Vec<Value> {
+            let mut received = vec![];
+
+            for _ in 0..n {
+                received.push(self.recv().await);
+            }
+
+            received
+        }
+        async fn wait_ready(&mut self) {
+            let payload = self.recv().await;
+
+            assert!(payload["ready"].is_string(), "Result received before client is ready, {}", payload);
+        }
+    }
+
+    /// Wraps the service to signal the fixture when its client is connected.
+    struct Ready<S> {
+        inner: Arc<S>,
+        tx: UnboundedSender<Value>
+    }
+
+    impl<S: Service> Service for Ready<S> {
+        type Payload = S::Payload;
+
+        async fn on_start(&self, config: Value, mb: MagicBall) {
+            signal_ready(&mb, &self.tx).await;
+
+            self.inner.on_start(config, mb).await
+        }
+        async fn on_event(&self, mb: MagicBall, msg: Message<S::Payload>) -> Result<(), Box<dyn Error>> {
+            self.inner.on_event(mb, msg).await
+        }
+        async fn on_rpc(&self, mb: MagicBall, msg: Message<S::Payload>) -> Result<Response<S::Payload>, Box<dyn Error>> {
+            self.inner.on_rpc(mb, msg).await
+        }
+        async fn on_stream(&self, mb: MagicBall, rx: UnboundedReceiver<ClientMsg>) {
+            self.inner.on_stream(mb, rx).await
+        }
+        async fn on_stop(&self) {
+            self.inner.on_stop().await
+        }
+    }
+
+    /// Signals the fixture when the client is connected, so frames routed after the signal reach it.
+    async fn signal_ready(mb: &MagicBall, tx: &UnboundedSender<Value>) {
+        mb.write_settings.wait_connected().await;
+
+        tx.send(json!({ "ready": mb.addr })).expect("Failed to send ready");
+    }
+
+    async fn startup_ready(_: Value, _: Value, mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        signal_ready(&mb, &tx).await;
+    }
+
+    pub async fn process_event(_: Value, _: MagicBall, msg: Message<Value>, tx: UnboundedSender<Value>) -> Result<(), Box<dyn Error>> {
+        tx.send(msg.payload)?;
+
+        Ok(())
+    }
+
+    pub async fn process_rpc(_: Value, _: MagicBall, msg: Message<Value>, _: UnboundedSender<Value>) -> Result<Response<Value>, Box<dyn Error>> {
+        match msg.meta.key.action.as_ref() {
+            "SlowRpc" => tokio::time::sleep(Duration::from_secs(1)).await,
+            "MissingRpc" => return resp_err(RemoteError::new(ErrorCode::NotFound, "Item not found").with_details(json!({ "id": 1 }))),
+            "FailingRpc" => return Err("handler failed".into()),
+            _ => {}
+        }
+
+        resp(json!({
+            "data": msg.payload["data"].as_str().unwrap_or_default().to_owned() + " back"
+        }))
+    }
+
+    pub async fn startup(_: Value, _: Value, _: MagicBall, _: Option<Value>, _: UnboundedSender<Value>) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+    use serde_derive::{Serialize, Deserialize};
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+    use sp_dto::{Codec, ErrorCode, Key, Message, RemoteError, Response, bytes::Bytes, resp};
+    use crate::client::{self, Transport};
+    use crate::proto::WriteMsg;
+    use crate::{CancelReason, ClientMsg, Frame, FrameType, MagicBall, ProcessError, RestreamMsg, Service};
+    use super::Loopback;
+    use super::fixture::{Fixture, process_event, process_rpc, startup};
+
+    async fn startup_caller(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        mb.send_event(Key::simple("HiEvent"), json!({
+            "data": "hello event"
+        })).await.expect("Failed to send event");
+
+        let msg = mb.rpc::<_, Value>(Key::simple("HiRpc"), json!({
+            "data": "hello rpc"
+        })).await.expect("Rpc failed");
+
+        tx.send(msg.payload).expect("Failed to send rpc result");
+    }
+
+    async fn startup_deadline_caller(_: Value, _: Value, mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        let res = mb.with_timeout(Duration::from_millis(100)).rpc::<_, Value>(Key::simple("SlowRpc"), json!({
+            "data": "hello slow rpc"
+        })).await;
+
+        // deadline is enforced by the server too, so either side may time the rpc out first
+        tx.send(json!({
+            "timeout": matches!(res, Err(ProcessError::Timeout)) || matches!(res, Err(ProcessError::Rpc(error)) if error.code == ErrorCode::Timeout)
+        })).expect("Failed to send rpc result");
+    }
+
+    async fn startup_error_caller(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        for action in &["MissingRpc", "FailingRpc", "UnknownRpc"] {
+            let res = mb.rpc::<_, Value>(Key::simple(action), json!({})).await;
+
+            let error = match res {
+                Err(ProcessError::Rpc(error)) => serde_json::to_value(error).expect("Failed to serialize rpc error"),
+                res => json!({ "unexpected": format!("{:?}", res) })
+            };
+
+            tx.send(error).expect("Failed to send rpc result");
+        }
+    }
+
+    async fn startup_publisher(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        mb.send_retained_event(Key::simple("Status"), json!({
+            "status": "deployed"
+        })).await.expect("Failed to send retained event");
+
+        // server handles messages of the client in order, so the event is retained when stats are returned
+        mb.get_stats().await.expect("Failed to get stats");
+
+        tx.send(json!({ "published": true })).expect("Failed to send publish result");
+    }
+
+    async fn startup_stats_reader(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        for n in 0..2 {
+            mb.send_event(Key::simple("Count"), json!({ "n": n })).await.expect("Failed to send event");
+        }
+
+        let stats = mb.get_stats().await.expect("Failed to get stats");
+
+        tx.send(json!({
+            "namespace": stats.namespace,
+            "clients": stats.clients,
+            "messages": stats.messages,
+            "dropped": stats.dropped
+        })).expect("Failed to send stats result");
+    }
+
+    async fn startup_big_sender(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, _: UnboundedSender<Value>) {
+        mb.send_event(Key::simple("BigEvent"), json!({
+            "data": "x".repeat(300_000)
+        })).await.expect("Failed to send event");
+    }
+
+    async fn startup_compressed_sender(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        mb.send_event(Key::simple("BigEvent"), json!({
+            "data": "x".repeat(300_000)
+        })).await.expect("Failed to send event");
+
+        let msg = mb.rpc::<_, Value>(Key::simple("HiRpc"), json!({
+            "data": "y".repeat(10_000)
+        })).await.expect("Rpc failed");
+
+        let stats = mb.get_stats().await.expect("Failed to get stats");
+
+        tx.send(json!({
+            "rpc_data_len": msg.payload["data"].as_str().map(|data| data.len()),
+            "compression": msg.meta.compression.is_some(),
+            "bytes": stats.bytes
+        })).expect("Failed to send rpc result");
+    }
+
+    async fn startup_codec_caller(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        mb.set_codec(Key::simple("HiEvent"), Codec::MessagePack);
+
+        mb.send_event(Key::simple("HiEvent"), json!({
+            "data": "hello event"
+        })).await.expect("Failed to send event");
+
+        let msg = mb.with_codec(Codec::Cbor).rpc::<_, Value>(Key::simple("HiRpc"), json!({
+            "data": "hello rpc"
+        })).await.expect("Rpc failed");
+
+        tx.send(json!({
+            "data": msg.payload["data"],
+            "codec": msg.meta.codec
+        })).expect("Failed to send rpc result");
+    }
+
+    async fn startup_bincode_caller(_: Value, _: Value, mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        // handler takes serde_json::Value, which bincode is not able to decode, error response is readable anyway
+        let res = mb.with_codec(Codec::Bincode).rpc::<_, Value>(Key::simple("HiRpc"), json!({
+            "data": "hello rpc"
+        })).await;
+
+        let error = match res {
+            Err(ProcessError::Rpc(error)) => serde_json::to_value(error).expect("Failed to serialize rpc error"),
+            res => json!({ "unexpected": format!("{:?}", res) })
+        };
+
+        tx.send(error).expect("Failed to send rpc result");
+    }
+
+    async fn process_attachments_event(_: Value, _: MagicBall, msg: Message<Value>, tx: UnboundedSender<Value>) -> Result<(), Box<dyn std::error::Error>> {
+        tx.send(json!({
+            "data": msg.payload["data"],
+            "attachments_data": msg.attachments_data.unwrap_or_default()
+        }))?;
+
+        Ok(())
+    }
+
+    async fn startup_stream_caller(_: Value, _: Value, mb: MagicBall, _: Option<Value>, _: UnboundedSender<Value>) {
+        let mut mb = mb.with_credits(2);
+
+        let mut stream = mb.start_event_stream(Key::simple("HiStream"), json!({
+            "data": "hello stream"
+        })).await.expect("Failed to start stream");
+
+        // window is smaller than the stream, so writing completes only if the receiver grants credits
+        for i in 0..8u8 {
+            stream.write_payload(Bytes::from(vec![i; 4])).await.expect("Failed to write stream frame");
+        }
+
+        stream.finish().expect("Failed to finish stream");
+    }
+
+    async fn startup_concurrent_streams_caller(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, _: UnboundedSender<Value>) {
+        let mut first = mb.start_event_stream(Key::simple("HiStream"), json!({ "data": "first" })).await.expect("Failed to start stream");
+        let mut second = mb.start_event_stream(Key::simple("HiStream"), json!({ "data": "second" })).await.expect("Failed to start stream");
+
+        // frames of both streams are interleaved on the connection
+        for i in 0..4u8 {
+            first.write_payload(Bytes::from(vec![i; 2])).await.expect("Failed to write stream frame");
+            second.write_payload(Bytes::from(vec![i + 10; 2])).await.expect("Failed to write stream frame");
+        }
+
+        second.finish().expect("Failed to finish stream");
+        first.finish().expect("Failed to finish stream");
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct AddRequest {
+        a: i64,
+        b: i64
+    }
+
+    async fn process_add_event(_: Value, _: MagicBall, _: Message<AddRequest>, _: UnboundedSender<Value>) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    async fn process_add_rpc(_: Value, _: MagicBall, msg: Message<AddRequest>, _: UnboundedSender<Value>) -> Result<Response<AddRequest>, Box<dyn std::error::Error>> {
+        resp(AddRequest {
+            a: msg.payload.a + msg.payload.b,
+            b: 0
+        })
+    }
+
+    async fn startup_undecodable_caller(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        mb.send_event(Key::simple("AddEvent"), json!({ "a": "2" })).await.expect("Failed to send event");
+
+        for payload in [json!({ "a": "2" }), json!({ "a": 2, "b": 3 })] {
+            let res = match mb.rpc::<_, Value>(Key::simple("AddRpc"), payload).await {
+                Ok(msg) => msg.payload,
+                Err(ProcessError::Rpc(error)) => json!({ "code": error.code }),
+                Err(e) => json!({ "unexpected": format!("{:?}", e) })
+            };
+
+            tx.send(res).expect("Failed to send rpc result");
+        }
+    }
+
+    /// Connects client without client loop, frames routed to it are read directly from returned receiver.
+    fn connect_raw(loopback: &Loopback, addr: &str) -> (MagicBall, UnboundedReceiver<WriteMsg>) {
+        let (write_tx, write_rx) = mpsc::unbounded_channel();
+        let (rpc_inbound_tx, _) = mpsc::unbounded_channel();
+        let read_rx = loopback.connect(addr.to_owned(), write_rx).expect("Failed to connect");
+
+        (MagicBall::new(addr.to_owned(), write_tx, rpc_inbound_tx), read_rx)
+    }
+
+    async fn recv_frame(rx: &mut UnboundedReceiver<WriteMsg>) -> Frame {
+        match tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.expect("Timeout") {
+            Some(WriteMsg::Frame(frame)) => frame,
+            _ => panic!("Channel closed")
+        }
+    }
+
+    async fn process_cancelled_stream(_: Value, _: MagicBall, mut rx: UnboundedReceiver<ClientMsg>, _: Option<UnboundedSender<RestreamMsg>>, _: Option<UnboundedReceiver<RestreamMsg>>, tx: UnboundedSender<Value>) {
+        while let Some(ClientMsg::Frame(frame)) = rx.recv().await {
+            tx.send(json!({
+                "frame_type": frame.frame_type,
+                "cancel_reason": frame.get_cancel_reason().map(|reason| reason.get_u16())
+            })).expect("Failed to send frame");
+        }
+    }
+
+    async fn startup_cancel_caller(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, _: UnboundedSender<Value>) {
+        let mut stream = mb.start_event_stream(Key::simple("HiStream"), json!({
+            "data": "hello stream"
+        })).await.expect("Failed to start stream");
+
+        stream.write_payload(Bytes::from_static(b"partial")).await.expect("Failed to write stream frame");
+        stream.abort(CancelReason::Custom(1000)).expect("Failed to abort stream");
+    }
+
+    /// Service keeping count of processed rpcs.
+    struct CountingService {
+        calls: AtomicU64,
+        tx: UnboundedSender<Value>
+    }
+
+    impl Service for CountingService {
+        type Payload = Value;
+
+        async fn on_event(&self, _: MagicBall, msg: Message<Value>) -> Result<(), Box<dyn std::error::Error>> {
+            self.tx.send(msg.payload)?;
+
+            Ok(())
+        }
+        async fn on_rpc(&self, _: MagicBall, _: Message<Value>) -> Result<Response<Value>, Box<dyn std::error::Error>> {
+            resp(json!({
+                "calls": self.calls.fetch_add(1, Ordering::Relaxed) + 1
+            }))
+        }
+    }
+
+    struct CallerService {
+        tx: UnboundedSender<Value>
+    }
+
+    impl Service for CallerService {
+        type Payload = Value;
+
+        async fn on_start(&self, _: Value, mut mb: MagicBall) {
+            mb.send_event(Key::simple("HiEvent"), json!({
+                "data": "hello service"
+            })).await.expect("Failed to send event");
+
+            for _ in 0..2 {
+                let msg = mb.rpc::<_, Value>(Key::simple("HiRpc"), json!({})).await.expect("Rpc failed");
+
+                self.tx.send(msg.payload).expect("Failed to send rpc result");
+            }
+        }
+    }
+
+    async fn startup_cancel_rpc_caller(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        let mut slow_mb = mb.clone();
+        let rpc = tokio::spawn(async move {
+            let _ = slow_mb.rpc::<_, Value>(Key::simple("SlowRpc"), json!({})).await;
+        });
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        rpc.abort();
+
+        let res = mb.with_rpc_timeout(Duration::from_millis(200)).rpc::<_, Value>(Key::simple("SlowRpc"), json!({})).await;
+
+        mb.set_max_pending_rpcs(0);
+
+        let rejected = mb.rpc::<_, Value>(Key::simple("SlowRpc"), json!({})).await;
+        let stats = mb.get_rpc_stats();
+
+        tx.send(json!({
+            "timeout": matches!(res, Err(ProcessError::Timeout)) || matches!(res, Err(ProcessError::Rpc(error)) if error.code == ErrorCode::Timeout),
+            "rejected": matches!(rejected, Err(ProcessError::TooManyPendingRpcs(0))),
+            "pending": stats.pending,
+            "cancelled": stats.cancelled,
+            "finished": stats.completed + stats.failed + stats.timed_out,
+            "rejected_count": stats.rejected
+        })).expect("Failed to send rpc result");
+    }
+
+    /// Sends "dropped" when rpc handler holding it is completed or cancelled.
+    struct DropNotifier(UnboundedSender<Value>);
+
+    impl Drop for DropNotifier {
+        fn drop(&mut self) {
+            let _ = self.0.send(json!("dropped"));
+        }
+    }
+
+    struct SlowService {
+        tx: UnboundedSender<Value>
+    }
+
+    impl Service for SlowService {
+        type Payload = Value;
+
+        async fn on_rpc(&self, _: MagicBall, _: Message<Value>) -> Result<Response<Value>, Box<dyn std::error::Error>> {
+            let _notifier = DropNotifier(self.tx.clone());
+
+            tokio::time::sleep(Duration::from_secs(10)).await;
+
+            resp(json!({}))
+        }
+    }
+
+    #[tokio::test]
+    async fn event_and_rpc() {
+        let mut fixture = Fixture::start(&[Key::simple("HiEvent")], &[Key::simple("HiRpc")]);
+
+        fixture.spawn_service(json!({ "addr": "Service" }), process_event, process_rpc).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_caller);
+
+        let received = fixture.recv_n(2).await;
+
+        assert!(received.contains(&json!({ "data": "hello event" })));
+        assert!(received.contains(&json!({ "data": "hello rpc back" })));
+    }
+
+    #[tokio::test]
+    async fn rpc_deadline() {
+        let mut fixture = Fixture::start(&[], &[Key::simple("SlowRpc")]);
+
+        fixture.spawn_service(json!({ "addr": "Service" }), process_event, process_rpc).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_deadline_caller);
+
+        assert_eq!(fixture.recv().await, json!({ "timeout": true }));
+    }
+
+    #[tokio::test]
+    async fn rpc_errors() {
+        let mut fixture = Fixture::start(&[], &[Key::simple("MissingRpc"), Key::simple("FailingRpc")]);
+
+        fixture.spawn_service(json!({ "addr": "Service" }), process_event, process_rpc).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_error_caller);
+
+        let errors: Vec<RemoteError> = fixture.recv_n(3).await.into_iter()
+            .map(|payload| serde_json::from_value(payload).expect("Unexpected rpc result"))
+            .collect();
+
+        assert_eq!(errors[0], RemoteError::new(ErrorCode::NotFound, "Item not found").with_details(json!({ "id": 1 })));
+        assert_eq!(errors[1], RemoteError::new(ErrorCode::Internal, "handler failed"));
+        assert_eq!(errors[2].code, ErrorCode::NoRoute);
+    }
+
+    #[tokio::test]
+    async fn retained_event() {
+        let mut fixture = Fixture::start(&[Key::simple("Status")], &[]);
+
+        fixture.spawn_client(json!({ "addr": "Publisher" }), startup_publisher);
+
+        assert_eq!(fixture.recv().await, json!({ "published": true }));
+
+        // subscriber is connected after the event was published
+        fixture.spawn_client(json!({ "addr": "Service" }), startup);
+
+        assert_eq!(fixture.recv().await, json!({ "status": "deployed" }));
+    }
+
+    #[tokio::test]
+    async fn namespace_stats() {
+        let mut fixture = Fixture::start(&[], &[]);
+
+        fixture.spawn_client(json!({ "addr": "Reader" }), startup_stats_reader);
+
+        // stats request is counted too
+        assert_eq!(fixture.recv().await, json!({
+            "namespace": "default",
+            "clients": 1,
+            "messages": 3,
+            "dropped": 0
+        }));
+    }
+
+    #[tokio::test]
+    async fn big_frames() {
+        let mut fixture = Fixture::start(&[Key::simple("BigEvent")], &[]);
+
+        fixture.spawn_service(json!({ "addr": "Service" }), process_event, process_rpc).await;
+        fixture.spawn_client(json!({ "addr": "Caller", "max_frame_payload_size": 65535 }), startup_big_sender);
+
+        let payload = fixture.recv().await;
+
+        assert_eq!(payload["data"].as_str().map(|data| data.len()), Some(300_000));
+    }
+
+    #[tokio::test]
+    async fn compressed_messages() {
+        let mut fixture = Fixture::start(&[Key::simple("BigEvent")], &[Key::simple("HiRpc")]);
+
+        fixture.spawn_service(json!({ "addr": "Service", "compression": true }), process_event, process_rpc).await;
+        fixture.spawn_client(json!({ "addr": "Caller", "compression": true }), startup_compressed_sender);
+
+        let received = fixture.recv_n(2).await;
+        let event = received.iter().find(|payload| payload["data"].is_string()).expect("Event not received");
+        let rpc_result = received.iter().find(|payload| payload["bytes"].is_u64()).expect("Rpc result not received");
+
+        assert_eq!(event["data"].as_str().map(|data| data.len()), Some(300_000));
+        assert_eq!(rpc_result["rpc_data_len"], json!(10_005));
+        assert_eq!(rpc_result["compression"], json!(false));
+        // both big messages are compressed on the wire
+        assert!(rpc_result["bytes"].as_u64().unwrap_or(u64::MAX) < 20_000);
+    }
+
+    #[tokio::test]
+    async fn payload_codecs() {
+        let mut fixture = Fixture::start(&[Key::simple("HiEvent")], &[Key::simple("HiRpc")]);
+
+        fixture.spawn_service(json!({ "addr": "Service" }), process_event, process_rpc).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_codec_caller);
+
+        let received = fixture.recv_n(2).await;
+
+        // rpc response is encoded with codec of the request
+        assert!(received.contains(&json!({ "data": "hello event" })));
+        assert!(received.contains(&json!({ "data": "hello rpc back", "codec": "Cbor" })));
+    }
+
+    #[tokio::test]
+    async fn bincode_to_value_handler() {
+        let mut fixture = Fixture::start(&[], &[Key::simple("HiRpc")]);
+
+        fixture.spawn_service(json!({ "addr": "Service" }), process_event, process_rpc).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_bincode_caller);
+
+        let error: RemoteError = serde_json::from_value(fixture.recv().await).expect("Unexpected rpc result");
+
+        assert_eq!(error.code, ErrorCode::BadRequest);
+        assert!(error.message.contains("Bincode payload can't be decoded"));
+    }
+
+    #[tokio::test]
+    async fn flow_controlled_stream() {
+        let mut fixture = Fixture::start(&[Key::simple("HiStream")], &[]);
+
+        fixture.spawn_service(json!({ "addr": "Service" }), process_attachments_event, process_rpc).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_stream_caller);
+
+        let attachments_data: Vec<u8> = (0..8u8).flat_map(|i| vec![i; 4]).collect();
+
+        assert_eq!(fixture.recv().await, json!({ "data": "hello stream", "attachments_data": attachments_data }));
+    }
+
+    #[tokio::test]
+    async fn forged_credits() {
+        let fixture = Fixture::start(&[Key::simple("HiStream")], &[]);
+        let (caller_mb, mut caller_rx) = connect_raw(&fixture.loopback, "Caller");
+        let (service_mb, mut service_rx) = connect_raw(&fixture.loopback, "Service");
+        let (forger_mb, _forger_rx) = connect_raw(&fixture.loopback, "Forger");
+
+        let _stream = caller_mb.with_credits(1).start_event_stream(Key::simple("HiStream"), json!({
+            "data": "hello stream"
+        })).await.expect("Failed to start stream");
+
+        let stream_id = recv_frame(&mut service_rx).await.stream_id;
+
+        // only the receiver of the stream grants credits and acks transfer to its sender
+        forger_mb.grant_credits("Caller", stream_id, 100).expect("Failed to grant credits");
+        forger_mb.ack_transfer("Caller", stream_id, 100).expect("Failed to ack transfer");
+        service_mb.grant_credits("Caller", stream_id, 1).expect("Failed to grant credits");
+
+        let frame = recv_frame(&mut caller_rx).await;
+
+        assert_eq!(frame.frame_type, FrameType::Credit as u8);
+        assert_eq!(frame.payload.expect("Credit payload is missing")[..4], 1u32.to_be_bytes());
+        assert!(tokio::time::timeout(Duration::from_millis(200), caller_rx.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cancelled_stream() {
+        let mut fixture = Fixture::start(&[Key::simple("HiStream")], &[]);
+
+        fixture.spawn_stream_service(json!({ "addr": "Service" }), process_cancelled_stream).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_cancel_caller);
+
+        let mut frames = vec![];
+
+        loop {
+            let frame = fixture.recv().await;
+            let frame_type = frame["frame_type"].as_u64();
+
+            frames.push(frame);
+
+            if frame_type == Some(FrameType::Cancel as u64) {
+                break;
+            }
+        }
+
+        // cancel frame follows stream frames sent before it
+        assert!(frames.contains(&json!({ "frame_type": FrameType::Attachment as u8, "cancel_reason": null })));
+        assert_eq!(frames.last(), Some(&json!({ "frame_type": FrameType::Cancel as u8, "cancel_reason": 1000 })));
+    }
+
+    #[tokio::test]
+    async fn undecodable_payload() {
+        let mut fixture = Fixture::start(&[Key::simple("AddEvent")], &[Key::simple("AddRpc")]);
+
+        fixture.spawn_service(json!({ "addr": "Service" }), process_add_event, process_add_rpc).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_undecodable_caller);
+
+        assert_eq!(fixture.recv_n(2).await, vec![
+            json!({ "code": ErrorCode::BadRequest }),
+            json!({ "a": 5, "b": 0 })
+        ]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_streams() {
+        let mut fixture = Fixture::start(&[Key::simple("HiStream")], &[]);
+
+        fixture.spawn_service(json!({ "addr": "Service" }), process_attachments_event, process_rpc).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_concurrent_streams_caller);
+
+        let received = fixture.recv_n(2).await;
+        let first: Vec<u8> = (0..4u8).flat_map(|i| vec![i; 2]).collect();
+        let second: Vec<u8> = (10..14u8).flat_map(|i| vec![i; 2]).collect();
+
+        assert_eq!(received[0], json!({ "data": "second", "attachments_data": second }));
+        assert_eq!(received[1], json!({ "data": "first", "attachments_data": first }));
+    }
+
+    #[tokio::test]
+    async fn service_api() {
+        let mut fixture = Fixture::start(&[Key::simple("HiEvent")], &[Key::simple("HiRpc")]);
+        let service = Arc::new(CountingService {
+            calls: AtomicU64::new(0),
+            tx: fixture.tx.clone()
+        });
+
+        fixture.run_service(service.clone(), json!({ "addr": "Service" })).await;
+
+        tokio::spawn(client::run_with_transport(Transport::Loopback(Box::new(fixture.loopback.clone())), Arc::new(CallerService { tx: fixture.tx.clone() }), json!({ "addr": "Caller" })));
+
+        let received = fixture.recv_n(3).await;
+
+        assert!(received.contains(&json!({ "data": "hello service" })));
+        assert!(received.contains(&json!({ "calls": 1 })));
+        assert!(received.contains(&json!({ "calls": 2 })));
+        assert_eq!(service.calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn cancelled_rpcs() {
+        let mut fixture = Fixture::start(&[], &[Key::simple("SlowRpc")]);
+        let (handler_tx, mut handler_rx) = mpsc::unbounded_channel();
+
+        fixture.run_service(Arc::new(SlowService { tx: handler_tx }), json!({ "addr": "Service" })).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_cancel_rpc_caller);
+
+        // handlers are stopped long before they complete, dropped rpc is cancelled by requester and timed out one by deadline or cancel
+        for _ in 0..2 {
+            assert_eq!(tokio::time::timeout(Duration::from_secs(2), handler_rx.recv()).await.expect("Timeout").expect("Channel closed"), json!("dropped"));
+        }
+
+        assert_eq!(fixture.recv().await, json!({
+            "timeout": true,
+            "rejected": true,
+            "pending": 0,
+            "cancelled": 1,
+            "finished": 1,
+            "rejected_count": 1
+        }));
+    }
+}
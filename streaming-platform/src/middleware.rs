@@ -0,0 +1,517 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::error::Error;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures::FutureExt;
+use log::*;
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedReceiver;
+use sp_dto::{ErrorCode, Key, Message, MsgType, RemoteError, Response};
+use crate::proto::{ClientMsg, MagicBall, ProcessError};
+use crate::service::Service;
+#[cfg(feature = "auth")]
+use crate::router::KeyPattern;
+
+/// Wraps service with another service adding behavior around its handlers, like tower Layer.
+/// Layers are applied with Service::layer, the last applied layer is called first.
+pub trait Layer<S> {
+    type Service;
+
+    fn layer(&self, inner: S) -> Self::Service;
+}
+
+/// Interceptor of messages sent with MagicBall, it is added with MagicBall add_interceptor.
+pub trait Interceptor: Send + Sync + 'static {
+    /// Called before event, rpc request or stream start is sent, returned error fails the call and the message is not sent.
+    fn on_send(&self, _mb: &MagicBall, _key: &Key, _msg_type: &MsgType) -> Result<(), ProcessError> {
+        Ok(())
+    }
+    /// Called when rpc, proxied rpc or rpc stream is completed, error is passed if it failed. Rpcs dropped by the caller are not reported.
+    fn on_rpc_completed(&self, _key: &Key, _elapsed: Duration, _error: Option<&ProcessError>) {}
+}
+
+/// Logs received events and rpcs with time of their processing.
+#[derive(Debug, Clone, Copy)]
+pub struct LogLayer {
+    level: Level
+}
+
+impl LogLayer {
+    pub fn new(level: Level) -> LogLayer {
+        LogLayer {
+            level
+        }
+    }
+}
+
+impl Default for LogLayer {
+    fn default() -> LogLayer {
+        LogLayer::new(Level::Info)
+    }
+}
+
+impl<S: Service> Layer<S> for LogLayer {
+    type Service = Logged<S>;
+
+    fn layer(&self, inner: S) -> Logged<S> {
+        Logged {
+            inner,
+            level: self.level
+        }
+    }
+}
+
+pub struct Logged<S> {
+    inner: S,
+    level: Level
+}
+
+impl<S: Service> Service for Logged<S> {
+    type Payload = S::Payload;
+
+    async fn on_start(&self, config: Value, mb: MagicBall) {
+        self.inner.on_start(config, mb).await
+    }
+    async fn on_event(&self, mb: MagicBall, msg: Message<S::Payload>) -> Result<(), Box<dyn Error>> {
+        let addr = mb.addr.clone();
+        let meta = msg.meta.display();
+        let started = Instant::now();
+
+        log!(self.level, "Client {} got event {}", addr, meta);
+
+        let res = self.inner.on_event(mb, msg).await;
+
+        match &res {
+            Ok(()) => log!(self.level, "Client {} processed event in {:?}, {}", addr, started.elapsed(), meta),
+            Err(e) => log!(self.level, "Client {} failed to process event in {:?}, {}, {}", addr, started.elapsed(), meta, e)
+        }
+
+        res
+    }
+    async fn on_rpc(&self, mb: MagicBall, msg: Message<S::Payload>) -> Result<Response<S::Payload>, Box<dyn Error>> {
+        let addr = mb.addr.clone();
+        let meta = msg.meta.display();
+        let started = Instant::now();
+
+        log!(self.level, "Client {} got rpc {}", addr, meta);
+
+        let res = self.inner.on_rpc(mb, msg).await;
+
+        match &res {
+            Ok(_) => log!(self.level, "Client {} processed rpc in {:?}, {}", addr, started.elapsed(), meta),
+            Err(e) => log!(self.level, "Client {} failed to process rpc in {:?}, {}, {}", addr, started.elapsed(), meta, e)
+        }
+
+        res
+    }
+    async fn on_stream(&self, mb: MagicBall, rx: UnboundedReceiver<ClientMsg>) {
+        self.inner.on_stream(mb, rx).await
+    }
+    async fn on_stop(&self) {
+        self.inner.on_stop().await
+    }
+}
+
+/// Counters of messages processed by handlers of one key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HandlerMetrics {
+    pub events: u64,
+    pub rpcs: u64,
+    /// Events and rpcs completed with error
+    pub failed: u64,
+    pub total_time: Duration,
+    pub max_time: Duration
+}
+
+/// Collects HandlerMetrics by keys of processed messages. Layer is cloned to read metrics of the service it was applied to.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsLayer {
+    metrics: Arc<Mutex<HashMap<Key, HandlerMetrics>>>
+}
+
+impl MetricsLayer {
+    pub fn new() -> MetricsLayer {
+        MetricsLayer::default()
+    }
+    pub fn get_metrics(&self) -> HashMap<Key, HandlerMetrics> {
+        self.metrics.lock().expect("Metrics lock is poisoned").clone()
+    }
+}
+
+impl<S: Service> Layer<S> for MetricsLayer {
+    type Service = Metered<S>;
+
+    fn layer(&self, inner: S) -> Metered<S> {
+        Metered {
+            inner,
+            metrics: self.metrics.clone()
+        }
+    }
+}
+
+pub struct Metered<S> {
+    inner: S,
+    metrics: Arc<Mutex<HashMap<Key, HandlerMetrics>>>
+}
+
+impl<S> Metered<S> {
+    fn add(&self, key: Key, is_rpc: bool, elapsed: Duration, failed: bool) {
+        let mut metrics = self.metrics.lock().expect("Metrics lock is poisoned");
+        let key_metrics = metrics.entry(key).or_default();
+
+        if is_rpc {
+            key_metrics.rpcs += 1;
+        } else {
+            key_metrics.events += 1;
+        }
+
+        if failed {
+            key_metrics.failed += 1;
+        }
+
+        key_metrics.total_time += elapsed;
+        key_metrics.max_time = key_metrics.max_time.max(elapsed);
+    }
+}
+
+impl<S: Service> Service for Metered<S> {
+    type Payload = S::Payload;
+
+    async fn on_start(&self, config: Value, mb: MagicBall) {
+        self.inner.on_start(config, mb).await
+    }
+    async fn on_event(&self, mb: MagicBall, msg: Message<S::Payload>) -> Result<(), Box<dyn Error>> {
+        let key = msg.meta.key.clone();
+        let started = Instant::now();
+        let res = self.inner.on_event(mb, msg).await;
+
+        self.add(key, false, started.elapsed(), res.is_err());
+
+        res
+    }
+    async fn on_rpc(&self, mb: MagicBall, msg: Message<S::Payload>) -> Result<Response<S::Payload>, Box<dyn Error>> {
+        let key = msg.meta.key.clone();
+        let started = Instant::now();
+        let res = self.inner.on_rpc(mb, msg).await;
+
+        self.add(key, true, started.elapsed(), res.is_err());
+
+        res
+    }
+    async fn on_stream(&self, mb: MagicBall, rx: UnboundedReceiver<ClientMsg>) {
+        self.inner.on_stream(mb, rx).await
+    }
+    async fn on_stop(&self) {
+        self.inner.on_stop().await
+    }
+}
+
+/// Turns panics of event and rpc handlers into errors, rpc requester gets Internal error instead of waiting for response until timeout.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CatchPanicLayer;
+
+impl<S: Service> Layer<S> for CatchPanicLayer {
+    type Service = CatchPanic<S>;
+
+    fn layer(&self, inner: S) -> CatchPanic<S> {
+        CatchPanic {
+            inner
+        }
+    }
+}
+
+pub struct CatchPanic<S> {
+    inner: S
+}
+
+impl<S: Service> Service for CatchPanic<S> {
+    type Payload = S::Payload;
+
+    async fn on_start(&self, config: Value, mb: MagicBall) {
+        self.inner.on_start(config, mb).await
+    }
+    async fn on_event(&self, mb: MagicBall, msg: Message<S::Payload>) -> Result<(), Box<dyn Error>> {
+        let key = msg.meta.key.clone();
+
+        match AssertUnwindSafe(self.inner.on_event(mb, msg)).catch_unwind().await {
+            Ok(res) => res,
+            Err(panic) => Err(Box::new(get_panic_error(&key, panic)))
+        }
+    }
+    async fn on_rpc(&self, mb: MagicBall, msg: Message<S::Payload>) -> Result<Response<S::Payload>, Box<dyn Error>> {
+        let key = msg.meta.key.clone();
+
+        match AssertUnwindSafe(self.inner.on_rpc(mb, msg)).catch_unwind().await {
+            Ok(res) => res,
+            Err(panic) => Err(Box::new(get_panic_error(&key, panic)))
+        }
+    }
+    async fn on_stream(&self, mb: MagicBall, rx: UnboundedReceiver<ClientMsg>) {
+        self.inner.on_stream(mb, rx).await
+    }
+    async fn on_stop(&self) {
+        self.inner.on_stop().await
+    }
+}
+
+fn get_panic_error(key: &Key, panic: Box<dyn Any + Send>) -> RemoteError {
+    let reason = match panic.downcast_ref::<&str>() {
+        Some(reason) => reason.to_string(),
+        None => panic.downcast_ref::<String>().cloned().unwrap_or_default()
+    };
+
+    error!("Handler of {:?} panicked, {}", key, reason);
+
+    RemoteError::new(ErrorCode::Internal, format!("Handler panicked, {}", reason))
+}
+
+/// Verifies auth tokens of received messages with sp_auth::verify_auth_token, payload of valid token is set as msg meta auth_data.
+/// Auth data set by the sender is dropped, public messages are passed without it.
+/// Messages without valid token are not passed to the service, rpc requester gets Unauthorized error.
+#[cfg(feature = "auth")]
+#[derive(Debug, Clone)]
+pub struct AuthLayer {
+    auth_token_key: Arc<Vec<u8>>,
+    public: Arc<Vec<KeyPattern>>
+}
+
+#[cfg(feature = "auth")]
+impl AuthLayer {
+    pub fn new(auth_token_key: &[u8]) -> AuthLayer {
+        AuthLayer {
+            auth_token_key: Arc::new(auth_token_key.to_vec()),
+            public: Arc::new(vec![])
+        }
+    }
+    /// Messages with keys matching the pattern are passed without auth token.
+    pub fn with_public(mut self, pattern: KeyPattern) -> AuthLayer {
+        Arc::make_mut(&mut self.public).push(pattern);
+        self
+    }
+}
+
+#[cfg(feature = "auth")]
+impl<S: Service> Layer<S> for AuthLayer {
+    type Service = Authorized<S>;
+
+    fn layer(&self, inner: S) -> Authorized<S> {
+        Authorized {
+            inner,
+            auth: self.clone()
+        }
+    }
+}
+
+#[cfg(feature = "auth")]
+pub struct Authorized<S> {
+    inner: S,
+    auth: AuthLayer
+}
+
+#[cfg(feature = "auth")]
+impl<S> Authorized<S> {
+    fn authorize<P>(&self, msg: &mut Message<P>) -> Result<(), RemoteError> {
+        // auth data set by the sender is not trusted, it is replaced with payload of verified token only
+        msg.meta.auth_data = None;
+
+        if self.auth.public.iter().any(|pattern| pattern.matches(&msg.meta.key)) {
+            return Ok(());
+        }
+
+        let auth_token = match &msg.meta.auth_token {
+            Some(auth_token) => auth_token,
+            None => return Err(RemoteError::new(ErrorCode::Unauthorized, format!("Auth token is not set for {:?}", msg.meta.key)))
+        };
+
+        match sp_auth::verify_auth_token(&self.auth.auth_token_key, auth_token) {
+            Ok(auth_data) => {
+                msg.meta.auth_data = Some(auth_data);
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Auth token verification failed, {:?}, {}", e, msg.meta.display());
+
+                Err(RemoteError::new(ErrorCode::Unauthorized, format!("Auth token is not valid for {:?}", msg.meta.key)))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "auth")]
+impl<S: Service> Service for Authorized<S> {
+    type Payload = S::Payload;
+
+    async fn on_start(&self, config: Value, mb: MagicBall) {
+        self.inner.on_start(config, mb).await
+    }
+    async fn on_event(&self, mb: MagicBall, mut msg: Message<S::Payload>) -> Result<(), Box<dyn Error>> {
+        match self.authorize(&mut msg) {
+            Ok(()) => self.inner.on_event(mb, msg).await,
+            Err(e) => Err(Box::new(e))
+        }
+    }
+    async fn on_rpc(&self, mb: MagicBall, mut msg: Message<S::Payload>) -> Result<Response<S::Payload>, Box<dyn Error>> {
+        match self.authorize(&mut msg) {
+            Ok(()) => self.inner.on_rpc(mb, msg).await,
+            Err(e) => Err(Box::new(e))
+        }
+    }
+    async fn on_stream(&self, mb: MagicBall, rx: UnboundedReceiver<ClientMsg>) {
+        self.inner.on_stream(mb, rx).await
+    }
+    async fn on_stop(&self) {
+        self.inner.on_stop().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc::UnboundedSender;
+    use sp_dto::{ErrorCode, Key, Message, MsgType, resp};
+    use crate::{KeyRouter, MagicBall, ProcessError, Service};
+    use crate::loopback::fixture::Fixture;
+    use super::{CatchPanicLayer, HandlerMetrics, Interceptor, LogLayer, MetricsLayer};
+    #[cfg(feature = "auth")]
+    use super::{AuthLayer, KeyPattern};
+
+    /// Counts sent messages and completed rpcs, rpcs with "Blocked" action are not sent.
+    #[derive(Clone, Default)]
+    struct CountingInterceptor {
+        sent: Arc<AtomicU64>,
+        completed: Arc<AtomicU64>
+    }
+
+    impl Interceptor for CountingInterceptor {
+        fn on_send(&self, _: &MagicBall, key: &Key, _: &MsgType) -> Result<(), ProcessError> {
+            self.sent.fetch_add(1, Ordering::Relaxed);
+
+            match key.action.as_ref() {
+                "Blocked" => Err(ProcessError::Custom("Blocked by interceptor".to_owned())),
+                _ => Ok(())
+            }
+        }
+        fn on_rpc_completed(&self, _: &Key, _: Duration, _: Option<&ProcessError>) {
+            self.completed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    async fn startup_middleware_caller(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        let interceptor = CountingInterceptor::default();
+
+        mb.add_interceptor(interceptor.clone());
+
+        let mut results = vec![];
+
+        for action in &["HiRpc", "PanicRpc", "Blocked"] {
+            results.push(match mb.rpc::<_, Value>(Key::simple(action), json!({})).await {
+                Ok(msg) => msg.payload,
+                Err(ProcessError::Rpc(error)) => json!({ "code": error.code }),
+                Err(e) => json!({ "error": format!("{:?}", e) })
+            });
+        }
+
+        tx.send(json!({
+            "results": results,
+            "sent": interceptor.sent.load(Ordering::Relaxed),
+            "completed": interceptor.completed.load(Ordering::Relaxed)
+        })).expect("Failed to send rpc result");
+    }
+
+    #[tokio::test]
+    async fn middleware() {
+        let mut fixture = Fixture::start(&[], &[Key::simple("HiRpc"), Key::simple("PanicRpc"), Key::simple("Blocked")]);
+        let metrics = MetricsLayer::new();
+        let service = KeyRouter::new()
+            .with_rpc(Key::simple("HiRpc"), |_, _: Message<Value>| async { resp(json!({ "data": "hi" })) })
+            .with_rpc(Key::simple("PanicRpc"), |_, _: Message<Value>| async {
+                if true {
+                    panic!("rpc handler panicked");
+                }
+
+                resp(json!({}))
+            })
+            .layer(CatchPanicLayer)
+            .layer(metrics.clone())
+            .layer(LogLayer::default());
+
+        fixture.run_service(Arc::new(service), json!({ "addr": "Service" })).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_middleware_caller);
+
+        // panic is turned into error response, blocked rpc is not sent
+        assert_eq!(fixture.recv().await, json!({
+            "results": [
+                { "data": "hi" },
+                { "code": ErrorCode::Internal },
+                { "error": "Custom(\"Blocked by interceptor\")" }
+            ],
+            "sent": 3,
+            "completed": 2
+        }));
+
+        let metrics = metrics.get_metrics();
+
+        assert_eq!(metrics[&Key::simple("HiRpc")].rpcs, 1);
+        assert_eq!(metrics[&Key::simple("PanicRpc")], HandlerMetrics {
+            rpcs: 1,
+            failed: 1,
+            ..metrics[&Key::simple("PanicRpc")].clone()
+        });
+        assert!(!metrics.contains_key(&Key::simple("Blocked")));
+    }
+
+    #[cfg(feature = "auth")]
+    async fn startup_auth_caller(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        let auth_tokens = [
+            None,
+            Some(sp_auth::create_auth_token_string(b"other key", &json!({ "user_id": 1 })).expect("Failed to create auth token")),
+            Some(sp_auth::create_auth_token_string(b"key", &json!({ "user_id": 1 })).expect("Failed to create auth token"))
+        ];
+
+        for auth_token in auth_tokens {
+            mb.auth_token = auth_token;
+
+            let res = match mb.rpc::<_, Value>(Key::simple("Private"), json!({})).await {
+                Ok(msg) => msg.payload,
+                Err(ProcessError::Rpc(error)) => json!({ "code": error.code }),
+                Err(e) => json!({ "unexpected": format!("{:?}", e) })
+            };
+
+            tx.send(res).expect("Failed to send rpc result");
+        }
+
+        mb.auth_token = None;
+        mb.auth_data = Some(json!({ "user_id": 1 }));
+
+        let msg = mb.rpc::<_, Value>(Key::simple("Public"), json!({})).await.expect("Rpc failed");
+
+        tx.send(msg.payload).expect("Failed to send rpc result");
+    }
+
+    #[cfg(feature = "auth")]
+    #[tokio::test]
+    async fn auth_layer() {
+        let mut fixture = Fixture::start(&[], &[Key::simple("Private"), Key::simple("Public")]);
+        let service = KeyRouter::new()
+            .with_rpc_pattern(KeyPattern::new("*", "*", "*"), |_, msg: Message<Value>| async move {
+                resp(json!({ "auth_data": msg.meta.auth_data }))
+            })
+            .layer(AuthLayer::new(b"key").with_public(KeyPattern::new("Public", "*", "*")));
+
+        fixture.run_service(Arc::new(service), json!({ "addr": "Service" })).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_auth_caller);
+
+        assert_eq!(fixture.recv_n(4).await, vec![
+            json!({ "code": ErrorCode::Unauthorized }),
+            json!({ "code": ErrorCode::Unauthorized }),
+            json!({ "auth_data": { "user_id": 1 } }),
+            json!({ "auth_data": null })
+        ]);
+    }
+}
@@ -5,26 +5,39 @@ use std::option;
 use std::io::Cursor;
 use std::net::SocketAddr;
 use std::hash::Hasher;
-use std::time::Duration;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use log::*;
 use rand::random;
 use byteorder::ByteOrder;
-use serde_json::{from_slice, Value, to_vec};
+use serde_json::{json, from_slice, Value, to_vec};
 use siphasher::sip::SipHasher24;
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc::{UnboundedSender, UnboundedReceiver, error::{SendError, TrySendError}}, oneshot};
+use tokio::sync::{mpsc::{UnboundedSender, UnboundedReceiver, error::{SendError, TrySendError}}, oneshot, Semaphore};
+use tokio::sync::watch;
 //use tokio::time::{timeout, error::Elapsed};
 use tokio::time::timeout;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use sp_dto::bytes::{Buf, BytesMut, BufMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use sp_dto::bytes::{Buf, Bytes, BytesMut, BufMut};
 use sp_dto::{*, uuid::Uuid};
+use crate::journal::{LogRecord, LogOffsets, ReadRequest, ReadResponse, CommitRequest, OffsetsRequest, get_read_key, get_commit_key, get_offsets_key};
+use crate::server::{NamespaceStats, get_stats_key};
+use crate::middleware::Interceptor;
 
 pub const LEN_BUF_SIZE: usize = 4;
 
 pub const FRAME_HEADER_SIZE: usize = 36;
+/// Max payload size of frames written before bigger size is agreed on handshake, all peers are able to read frames of this size
 pub const MAX_FRAME_PAYLOAD_SIZE: usize = 1024;
+/// Max frame payload size which can be agreed on handshake, payload size is u16 in frame header
+pub const MAX_NEGOTIATED_FRAME_PAYLOAD_SIZE: usize = u16::MAX as usize;
+/// Frame payload size used by writers if other size is not set, all peers are able to read frames of this size
+pub const DEFAULT_FRAME_PAYLOAD_SIZE: usize = MAX_FRAME_PAYLOAD_SIZE;
 
 pub const MAX_FRAME_SIZE: usize = FRAME_HEADER_SIZE + MAX_FRAME_PAYLOAD_SIZE;
+/// Messages with payload and attachments data smaller than this are sent uncompressed if other threshold is not set
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
 
 /*
 u8 frame_type 1
@@ -32,10 +45,43 @@ u16 frame_size 2
 u8 msg_type 1
 u64 key_hash 8
 u64 stream_id 8
-u64 frame_signature 8
+u64 frame_signature 8 - CRC32C of the frame if checksums are agreed on handshake
+u64 source_hash 8
 */
 
 pub const RPC_TIMEOUT_MS_AMOUNT: u64 = 30000;
+/// Max amount of rpcs waiting for response in one client, if other limit is not set in config
+pub const DEFAULT_MAX_PENDING_RPCS: u64 = 10000;
+
+/// Payload of credit frame starts with u32 amount of frames granted to the stream sender, sender addr follows it
+pub const CREDIT_PAYLOAD_SIZE: usize = 4;
+/// Payload of transfer ack frame starts with u64 offset of transfer data persisted by the stream receiver, sender addr follows it
+pub const TRANSFER_ACK_PAYLOAD_SIZE: usize = 8;
+/// Payload of cancel frame is u16 cancel reason code
+pub const CANCEL_PAYLOAD_SIZE: usize = 2;
+/// Payload of rpc cancel frame is 16 bytes of cancelled rpc correlation id
+pub const RPC_CANCEL_PAYLOAD_SIZE: usize = 16;
+/// Payload of ping frame is u64 timestamp of the sender in microseconds, pong frame returns it back
+pub const HEARTBEAT_PAYLOAD_SIZE: usize = 8;
+/// Amount of heartbeat intervals without frames from the peer after which connection is considered dead
+pub const HEARTBEAT_TIMEOUT_INTERVALS: u32 = 3;
+
+/// Protocol version of this implementation, exchanged on handshake
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Oldest protocol version this implementation is able to work with
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+pub const HANDSHAKE_SIZE: usize = 14;
+const HANDSHAKE_MAGIC: &[u8; 4] = b"SPHS";
+
+/*
+handshake, sent by both peers right after connection is established
+[u8; 4] magic 4
+u16 version 2
+u16 min_version 2
+u32 capabilities 4
+u16 max_frame_payload_size 2
+*/
 
 pub fn get_key_hasher() -> SipHasher24 {    
     SipHasher24::new_with_keys(0, 0)
@@ -91,6 +137,26 @@ pub fn get_addr_hash(addr: &str) -> u64 {
     res
 }
 
+/// Current unix time in milliseconds, message deadlines are set in this units.
+pub fn get_timestamp_ms() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_millis() as u64,
+        Err(_) => 0
+    }
+}
+
+/// Returns time left before deadline, None is returned if there is no deadline. Zero duration means deadline is expired.
+pub fn get_time_left(deadline: Option<u64>) -> Option<Duration> {
+    deadline.map(|deadline| Duration::from_millis(deadline.saturating_sub(get_timestamp_ms())))
+}
+
+pub fn deadline_expired(deadline: Option<u64>) -> bool {
+    match get_time_left(deadline) {
+        Some(time_left) => time_left.as_millis() == 0,
+        None => false
+    }
+}
+
 pub fn get_stream_id_onetime(addr: &str) -> u64 {
     let mut buf = BytesMut::new();
     buf.put(addr.as_bytes());    
@@ -100,6 +166,375 @@ pub fn get_stream_id_onetime(addr: &str) -> u64 {
     hasher.finish()
 }
 
+/// Set of optional protocol features, peers use only features supported by both of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const COMPRESSION: Capabilities = Capabilities(1);
+    pub const SIGNING: Capabilities = Capabilities(1 << 1);
+    /// Frames of several streams are interleaved on one connection
+    pub const MULTIPLEXING: Capabilities = Capabilities(1 << 2);
+    /// Frames carry CRC32C of header and payload in frame signature, corrupted frames close the connection
+    pub const CHECKSUM: Capabilities = Capabilities(1 << 3);
+    /// Capabilities supported by this implementation
+    pub const SUPPORTED: Capabilities = Capabilities(Capabilities::MULTIPLEXING.0 | Capabilities::COMPRESSION.0 | Capabilities::CHECKSUM.0);
+
+    pub fn contains(&self, capabilities: Capabilities) -> bool {
+        self.0 & capabilities.0 == capabilities.0
+    }
+    pub fn intersection(&self, capabilities: Capabilities) -> Capabilities {
+        Capabilities(self.0 & capabilities.0)
+    }
+    pub fn difference(&self, capabilities: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !capabilities.0)
+    }
+}
+
+/// Protocol parameters sent by peer on handshake. After negotiation same struct holds parameters agreed by both peers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub version: u16,
+    pub min_version: u16,
+    pub capabilities: Capabilities,
+    /// Max payload size of frames peer is going to write and read
+    pub max_frame_payload_size: u16
+}
+
+impl Handshake {
+    /// Handshake with protocol versions and capabilities of this implementation.
+    pub fn new(max_frame_payload_size: u16) -> Handshake {
+        Handshake {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+            max_frame_payload_size
+        }
+    }
+    /// Offers only provided capabilities, for example without checksums on trusted local links.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Handshake {
+        self.capabilities = capabilities;
+        self
+    }
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_SIZE] {
+        let mut buf = [0; HANDSHAKE_SIZE];
+
+        buf[..4].copy_from_slice(HANDSHAKE_MAGIC);
+        byteorder::BigEndian::write_u16(&mut buf[4..6], self.version);
+        byteorder::BigEndian::write_u16(&mut buf[6..8], self.min_version);
+        byteorder::BigEndian::write_u32(&mut buf[8..12], self.capabilities.0);
+        byteorder::BigEndian::write_u16(&mut buf[12..14], self.max_frame_payload_size);
+
+        buf
+    }
+    pub fn from_bytes(buf: &[u8; HANDSHAKE_SIZE]) -> Result<Handshake, ProcessError> {
+        if &buf[..4] != HANDSHAKE_MAGIC {
+            return Err(ProcessError::HandshakeFailed("peer did not send protocol handshake, probably it uses protocol without version".to_owned()));
+        }
+
+        Ok(Handshake {
+            version: byteorder::BigEndian::read_u16(&buf[4..6]),
+            min_version: byteorder::BigEndian::read_u16(&buf[6..8]),
+            capabilities: Capabilities(byteorder::BigEndian::read_u32(&buf[8..12])),
+            max_frame_payload_size: byteorder::BigEndian::read_u16(&buf[12..14])
+        })
+    }
+    /// Agrees on protocol version, capabilities and frame size with remote peer. Highest version supported by both peers is used,
+    /// ProcessError::IncompatibleProtocolVersion is returned if there is no such version.
+    pub fn negotiate(&self, remote: &Handshake) -> Result<Handshake, ProcessError> {
+        let version = self.version.min(remote.version);
+        let min_version = self.min_version.max(remote.min_version);
+
+        if version < min_version {
+            return Err(ProcessError::IncompatibleProtocolVersion(remote.min_version, remote.version));
+        }
+
+        Ok(Handshake {
+            version,
+            min_version,
+            capabilities: self.capabilities.intersection(remote.capabilities),
+            max_frame_payload_size: self.max_frame_payload_size.min(remote.max_frame_payload_size).max(1)
+        })
+    }
+}
+
+/// Exchanges handshakes with peer, both sides call this right after connection is established. Agreed parameters are returned.
+pub async fn handshake<S>(stream: &mut S, local: &Handshake) -> Result<Handshake, ProcessError> where S: AsyncRead + AsyncWrite + Unpin {
+    stream.write_all(&local.to_bytes()).await?;
+
+    let mut buf = [0; HANDSHAKE_SIZE];
+
+    stream.read_exact(&mut buf).await?;
+
+    let remote = Handshake::from_bytes(&buf)?;
+
+    debug!("Handshake received, {:?}", remote);
+
+    local.negotiate(&remote)
+}
+
+/// State of the client connection to the server.
+#[derive(Debug, Clone, Copy, Default)]
+struct ConnectionState {
+    /// Number of connections established by the client
+    epoch: u64,
+    connected: bool
+}
+
+impl ConnectionState {
+    /// Epoch of the connection which carries frames written in this state, it is the next connection if client is disconnected.
+    fn get_write_epoch(&self) -> u64 {
+        match self.connected {
+            true => self.epoch,
+            false => self.epoch + 1
+        }
+    }
+    fn is_lost(&self, epoch: u64) -> bool {
+        self.epoch > epoch || (self.epoch == epoch && !self.connected)
+    }
+}
+
+/// Handling of rpc requests waiting for response when connection to the server is lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcRetryPolicy {
+    /// Rpc fails with ProcessError::ConnectionLost
+    Fail,
+    /// Rpc request is sent again after reconnect, rpc timeout limits all attempts. Streamed and proxied rpcs are not retried.
+    Retry
+}
+
+/// Writing settings of the client, shared by all MagicBall clones. Frame size and capabilities are limited by values agreed on handshake.
+#[derive(Debug)]
+pub struct WriteSettings {
+    /// Max frame payload size agreed with the server, small default is used before handshake is completed
+    agreed_frame_payload_size: AtomicUsize,
+    /// Max frame payload size requested by client
+    requested_frame_payload_size: AtomicUsize,
+    /// Capabilities offered to the server on handshake
+    requested_capabilities: AtomicU32,
+    capabilities: AtomicU32,
+    /// Min size of payload and attachments data compressed on write, usize::MAX disables compression
+    compression_threshold: AtomicUsize,
+    /// Payload codecs chosen for keys, JSON is used for other keys
+    codecs: RwLock<HashMap<Key, Codec>>,
+    /// Credits available for flow controlled streams written by the client, by stream id
+    stream_credits: Mutex<HashMap<u64, Arc<Semaphore>>>,
+    /// Offsets acknowledged by receivers of transfers written by the client, by stream id
+    transfer_offsets: Mutex<HashMap<u64, watch::Sender<Option<u64>>>>,
+    /// Changed by client on connect and disconnect, rpcs waiting for response are failed or retried when connection is lost
+    connection: watch::Sender<ConnectionState>
+}
+
+impl WriteSettings {
+    pub fn new() -> WriteSettings {
+        WriteSettings {
+            agreed_frame_payload_size: AtomicUsize::new(DEFAULT_FRAME_PAYLOAD_SIZE),
+            requested_frame_payload_size: AtomicUsize::new(MAX_NEGOTIATED_FRAME_PAYLOAD_SIZE),
+            requested_capabilities: AtomicU32::new(Capabilities::SUPPORTED.0),
+            capabilities: AtomicU32::new(0),
+            compression_threshold: AtomicUsize::new(usize::MAX),
+            codecs: RwLock::new(HashMap::new()),
+            stream_credits: Mutex::new(HashMap::new()),
+            transfer_offsets: Mutex::new(HashMap::new()),
+            connection: watch::channel(ConnectionState::default()).0
+        }
+    }
+    pub fn get_max_frame_payload_size(&self) -> usize {
+        self.agreed_frame_payload_size.load(Ordering::Relaxed).min(self.requested_frame_payload_size.load(Ordering::Relaxed))
+    }
+    pub fn get_requested_frame_payload_size(&self) -> usize {
+        self.requested_frame_payload_size.load(Ordering::Relaxed)
+    }
+    pub fn set_requested_frame_payload_size(&self, max_frame_payload_size: usize) {
+        self.requested_frame_payload_size.store(max_frame_payload_size.clamp(1, MAX_NEGOTIATED_FRAME_PAYLOAD_SIZE), Ordering::Relaxed);
+    }
+    pub fn get_requested_capabilities(&self) -> Capabilities {
+        Capabilities(self.requested_capabilities.load(Ordering::Relaxed))
+    }
+    /// Frame checksums are offered on handshake if enabled, they are used only if the server agrees.
+    pub fn set_frame_checksums(&self, enabled: bool) {
+        let requested = self.get_requested_capabilities();
+        let requested = match enabled {
+            true => Capabilities(requested.0 | Capabilities::CHECKSUM.0),
+            false => requested.difference(Capabilities::CHECKSUM)
+        };
+
+        self.requested_capabilities.store(requested.0, Ordering::Relaxed);
+    }
+    /// Capabilities agreed with the server
+    pub fn get_capabilities(&self) -> Capabilities {
+        Capabilities(self.capabilities.load(Ordering::Relaxed))
+    }
+    /// Compression threshold if compression is enabled and agreed with the server
+    pub fn get_compression_threshold(&self) -> Option<usize> {
+        match (self.compression_threshold.load(Ordering::Relaxed), self.get_capabilities().contains(Capabilities::COMPRESSION)) {
+            (usize::MAX, _) | (_, false) => None,
+            (threshold, true) => Some(threshold)
+        }
+    }
+    pub fn set_compression_threshold(&self, compression_threshold: Option<usize>) {
+        self.compression_threshold.store(compression_threshold.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+    pub fn get_codec(&self, key: &Key) -> Option<Codec> {
+        self.codecs.read().ok().and_then(|codecs| codecs.get(key).copied())
+    }
+    pub fn set_codec(&self, key: Key, codec: Codec) {
+        if let Ok(mut codecs) = self.codecs.write() {
+            codecs.insert(key, codec);
+        }
+    }
+    pub fn open_stream_credits(&self, stream_id: u64, credits: u32) {
+        if let Ok(mut stream_credits) = self.stream_credits.lock() {
+            stream_credits.insert(stream_id, Arc::new(Semaphore::new(credits as usize)));
+        }
+    }
+    /// Stream credits are removed when stream is completed, writers waiting for credits get an error.
+    pub fn close_stream_credits(&self, stream_id: u64) {
+        if let Some(credits) = self.stream_credits.lock().ok().and_then(|mut stream_credits| stream_credits.remove(&stream_id)) {
+            credits.close();
+        }
+    }
+    pub fn get_stream_credits(&self, stream_id: u64) -> Option<Arc<Semaphore>> {
+        self.stream_credits.lock().ok().and_then(|stream_credits| stream_credits.get(&stream_id).cloned())
+    }
+    /// Adds credits granted by receiver of the stream, credits for unknown or completed streams are ignored.
+    pub fn grant_credits(&self, stream_id: u64, credits: u32) {
+        match self.get_stream_credits(stream_id) {
+            Some(stream_credits) => stream_credits.add_permits(credits as usize),
+            None => debug!("Credits granted for unknown stream {}", stream_id)
+        }
+    }
+    /// Applies credits carried by credit frame received from the stream receiver.
+    pub fn process_credit_frame(&self, frame: &Frame) {
+        match &frame.payload {
+            Some(payload) if frame.payload_size as usize >= CREDIT_PAYLOAD_SIZE => self.grant_credits(frame.stream_id, byteorder::BigEndian::read_u32(&payload[..CREDIT_PAYLOAD_SIZE])),
+            _ => warn!("Malformed credit frame for stream {}", frame.stream_id)
+        }
+    }
+    pub fn open_transfer(&self, stream_id: u64) {
+        if let Ok(mut transfer_offsets) = self.transfer_offsets.lock() {
+            transfer_offsets.insert(stream_id, watch::channel(None).0);
+        }
+    }
+    /// Transfer offsets are removed when stream is completed, writers waiting for offset get an error.
+    pub fn close_transfer(&self, stream_id: u64) {
+        if let Ok(mut transfer_offsets) = self.transfer_offsets.lock() {
+            transfer_offsets.remove(&stream_id);
+        }
+    }
+    pub fn subscribe_transfer_offset(&self, stream_id: u64) -> Option<watch::Receiver<Option<u64>>> {
+        self.transfer_offsets.lock().ok().and_then(|transfer_offsets| transfer_offsets.get(&stream_id).map(watch::Sender::subscribe))
+    }
+    /// Stores offset carried by transfer ack frame received from the stream receiver, acks for unknown or completed streams are ignored.
+    pub fn process_transfer_ack_frame(&self, frame: &Frame) {
+        let offset = match &frame.payload {
+            Some(payload) if frame.payload_size as usize >= TRANSFER_ACK_PAYLOAD_SIZE => byteorder::BigEndian::read_u64(&payload[..TRANSFER_ACK_PAYLOAD_SIZE]),
+            _ => {
+                warn!("Malformed transfer ack frame for stream {}", frame.stream_id);
+                return;
+            }
+        };
+
+        match self.transfer_offsets.lock().ok().as_ref().and_then(|transfer_offsets| transfer_offsets.get(&frame.stream_id)) {
+            Some(transfer_offset) => {
+                transfer_offset.send_replace(Some(offset));
+            }
+            None => debug!("Transfer offset acknowledged for unknown stream {}", frame.stream_id)
+        }
+    }
+    /// Epoch is incremented on every connect, so frames written later are known to be carried by the new connection.
+    pub fn set_connected(&self, connected: bool) {
+        self.connection.send_modify(|state| {
+            if connected {
+                state.epoch += 1;
+            }
+
+            state.connected = connected;
+        });
+    }
+    pub fn is_connected(&self) -> bool {
+        self.connection.borrow().connected
+    }
+    /// Epoch of the connection which carries frames written now.
+    pub fn get_connection_epoch(&self) -> u64 {
+        self.connection.borrow().get_write_epoch()
+    }
+    pub fn is_connection_lost(&self, epoch: u64) -> bool {
+        self.connection.borrow().is_lost(epoch)
+    }
+    pub async fn wait_connection_lost(&self, epoch: u64) {
+        let _ = self.connection.subscribe().wait_for(|state| state.is_lost(epoch)).await;
+    }
+    pub async fn wait_connected(&self) {
+        let _ = self.connection.subscribe().wait_for(|state| state.connected).await;
+    }
+    pub fn set_agreed(&self, agreed: &Handshake) {
+        self.agreed_frame_payload_size.store(agreed.max_frame_payload_size as usize, Ordering::Relaxed);
+        self.capabilities.store(agreed.capabilities.0, Ordering::Relaxed);
+    }
+}
+
+/// Heartbeat settings and round trip time of the client connection, shared by MagicBall clones.
+pub struct Heartbeat {
+    /// Timestamps of pings are measured from this instant
+    started: Instant,
+    /// Ping interval in milliseconds, 0 if client doesn't send pings
+    interval: AtomicU64,
+    /// Idle timeout of the connection in milliseconds
+    timeout: AtomicU64,
+    /// Round trip time of the last answered ping in microseconds, u64::MAX if no ping is answered yet
+    rtt: AtomicU64
+}
+
+impl Heartbeat {
+    pub fn new() -> Heartbeat {
+        Heartbeat {
+            started: Instant::now(),
+            interval: AtomicU64::new(0),
+            timeout: AtomicU64::new(0),
+            rtt: AtomicU64::new(u64::MAX)
+        }
+    }
+    /// Enables pings with provided interval, connection without frames from the server during timeout is closed.
+    /// Timeout of HEARTBEAT_TIMEOUT_INTERVALS intervals is used if it is not set.
+    pub fn set(&self, interval: Duration, timeout: Option<Duration>) {
+        self.interval.store(interval.as_millis() as u64, Ordering::Relaxed);
+        self.timeout.store(timeout.unwrap_or(interval * HEARTBEAT_TIMEOUT_INTERVALS).as_millis() as u64, Ordering::Relaxed);
+    }
+    pub fn get_interval(&self) -> Option<Duration> {
+        match self.interval.load(Ordering::Relaxed) {
+            0 => None,
+            interval => Some(Duration::from_millis(interval))
+        }
+    }
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.get_interval().map(|_| Duration::from_millis(self.timeout.load(Ordering::Relaxed)))
+    }
+    /// Timestamp for ping frame in microseconds.
+    pub fn get_timestamp(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+    /// Updates round trip time with timestamp returned by pong frame.
+    pub fn process_pong(&self, frame: &Frame) {
+        match frame.get_heartbeat_timestamp() {
+            Some(timestamp) => self.rtt.store(self.get_timestamp().saturating_sub(timestamp), Ordering::Relaxed),
+            None => warn!("Malformed pong frame")
+        }
+    }
+    pub fn get_rtt(&self) -> Option<Duration> {
+        match self.rtt.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            rtt => Some(Duration::from_micros(rtt))
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Heartbeat {
+        Heartbeat::new()
+    }
+}
+
 pub struct StreamLayout {
     pub id: u64,
     pub msg_meta: Vec<u8>,
@@ -116,7 +551,8 @@ pub struct Frame {
     pub stream_id: u64,
     pub frame_signature: u64,
     pub source_hash: u64,
-    pub payload: Option<[u8; MAX_FRAME_PAYLOAD_SIZE]>
+    /// Frame payload, it is a slice of read buffer or message data, so frames are cloned without copying payload
+    pub payload: Option<Bytes>
 }
 
 pub enum FrameType {
@@ -126,11 +562,57 @@ pub enum FrameType {
     PayloadEnd = 3,
     Attachment = 4,
 	AttachmentEnd = 5,
-    End = 6
+    End = 6,
+    /// Credits granted by stream receiver, routed to the stream sender
+    Credit = 7,
+    /// Stream is aborted by the sender (or by the server if sender is disconnected), receivers should drop collected stream data
+    Cancel = 8,
+    /// Heartbeat request, it is answered with pong frame with the same payload. Ping and pong frames are not part of any stream
+    Ping = 9,
+    Pong = 10,
+    /// Offset of transfer data persisted by stream receiver, routed to the stream sender
+    TransferAck = 11,
+    /// Rpc requester doesn't wait for the response anymore, server forwards it to the callee. It is not part of any stream, stream id of the request is set for logging
+    RpcCancel = 12
+}
+
+/// Reason of stream cancellation, it is carried in cancel frame payload as u16 code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelReason {
+    /// Sender failed to produce the rest of the stream
+    SenderFailed,
+    /// Sender connection to the server is closed, set by the server for streams left incomplete
+    SenderDisconnected,
+    /// Receiver of the data produced by the stream is gone, for example downstream http client is disconnected
+    ReceiverGone,
+    DeadlineExpired,
+    /// Application specific reason, codes up to 255 are reserved for the platform
+    Custom(u16)
+}
+
+impl CancelReason {
+    pub fn get_u16(&self) -> u16 {
+        match self {
+            CancelReason::SenderFailed => 1,
+            CancelReason::SenderDisconnected => 2,
+            CancelReason::ReceiverGone => 3,
+            CancelReason::DeadlineExpired => 4,
+            CancelReason::Custom(code) => *code
+        }
+    }
+    pub fn from_u16(code: u16) -> CancelReason {
+        match code {
+            1 => CancelReason::SenderFailed,
+            2 => CancelReason::SenderDisconnected,
+            3 => CancelReason::ReceiverGone,
+            4 => CancelReason::DeadlineExpired,
+            _ => CancelReason::Custom(code)
+        }
+    }
 }
 
 impl Frame {
-    pub fn new(frame_type: u8, payload_size: u16, msg_type: u8, key_hash: u64, stream_id: u64, source_hash: u64, payload: Option<[u8; MAX_FRAME_PAYLOAD_SIZE]>) -> Frame {
+    pub fn new(frame_type: u8, payload_size: u16, msg_type: u8, key_hash: u64, stream_id: u64, source_hash: u64, payload: Option<Bytes>) -> Frame {
         let frame_signature = 0;
 
         Frame {
@@ -153,9 +635,60 @@ impl Frame {
             4 => FrameType::Attachment,
             5 => FrameType::AttachmentEnd,
 			6 => FrameType::End,
+            7 => FrameType::Credit,
+            8 => FrameType::Cancel,
+            9 => FrameType::Ping,
+            10 => FrameType::Pong,
+            11 => FrameType::TransferAck,
+            12 => FrameType::RpcCancel,
             _ => return Err(ProcessError::IncorrectFrameType)
         })
     }
+    /// Creates cancel frame for the stream.
+    pub fn new_cancel(msg_type: u8, key_hash: u64, stream_id: u64, source_hash: u64, reason: CancelReason) -> Frame {
+        let mut payload = BytesMut::with_capacity(CANCEL_PAYLOAD_SIZE);
+
+        payload.put_u16(reason.get_u16());
+
+        Frame::new(FrameType::Cancel as u8, CANCEL_PAYLOAD_SIZE as u16, msg_type, key_hash, stream_id, source_hash, Some(payload.freeze()))
+    }
+    /// Creates rpc cancel frame for the rpc request written to the stream.
+    pub fn new_rpc_cancel(stream_id: u64, correlation_id: Uuid) -> Frame {
+        Frame::new(FrameType::RpcCancel as u8, RPC_CANCEL_PAYLOAD_SIZE as u16, MsgType::RpcRequest.get_u8(), 0, stream_id, 0, Some(Bytes::copy_from_slice(correlation_id.as_bytes())))
+    }
+    /// Reads correlation id of rpc cancel frame, None is returned for other frame types.
+    pub fn get_rpc_cancel_correlation_id(&self) -> Option<Uuid> {
+        match &self.payload {
+            Some(payload) if self.frame_type == FrameType::RpcCancel as u8 && self.payload_size as usize == RPC_CANCEL_PAYLOAD_SIZE => Uuid::from_slice(&payload[..RPC_CANCEL_PAYLOAD_SIZE]).ok(),
+            _ => None
+        }
+    }
+    /// Creates ping frame with provided timestamp.
+    pub fn new_ping(timestamp: u64) -> Frame {
+        let mut payload = BytesMut::with_capacity(HEARTBEAT_PAYLOAD_SIZE);
+
+        payload.put_u64(timestamp);
+
+        Frame::new(FrameType::Ping as u8, HEARTBEAT_PAYLOAD_SIZE as u16, 0, 0, 0, 0, Some(payload.freeze()))
+    }
+    /// Creates pong frame answering the ping frame.
+    pub fn new_pong(&self) -> Frame {
+        Frame::new(FrameType::Pong as u8, self.payload_size, 0, 0, 0, 0, self.payload.clone())
+    }
+    /// Reads timestamp of ping or pong frame.
+    pub fn get_heartbeat_timestamp(&self) -> Option<u64> {
+        match &self.payload {
+            Some(payload) if self.payload_size as usize == HEARTBEAT_PAYLOAD_SIZE => Some(byteorder::BigEndian::read_u64(&payload[..HEARTBEAT_PAYLOAD_SIZE])),
+            _ => None
+        }
+    }
+    /// Reads reason of cancel frame, None is returned for other frame types.
+    pub fn get_cancel_reason(&self) -> Option<CancelReason> {
+        match &self.payload {
+            Some(payload) if self.frame_type == FrameType::Cancel as u8 && self.payload_size as usize >= CANCEL_PAYLOAD_SIZE => Some(CancelReason::from_u16(byteorder::BigEndian::read_u16(&payload[..CANCEL_PAYLOAD_SIZE]))),
+            _ => None
+        }
+    }
     pub fn get_msg_type(&self) -> Result<MsgType, ProcessError> {
         Ok(match self.msg_type {
             0 => MsgType::Event,
@@ -166,19 +699,12 @@ impl Frame {
         })
     }
 }
-pub struct State {
-	frame_reading_status: FrameReadingStatus,
-    bytes_read: usize,
-	bytes_processed: usize,
-	read_buf: [u8; MAX_FRAME_SIZE],
-    frame_buf: [u8; MAX_FRAME_SIZE],
-	payload_size_u16: u16,
-	payload_size: usize,
-	key_hash: u64,
-	stream_id: u64,
-	frame_signature: u64,
-    source_hash: u64,
-	frame_size: usize
+/// CRC32C of the frame, frame signature bytes of the header are not included.
+pub fn get_frame_checksum(header: &[u8], payload: &[u8]) -> u32 {
+    let checksum = crc32c::crc32c(&header[..20]);
+    let checksum = crc32c::crc32c_append(checksum, &header[28..FRAME_HEADER_SIZE]);
+
+    crc32c::crc32c_append(checksum, payload)
 }
 
 pub enum CompleteCondition {
@@ -186,206 +712,53 @@ pub enum CompleteCondition {
 	Never
 }
 
-pub enum FrameReadingStatus {
-	Header,
-	Payload
-}
-pub enum ReadFrameResult {
-	NotEnoughBytesForFrame,
-	NextStep,
-    Frame(Frame)
-}
-
-impl State {
-    pub fn new() -> State {
-        State {
-			frame_reading_status: FrameReadingStatus::Header,
-            bytes_read: 0,
-			bytes_processed: 0,
-            frame_buf: [0; MAX_FRAME_SIZE],
-            read_buf: [0; MAX_FRAME_SIZE],
-			payload_size_u16: 0,
-			payload_size: 0,
-			key_hash: 0,
-			stream_id: 0,
-			frame_signature: 0,
-            source_hash: 0,
-			frame_size: 0
-        }
-    }
-    pub fn clear(&mut self) {
-		self.frame_reading_status = FrameReadingStatus::Header;
-        self.bytes_read = 0;
-        self.bytes_processed = 0;
-    }
-    pub async fn read_from_tcp_stream(&mut self, tcp_stream: &mut TcpStream) -> Result<(), ProcessError> {
-        let bytes_read = tcp_stream.read(&mut self.read_buf[self.bytes_read..]).await?;
-
-		debug!("Read {} bytes, self.bytes_read was {}", bytes_read, self.bytes_read);
-
-        match bytes_read {
-            0 => {
-                warn!("Read 0 bytes");
-                Err(ProcessError::StreamClosed)
-            }
-            _ => {
-                let mut i = 0;
-
-				while i < bytes_read {
-                    self.frame_buf[self.bytes_read + i] = self.read_buf[self.bytes_read + i];
-
-                    i = i + 1;
-                }
-
-                self.bytes_read = self.bytes_read + bytes_read;
-
-                debug!("Bytes read set to {}", self.bytes_read);
-
-				Ok(())
-            }
-        }
-    }
-    pub fn read_frame(&mut self) -> ReadFrameResult {
-		match self.frame_reading_status {
-			FrameReadingStatus::Header => {
-                debug!("FrameReadingStatus::Header");
-				if self.bytes_read < self.bytes_processed + FRAME_HEADER_SIZE {
-                    debug!("ReadFrameResult::NotEnoughBytesForFrame");
-					return ReadFrameResult::NotEnoughBytesForFrame;
-				}            
-		
-				self.payload_size_u16 = byteorder::BigEndian::read_u16(&self.frame_buf[self.bytes_processed + 1..self.bytes_processed + 3]);
-				self.payload_size = self.payload_size_u16 as usize;
-				self.key_hash = byteorder::BigEndian::read_u64(&self.frame_buf[self.bytes_processed + 4..self.bytes_processed + 12]);
-				self.stream_id = byteorder::BigEndian::read_u64(&self.frame_buf[self.bytes_processed + 12..self.bytes_processed + 20]);
-				self.frame_signature = byteorder::BigEndian::read_u64(&self.frame_buf[self.bytes_processed + 20..self.bytes_processed + 28]);
-                self.source_hash = byteorder::BigEndian::read_u64(&self.frame_buf[self.bytes_processed + 28..self.bytes_processed + 36]);
-		
-				debug!("Got payload size {}", self.payload_size_u16);
-		
-				self.frame_size = FRAME_HEADER_SIZE + self.payload_size;
-
-				self.frame_reading_status = FrameReadingStatus::Payload;
-
-				ReadFrameResult::NextStep
-			}
-			FrameReadingStatus::Payload => {
-                debug!("FrameReadingStatus::Payload");
-                debug!("Bytes read {}, bytes processed {}, frame size {}", self.bytes_read, self.bytes_processed, self.frame_size);
-				if self.bytes_read < self.bytes_processed + self.frame_size {
-                    debug!("ReadFrameResult::NotEnoughBytesForFrame");
-
-                    // MAX_FRAME_SIZE const is used as buffer length here, so we compare against buffer size, not frame size actually
-                    match self.bytes_processed + self.frame_size > MAX_FRAME_SIZE {
-                        true => {
-                            debug!("No full frame left for processing, but some bytes left");
-
-                            let bytes_left = self.bytes_read - self.bytes_processed;
-
-                            let mut i = 0;
-            
-                            while i < bytes_left {
-                                self.frame_buf[i] = self.read_buf[self.bytes_processed + i];
-                                i = i + 1;
-                            }
-
-                            self.bytes_read = bytes_left;
-                            self.bytes_processed = 0;
-
-                            debug!("Buffer process complete, bytes left: {}, bytes read set to {}", bytes_left, bytes_left);
-                        }
-                        false => {}
-                    }
-					return ReadFrameResult::NotEnoughBytesForFrame;
-				}
-
-                let mut i = 0;
-
-                let res = match self.payload_size_u16 {
-                    0 => Frame {
-                        frame_type: self.frame_buf[self.bytes_processed],
-                        payload_size: self.payload_size_u16,
-                        msg_type: self.frame_buf[self.bytes_processed + 3],
-                        key_hash: self.key_hash,
-                        stream_id: self.stream_id,
-                        frame_signature: self.frame_signature,
-                        source_hash: self.source_hash,
-                        payload: None
-                    },
-                    _ => {
-                        let payload_slice = &self.frame_buf[self.bytes_processed + FRAME_HEADER_SIZE..self.bytes_processed + self.frame_size];
-                        let mut payload = [0; MAX_FRAME_PAYLOAD_SIZE];        
-                
-                        while i < self.payload_size {
-                            payload[i] = payload_slice[i];
-                            i = i + 1;
-                        }				
-                
-                        Frame {
-                            frame_type: self.frame_buf[self.bytes_processed],
-                            payload_size: self.payload_size_u16,
-                            msg_type: self.frame_buf[self.bytes_processed + 3],
-                            key_hash: self.key_hash,
-                            stream_id: self.stream_id,
-                            frame_signature: self.frame_signature,
-                            source_hash: self.source_hash,
-                            payload: Some(payload)
-                        }
-                    }
-                };
-	
-
-				self.bytes_processed = self.bytes_processed + self.frame_size;
-				self.frame_reading_status = FrameReadingStatus::Header;
-
-				match self.bytes_processed < self.bytes_read {
-					true => {
-						match self.bytes_processed + FRAME_HEADER_SIZE <= self.bytes_read {
-							true => {
-								debug!("At least one frame header left for processing, bytes processed {}", self.bytes_processed);
-							}
-							false => {
-								debug!("No frame headers left for processing, but some bytes left");
-
-								let bytes_left = self.bytes_read - self.bytes_processed;
-
-								i = 0;
-				
-								while i < bytes_left {
-									self.frame_buf[i] = self.read_buf[self.bytes_processed + i];
-									i = i + 1;
-								}
-
-								self.bytes_read = bytes_left;
-								self.bytes_processed = 0;
-
-                                debug!("Buffer process complete, bytes left: {}, bytes read set to {}", bytes_left, bytes_left);
-							}
-						}												
-					}
-					false => {
-						debug!("No frame headers left for processing, no bytes left");
-						self.bytes_read = 0;
-						self.bytes_processed = 0;
-					}
-				}				
-				
-				ReadFrameResult::Frame(res)
-			}
-		}
+/// Reads next frame from the connection. ProcessError::StreamClosed is returned if connection is closed,
+/// ProcessError::HeartbeatTimeout is returned if idle timeout is set and nothing is read during it.
+pub async fn read_frame<S>(frames: &mut S, idle_timeout: Option<Duration>) -> Result<Frame, ProcessError> where S: Stream<Item = Result<Frame, ProcessError>> + Unpin {
+    let frame = match idle_timeout {
+        Some(idle_timeout) => timeout(idle_timeout, frames.next()).await.map_err(|_| ProcessError::HeartbeatTimeout)?,
+        None => frames.next().await
+    };
+
+    match frame {
+        Some(frame) => frame,
+        None => Err(ProcessError::StreamClosed)
     }
 }
 
 pub struct Client {
     pub addr: String,
-    pub net_addr: SocketAddr,
-    pub tx: UnboundedSender<WriteMsg>
+    /// Network addr of the client, empty for in-process clients
+    pub net_addr: Option<SocketAddr>,
+    pub tx: UnboundedSender<WriteMsg>,
+    /// Last time the client answered ping, it is connection time before the first pong
+    pub last_pong: Instant,
+    /// Round trip time of the last answered ping
+    pub rtt: Option<Duration>
 }
 
 pub enum ServerMsg {
-    AddClient(String, SocketAddr, UnboundedSender<WriteMsg>),
+    AddClient(String, Option<SocketAddr>, UnboundedSender<WriteMsg>),
     RemoveClient(u64),
-    Send(u64, Frame)
+    Send(u64, Frame),
+    /// Sends namespace statistics as response to the rpc request, requester addr hash and request msg meta are passed
+    GetStats(u64, Box<MsgMeta>),
+    /// Registers rpc requester addr hash for the request, request msg meta and addr hashes of callees are passed
+    AddRpc(u64, Box<MsgMeta>, Vec<u64>),
+    /// Requester with addr hash doesn't wait for the rpc response, rpc is removed and cancel frame is forwarded to callees
+    CancelRpc(u64, Uuid, Frame),
+    /// Rpc timeout of the correlation id is elapsed, requester gets timeout error if response is not started
+    RpcTimeout(Uuid),
+    /// Sends frame of rpc response to the requester registered for the correlation id
+    SendRpcResponse(Uuid, Frame),
+    /// Keeps frames of retained event for the key, previously kept frames are replaced
+    Retain(Key, Vec<Frame>),
+    /// Removes retained event for the key
+    ClearRetained(Key),
+    /// Sends pings to connected clients and disconnects clients which didn't answer them during heartbeat timeout
+    Heartbeat,
+    /// Pong from the client with addr hash, timestamp of answered ping is passed
+    Pong(u64, u64)
 }
 
 /// Type for function called on data stream processing
@@ -402,14 +775,20 @@ pub enum ClientMsg {
     /// This is sent in Stream mode
     Frame(Frame),
     /// This is sent in FullMessage mode
-    Message(u64, MsgMeta, Vec<u8>, Option<Vec<u8>>)
+    Message(u64, MsgMeta, Vec<u8>, Option<Vec<u8>>),
+    /// This is sent in FullMessage mode for cancelled streams with complete msg meta
+    Cancelled(u64, MsgMeta, CancelReason),
+    /// This is sent in FullMessage mode when requester cancels the rpc, request stream id and correlation id are passed
+    RpcCancelled(u64, Uuid)
 }
 
 impl ClientMsg {
     pub fn get_stream_id(&self) -> u64 {
         match self {
             ClientMsg::Frame(frame) => frame.stream_id, 
-            ClientMsg::Message(stream_id, _, _, _) => *stream_id
+            ClientMsg::Message(stream_id, _, _, _) => *stream_id,
+            ClientMsg::Cancelled(stream_id, _, _) => *stream_id,
+            ClientMsg::RpcCancelled(stream_id, _) => *stream_id
         }
     }
 }
@@ -426,43 +805,20 @@ pub enum StreamCompletion {
     Err
 }
 
-pub async fn write_frame(tcp_stream: &mut TcpStream, frame: Frame) -> Result<(), ProcessError> {
-    debug!("Frame write to socket attempt, stream_id {}, frame type {}, payload size {}", frame.stream_id, frame.frame_type, frame.payload_size);
-
-    let mut header = [0; FRAME_HEADER_SIZE];
-
-    header[0] = frame.frame_type;
-    byteorder::BigEndian::write_u16(&mut header[1..3], frame.payload_size);
-    header[3] = frame.msg_type;
-    byteorder::BigEndian::write_u64(&mut header[4..12], frame.key_hash);
-    byteorder::BigEndian::write_u64(&mut header[12..20], frame.stream_id);
-    byteorder::BigEndian::write_u64(&mut header[20..28], frame.frame_signature);
-    byteorder::BigEndian::write_u64(&mut header[28..36], frame.source_hash);
-
-    tcp_stream.write_all(&header[..]).await?;
-
-    match frame.payload {
-        Some(payload) => {
-            tcp_stream.write_all(&payload[..frame.payload_size as usize]).await?;
-        }
-        None => {}
-    }
-
-    Ok(())
-}
-
 pub enum WriteMsg {
 	Frame(Frame),
 	Complete
 }
 
-pub async fn write_loop(mut client_rx: UnboundedReceiver<WriteMsg>, tcp_stream: &mut TcpStream) -> Result<(), ProcessError> {    
+/// Writes frames from the channel to the connection, frames are encoded by the connection codec.
+/// Channel is borrowed, so frames left in it are written to the next connection after reconnect.
+pub async fn write_loop<S>(client_rx: &mut UnboundedReceiver<WriteMsg>, frames: &mut S) -> Result<(), ProcessError> where S: Sink<Frame, Error = ProcessError> + Unpin {
     loop {
         match client_rx.recv().await {
             Some(msg) => {
 				match msg {
 					WriteMsg::Frame(frame) => {
-						match write_frame(tcp_stream, frame).await {
+						match frames.send(frame).await {
 							Ok(()) => {}
 							Err(e) => error!("Error writing frame in write loop: {:?}", e)
 						}
@@ -478,98 +834,285 @@ pub async fn write_loop(mut client_rx: UnboundedReceiver<WriteMsg>, tcp_stream:
 	Ok(())
 }
 
-// Use this only for single message or parts of it
-pub async fn write_to_tcp_stream(tcp_stream: &mut TcpStream, msg_type: u8, key_hash: u64, stream_id: u64, source_hash: u64, data: Vec<u8>, msg_meta_size: u64, payload_size: u64, attachments_sizes: Vec<u64>, send_end_frame: bool) -> Result<(), ProcessError> {
+/// Sizes of message parts, dto functions return them along with message data.
+pub struct MsgSizes {
+    pub msg_meta: u64,
+    pub payload: u64,
+    pub attachments: Vec<u64>
+}
+
+/// Splits message data (as created by dto functions) to frames with payloads not bigger than max_frame_payload_size, last frame is stream end frame.
+/// Frame payloads are slices of data, no copying is made.
+pub fn get_message_frames(msg_type: u8, key_hash: u64, stream_id: u64, source_hash: u64, data: Bytes, sizes: MsgSizes, max_frame_payload_size: usize) -> Vec<Frame> {
+    let msg_meta_offset = LEN_BUF_SIZE + sizes.msg_meta as usize;
+    let payload_offset = msg_meta_offset + sizes.payload as usize;
+    let max_frame_payload_size = max_frame_payload_size.clamp(1, MAX_NEGOTIATED_FRAME_PAYLOAD_SIZE);
+    let mut parts = vec![
+        (LEN_BUF_SIZE, msg_meta_offset, FrameType::MsgMeta as u8, FrameType::MsgMetaEnd as u8),
+        (msg_meta_offset, payload_offset, FrameType::Payload as u8, FrameType::PayloadEnd as u8)
+    ];
+
+    let mut prev = payload_offset;
+
+    for attachment_size in sizes.attachments {
+        let attachment_offset = prev + attachment_size as usize;
+
+        parts.push((prev, attachment_offset, FrameType::Attachment as u8, FrameType::AttachmentEnd as u8));
+
+        prev = attachment_offset;
+    }
+
+    let mut frames = vec![];
+
+    for (start, end, frame_type, end_frame_type) in parts {
+        let mut chunk_start = start;
+
+        while chunk_start < end {
+            let chunk_end = end.min(chunk_start + max_frame_payload_size);
+
+            let frame_type = match chunk_end == end {
+                true => end_frame_type,
+                false => frame_type
+            };
+
+            frames.push(Frame::new(frame_type, (chunk_end - chunk_start) as u16, msg_type, key_hash, stream_id, source_hash, Some(data.slice(chunk_start..chunk_end))));
+
+            chunk_start = chunk_end;
+        }
+    }
+
+    frames.push(Frame::new(FrameType::End as u8, 0, msg_type, key_hash, stream_id, source_hash, None));
+
+    frames
+}
+
+/// Compresses payload and each attachment of message data (as created by dto functions) with lz4, compression is marked in msg meta and sizes are replaced with compressed ones.
+/// Data is returned as is if payload and attachments are smaller than threshold, already compressed or don't shrink.
+pub fn compress_message(data: Vec<u8>, msg_meta_size: u64, payload_size: u64, attachments_sizes: Vec<u64>, threshold: usize) -> Result<(Vec<u8>, u64, u64, Vec<u64>), ProcessError> {
     let msg_meta_offset = LEN_BUF_SIZE + msg_meta_size as usize;
+    let content_len = data.len() - msg_meta_offset;
+
+    if content_len < threshold {
+        return Ok((data, msg_meta_size, payload_size, attachments_sizes));
+    }
+
+    let mut msg_meta: MsgMeta = from_slice(&data[LEN_BUF_SIZE..msg_meta_offset])?;
+
+    if msg_meta.compression.is_some() {
+        return Ok((data, msg_meta_size, payload_size, attachments_sizes));
+    }
+
     let payload_offset = msg_meta_offset + payload_size as usize;
-    let mut data_buf = [0; MAX_FRAME_PAYLOAD_SIZE];
+    let payload = compress(&data[msg_meta_offset..payload_offset])?;
+    let mut compressed_len = payload.len();
+    let mut compressed_attachments = vec![];
+    let mut prev = payload_offset;
 
-    let mut source = &data[LEN_BUF_SIZE..msg_meta_offset];
+    for attachment_size in &attachments_sizes {
+        let attachment_offset = prev + *attachment_size as usize;
+        let attachment = compress(&data[prev..attachment_offset])?;
 
-	let mut bytes_send = 0;
+        compressed_len += attachment.len();
+        compressed_attachments.push(attachment);
 
-    loop {        
-        let n = source.read(&mut data_buf).await?;
-        match n {
-            0 => break,
-            _ => {
-				bytes_send = bytes_send + n as u64;
+        prev = attachment_offset;
+    }
 
-				let frame_type = match bytes_send == msg_meta_size {
-					true => FrameType::MsgMetaEnd,
-					false => FrameType::MsgMeta
-				};
+    if compressed_len >= content_len {
+        return Ok((data, msg_meta_size, payload_size, attachments_sizes));
+    }
 
-				write_frame(tcp_stream, Frame::new(FrameType::MsgMeta as u8, n as u16, msg_type, key_hash, stream_id, source_hash, Some(data_buf))).await?;
-			}
-        }
+    let compressed_attachments_sizes: Vec<u64> = compressed_attachments.iter().map(|attachment| attachment.len() as u64).collect();
+
+    for (attachment, size) in msg_meta.attachments.iter_mut().zip(compressed_attachments_sizes.iter()) {
+        attachment.size = *size;
     }
 
-	bytes_send = 0;
+    msg_meta.payload_size = payload.len() as u64;
+    msg_meta.compression = Some(Compression::Lz4);
 
-    match payload_size {
-        0 => {}
-        _ => {            
-            let mut source = &data[msg_meta_offset..payload_offset];
+    let msg_meta = to_vec(&msg_meta)?;
+    let mut buf = Vec::with_capacity(LEN_BUF_SIZE + msg_meta.len() + compressed_len);
 
-            loop {        
-                let n = source.read(&mut data_buf).await?;        
-                match n {
-                    0 => break,
-                    _ => {
-						bytes_send = bytes_send + n as u64;
+    buf.put_u32(msg_meta.len() as u32);
+    buf.extend_from_slice(&msg_meta);
+    buf.extend_from_slice(&payload);
 
-						let frame_type = match bytes_send == payload_size {
-							true => FrameType::PayloadEnd,
-							false => FrameType::Payload
-						};
+    for attachment in compressed_attachments {
+        buf.extend_from_slice(&attachment);
+    }
 
-						write_frame(tcp_stream, Frame::new(FrameType::Payload as u8, n as u16, msg_type, key_hash, stream_id, source_hash, Some(data_buf))).await?;
-					}
+    Ok((buf, msg_meta.len() as u64, payload.len() as u64, compressed_attachments_sizes))
+}
+
+/// Time to wait for rpc response, provided timeout (RPC_TIMEOUT_MS_AMOUNT if it is not set) or time left before deadline, if it is earlier.
+fn get_rpc_timeout(deadline: Option<u64>, rpc_timeout: Option<Duration>) -> Result<Duration, ProcessError> {
+    let rpc_timeout = rpc_timeout.unwrap_or(Duration::from_millis(RPC_TIMEOUT_MS_AMOUNT));
+
+    match get_time_left(deadline) {
+        Some(time_left) if time_left.as_millis() == 0 => Err(ProcessError::DeadlineExpired),
+        Some(time_left) if time_left < rpc_timeout => Ok(time_left),
+        _ => Ok(rpc_timeout)
+    }
+}
+
+/// Waits for rpc response, ProcessError::ConnectionLost is returned if connection of the epoch is lost first.
+async fn wait_rpc_response(write_settings: &WriteSettings, connection_epoch: u64, rpc_rx: oneshot::Receiver<(MsgMeta, Vec<u8>, Option<Vec<u8>>)>) -> Result<(MsgMeta, Vec<u8>, Option<Vec<u8>>), ProcessError> {
+    tokio::select! {
+        res = rpc_rx => Ok(res?),
+        _ = write_settings.wait_connection_lost(connection_epoch) => Err(ProcessError::ConnectionLost)
+    }
+}
+
+/// Sets initial credit window and transfer of the stream in msg meta of message data (as created by dto functions), data with new msg meta and its size are returned.
+fn set_stream_options(data: Vec<u8>, msg_meta_size: u64, credits: Option<u32>, transfer: Option<Transfer>) -> Result<(Vec<u8>, u64), ProcessError> {
+    let msg_meta_offset = LEN_BUF_SIZE + msg_meta_size as usize;
+    let mut msg_meta: MsgMeta = from_slice(&data[LEN_BUF_SIZE..msg_meta_offset])?;
+
+    msg_meta.credits = credits;
+    msg_meta.transfer = transfer;
+
+    let msg_meta = to_vec(&msg_meta)?;
+    let mut buf = Vec::with_capacity(LEN_BUF_SIZE + msg_meta.len() + data.len() - msg_meta_offset);
+
+    buf.put_u32(msg_meta.len() as u32);
+    buf.extend_from_slice(&msg_meta);
+    buf.extend_from_slice(&data[msg_meta_offset..]);
+
+    Ok((buf, msg_meta.len() as u64))
+}
+
+/// Restores payload and attachments data compressed by sender, compression is removed from msg meta and sizes are set back to uncompressed ones.
+/// If attachments data is not passed, only payload is restored.
+pub fn decompress_message(msg_meta: &mut MsgMeta, payload: Vec<u8>, attachments_data: Option<Vec<u8>>) -> Result<(Vec<u8>, Option<Vec<u8>>), ProcessError> {
+    match msg_meta.compression {
+        Some(Compression::Lz4) => {}
+        None => return Ok((payload, attachments_data))
+    }
+
+    let payload = decompress(&payload)?;
+
+    msg_meta.payload_size = payload.len() as u64;
+    msg_meta.compression = None;
+
+    let attachments_data = match attachments_data {
+        Some(compressed) => {
+            let mut attachments_data = vec![];
+            let mut prev = 0;
+
+            for attachment in msg_meta.attachments.iter_mut() {
+                let attachment_offset = prev + attachment.size as usize;
+
+                if attachment_offset > compressed.len() {
+                    return Err(ProcessError::Custom("Attachments data is inconsistent with msg meta".to_owned()));
                 }
+
+                let data = decompress(&compressed[prev..attachment_offset])?;
+
+                attachment.size = data.len() as u64;
+                attachments_data.extend_from_slice(&data);
+
+                prev = attachment_offset;
             }
+
+            Some(attachments_data)
         }
+        None => None
+    };
+
+    Ok((payload, attachments_data))
+}
+
+fn compress(data: &[u8]) -> Result<Vec<u8>, ProcessError> {
+    match data.is_empty() {
+        true => Ok(vec![]),
+        false => Ok(lz4::block::compress(data, None, true)?)
     }
+}
 
-    let mut prev = payload_offset as usize;
+fn decompress(data: &[u8]) -> Result<Vec<u8>, ProcessError> {
+    match data.is_empty() {
+        true => Ok(vec![]),
+        false => Ok(lz4::block::decompress(data, None)?)
+    }
+}
 
-    for attachment_size in attachments_sizes {
-        let attachment_offset = prev + attachment_size as usize;
+/// Collects message data from frames of single stream, result has the same layout as data created by dto functions.
+pub fn get_frames_data(frames: &[Frame]) -> Result<Vec<u8>, ProcessError> {
+    let mut msg_meta = vec![];
+    let mut rest = vec![];
 
-		bytes_send = 0;
+    for frame in frames {
+        let payload = match &frame.payload {
+            Some(payload) => &payload[..frame.payload_size as usize],
+            None => continue
+        };
 
-        match attachment_size {
-            0 => {}
-            _ => {
-                let mut source = &data[prev..attachment_offset];
+        match frame.get_frame_type()? {
+            FrameType::MsgMeta | FrameType::MsgMetaEnd => msg_meta.extend_from_slice(payload),
+            _ => rest.extend_from_slice(payload)
+        }
+    }
 
-                loop {        
-                    let n = source.read(&mut data_buf).await?;        
-                    match n {
-                        0 => break,
-                        _ => {
-							bytes_send = bytes_send + n as u64;
+    let mut buf = vec![];
 
-							let frame_type = match bytes_send == attachment_size {
-								true => FrameType::AttachmentEnd,
-								false => FrameType::Attachment
-							};
+    buf.put_u32(msg_meta.len() as u32);
+    buf.append(&mut msg_meta);
+    buf.append(&mut rest);
 
-							write_frame(tcp_stream, Frame::new(FrameType::Attachment as u8, n as u16, msg_type, key_hash, source_hash, stream_id, Some(data_buf))).await?;
-						}
-                    }
-                }                    
-            }
-        }            
+    Ok(buf)
+}
 
-        prev = attachment_offset;
+/// Creates frames of rpc response for the request processed by the server itself, addr is used as response sender.
+pub fn get_rpc_response_frames(addr: &str, msg_meta: &MsgMeta, payload: Value, attachments: Vec<(String, u64)>, attachments_data: Vec<u8>, rpc_result: RpcResult) -> Result<Vec<Frame>, ProcessError> {
+    let mut route = msg_meta.route.clone();
+
+    route.points.push(Participator::Service(addr.to_owned()));
+
+    let key_hash = get_key_hash(&msg_meta.key);
+    let msg_type = MsgType::RpcResponse(rpc_result.clone()).get_u8();
+    let (dto, msg_meta_size, payload_size, attachments_sizes) = rpc_response_dto_sizes(addr.to_owned(), msg_meta.key.clone(), msg_meta.correlation_id, payload, attachments, attachments_data, rpc_result, route, None, None)?;
+
+    let sizes = MsgSizes {
+        msg_meta: msg_meta_size,
+        payload: payload_size,
+        attachments: attachments_sizes
+    };
+
+    Ok(get_message_frames(msg_type, key_hash, get_stream_id_onetime(addr), get_addr_hash(&msg_meta.tx), Bytes::from(dto), sizes, DEFAULT_FRAME_PAYLOAD_SIZE))
+}
+
+/// Creates frames of rpc error response sent by the server itself, for example when no one is subscribed to the rpc key.
+pub fn get_rpc_error_frames(addr: &str, msg_meta: &MsgMeta, error: RemoteError) -> Result<Vec<Frame>, ProcessError> {
+    get_rpc_response_frames(addr, msg_meta, serde_json::to_value(error)?, vec![], vec![], RpcResult::Err)
+}
+
+/// Response of rpc with its payload decoded, rpc is failed if it is timed out or completed with error response.
+fn get_rpc_response<R>(res: Result<Result<RpcResponseData, ProcessError>, tokio::time::error::Elapsed>) -> Result<(MsgMeta, R, Option<Vec<u8>>), ProcessError> where for<'de> R: serde::Deserialize<'de> {
+    let (msg_meta, payload, attachments_data) = res??;
+    let payload = decode_rpc_response(&msg_meta, &payload)?;
+
+    Ok((msg_meta, payload, attachments_data))
+}
+
+/// Decodes payload of rpc response, error responses are returned as ProcessError::Rpc.
+fn decode_rpc_response<R>(msg_meta: &MsgMeta, payload: &[u8]) -> Result<R, ProcessError> where for<'de> R: serde::Deserialize<'de> {
+    match msg_meta.msg_type {
+        MsgType::RpcResponse(RpcResult::Err) => Err(ProcessError::Rpc(RemoteError::from_payload(msg_meta.codec, payload))),
+        _ => Ok(decode(msg_meta.codec, payload)?)
     }
+}
 
-    if send_end_frame {
-		write_frame(tcp_stream, Frame::new(FrameType::End as u8, 0, msg_type, key_hash, stream_id, source_hash, None)).await?;
-	}
+/// Msg meta, payload and attachments data of rpc response
+type RpcResponseData = (MsgMeta, Vec<u8>, Option<Vec<u8>>);
+/// Receiver of rpc response msg meta, payload and attachments data
+type RpcResponseReceiver = oneshot::Receiver<RpcResponseData>;
+type Interceptors = Arc<RwLock<Vec<Arc<dyn Interceptor>>>>;
 
-    Ok(())
+fn intercept_rpc_completed(interceptors: &Interceptors, key: &Key, started: Instant, error: Option<&ProcessError>) {
+    for interceptor in interceptors.read().expect("Interceptors lock is poisoned").iter() {
+        interceptor.on_rpc_completed(key, started.elapsed(), error);
+    }
 }
 
 // Used for RPC implementation
@@ -577,21 +1120,176 @@ pub enum RpcMsg {
     AddRpc(Uuid, oneshot::Sender<(MsgMeta, Vec<u8>, Option<Vec<u8>>)>),    
     RpcDataRequest(Uuid),
     RpcDataResponse(Uuid, oneshot::Sender<(MsgMeta, Vec<u8>, Option<Vec<u8>>)>),
+    /// Nobody waits for the response, for example it came after rpc timeout
+    RpcDataNotFound(Uuid),
+    /// Requester doesn't wait for the response anymore
+    RemoveRpc(Uuid),
 	Complete
 }
 
+/// Counters of rpcs sent by the client and its MagicBall clones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RpcStats {
+    /// Rpcs waiting for response
+    pub pending: u64,
+    /// Rpcs with received response, error responses are counted here too
+    pub completed: u64,
+    /// Rpcs failed without response, for example when connection is lost
+    pub failed: u64,
+    pub timed_out: u64,
+    /// Rpcs dropped by the requester before response is received
+    pub cancelled: u64,
+    /// Rpcs not sent because max amount of pending rpcs is reached
+    pub rejected: u64
+}
+
+struct RpcCounters {
+    max_pending: AtomicU64,
+    pending: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+    timed_out: AtomicU64,
+    cancelled: AtomicU64,
+    rejected: AtomicU64
+}
+
+impl RpcCounters {
+    pub fn new() -> RpcCounters {
+        RpcCounters {
+            max_pending: AtomicU64::new(DEFAULT_MAX_PENDING_RPCS),
+            pending: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+            cancelled: AtomicU64::new(0),
+            rejected: AtomicU64::new(0)
+        }
+    }
+    fn add_pending(&self) -> Result<(), ProcessError> {
+        let max_pending = self.max_pending.load(Ordering::Relaxed);
+
+        match self.pending.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pending| (pending < max_pending).then(|| pending + 1)) {
+            Ok(_) => Ok(()),
+            Err(pending) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+
+                Err(ProcessError::TooManyPendingRpcs(pending))
+            }
+        }
+    }
+    pub fn get_stats(&self) -> RpcStats {
+        RpcStats {
+            pending: self.pending.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            cancelled: self.cancelled.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// Rpc waiting for response, it is counted as pending while it exists. Rpc dropped before the response is received
+/// (when its future is dropped or timed out) is removed from rpcs waiting for response and rpc cancel frame is written,
+/// so the server and the callee stop processing it.
+struct PendingRpc {
+    correlation_id: Uuid,
+    /// Stream of the last written request, cancel frame is not written if request is not written yet
+    stream_id: Option<u64>,
+    responded: bool,
+    /// Result is counted, rpc dropped without it is counted as cancelled
+    counted: bool,
+    counters: Arc<RpcCounters>,
+    write_tx: UnboundedSender<WriteMsg>,
+    rpc_inbound_tx: UnboundedSender<RpcMsg>
+}
+
+impl PendingRpc {
+    /// Counts new pending rpc, ProcessError::TooManyPendingRpcs is returned if max amount of pending rpcs is reached.
+    fn new(correlation_id: Uuid, counters: &Arc<RpcCounters>, write_tx: &UnboundedSender<WriteMsg>, rpc_inbound_tx: &UnboundedSender<RpcMsg>) -> Result<PendingRpc, ProcessError> {
+        counters.add_pending()?;
+
+        Ok(PendingRpc {
+            correlation_id,
+            stream_id: None,
+            responded: false,
+            counted: false,
+            counters: counters.clone(),
+            write_tx: write_tx.clone(),
+            rpc_inbound_tx: rpc_inbound_tx.clone()
+        })
+    }
+    /// Registers the rpc for the response to the request written to the stream, registration of the previous request is replaced.
+    fn wait_response(&mut self, stream_id: u64) -> Result<RpcResponseReceiver, ProcessError> {
+        let (rpc_tx, rpc_rx) = oneshot::channel();
+
+        self.rpc_inbound_tx.send(RpcMsg::AddRpc(self.correlation_id, rpc_tx))?;
+        self.stream_id = Some(stream_id);
+
+        Ok(rpc_rx)
+    }
+    fn count<T>(&mut self, res: &Result<Result<T, ProcessError>, tokio::time::error::Elapsed>) {
+        let counter = match res {
+            Ok(Ok(_)) => {
+                self.responded = true;
+                &self.counters.completed
+            }
+            Ok(Err(_)) => &self.counters.failed,
+            Err(_) => &self.counters.timed_out
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.counted = true;
+    }
+}
+
+impl Drop for PendingRpc {
+    fn drop(&mut self) {
+        if !self.counted {
+            self.counters.cancelled.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.counters.pending.fetch_sub(1, Ordering::Relaxed);
+
+        if self.responded {
+            return;
+        }
+
+        let _ = self.rpc_inbound_tx.send(RpcMsg::RemoveRpc(self.correlation_id));
+
+        if let Some(stream_id) = self.stream_id {
+            debug!("Rpc is not waiting for response anymore, cancelling, correlation id {}", self.correlation_id);
+
+            let _ = self.write_tx.send(WriteMsg::Frame(Frame::new_rpc_cancel(stream_id, self.correlation_id)));
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct MagicBall {    
     pub addr: String,
     pub auth_token: Option<String>,
-    pub auth_data: Option<Value>,    
+    pub auth_data: Option<Value>,
+    /// Deadline attached to sent messages, unix time in milliseconds. When MagicBall is passed to message handler, deadline of processed message is set here.
+    pub deadline: Option<u64>,
+    /// Payload codec of sent messages, overrides codecs chosen for keys
+    pub codec: Option<Codec>,
+    /// Credit window of started streams, streams are flow controlled if it is set
+    pub credits: Option<u32>,
+    /// Resumable transfer carried by the next started stream
+    pub transfer: Option<Transfer>,
+    /// Handling of rpcs waiting for response when connection to the server is lost
+    pub rpc_retry_policy: RpcRetryPolicy,
+    /// Time to wait for rpc response, RPC_TIMEOUT_MS_AMOUNT is used if it is not set
+    pub rpc_timeout: Option<Duration>,
+    /// Frame size and capabilities of the connection
+    pub(crate) write_settings: Arc<WriteSettings>,
+    pub(crate) heartbeat: Arc<Heartbeat>,
+    rpc_counters: Arc<RpcCounters>,
+    /// Interceptors of sent messages, they are shared by all clones
+    interceptors: Interceptors,
     hash_buf: BytesMut,
     addr_bytes_len: usize,
-	frame_type: u8,
-	msg_type: u8,
-	key_hash: u64,
-	stream_id: u64,
-    source_hash: u64,
     write_tx: UnboundedSender<WriteMsg>,
     rpc_inbound_tx: UnboundedSender<RpcMsg>
 }
@@ -608,21 +1306,182 @@ impl MagicBall {
         let addr_bytes = addr.as_bytes();
         let addr_bytes_len = addr_bytes.len();
 
-        hash_buf.put(addr_bytes);
-        
-        MagicBall {            
-            addr,
-            auth_token: None,
-            auth_data: None,            
-            hash_buf,
-            addr_bytes_len,
-			frame_type: 0,
-			msg_type: 0,
-			key_hash: 0,
-			stream_id: 0,
-            source_hash: 0,
-            write_tx,
-            rpc_inbound_tx
+        hash_buf.put(addr_bytes);
+        
+        MagicBall {            
+            addr,
+            auth_token: None,
+            auth_data: None,
+            deadline: None,
+            codec: None,
+            credits: None,
+            transfer: None,
+            rpc_retry_policy: RpcRetryPolicy::Fail,
+            rpc_timeout: None,
+            write_settings: Arc::new(WriteSettings::new()),
+            heartbeat: Arc::new(Heartbeat::new()),
+            rpc_counters: Arc::new(RpcCounters::new()),
+            interceptors: Arc::new(RwLock::new(vec![])),
+            hash_buf,
+            addr_bytes_len,
+            write_tx,
+            rpc_inbound_tx
+        }
+    }
+    /// Returns MagicBall which attaches provided deadline (unix time in milliseconds) to sent messages.
+    /// Already set deadline is kept if it is earlier, so nested calls can't outlive the processed message.
+    pub fn with_deadline(&self, deadline: u64) -> MagicBall {
+        let mut mb = self.clone();
+
+        mb.deadline = match self.deadline {
+            Some(current) if current < deadline => Some(current),
+            _ => Some(deadline)
+        };
+
+        mb
+    }
+    /// Same as with_deadline, deadline is calculated from provided timeout.
+    pub fn with_timeout(&self, timeout: Duration) -> MagicBall {
+        self.with_deadline(get_timestamp_ms() + timeout.as_millis() as u64)
+    }
+    /// Returns MagicBall which waits for rpc responses during provided timeout. Rpc requests carry deadline of the timeout,
+    /// so the server and the callee don't process them longer, earlier deadline of the MagicBall is kept.
+    pub fn with_rpc_timeout(&self, rpc_timeout: Duration) -> MagicBall {
+        let mut mb = self.clone();
+
+        mb.rpc_timeout = Some(rpc_timeout);
+
+        mb
+    }
+    /// Meta fields set on messages sent with the key by this MagicBall.
+    fn msg_options(&self, key: &Key) -> MsgOptions {
+        MsgOptions {
+            auth_token: self.auth_token.clone(),
+            auth_data: self.auth_data.clone(),
+            deadline: self.deadline,
+            codec: self.get_codec(key)
+        }
+    }
+    /// Meta fields set on rpc responses sent by this MagicBall, responses use codec of the request.
+    fn response_options(&self, codec: Codec) -> MsgOptions {
+        MsgOptions {
+            auth_token: self.auth_token.clone(),
+            auth_data: self.auth_data.clone(),
+            deadline: None,
+            codec
+        }
+    }
+    /// Returns MagicBall which encodes payloads of sent messages with provided codec.
+    pub fn with_codec(&self, codec: Codec) -> MagicBall {
+        let mut mb = self.clone();
+
+        mb.codec = Some(codec);
+
+        mb
+    }
+    /// Returns MagicBall which starts flow controlled streams with provided credit window (in frames).
+    /// Stream frames written by StreamWriter wait for credits granted by the stream receiver, full message mode receivers grant credits automatically.
+    pub fn with_credits(&self, credits: u32) -> MagicBall {
+        let mut mb = self.clone();
+
+        mb.credits = Some(credits);
+
+        mb
+    }
+    /// Returns MagicBall which starts streams carrying provided resumable transfer. After the stream is started,
+    /// sender waits for offset acknowledged by the receiver with StreamWriter::wait_transfer_offset and sends transfer data from this offset.
+    pub fn with_transfer(&self, transfer: Transfer) -> MagicBall {
+        let mut mb = self.clone();
+
+        mb.transfer = Some(transfer);
+
+        mb
+    }
+    /// Chooses payload codec for messages sent with the key by this client and all its MagicBall clones.
+    pub fn set_codec(&mut self, key: Key, codec: Codec) {
+        self.write_settings.set_codec(key, codec);
+    }
+    /// Codec for payload of sent message, rpc responses use codec of the request.
+    fn get_codec(&self, key: &Key) -> Codec {
+        self.codec.or_else(|| self.write_settings.get_codec(key)).unwrap_or_default()
+    }
+    /// Deadline of rpc request, it is set by rpc timeout if it is earlier than deadline of the MagicBall.
+    fn get_rpc_deadline(&self) -> Option<u64> {
+        let rpc_deadline = self.rpc_timeout.map(|rpc_timeout| get_timestamp_ms() + rpc_timeout.as_millis() as u64);
+
+        match (self.deadline, rpc_deadline) {
+            (Some(deadline), Some(rpc_deadline)) => Some(deadline.min(rpc_deadline)),
+            (deadline, rpc_deadline) => deadline.or(rpc_deadline)
+        }
+    }
+    /// Statistics of rpcs sent by the client.
+    pub fn get_rpc_stats(&self) -> RpcStats {
+        self.rpc_counters.get_stats()
+    }
+    /// Adds interceptor of messages sent with this MagicBall and all its clones, interceptors are called in order of adding.
+    pub fn add_interceptor<I: Interceptor>(&self, interceptor: I) {
+        self.interceptors.write().expect("Interceptors lock is poisoned").push(Arc::new(interceptor));
+    }
+    fn intercept_send(&self, key: &Key, msg_type: &MsgType) -> Result<(), ProcessError> {
+        for interceptor in self.interceptors.read().expect("Interceptors lock is poisoned").iter() {
+            interceptor.on_send(self, key, msg_type)?;
+        }
+
+        Ok(())
+    }
+    /// Sets max amount of rpcs waiting for response in the client, new rpcs fail with ProcessError::TooManyPendingRpcs when it is reached.
+    pub fn set_max_pending_rpcs(&self, max_pending: u64) {
+        self.rpc_counters.max_pending.store(max_pending, Ordering::Relaxed);
+    }
+    fn add_pending_rpc(&self, correlation_id: Uuid) -> Result<PendingRpc, ProcessError> {
+        PendingRpc::new(correlation_id, &self.rpc_counters, &self.write_tx, &self.rpc_inbound_tx)
+    }
+    /// Writes rpc request and waits for the response. If connection is lost before the response is received,
+    /// request is written again after reconnect with RpcRetryPolicy::Retry, otherwise ProcessError::ConnectionLost is returned.
+    async fn write_rpc_request(&mut self, pending_rpc: &mut PendingRpc, key: &Key, mut dto: Vec<u8>, msg_meta_size: u64, payload_size: u64, attachments_sizes: Vec<u64>) -> Result<(MsgMeta, Vec<u8>, Option<Vec<u8>>), ProcessError> {
+        let key_hash = get_key_hash(key);
+        let source_hash = get_addr_hash(&self.addr);
+
+        loop {
+            let stream_id = self.get_stream_id();
+            let rpc_rx = pending_rpc.wait_response(stream_id)?;
+
+            // request data is kept only if it can be written again
+            let data = match self.rpc_retry_policy {
+                RpcRetryPolicy::Retry => dto.clone(),
+                RpcRetryPolicy::Fail => std::mem::take(&mut dto)
+            };
+            let connection_epoch = self.write_settings.get_connection_epoch();
+
+            self.write_full_message(MsgType::RpcRequest.get_u8(), key_hash, stream_id, source_hash, data, msg_meta_size, payload_size, attachments_sizes.clone(), true).await?;
+
+            match wait_rpc_response(&self.write_settings, connection_epoch, rpc_rx).await {
+                Err(ProcessError::ConnectionLost) if self.rpc_retry_policy == RpcRetryPolicy::Retry => {
+                    warn!("Connection lost before rpc response, retrying after reconnect, correlation id {}", pending_rpc.correlation_id);
+
+                    self.write_settings.wait_connected().await;
+                }
+                res => return res
+            }
+        }
+    }
+    fn get_stream_writer(&self, msg_type: u8, key: &Key, stream_id: u64, source_hash: u64, correlation_id: Uuid) -> StreamWriter {
+        StreamWriter {
+            msg_type,
+            key: key.clone(),
+            key_hash: get_key_hash(key),
+            stream_id,
+            source_hash,
+            correlation_id,
+            deadline: self.deadline,
+            rpc_timeout: self.rpc_timeout,
+            connection_epoch: self.write_settings.get_connection_epoch(),
+            completed: false,
+            write_settings: self.write_settings.clone(),
+            write_tx: self.write_tx.clone(),
+            rpc_inbound_tx: self.rpc_inbound_tx.clone(),
+            rpc_counters: self.rpc_counters.clone(),
+            interceptors: self.interceptors.clone()
         }
     }
     /// This function generates new stream id
@@ -638,236 +1497,227 @@ impl MagicBall {
     /// This function should be called for single message write.
     /// stream_id value MUST BE ACQUIRED with get_stream_id() function. stream_id generation can be implicit, however this will leads to less flexible API (if for example you need stream payload or attachments data).
     /// If you plan to write attachments data later (for example in streaming fashion), leave attachments_sizes parameter empty and only fill attachment sizes in msg_meta
+    /// Complete messages (send_end_frame is true) are compressed if compression threshold is set and compression is agreed with the server.
     pub async fn write_full_message(&mut self, msg_type: u8, key_hash: u64, stream_id: u64, source_hash: u64, data: Vec<u8>, msg_meta_size: u64, payload_size: u64, attachments_sizes: Vec<u64>, send_end_frame: bool) -> Result<(), ProcessError> {
-        let msg_meta_offset = LEN_BUF_SIZE + msg_meta_size as usize;
-        let payload_offset = msg_meta_offset + payload_size as usize;
-        let mut data_buf = [0; MAX_FRAME_PAYLOAD_SIZE];
-
-        let mut source = &data[LEN_BUF_SIZE..msg_meta_offset];
-
-		let mut bytes_send = 0;
-
-        loop {        
-            let n = source.read(&mut data_buf).await?;
-            match n {
-                0 => break,
-                _ => {
-					bytes_send = bytes_send + n as u64;
-
-					let frame_type = match bytes_send == msg_meta_size {
-						true => FrameType::MsgMetaEnd,
-						false => FrameType::MsgMeta
-					};
-
-					self.write_tx.send(WriteMsg::Frame(Frame::new(frame_type as u8, n as u16, msg_type, key_hash, stream_id, source_hash, Some(data_buf))))?;
-				}
-            }
-        }
-
-		bytes_send = 0;
-
-        match payload_size {
-            0 => {}
-            _ => {            
-                let mut source = &data[msg_meta_offset..payload_offset];
-
-                loop {        
-                    let n = source.read(&mut data_buf).await?;        
-                    match n {
-                        0 => break,
-                        _ => {
-							bytes_send = bytes_send + n as u64;
-
-							let frame_type = match bytes_send == payload_size {
-								true => FrameType::PayloadEnd,
-								false => FrameType::Payload
-							};
+        let (data, msg_meta_size, payload_size, attachments_sizes) = match (send_end_frame, self.write_settings.get_compression_threshold()) {
+            (true, Some(threshold)) => compress_message(data, msg_meta_size, payload_size, attachments_sizes, threshold)?,
+            _ => (data, msg_meta_size, payload_size, attachments_sizes)
+        };
+        let sizes = MsgSizes {
+            msg_meta: msg_meta_size,
+            payload: payload_size,
+            attachments: attachments_sizes
+        };
+        let mut frames = get_message_frames(msg_type, key_hash, stream_id, source_hash, Bytes::from(data), sizes, self.get_max_frame_payload_size());
 
-							self.write_tx.send(WriteMsg::Frame(Frame::new(frame_type as u8, n as u16, msg_type, key_hash, stream_id, source_hash, Some(data_buf))))?;
-						}
-                    }
-                }
-            }
+        if !send_end_frame {
+            frames.pop();
         }
 
-        let mut prev = payload_offset as usize;
-
-        for attachment_size in attachments_sizes {
-            let attachment_offset = prev + attachment_size as usize;
-
-			bytes_send = 0;
-
-            match attachment_size {
-                0 => {}
-                _ => {
-                    let mut source = &data[prev..attachment_offset];
-
-                    loop {        
-                        let n = source.read(&mut data_buf).await?;        
-                        match n {
-                            0 => break,
-                            _ => {
-								bytes_send = bytes_send + n as u64;
-
-								let frame_type = match bytes_send == attachment_size {
-									true => FrameType::AttachmentEnd,
-									false => FrameType::Attachment
-								};
-								
-								self.write_tx.send(WriteMsg::Frame(Frame::new(frame_type as u8, n as u16, msg_type, key_hash, stream_id, source_hash, Some(data_buf))))?
-							}
-                        }
-                    }                    
-                }
-            }            
-
-            prev = attachment_offset;
+        for frame in frames {
+            self.write_tx.send(WriteMsg::Frame(frame))?;
         }
 
-		if send_end_frame {
-			self.write_tx.send(WriteMsg::Frame(Frame::new(FrameType::End as u8, 0, msg_type, key_hash, stream_id, source_hash, None)))?;
-		}
-
         Ok(())
     }
     pub async fn send_event<T>(&mut self, key: Key, payload: T) -> Result<Uuid, ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+        self.intercept_send(&key, &MsgType::Event)?;
+
         let route = Route {
             source: Participator::Service(self.addr.clone()),
             spec: RouteSpec::Simple,
             points: vec![Participator::Service(self.addr.to_owned())]
         };
 
-        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = event_dto_with_sizes(self.addr.clone(), key.clone(), payload, route, self.auth_token.clone(), self.auth_data.clone())?;
+        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = event_dto_with_options(self.addr.clone(), key.clone(), payload, route, self.msg_options(&key))?;
 
-        self.key_hash = get_key_hash(&key);
-        self.stream_id = self.get_stream_id();
-        self.source_hash = get_addr_hash(&self.addr);
+        let key_hash = get_key_hash(&key);
+        let stream_id = self.get_stream_id();
+        let source_hash = get_addr_hash(&self.addr);
 
-        self.write_full_message(MsgType::Event.get_u8(), self.key_hash, self.stream_id, self.source_hash, dto, msg_meta_size, payload_size, attachments_sizes, true).await?;
+        self.write_full_message(MsgType::Event.get_u8(), key_hash, stream_id, source_hash, dto, msg_meta_size, payload_size, attachments_sizes, true).await?;
         
         Ok(correlation_id)
     }
     pub async fn send_rpc<T>(&mut self, key: Key, payload: T) -> Result<Uuid, ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+        self.intercept_send(&key, &MsgType::RpcRequest)?;
+
         let route = Route {
             source: Participator::Service(self.addr.clone()),
             spec: RouteSpec::Simple,
             points: vec![Participator::Service(self.addr.clone())]
         };
 
-        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = rpc_dto_with_sizes(self.addr.clone(), key.clone(), payload, route, self.auth_token.clone(), self.auth_data.clone())?;
+        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = rpc_dto_with_options(self.addr.clone(), key.clone(), payload, route, self.msg_options(&key))?;
 
-		self.frame_type = FrameType::Attachment as u8;
-		self.msg_type = MsgType::RpcRequest.get_u8();
-        self.key_hash = get_key_hash(&key);
-        self.stream_id = self.get_stream_id();
-        self.source_hash = get_addr_hash(&self.addr);
+		let msg_type = MsgType::RpcRequest.get_u8();
+        let key_hash = get_key_hash(&key);
+        let stream_id = self.get_stream_id();
+        let source_hash = get_addr_hash(&self.addr);
 
-        self.write_full_message(self.msg_type, self.key_hash, self.stream_id, self.source_hash, dto, msg_meta_size, payload_size, attachments_sizes, true).await?;
+        self.write_full_message(msg_type, key_hash, stream_id, source_hash, dto, msg_meta_size, payload_size, attachments_sizes, true).await?;
         
         Ok(correlation_id)
     }
     pub async fn send_event_with_route<T>(&mut self, key: Key, payload: T, mut route: Route) -> Result<Uuid, ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+        self.intercept_send(&key, &MsgType::Event)?;
+
         //info!("send_event, route {:?}, key {}, payload {:?}, ", route, addr, key, payload);
 
         route.points.push(Participator::Service(self.addr.clone()));
 
-        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = event_dto_with_sizes(self.addr.clone(), key.clone(), payload, route, self.auth_token.clone(), self.auth_data.clone())?;
+        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = event_dto_with_options(self.addr.clone(), key.clone(), payload, route, self.msg_options(&key))?;
 
-        self.key_hash = get_key_hash(&key);
-        self.stream_id = self.get_stream_id();
-        self.source_hash = get_addr_hash(&self.addr);
+        let key_hash = get_key_hash(&key);
+        let stream_id = self.get_stream_id();
+        let source_hash = get_addr_hash(&self.addr);
 
-        self.write_full_message(MsgType::Event.get_u8(), self.key_hash, self.stream_id, self.source_hash, dto, msg_meta_size, payload_size, attachments_sizes, true).await?;
+        self.write_full_message(MsgType::Event.get_u8(), key_hash, stream_id, source_hash, dto, msg_meta_size, payload_size, attachments_sizes, true).await?;
         
         Ok(correlation_id)
     }
-	pub async fn start_event_stream<T>(&mut self, key: Key, payload: T) -> Result<Uuid, ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+    /// Sends event marked as retained. Server keeps last retained event for the key and delivers it to subscribers connected later.
+    pub async fn send_retained_event<T>(&mut self, key: Key, payload: T) -> Result<Uuid, ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+        let payload = encode(self.get_codec(&key), &payload)?;
+
+        self.write_retained_event(key, payload).await
+    }
+    /// Clears retained event for the key, nothing is delivered to subscribers.
+    pub async fn clear_retained_event(&mut self, key: Key) -> Result<Uuid, ProcessError> {
+        self.write_retained_event(key, vec![]).await
+    }
+    async fn write_retained_event(&mut self, key: Key, payload: Vec<u8>) -> Result<Uuid, ProcessError> {
+        self.intercept_send(&key, &MsgType::Event)?;
+
         let route = Route {
             source: Participator::Service(self.addr.clone()),
             spec: RouteSpec::Simple,
             points: vec![Participator::Service(self.addr.to_owned())]
         };
 
-        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = event_dto_with_sizes(self.addr.clone(), key.clone(), payload, route, self.auth_token.clone(), self.auth_data.clone())?;
+        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = retained_event_dto_with_options(self.addr.clone(), key.clone(), payload, route, self.msg_options(&key))?;
 
-		self.frame_type = FrameType::Attachment as u8;
-		self.msg_type = MsgType::Event.get_u8();
-        self.key_hash = get_key_hash(&key);
-        self.stream_id = self.get_stream_id();
-        self.source_hash = get_addr_hash(&self.addr);
+        let key_hash = get_key_hash(&key);
+        let stream_id = self.get_stream_id();
+        let source_hash = get_addr_hash(&self.addr);
 
-        self.write_full_message(self.msg_type, self.key_hash, self.stream_id, self.source_hash, dto, msg_meta_size, payload_size, attachments_sizes, false).await?;
+        self.write_full_message(MsgType::Event.get_u8(), key_hash, stream_id, source_hash, dto, msg_meta_size, payload_size, attachments_sizes, true).await?;
         
         Ok(correlation_id)
     }
-    pub async fn start_rpc_stream<T>(&mut self, key: Key, payload: T) -> Result<Uuid, ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+    /// Starts event stream, data of the stream is written with returned StreamWriter.
+	pub async fn start_event_stream<T>(&mut self, key: Key, payload: T) -> Result<StreamWriter, ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+        self.intercept_send(&key, &MsgType::Event)?;
+
+        let route = Route {
+            source: Participator::Service(self.addr.clone()),
+            spec: RouteSpec::Simple,
+            points: vec![Participator::Service(self.addr.to_owned())]
+        };
+
+        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = event_dto_with_options(self.addr.clone(), key.clone(), payload, route, self.msg_options(&key))?;
+        let (dto, msg_meta_size) = match (self.credits, self.transfer) {
+            (None, None) => (dto, msg_meta_size),
+            (credits, transfer) => set_stream_options(dto, msg_meta_size, credits, transfer)?
+        };
+
+		let msg_type = MsgType::Event.get_u8();
+        let key_hash = get_key_hash(&key);
+        let stream_id = self.get_stream_id();
+        let source_hash = get_addr_hash(&self.addr);
+
+        if let Some(credits) = self.credits {
+            self.write_settings.open_stream_credits(stream_id, credits);
+        }
+
+        if self.transfer.is_some() {
+            self.write_settings.open_transfer(stream_id);
+        }
+
+        self.write_full_message(msg_type, key_hash, stream_id, source_hash, dto, msg_meta_size, payload_size, attachments_sizes, false).await?;
+        
+        Ok(self.get_stream_writer(msg_type, &key, stream_id, source_hash, correlation_id))
+    }
+    /// Starts rpc stream, data of the stream is written with returned StreamWriter and the response is awaited with StreamWriter::finish_rpc.
+    pub async fn start_rpc_stream<T>(&mut self, key: Key, payload: T) -> Result<StreamWriter, ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+        self.intercept_send(&key, &MsgType::RpcRequest)?;
+
         let route = Route {
             source: Participator::Service(self.addr.clone()),
             spec: RouteSpec::Simple,
             points: vec![Participator::Service(self.addr.clone())]
         };
 
-        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = rpc_dto_with_sizes(self.addr.clone(), key.clone(), payload, route, self.auth_token.clone(), self.auth_data.clone())?;
+        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = rpc_dto_with_options(self.addr.clone(), key.clone(), payload, route, self.msg_options(&key))?;
+        let (dto, msg_meta_size) = match (self.credits, self.transfer) {
+            (None, None) => (dto, msg_meta_size),
+            (credits, transfer) => set_stream_options(dto, msg_meta_size, credits, transfer)?
+        };
+
+		let msg_type = MsgType::RpcRequest.get_u8();
+        let key_hash = get_key_hash(&key);
+        let stream_id = self.get_stream_id();
+        let source_hash = get_addr_hash(&self.addr);
+
+        if let Some(credits) = self.credits {
+            self.write_settings.open_stream_credits(stream_id, credits);
+        }
 
-		self.frame_type = FrameType::Attachment as u8;
-		self.msg_type = MsgType::RpcRequest.get_u8();
-        self.key_hash = get_key_hash(&key);
-        self.stream_id = self.get_stream_id();
-        self.source_hash = get_addr_hash(&self.addr);
+        if self.transfer.is_some() {
+            self.write_settings.open_transfer(stream_id);
+        }
 
-        self.write_full_message(self.msg_type, self.key_hash, self.stream_id, self.source_hash, dto, msg_meta_size, payload_size, attachments_sizes, false).await?;
+        self.write_full_message(msg_type, key_hash, stream_id, source_hash, dto, msg_meta_size, payload_size, attachments_sizes, false).await?;
         
-        Ok(correlation_id)
+        Ok(self.get_stream_writer(msg_type, &key, stream_id, source_hash, correlation_id))
     }
-    pub async fn start_rpc_stream_response<T>(&mut self, mut msg_meta: MsgMeta, payload: T) -> Result<(), ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+    /// Starts response stream for rpc request with msg meta, data of the stream is written with returned StreamWriter.
+    pub async fn start_rpc_stream_response<T>(&mut self, mut msg_meta: MsgMeta, payload: T) -> Result<StreamWriter, ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
         msg_meta.route.points.push(Participator::Service(self.addr.clone()));
 
         let rpc_result = RpcResult::Ok;
 
-        self.source_hash = get_addr_hash(msg_meta.route.get_source_addr());
+        let source_hash = get_addr_hash(msg_meta.route.get_source_addr());
 
-        let (dto, msg_meta_size, payload_size, attachments_sizes) = rpc_response_dto_sizes(self.addr.clone(), msg_meta.key.clone(), msg_meta.correlation_id, payload, vec![], vec![], rpc_result.clone(), msg_meta.route, self.auth_token.clone(), self.auth_data.clone())?;
+        let (dto, msg_meta_size, payload_size, attachments_sizes) = rpc_response_dto_with_options(self.addr.clone(), msg_meta.key.clone(), msg_meta.correlation_id, Response::Simple(payload), rpc_result.clone(), msg_meta.route, self.response_options(msg_meta.codec))?;
 
-		self.frame_type = FrameType::Attachment as u8;
-		self.msg_type = MsgType::RpcResponse(rpc_result).get_u8();
-        self.key_hash = get_key_hash(&msg_meta.key);
-        self.stream_id = self.get_stream_id();
+		let msg_type = MsgType::RpcResponse(rpc_result).get_u8();
+        let key_hash = get_key_hash(&msg_meta.key);
+        let stream_id = self.get_stream_id();
 
-        self.write_full_message(self.msg_type, self.key_hash, self.stream_id, self.source_hash, dto, msg_meta_size, payload_size, attachments_sizes, false).await?;
+        self.write_full_message(msg_type, key_hash, stream_id, source_hash, dto, msg_meta_size, payload_size, attachments_sizes, false).await?;
         
-        Ok(())
+        Ok(self.get_stream_writer(msg_type, &msg_meta.key, stream_id, source_hash, msg_meta.correlation_id))
     }
-    pub async fn start_rpc_stream_response_custom_res<T>(&mut self, mut msg_meta: MsgMeta, payload: T, rpc_result: RpcResult) -> Result<(), ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+    pub async fn start_rpc_stream_response_custom_res<T>(&mut self, mut msg_meta: MsgMeta, payload: T, rpc_result: RpcResult) -> Result<StreamWriter, ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
         msg_meta.route.points.push(Participator::Service(self.addr.clone()));
 
-        self.source_hash = get_addr_hash(msg_meta.route.get_source_addr());
+        let source_hash = get_addr_hash(msg_meta.route.get_source_addr());
 
-        let (dto, msg_meta_size, payload_size, attachments_sizes) = rpc_response_dto_sizes(self.addr.clone(), msg_meta.key.clone(), msg_meta.correlation_id, payload, vec![], vec![], rpc_result.clone(), msg_meta.route, self.auth_token.clone(), self.auth_data.clone())?;
+        let (dto, msg_meta_size, payload_size, attachments_sizes) = rpc_response_dto_with_options(self.addr.clone(), msg_meta.key.clone(), msg_meta.correlation_id, Response::Simple(payload), rpc_result.clone(), msg_meta.route, self.response_options(msg_meta.codec))?;
 
-		self.frame_type = FrameType::Attachment as u8;
-		self.msg_type = MsgType::RpcResponse(rpc_result).get_u8();
-        self.key_hash = get_key_hash(&msg_meta.key);
-        self.stream_id = self.get_stream_id();;
+		let msg_type = MsgType::RpcResponse(rpc_result).get_u8();
+        let key_hash = get_key_hash(&msg_meta.key);
+        let stream_id = self.get_stream_id();
 
-        self.write_full_message(self.msg_type, self.key_hash, self.stream_id, self.source_hash, dto, msg_meta_size, payload_size, attachments_sizes, false).await?;
+        self.write_full_message(msg_type, key_hash, stream_id, source_hash, dto, msg_meta_size, payload_size, attachments_sizes, false).await?;
         
-        Ok(())
+        Ok(self.get_stream_writer(msg_type, &msg_meta.key, stream_id, source_hash, msg_meta.correlation_id))
     }
     pub async fn send_rpc_response<T>(&mut self, mut msg_meta: MsgMeta, payload: T) -> Result<(), ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
         msg_meta.route.points.push(Participator::Service(self.addr.clone()));
 
         let rpc_result = RpcResult::Ok;
 
-        self.source_hash = get_addr_hash(msg_meta.route.get_source_addr());
+        let source_hash = get_addr_hash(msg_meta.route.get_source_addr());
 
-        let (dto, msg_meta_size, payload_size, attachments_sizes) = rpc_response_dto_sizes(self.addr.clone(), msg_meta.key.clone(), msg_meta.correlation_id, payload, vec![], vec![], rpc_result.clone(), msg_meta.route, self.auth_token.clone(), self.auth_data.clone())?;
+        let (dto, msg_meta_size, payload_size, attachments_sizes) = rpc_response_dto_with_options(self.addr.clone(), msg_meta.key.clone(), msg_meta.correlation_id, Response::Simple(payload), rpc_result.clone(), msg_meta.route, self.response_options(msg_meta.codec))?;
 
-		self.frame_type = FrameType::Attachment as u8;
-		self.msg_type = MsgType::RpcResponse(rpc_result).get_u8();
-        self.key_hash = get_key_hash(&msg_meta.key);
-        self.stream_id = self.get_stream_id();
+		let msg_type = MsgType::RpcResponse(rpc_result).get_u8();
+        let key_hash = get_key_hash(&msg_meta.key);
+        let stream_id = self.get_stream_id();
 
-        self.write_full_message(self.msg_type, self.key_hash, self.stream_id, self.source_hash, dto, msg_meta_size, payload_size, attachments_sizes, true).await?;
+        self.write_full_message(msg_type, key_hash, stream_id, source_hash, dto, msg_meta_size, payload_size, attachments_sizes, true).await?;
         
         Ok(())
     }
@@ -876,71 +1726,92 @@ impl MagicBall {
 
         let rpc_result = RpcResult::Ok;
 
-        self.source_hash = get_addr_hash(msg_meta.route.get_source_addr());
+        let source_hash = get_addr_hash(msg_meta.route.get_source_addr());
 
-        let (dto, msg_meta_size, payload_size, attachments_sizes) = rpc_response_dto_sizes(self.addr.clone(), msg_meta.key.clone(), msg_meta.correlation_id, payload, attachments, attachments_data, rpc_result.clone(), msg_meta.route, self.auth_token.clone(), self.auth_data.clone())?;
+        let (dto, msg_meta_size, payload_size, attachments_sizes) = rpc_response_dto_with_options(self.addr.clone(), msg_meta.key.clone(), msg_meta.correlation_id, Response::Full(payload, attachments, attachments_data), rpc_result.clone(), msg_meta.route, self.response_options(msg_meta.codec))?;
 
-		self.frame_type = FrameType::Attachment as u8;
-		self.msg_type = MsgType::RpcResponse(rpc_result).get_u8();
-        self.key_hash = get_key_hash(&msg_meta.key);
-        self.stream_id = self.get_stream_id();
+		let msg_type = MsgType::RpcResponse(rpc_result).get_u8();
+        let key_hash = get_key_hash(&msg_meta.key);
+        let stream_id = self.get_stream_id();
 
-        self.write_full_message(self.msg_type, self.key_hash, self.stream_id, self.source_hash, dto, msg_meta_size, payload_size, attachments_sizes, true).await?;
+        self.write_full_message(msg_type, key_hash, stream_id, source_hash, dto, msg_meta_size, payload_size, attachments_sizes, true).await?;
         
         Ok(())
     }
-	pub fn send_frame(&mut self, payload: &[u8], payload_size: usize) -> Result<(), ProcessError> {
-        if payload_size == 0 {
-            return Err(ProcessError::ZeroSizedPayloadNotAllowed);
-        }	
-
-		let mut buf = [0; MAX_FRAME_PAYLOAD_SIZE];
+    /// Grants credits to the sender (tx addr of msg meta) of flow controlled stream, should be called by stream mode receivers as stream frames are consumed.
+    pub fn grant_credits(&self, tx: &str, stream_id: u64, credits: u32) -> Result<(), ProcessError> {
+        let mut payload = BytesMut::with_capacity(CREDIT_PAYLOAD_SIZE + tx.len());
 
-		let mut i = 0;
+        payload.put_u32(credits);
+        payload.extend_from_slice(tx.as_bytes());
 
-		while i < payload_size {
-			buf[i] = payload[i];
-			i = i + 1;
-		}
+        self.write_tx.send(WriteMsg::Frame(Frame::new(FrameType::Credit as u8, payload.len() as u16, 0, 0, stream_id, get_addr_hash(&self.addr), Some(payload.freeze()))))?;
 
-        self.write_tx.send(WriteMsg::Frame(Frame::new(self.frame_type, payload_size as u16, self.msg_type, self.key_hash, self.stream_id, self.source_hash, Some(buf))))?;
+        Ok(())
+    }
+    /// Acknowledges offset of transfer data persisted by the receiver to the sender (tx addr of msg meta) of the stream.
+    /// Stream mode receivers ack persisted offset when transfer stream is started, this is where the sender resumes, and then periodically as data is persisted.
+    pub fn ack_transfer(&self, tx: &str, stream_id: u64, offset: u64) -> Result<(), ProcessError> {
+        let mut payload = BytesMut::with_capacity(TRANSFER_ACK_PAYLOAD_SIZE + tx.len());
 
-		Ok(())
-	}
-    pub fn complete_msg_meta(&mut self) -> Result<(), ProcessError> {
-        self.write_tx.send(WriteMsg::Frame(Frame::new(FrameType::MsgMetaEnd as u8, 0, self.msg_type, self.key_hash, self.stream_id, self.source_hash, None)))?;
-		Ok(())
-	}
-    pub fn complete_payload(&mut self) -> Result<(), ProcessError> {
-        self.write_tx.send(WriteMsg::Frame(Frame::new(FrameType::PayloadEnd as u8, 0, self.msg_type, self.key_hash, self.stream_id, self.source_hash, None)))?;
-		Ok(())
-	}
-    pub fn complete_attachment(&mut self) -> Result<(), ProcessError> {
-        self.write_tx.send(WriteMsg::Frame(Frame::new(FrameType::AttachmentEnd as u8, 0, self.msg_type, self.key_hash, self.stream_id, self.source_hash, None)))?;
-		Ok(())
-	}
-    /// This function will be waiting for rpc response, please note (in async function).
-    pub async fn complete_rpc_stream<T>(&mut self, correlation_id: Uuid) -> Result<Message<T>, ProcessError> where for<'de> T: serde::Deserialize<'de>, T: Debug {
-        let (rpc_tx, rpc_rx) = oneshot::channel();
-        
-        self.source_hash = get_addr_hash(&self.addr);
-        self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        payload.put_u64(offset);
+        payload.extend_from_slice(tx.as_bytes());
 
-        self.write_tx.send(WriteMsg::Frame(Frame::new(FrameType::End as u8, 0, self.msg_type, self.key_hash, self.stream_id, self.source_hash, None)))?;
+        self.write_tx.send(WriteMsg::Frame(Frame::new(FrameType::TransferAck as u8, payload.len() as u16, 0, 0, stream_id, get_addr_hash(&self.addr), Some(payload.freeze()))))?;
 
-        let (msg_meta, payload, attachments_data) = timeout(Duration::from_millis(RPC_TIMEOUT_MS_AMOUNT), rpc_rx).await??;
-        let payload: T = from_slice(&payload)?;
+        Ok(())
+    }
+    /// Max payload size of written frames, it is limited by size agreed with the server on handshake.
+    pub fn get_max_frame_payload_size(&self) -> usize {
+        self.write_settings.get_max_frame_payload_size()
+    }
+    /// Sets max payload size of written frames for all clones of this MagicBall. Size agreed with the server on handshake is not exceeded.
+    pub fn set_max_frame_payload_size(&mut self, max_frame_payload_size: usize) {
+        self.write_settings.set_requested_frame_payload_size(max_frame_payload_size);
+    }
+    /// Enables or disables frame checksums offered to the server, they are enabled by default. Checksums can be disabled on trusted local links.
+    /// Must be called before connection, checksums are not used with loopback transport.
+    pub fn set_frame_checksums(&mut self, enabled: bool) {
+        self.write_settings.set_frame_checksums(enabled);
+    }
+    /// Sets min size of payload and attachments data for compression of sent messages, None disables compression.
+    pub fn set_compression_threshold(&mut self, compression_threshold: Option<usize>) {
+        self.write_settings.set_compression_threshold(compression_threshold);
+    }
+    /// Enables pings sent to the server with provided interval. Connection is closed if nothing is received from the server during timeout.
+    /// Must be called before connection, heartbeats are not used with loopback transport.
+    pub fn set_heartbeat(&mut self, interval: Duration, timeout: Option<Duration>) {
+        self.heartbeat.set(interval, timeout);
+    }
+    /// Round trip time of the last ping answered by the server.
+    pub fn get_rtt(&self) -> Option<Duration> {
+        self.heartbeat.get_rtt()
+    }
+    pub(crate) fn send_ping(&self) -> Result<(), ProcessError> {
+        self.write_tx.send(WriteMsg::Frame(Frame::new_ping(self.heartbeat.get_timestamp())))?;
+        Ok(())
+    }
+    /// Processes frames of the connection itself: credits and transfer acks for streams written by the client and heartbeats.
+    /// Returns false for frames of received streams.
+    pub(crate) fn process_connection_frame(&self, frame: &Frame) -> Result<bool, ProcessError> {
+        match frame.get_frame_type() {
+            Ok(FrameType::Credit) => self.write_settings.process_credit_frame(frame),
+            Ok(FrameType::TransferAck) => self.write_settings.process_transfer_ack_frame(frame),
+            Ok(FrameType::Ping) => self.write_tx.send(WriteMsg::Frame(frame.new_pong()))?,
+            Ok(FrameType::Pong) => self.heartbeat.process_pong(frame),
+            _ => return Ok(false)
+        }
 
-        Ok(Message {
-            meta: msg_meta,
-            payload, 
-            attachments_data
-        })
-	}
-	pub fn complete_stream(&mut self) -> Result<(), ProcessError> {
-        self.write_tx.send(WriteMsg::Frame(Frame::new(FrameType::End as u8, 0, self.msg_type, self.key_hash, self.stream_id, self.source_hash, None)))?;
-		Ok(())
-	}
+        Ok(true)
+    }
+    /// Protocol capabilities agreed with the server on handshake.
+    pub fn get_capabilities(&self) -> Capabilities {
+        self.write_settings.get_capabilities()
+    }
+    /// Messages written while client is disconnected are sent after reconnect.
+    pub fn is_connected(&self) -> bool {
+        self.write_settings.is_connected()
+    }
     pub async fn rpc<T, R>(&mut self, key: Key, payload: T) -> Result<Message<R>, ProcessError> where T: serde::Serialize, T: Debug, for<'de> R: serde::Deserialize<'de>, R: Debug {
         let route = Route {
             source: Participator::Service(self.addr.clone()),
@@ -950,19 +1821,26 @@ impl MagicBall {
 
 		//info!("send_rpc, route {:?}, key {}, payload {:?}, ", route, key, payload);
 		
-        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = rpc_dto_with_sizes(self.addr.clone(), key.clone(), payload, route, self.auth_token.clone(), self.auth_data.clone())?;
-        let (rpc_tx, rpc_rx) = oneshot::channel();
-        
-        self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        self.intercept_send(&key, &MsgType::RpcRequest)?;
+
+        let deadline = self.get_rpc_deadline();
+        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = rpc_dto_with_options(self.addr.clone(), key.clone(), payload, route, MsgOptions {
+            deadline,
+            ..self.msg_options(&key)
+        })?;
+        let rpc_timeout = get_rpc_timeout(deadline, self.rpc_timeout)?;
+        let mut pending_rpc = self.add_pending_rpc(correlation_id)?;
+        let started = Instant::now();
+        let res = timeout(rpc_timeout, self.write_rpc_request(&mut pending_rpc, &key, dto, msg_meta_size, payload_size, attachments_sizes)).await;
+
+        // dropped or timed out rpc is cancelled with pending rpc
+        pending_rpc.count(&res);
 
-        self.key_hash = get_key_hash(&key);
-        self.stream_id = self.get_stream_id();
-        self.source_hash = get_addr_hash(&self.addr);
+        let res = get_rpc_response(res);
 
-        self.write_full_message(MsgType::RpcRequest.get_u8(), self.key_hash, self.stream_id, self.source_hash, dto, msg_meta_size, payload_size, attachments_sizes, true).await?;       
+        intercept_rpc_completed(&self.interceptors, &key, started, res.as_ref().err());
 
-        let (msg_meta, payload, attachments_data) = timeout(Duration::from_millis(RPC_TIMEOUT_MS_AMOUNT), rpc_rx).await??;
-        let payload: R = from_slice(&payload)?;
+        let (msg_meta, payload, attachments_data) = res?;
 
         Ok(Message {
             meta: msg_meta, 
@@ -975,19 +1853,26 @@ impl MagicBall {
 
         route.points.push(Participator::Service(self.addr.to_owned()));
 		
-        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = rpc_dto_with_sizes(self.addr.clone(), key.clone(), payload, route, self.auth_token.clone(), self.auth_data.clone())?;
-        let (rpc_tx, rpc_rx) = oneshot::channel();
-        
-        self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        self.intercept_send(&key, &MsgType::RpcRequest)?;
+
+        let deadline = self.get_rpc_deadline();
+        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = rpc_dto_with_options(self.addr.clone(), key.clone(), payload, route, MsgOptions {
+            deadline,
+            ..self.msg_options(&key)
+        })?;
+        let rpc_timeout = get_rpc_timeout(deadline, self.rpc_timeout)?;
+        let mut pending_rpc = self.add_pending_rpc(correlation_id)?;
+        let started = Instant::now();
+        let res = timeout(rpc_timeout, self.write_rpc_request(&mut pending_rpc, &key, dto, msg_meta_size, payload_size, attachments_sizes)).await;
+
+        // dropped or timed out rpc is cancelled with pending rpc
+        pending_rpc.count(&res);
 
-        self.key_hash = get_key_hash(&key);
-        self.stream_id = self.get_stream_id();
-        self.source_hash = get_addr_hash(&self.addr);
+        let res = get_rpc_response(res);
 
-        self.write_full_message(MsgType::RpcRequest.get_u8(), self.key_hash, self.stream_id, self.source_hash, dto, msg_meta_size, payload_size, attachments_sizes, true).await?;
+        intercept_rpc_completed(&self.interceptors, &key, started, res.as_ref().err());
 
-        let (msg_meta, payload, attachments_data) = timeout(Duration::from_millis(RPC_TIMEOUT_MS_AMOUNT), rpc_rx).await??;
-        let payload: R = from_slice(&payload)?;        
+        let (msg_meta, payload, attachments_data) = res?;        
 
         Ok(Message {
             meta: msg_meta, 
@@ -995,6 +1880,56 @@ impl MagicBall {
             attachments_data
         })
     }
+    /// Reads records from persistent log of the key. If offset is not set, reading starts from offset committed by this client.
+    pub async fn read_log<R>(&mut self, key: Key, offset: Option<u64>, max_count: u64) -> Result<Vec<LogRecord<R>>, ProcessError> where for<'de> R: serde::Deserialize<'de>, R: Debug {
+        let msg = self.server_rpc::<_, ReadResponse>(get_read_key(), ReadRequest { key, offset, max_count }).await?;
+        let attachments_data = msg.attachments_data.unwrap_or_default();
+        let mut res = vec![];
+        let mut prev = 0;
+
+        for (record, attachment) in msg.payload.records.into_iter().zip(msg.meta.attachments.iter()) {
+            let attachment_offset = prev + attachment.size as usize;
+            let data = attachments_data.get(prev..attachment_offset).ok_or_else(|| ProcessError::Custom(format!("Log record at offset {} is out of read response data", record.offset)))?;
+            let mut msg_meta = record.msg_meta;
+            let (payload, _) = decompress_message(&mut msg_meta, data.to_vec(), None)?;
+            let payload: R = decode(msg_meta.codec, &payload)?;
+
+            res.push(LogRecord {
+                offset: record.offset,
+                msg: Message {
+                    meta: msg_meta,
+                    payload,
+                    attachments_data: None
+                }
+            });
+
+            prev = attachment_offset;
+        }
+
+        Ok(res)
+    }
+    /// Stores consumer offset for the key, next read_log call without offset starts from it. Committing earlier offset rewinds the consumer.
+    pub async fn commit_log_offset(&mut self, key: Key, offset: u64) -> Result<(), ProcessError> {
+        self.server_rpc::<_, Value>(get_commit_key(), CommitRequest { key, offset }).await?;
+
+        Ok(())
+    }
+    /// Returns offset committed by this client and offset of next appended record for the key.
+    pub async fn get_log_offsets(&mut self, key: Key) -> Result<LogOffsets, ProcessError> {
+        let msg = self.server_rpc::<_, LogOffsets>(get_offsets_key(), OffsetsRequest { key }).await?;
+
+        Ok(msg.payload)
+    }
+    /// Returns statistics of the server namespace this client is connected to.
+    pub async fn get_stats(&mut self) -> Result<NamespaceStats, ProcessError> {
+        let msg = self.server_rpc::<_, NamespaceStats>(get_stats_key(), json!({})).await?;
+
+        Ok(msg.payload)
+    }
+    /// Rpc to the service processed by the server itself.
+    async fn server_rpc<T, R>(&mut self, key: Key, payload: T) -> Result<Message<R>, ProcessError> where T: serde::Serialize, T: Debug, for<'de> R: serde::Deserialize<'de>, R: Debug {
+        self.rpc(key, payload).await
+    }
     pub async fn proxy_event(&mut self, tx: String, mut data: Vec<u8>) -> Result<(), ProcessError> {
         let (res, len) = {
             let mut buf = Cursor::new(&data);
@@ -1028,11 +1963,11 @@ impl MagicBall {
         buf.append(&mut msg_meta_vec);
         buf.append(&mut payload_with_attachments);
 
-        self.key_hash = get_key_hash(&msg_meta.key);
-        self.stream_id = self.get_stream_id();
-        self.source_hash = get_addr_hash(&msg_meta.tx);
+        let key_hash = get_key_hash(&msg_meta.key);
+        let stream_id = self.get_stream_id();
+        let source_hash = get_addr_hash(&msg_meta.tx);
 
-        self.write_full_message(msg_meta.msg_type.get_u8(), self.key_hash, self.stream_id, self.source_hash, buf, msg_meta_size, payload_size, attachments_sizes, true).await?;
+        self.write_full_message(msg_meta.msg_type.get_u8(), key_hash, stream_id, source_hash, buf, msg_meta_size, payload_size, attachments_sizes, true).await?;
         
         Ok(())
     }
@@ -1074,11 +2009,11 @@ impl MagicBall {
         buf.append(&mut msg_meta_vec);
         buf.append(&mut payload_with_attachments);
 
-        self.key_hash = get_key_hash(&msg_meta.key);
-        self.stream_id = self.get_stream_id();
-        self.source_hash = get_addr_hash(&msg_meta.tx);
+        let key_hash = get_key_hash(&msg_meta.key);
+        let stream_id = self.get_stream_id();
+        let source_hash = get_addr_hash(&msg_meta.tx);
 
-        self.write_full_message(msg_meta.msg_type.get_u8(), self.key_hash, self.stream_id, self.source_hash, buf, msg_meta_size, payload_size, attachments_sizes, true).await?;
+        self.write_full_message(msg_meta.msg_type.get_u8(), key_hash, stream_id, source_hash, buf, msg_meta_size, payload_size, attachments_sizes, true).await?;
         
         Ok(())
     }
@@ -1117,21 +2052,35 @@ impl MagicBall {
         buf.append(&mut msg_meta_vec);
         buf.append(&mut payload_with_attachments);
 
-        let (rpc_tx, rpc_rx) = oneshot::channel();
-                
-        self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        let rpc_timeout = get_rpc_timeout(msg_meta.deadline, self.rpc_timeout)?;
+        let mut pending_rpc = self.add_pending_rpc(correlation_id)?;
 
         debug!("proxy_rpc write attempt");
 
-        self.key_hash = get_key_hash(&msg_meta.key);
-        self.stream_id = self.get_stream_id();
-        self.source_hash = get_addr_hash(&msg_meta.tx);
+        let key_hash = get_key_hash(&msg_meta.key);
+        let stream_id = self.get_stream_id();
+        let rpc_rx = pending_rpc.wait_response(stream_id)?;
+        let source_hash = get_addr_hash(&msg_meta.tx);
+        let connection_epoch = self.write_settings.get_connection_epoch();
+
+        let started = Instant::now();
 
-        self.write_full_message(msg_meta.msg_type.get_u8(), self.key_hash, self.stream_id, self.source_hash, buf, msg_meta_size, payload_size, attachments_sizes, true).await?;
+        if let Err(e) = self.write_full_message(msg_meta.msg_type.get_u8(), key_hash, stream_id, source_hash, buf, msg_meta_size, payload_size, attachments_sizes, true).await {
+            intercept_rpc_completed(&self.interceptors, &msg_meta.key, started, Some(&e));
+            return Err(e);
+        }
 
         debug!("proxy_rpc write attempt succeeded");
 
-        let (msg_meta, mut payload, mut attachments_data) = timeout(Duration::from_millis(RPC_TIMEOUT_MS_AMOUNT), rpc_rx).await??;
+        let res = timeout(rpc_timeout, wait_rpc_response(&self.write_settings, connection_epoch, rpc_rx)).await;
+
+        pending_rpc.count(&res);
+
+        let res = res.map_err(ProcessError::from).and_then(|res| res);
+
+        intercept_rpc_completed(&self.interceptors, &msg_meta.key, started, res.as_ref().err());
+
+        let (msg_meta, mut payload, mut attachments_data) = res?;
 
         let mut buf = vec![];
         let mut msg_meta_buf = to_vec(&msg_meta)?;
@@ -1188,21 +2137,35 @@ impl MagicBall {
         buf.append(&mut msg_meta_vec);
         buf.append(&mut payload_with_attachments);
 
-        let (rpc_tx, rpc_rx) = oneshot::channel();
-                
-        self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        let rpc_timeout = get_rpc_timeout(msg_meta.deadline, self.rpc_timeout)?;
+        let mut pending_rpc = self.add_pending_rpc(correlation_id)?;
 
         debug!("proxy_rpc_with_auth_data write attempt");
 
-        self.key_hash = get_key_hash(&msg_meta.key);
-        self.stream_id = self.get_stream_id();
-        self.source_hash = get_addr_hash(&msg_meta.tx);
+        let key_hash = get_key_hash(&msg_meta.key);
+        let stream_id = self.get_stream_id();
+        let rpc_rx = pending_rpc.wait_response(stream_id)?;
+        let source_hash = get_addr_hash(&msg_meta.tx);
+        let connection_epoch = self.write_settings.get_connection_epoch();
 
-        self.write_full_message(msg_meta.msg_type.get_u8(), self.key_hash, self.stream_id, self.source_hash, buf, msg_meta_size, payload_size, attachments_sizes, true).await?;
+        let started = Instant::now();
+
+        if let Err(e) = self.write_full_message(msg_meta.msg_type.get_u8(), key_hash, stream_id, source_hash, buf, msg_meta_size, payload_size, attachments_sizes, true).await {
+            intercept_rpc_completed(&self.interceptors, &msg_meta.key, started, Some(&e));
+            return Err(e);
+        }
 
         debug!("proxy_rpc_with_auth_data write attempt succeeded");
 
-        let (msg_meta, mut payload, mut attachments_data) = timeout(Duration::from_millis(RPC_TIMEOUT_MS_AMOUNT), rpc_rx).await??;
+        let res = timeout(rpc_timeout, wait_rpc_response(&self.write_settings, connection_epoch, rpc_rx)).await;
+
+        pending_rpc.count(&res);
+
+        let res = res.map_err(ProcessError::from).and_then(|res| res);
+
+        intercept_rpc_completed(&self.interceptors, &msg_meta.key, started, res.as_ref().err());
+
+        let (msg_meta, mut payload, mut attachments_data) = res?;
 
         let mut buf = vec![];
         let mut msg_meta_buf = to_vec(&msg_meta)?;
@@ -1254,23 +2217,37 @@ impl MagicBall {
         buf.append(&mut msg_meta_vec);
         buf.append(&mut payload_with_attachments);
 
-        let (rpc_tx, rpc_rx) = oneshot::channel();
-                
-        self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        let rpc_timeout = get_rpc_timeout(msg_meta.deadline, self.rpc_timeout)?;
+        let mut pending_rpc = self.add_pending_rpc(correlation_id)?;
 
         debug!("proxy_rpc_with_payload write attempt");
 
-        self.key_hash = get_key_hash(&msg_meta.key);
-        self.stream_id = self.get_stream_id();
-        self.source_hash = get_addr_hash(&msg_meta.tx);
+        let key_hash = get_key_hash(&msg_meta.key);
+        let stream_id = self.get_stream_id();
+        let rpc_rx = pending_rpc.wait_response(stream_id)?;
+        let source_hash = get_addr_hash(&msg_meta.tx);
+        let connection_epoch = self.write_settings.get_connection_epoch();
+
+        let started = Instant::now();
 
-        self.write_full_message(msg_meta.msg_type.get_u8(), self.key_hash, self.stream_id, self.source_hash, buf, msg_meta_size, payload_size, attachments_sizes, true).await?;
+        if let Err(e) = self.write_full_message(msg_meta.msg_type.get_u8(), key_hash, stream_id, source_hash, buf, msg_meta_size, payload_size, attachments_sizes, true).await {
+            intercept_rpc_completed(&self.interceptors, &msg_meta.key, started, Some(&e));
+            return Err(e);
+        }
 
         debug!("proxy_rpc_with_payload write attempt succeeded");
 
-        let (msg_meta, payload, attachments_data) = timeout(Duration::from_millis(RPC_TIMEOUT_MS_AMOUNT), rpc_rx).await??;
+        let res = timeout(rpc_timeout, wait_rpc_response(&self.write_settings, connection_epoch, rpc_rx)).await;
+
+        pending_rpc.count(&res);
 
-        let payload: T = from_slice(&payload)?;
+        let res = res.map_err(ProcessError::from).and_then(|res| res);
+
+        intercept_rpc_completed(&self.interceptors, &msg_meta.key, started, res.as_ref().err());
+
+        let (msg_meta, payload, attachments_data) = res?;
+
+        let payload: T = decode(msg_meta.codec, &payload)?;
         
         Ok(Message {
             meta: msg_meta,
@@ -1280,6 +2257,172 @@ impl MagicBall {
     }
 }
 
+/// Writer of stream started by MagicBall. It owns identity of the stream, so several streams can be written concurrently by one client.
+/// Stream dropped before it is finished is cancelled with CancelReason::SenderFailed.
+pub struct StreamWriter {
+    msg_type: u8,
+    key: Key,
+    key_hash: u64,
+    stream_id: u64,
+    source_hash: u64,
+    /// Correlation id of the stream message, rpc response is matched by it
+    correlation_id: Uuid,
+    /// Deadline of the stream message, it limits the wait for rpc response
+    deadline: Option<u64>,
+    rpc_timeout: Option<Duration>,
+    /// Epoch of the connection carrying the stream, stream can't be continued on other connection
+    connection_epoch: u64,
+    completed: bool,
+    write_settings: Arc<WriteSettings>,
+    write_tx: UnboundedSender<WriteMsg>,
+    rpc_inbound_tx: UnboundedSender<RpcMsg>,
+    rpc_counters: Arc<RpcCounters>,
+    interceptors: Interceptors
+}
+
+impl StreamWriter {
+    pub fn get_stream_id(&self) -> u64 {
+        self.stream_id
+    }
+    pub fn get_correlation_id(&self) -> Uuid {
+        self.correlation_id
+    }
+    /// Writes stream data, data bigger than max frame payload size is written in several frames.
+    /// For flow controlled stream each frame waits for credit granted by the stream receiver.
+    /// ProcessError::ConnectionLost is returned if connection carrying the stream is lost, the stream should be started again.
+    pub async fn write_payload(&mut self, payload: Bytes) -> Result<(), ProcessError> {
+        if payload.is_empty() {
+            return Err(ProcessError::ZeroSizedPayloadNotAllowed);
+        }
+
+        if self.write_settings.is_connection_lost(self.connection_epoch) {
+            return Err(ProcessError::ConnectionLost);
+        }
+
+        let stream_credits = self.write_settings.get_stream_credits(self.stream_id);
+        let max_frame_payload_size = self.write_settings.get_max_frame_payload_size();
+        let mut start = 0;
+
+        while start < payload.len() {
+            let end = payload.len().min(start + max_frame_payload_size);
+
+            if let Some(stream_credits) = &stream_credits {
+                stream_credits.acquire().await.map_err(|_| ProcessError::StreamClosed)?.forget();
+            }
+
+            self.write_tx.send(WriteMsg::Frame(Frame::new(FrameType::Attachment as u8, (end - start) as u16, self.msg_type, self.key_hash, self.stream_id, self.source_hash, Some(payload.slice(start..end)))))?;
+
+            start = end;
+        }
+
+		Ok(())
+    }
+    /// Writes data read from reader until its end as one attachment, returns attachment size.
+    pub async fn write_attachment<R>(&mut self, mut reader: R) -> Result<u64, ProcessError> where R: AsyncRead + Unpin {
+        let max_frame_payload_size = self.write_settings.get_max_frame_payload_size();
+        let mut buf = BytesMut::with_capacity(max_frame_payload_size);
+        let mut size = 0;
+
+        loop {
+            buf.reserve(max_frame_payload_size);
+
+            match reader.read_buf(&mut buf).await? {
+                0 => break,
+                n => size += n as u64
+            }
+
+            if buf.len() >= max_frame_payload_size {
+                self.write_payload(buf.split().freeze()).await?;
+            }
+        }
+
+        if !buf.is_empty() {
+            self.write_payload(buf.split().freeze()).await?;
+        }
+
+        self.complete_attachment()?;
+
+        debug!("Attachment written to stream {}, size {}", self.stream_id, size);
+
+        Ok(size)
+    }
+    /// Marks end of attachment written with write_payload.
+    pub fn complete_attachment(&mut self) -> Result<(), ProcessError> {
+        self.write_tx.send(WriteMsg::Frame(Frame::new(FrameType::AttachmentEnd as u8, 0, self.msg_type, self.key_hash, self.stream_id, self.source_hash, None)))?;
+		Ok(())
+	}
+    /// Waits for the first offset acknowledged by receiver of the transfer carried by the stream, transfer data must be written starting from it.
+    /// The wait is limited by rpc timeout.
+    pub async fn wait_transfer_offset(&self) -> Result<u64, ProcessError> {
+        let rpc_timeout = get_rpc_timeout(self.deadline, self.rpc_timeout)?;
+        let mut transfer_offset = self.write_settings.subscribe_transfer_offset(self.stream_id).ok_or(ProcessError::StreamClosed)?;
+        let offset = timeout(rpc_timeout, transfer_offset.wait_for(Option::is_some)).await?.map_err(|_| ProcessError::StreamClosed)?;
+
+        Ok(offset.unwrap_or_default())
+    }
+    /// Last offset acknowledged by receiver of the transfer carried by the stream.
+    pub fn get_transfer_offset(&self) -> Option<u64> {
+        self.write_settings.subscribe_transfer_offset(self.stream_id).and_then(|transfer_offset| *transfer_offset.borrow())
+    }
+    /// Completes the stream.
+	pub fn finish(mut self) -> Result<(), ProcessError> {
+        self.write_tx.send(WriteMsg::Frame(Frame::new(FrameType::End as u8, 0, self.msg_type, self.key_hash, self.stream_id, self.source_hash, None)))?;
+        self.close();
+		Ok(())
+	}
+    /// Completes rpc stream and waits for the response.
+    pub async fn finish_rpc<T>(mut self) -> Result<Message<T>, ProcessError> where for<'de> T: serde::Deserialize<'de>, T: Debug {
+        let rpc_timeout = get_rpc_timeout(self.deadline, self.rpc_timeout)?;
+        let mut pending_rpc = PendingRpc::new(self.correlation_id, &self.rpc_counters, &self.write_tx, &self.rpc_inbound_tx)?;
+        let rpc_rx = pending_rpc.wait_response(self.stream_id)?;
+        let started = Instant::now();
+
+        self.write_tx.send(WriteMsg::Frame(Frame::new(FrameType::End as u8, 0, self.msg_type, self.key_hash, self.stream_id, self.source_hash, None)))?;
+        self.close();
+
+        let res = timeout(rpc_timeout, wait_rpc_response(&self.write_settings, self.connection_epoch, rpc_rx)).await;
+
+        pending_rpc.count(&res);
+
+        let res = get_rpc_response(res);
+
+        intercept_rpc_completed(&self.interceptors, &self.key, started, res.as_ref().err());
+
+        let (msg_meta, payload, attachments_data) = res?;
+
+        Ok(Message {
+            meta: msg_meta,
+            payload, 
+            attachments_data
+        })
+	}
+    /// Aborts the stream, receivers drop data collected for it, but keep persisted data of resumable transfer. Rpc stream requester doesn't wait for the response after cancellation.
+	pub fn abort(mut self, reason: CancelReason) -> Result<(), ProcessError> {
+        self.write_tx.send(WriteMsg::Frame(Frame::new_cancel(self.msg_type, self.key_hash, self.stream_id, self.source_hash, reason)))?;
+        self.close();
+		Ok(())
+	}
+    fn close(&mut self) {
+        self.completed = true;
+        self.write_settings.close_stream_credits(self.stream_id);
+        self.write_settings.close_transfer(self.stream_id);
+    }
+}
+
+impl Drop for StreamWriter {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+
+        warn!("Stream {} dropped before it is finished, cancelling", self.stream_id);
+
+        let _ = self.write_tx.send(WriteMsg::Frame(Frame::new_cancel(self.msg_type, self.key_hash, self.stream_id, self.source_hash, CancelReason::SenderFailed)));
+
+        self.close();
+    }
+}
+
 #[derive(Debug)]
 pub enum ProcessError {
     None,
@@ -1292,12 +2435,35 @@ pub enum ProcessError {
     IncorrectMsgType,
     StreamLayoutNotFound,
     StreamClosed,    
+    StreamCancelled(CancelReason),
+    /// Nothing is received from the peer during heartbeat timeout
+    HeartbeatTimeout,
+    /// Frame read from the peer is corrupted, connection is closed
+    FrameChecksumMismatch,
     WriteChannelDropped,        
+    /// Channel of messages read by client is closed, nobody processes them
+    ReadChannelDropped,
+    /// Connection to the server is lost before rpc response is received
+    ConnectionLost,
+    /// Max amount of rpcs waiting for response is reached, amount of pending rpcs is passed
+    TooManyPendingRpcs(u64),
     SendWriteMsgError,
     SendServerMsgError,
     SendRpcMsgError,
+    SendJournalMsgError,
     OneshotRecvError(oneshot::error::RecvError),
     Timeout,
+    DeadlineExpired,
+    /// Two different addrs have the same hash on the server
+    AddrHashCollision(String, String),
+    NamespaceNotFound(String),
+    HandshakeFailed(String),
+    /// Peer protocol versions don't overlap with supported ones, peer min version and version are passed
+    IncompatibleProtocolVersion(u16, u16),
+    /// Access key is not accepted by the namespace
+    NamespaceAccessDenied(String),
+    /// Rpc is completed with error response
+    Rpc(RemoteError),
     Custom(String)
 }
 
@@ -1356,6 +2522,77 @@ impl From<tokio::time::error::Elapsed> for ProcessError {
 }
 
 
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{Capabilities, Frame, FrameType, Handshake, Heartbeat, ProcessError, HANDSHAKE_SIZE, HEARTBEAT_TIMEOUT_INTERVALS};
+
+    #[test]
+    fn handshake_negotiation() {
+        let local = Handshake::new(65535);
+        let remote = Handshake {
+            version: 3,
+            min_version: 1,
+            capabilities: Capabilities(Capabilities::MULTIPLEXING.0 | Capabilities::SIGNING.0),
+            max_frame_payload_size: 4096
+        };
+
+        assert_eq!(Handshake::from_bytes(&remote.to_bytes()).expect("Failed to read handshake"), remote);
+
+        let agreed = local.negotiate(&remote).expect("Negotiation failed");
+
+        assert_eq!(agreed.version, local.version);
+        assert_eq!(agreed.capabilities, Capabilities::MULTIPLEXING);
+        assert_eq!(agreed.max_frame_payload_size, 4096);
+    }
+
+    #[test]
+    fn handshake_rejection() {
+        let local = Handshake::new(1024);
+        let remote = Handshake {
+            version: local.version + 2,
+            min_version: local.version + 1,
+            capabilities: Capabilities::SUPPORTED,
+            max_frame_payload_size: 1024
+        };
+
+        assert!(matches!(local.negotiate(&remote), Err(ProcessError::IncompatibleProtocolVersion(_, _))));
+        assert!(matches!(Handshake::from_bytes(&[0; HANDSHAKE_SIZE]), Err(ProcessError::HandshakeFailed(_))));
+    }
+
+    #[test]
+    fn checksum_negotiation() {
+        let trusted = Handshake::new(1024).with_capabilities(Capabilities::SUPPORTED.difference(Capabilities::CHECKSUM));
+
+        assert!(Handshake::new(1024).negotiate(&Handshake::new(1024)).expect("Negotiation failed").capabilities.contains(Capabilities::CHECKSUM));
+        assert!(!Handshake::new(1024).negotiate(&trusted).expect("Negotiation failed").capabilities.contains(Capabilities::CHECKSUM));
+    }
+
+    #[test]
+    fn heartbeat_rtt() {
+        let heartbeat = Heartbeat::new();
+
+        assert_eq!(heartbeat.get_interval(), None);
+        assert_eq!(heartbeat.get_timeout(), None);
+        assert_eq!(heartbeat.get_rtt(), None);
+
+        heartbeat.set(Duration::from_millis(100), None);
+
+        assert_eq!(heartbeat.get_timeout(), Some(Duration::from_millis(100) * HEARTBEAT_TIMEOUT_INTERVALS));
+
+        let ping = Frame::new_ping(heartbeat.get_timestamp());
+        let pong = ping.new_pong();
+
+        assert!(matches!(pong.get_frame_type(), Ok(FrameType::Pong)));
+        assert_eq!(pong.get_heartbeat_timestamp(), ping.get_heartbeat_timestamp());
+
+        std::thread::sleep(Duration::from_millis(10));
+        heartbeat.process_pong(&pong);
+
+        assert!(heartbeat.get_rtt().expect("Rtt is not set") >= Duration::from_millis(10));
+    }
+}
+
 /*
 #![feature(unboxed_closures, fn_traits)]
 use std::future::Future;
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use log::*;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Value, from_value, to_value};
+use sp_dto::{ErrorCode, Key, Message, RemoteError, Response, Subscribes};
+use crate::proto::MagicBall;
+use crate::service::Service;
+
+/// Part of key pattern which matches any value.
+pub const ANY_KEY_PART: &str = "*";
+
+type HandlerFuture<T> = Pin<Box<dyn Future<Output = Result<T, Box<dyn Error>>> + Send>>;
+type EventHandler = Box<dyn Fn(MagicBall, Message<Value>) -> HandlerFuture<()> + Send + Sync>;
+type RpcHandler = Box<dyn Fn(MagicBall, Message<Value>) -> HandlerFuture<Response<Value>> + Send + Sync>;
+
+/// Pattern of message keys, "*" matches any action, service or domain. Key source and tags are not matched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPattern {
+    pub action: String,
+    pub service: String,
+    pub domain: String
+}
+
+impl KeyPattern {
+    pub fn new(action: &str, service: &str, domain: &str) -> KeyPattern {
+        KeyPattern {
+            action: action.to_owned(),
+            service: service.to_owned(),
+            domain: domain.to_owned()
+        }
+    }
+    pub fn matches(&self, key: &Key) -> bool {
+        matches_part(&self.action, &key.action) && matches_part(&self.service, &key.service) && matches_part(&self.domain, &key.domain)
+    }
+}
+
+fn matches_part(pattern: &str, value: &str) -> bool {
+    pattern == ANY_KEY_PART || pattern == value
+}
+
+/// Handlers of one message type. Handler registered for exact key is selected first, then the first matching pattern in order of registration.
+struct Handlers<H> {
+    by_key: HashMap<Key, H>,
+    by_pattern: Vec<(KeyPattern, H)>
+}
+
+impl<H> Handlers<H> {
+    fn new() -> Handlers<H> {
+        Handlers {
+            by_key: HashMap::new(),
+            by_pattern: vec![]
+        }
+    }
+    fn get(&self, key: &Key) -> Option<&H> {
+        self.by_key.get(key).or_else(|| self.by_pattern.iter().find(|(pattern, _)| pattern.matches(key)).map(|(_, handler)| handler))
+    }
+}
+
+/// Service which passes events and rpcs to handlers registered for their keys. Payloads are deserialized to request types of handlers,
+/// rpc requester gets BadRequest error if payload doesn't match and NotFound error if no handler is registered for the key.
+/// Handlers keep their state by capturing it, router can be run with client::run or used by other service with route_event and route_rpc.
+pub struct KeyRouter {
+    events: Handlers<EventHandler>,
+    rpcs: Handlers<RpcHandler>
+}
+
+impl KeyRouter {
+    pub fn new() -> KeyRouter {
+        KeyRouter {
+            events: Handlers::new(),
+            rpcs: Handlers::new()
+        }
+    }
+    /// Registers handler of events with the key, handler registered before for the same key is replaced.
+    pub fn with_event<T, F, R>(mut self, key: Key, handler: F) -> KeyRouter
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(MagicBall, Message<T>) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), Box<dyn Error>>> + Send + 'static
+    {
+        self.events.by_key.insert(key, get_event_handler(handler));
+        self
+    }
+    /// Registers handler of events with keys matching the pattern.
+    pub fn with_event_pattern<T, F, R>(mut self, pattern: KeyPattern, handler: F) -> KeyRouter
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(MagicBall, Message<T>) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), Box<dyn Error>>> + Send + 'static
+    {
+        self.events.by_pattern.push((pattern, get_event_handler(handler)));
+        self
+    }
+    /// Registers handler of rpcs with the key, handler registered before for the same key is replaced.
+    pub fn with_rpc<T, U, F, R>(mut self, key: Key, handler: F) -> KeyRouter
+    where
+        T: DeserializeOwned + Send + 'static,
+        U: Serialize,
+        F: Fn(MagicBall, Message<T>) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<Response<U>, Box<dyn Error>>> + Send + 'static
+    {
+        self.rpcs.by_key.insert(key, get_rpc_handler(handler));
+        self
+    }
+    /// Registers handler of rpcs with keys matching the pattern.
+    pub fn with_rpc_pattern<T, U, F, R>(mut self, pattern: KeyPattern, handler: F) -> KeyRouter
+    where
+        T: DeserializeOwned + Send + 'static,
+        U: Serialize,
+        F: Fn(MagicBall, Message<T>) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<Response<U>, Box<dyn Error>>> + Send + 'static
+    {
+        self.rpcs.by_pattern.push((pattern, get_rpc_handler(handler)));
+        self
+    }
+    /// Keys of registered handlers as server subscribes of the addr. Keys matched by patterns can't be listed, they are not included.
+    pub fn get_subscribes(&self, addr: &str) -> Subscribes {
+        let mut event_subscribes = HashMap::new();
+        let mut rpc_subscribes = HashMap::new();
+
+        event_subscribes.insert(addr.to_owned(), self.events.by_key.keys().cloned().collect());
+        rpc_subscribes.insert(addr.to_owned(), self.rpcs.by_key.keys().cloned().collect());
+
+        Subscribes::ByAddr(event_subscribes, rpc_subscribes)
+    }
+    /// Passes event to the handler of its key, events without handler are dropped.
+    pub async fn route_event(&self, mb: MagicBall, msg: Message<Value>) -> Result<(), Box<dyn Error>> {
+        match self.events.get(&msg.meta.key) {
+            Some(handler) => handler(mb, msg).await,
+            None => {
+                warn!("No handler for event {}", msg.meta.display());
+                Ok(())
+            }
+        }
+    }
+    /// Passes rpc to the handler of its key.
+    pub async fn route_rpc(&self, mb: MagicBall, msg: Message<Value>) -> Result<Response<Value>, Box<dyn Error>> {
+        match self.rpcs.get(&msg.meta.key) {
+            Some(handler) => handler(mb, msg).await,
+            None => Err(Box::new(RemoteError::new(ErrorCode::NotFound, format!("No handler for rpc key {:?}", msg.meta.key))))
+        }
+    }
+}
+
+impl Default for KeyRouter {
+    fn default() -> KeyRouter {
+        KeyRouter::new()
+    }
+}
+
+impl Service for KeyRouter {
+    type Payload = Value;
+
+    async fn on_event(&self, mb: MagicBall, msg: Message<Value>) -> Result<(), Box<dyn Error>> {
+        self.route_event(mb, msg).await
+    }
+    async fn on_rpc(&self, mb: MagicBall, msg: Message<Value>) -> Result<Response<Value>, Box<dyn Error>> {
+        self.route_rpc(mb, msg).await
+    }
+}
+
+fn get_event_handler<T, F, R>(handler: F) -> EventHandler
+where
+    T: DeserializeOwned + Send + 'static,
+    F: Fn(MagicBall, Message<T>) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<(), Box<dyn Error>>> + Send + 'static
+{
+    Box::new(move |mb, msg| {
+        match deserialize_payload(msg) {
+            Ok(msg) => Box::pin(handler(mb, msg)),
+            Err(e) => Box::pin(async move { Err(Box::new(e) as Box<dyn Error>) })
+        }
+    })
+}
+
+fn get_rpc_handler<T, U, F, R>(handler: F) -> RpcHandler
+where
+    T: DeserializeOwned + Send + 'static,
+    U: Serialize,
+    F: Fn(MagicBall, Message<T>) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<Response<U>, Box<dyn Error>>> + Send + 'static
+{
+    Box::new(move |mb, msg| {
+        match deserialize_payload(msg) {
+            Ok(msg) => {
+                let res = handler(mb, msg);
+
+                Box::pin(async move { serialize_response(res.await?) })
+            }
+            Err(e) => Box::pin(async move { Err(Box::new(e) as Box<dyn Error>) })
+        }
+    })
+}
+
+fn deserialize_payload<T>(msg: Message<Value>) -> Result<Message<T>, RemoteError> where T: DeserializeOwned {
+    let payload = match from_value(msg.payload) {
+        Ok(payload) => payload,
+        Err(e) => return Err(RemoteError::new(ErrorCode::BadRequest, format!("Failed to deserialize payload of {:?}, {}", msg.meta.key, e)))
+    };
+
+    Ok(Message {
+        meta: msg.meta,
+        payload,
+        attachments_data: msg.attachments_data
+    })
+}
+
+fn serialize_response<U>(res: Response<U>) -> Result<Response<Value>, Box<dyn Error>> where U: Serialize {
+    Ok(match res {
+        Response::Simple(payload) => Response::Simple(to_value(payload)?),
+        Response::Full(payload, attachments, attachments_data) => Response::Full(to_value(payload)?, attachments, attachments_data)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use serde_derive::{Serialize, Deserialize};
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc::UnboundedSender;
+    use sp_dto::{ErrorCode, Key, Message, Subscribes, resp};
+    use crate::{MagicBall, ProcessError};
+    use crate::loopback::fixture::Fixture;
+    use super::{KeyPattern, KeyRouter};
+
+    #[test]
+    fn key_patterns() {
+        let key = Key::new("Add", "Cfg", "Cfg");
+
+        assert!(KeyPattern::new("Add", "Cfg", "Cfg").matches(&key));
+        assert!(KeyPattern::new("*", "Cfg", "*").matches(&key));
+        assert!(!KeyPattern::new("Get", "*", "*").matches(&key));
+
+        let router = KeyRouter::new()
+            .with_rpc(key.clone(), |_, _: sp_dto::Message<()>| async { sp_dto::resp(()) })
+            .with_rpc_pattern(KeyPattern::new("*", "Cfg", "Cfg"), |_, _: sp_dto::Message<()>| async { sp_dto::resp(()) });
+
+        match router.get_subscribes("Cfg") {
+            Subscribes::ByAddr(event_subscribes, rpc_subscribes) => {
+                assert_eq!(event_subscribes["Cfg"], vec![]);
+                assert_eq!(rpc_subscribes["Cfg"], vec![key]);
+            }
+            Subscribes::ByKey(_, _) => panic!("Subscribes are not by addr")
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct AddRequest {
+        a: i64,
+        b: i64
+    }
+
+    #[derive(Serialize)]
+    struct AddResponse {
+        sum: i64
+    }
+
+    async fn startup_router_caller(_: Value, _: Value, mut mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        let calls = [
+            (Key::new("Add", "Calc", "Calc"), json!({ "a": 2, "b": 3 })),
+            (Key::new("Mul", "Calc", "Calc"), json!({})),
+            (Key::new("Add", "Calc", "Calc"), json!({ "a": "2" })),
+            (Key::new("Sub", "Other", "Other"), json!({}))
+        ];
+
+        for (key, payload) in calls {
+            let res = match mb.rpc::<_, Value>(key, payload).await {
+                Ok(msg) => msg.payload,
+                Err(ProcessError::Rpc(error)) => json!({ "code": error.code }),
+                Err(e) => json!({ "unexpected": format!("{:?}", e) })
+            };
+
+            tx.send(res).expect("Failed to send rpc result");
+        }
+    }
+
+    #[tokio::test]
+    async fn key_router() {
+        let mut fixture = Fixture::start(&[], &[Key::new("Add", "Calc", "Calc"), Key::new("Mul", "Calc", "Calc"), Key::new("Sub", "Other", "Other")]);
+        let router = KeyRouter::new()
+            .with_rpc(Key::new("Add", "Calc", "Calc"), |_, msg: Message<AddRequest>| async move {
+                resp(AddResponse {
+                    sum: msg.payload.a + msg.payload.b
+                })
+            })
+            .with_rpc_pattern(KeyPattern::new("*", "Calc", "Calc"), |_, msg: Message<Value>| async move {
+                resp(json!({
+                    "action": msg.meta.key.action
+                }))
+            });
+
+        fixture.run_service(Arc::new(router), json!({ "addr": "Service" })).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_router_caller);
+
+        assert_eq!(fixture.recv_n(4).await, vec![
+            json!({ "sum": 5 }),
+            json!({ "action": "Mul" }),
+            json!({ "code": ErrorCode::BadRequest }),
+            json!({ "code": ErrorCode::NotFound })
+        ]);
+    }
+}
@@ -1,26 +1,219 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::hash::Hasher;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use log::*;
+use rand::random;
 use siphasher::sip::SipHasher24;
+use serde_derive::{Serialize, Deserialize};
 use serde_json::from_slice;
 use tokio::runtime::Runtime;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::{self, UnboundedSender};
-use sp_dto::bytes::{BytesMut, BufMut};
-use sp_dto::{Key, MsgMeta, MsgType, Subscribes};
-use sp_cfg::ServerConfig;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+use tokio::sync::mpsc::{self, UnboundedSender, UnboundedReceiver, WeakUnboundedSender};
+use sp_dto::{Key, MsgMeta, MsgType, RpcResult, RemoteError, ErrorCode, Subscribes, uuid::Uuid};
+use sp_cfg::{ServerConfig, NamespaceConfig};
 use crate::proto::*;
+use crate::codec::FrameCodec;
+use crate::journal::{Journal, JournalMsg};
 
-fn to_hashed_subscribes(key_hasher: &mut SipHasher24, subscribes: HashMap<Key, Vec<String>>) -> HashMap<u64, Vec<u64>> {
-    let mut res = HashMap::new();
-    let mut buf = BytesMut::new();
+/// Namespace for clients which don't select one on authorization
+pub const DEFAULT_NAMESPACE: &str = "default";
+const STATS_ADDR: &str = "Stats";
+/// Time for handshake and authorization of new connection, connections not authorized in time are closed
+const AUTH_TIMEOUT_MS: u64 = 10000;
+/// Sender addr of rpc error responses created by the server
+const SERVER_ADDR: &str = "Server";
 
-    for (key, addrs) in subscribes {
-        res.insert(get_key_hash(&key), addrs.iter().map(|a| get_addr_hash(a)).collect());
+/// Key of rpc for namespace statistics, processed by the server itself.
+pub fn get_stats_key() -> Key {
+    Key::new("Get", "Stats", "Server")
+}
+
+/// Statistics of single namespace, counters are kept since server start.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NamespaceStats {
+    pub namespace: String,
+    /// Connected clients
+    pub clients: u64,
+    /// Messages routed to subscribers or processed by the server
+    pub messages: u64,
+    /// Messages dropped by the server, for example with expired deadline
+    pub dropped: u64,
+    /// Frames written by clients
+    pub frames: u64,
+    /// Frame payload bytes written by clients
+    pub bytes: u64,
+    /// Kept retained events
+    pub retained: u64,
+    /// Rpc requests waiting for response
+    pub pending_rpcs: u64,
+    /// Round trip times of pings answered by clients in microseconds, by client addr
+    #[serde(default)]
+    pub rtts: HashMap<String, u64>
+}
+
+#[derive(Default)]
+pub(crate) struct Counters {
+    messages: AtomicU64,
+    dropped: AtomicU64,
+    frames: AtomicU64,
+    bytes: AtomicU64
+}
+
+/// Heartbeat settings of the server, pings are sent to clients with the interval.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HeartbeatConfig {
+    pub interval: Duration,
+    /// Clients which don't answer pings or write frames for this time are disconnected
+    pub timeout: Duration
+}
+
+impl HeartbeatConfig {
+    pub fn from_config(config: &ServerConfig) -> Option<HeartbeatConfig> {
+        config.heartbeat_interval.map(|interval| HeartbeatConfig {
+            interval: Duration::from_millis(interval),
+            timeout: config.heartbeat_timeout.map(Duration::from_millis).unwrap_or(Duration::from_millis(interval) * HEARTBEAT_TIMEOUT_INTERVALS)
+        })
     }
+}
 
-    res
+/// Running namespace: clients loop with routing tables, optional journal and counters shared by routers of namespace clients.
+#[derive(Clone)]
+pub(crate) struct Namespace {
+    pub name: String,
+    pub server_tx: UnboundedSender<ServerMsg>,
+    access_keys: Option<Vec<String>>,
+    routes: Routes,
+    journal: Option<Journal>,
+    counters: Arc<Counters>,
+    flow_streams: FlowStreams
+}
+
+/// Receivers of flow controlled and transfer streams by sender addr hash and stream id, credit and transfer ack frames for the stream are routed only from them
+type FlowStreams = Arc<Mutex<HashMap<(u64, u64), Vec<u64>>>>;
+
+impl Namespace {
+    /// Starts clients loop and journal of the namespace. Must be called inside tokio runtime.
+    /// Pings are sent to namespace clients if heartbeat is set.
+    pub fn start(config: NamespaceConfig, subscribes: Subscribes, hash_keys: Option<(u64, u64)>, heartbeat: Option<HeartbeatConfig>) -> Result<Namespace, ProcessError> {
+        let (server_tx, server_rx) = mpsc::unbounded_channel();
+        let routes = Routes::new(subscribes, hash_keys)?;
+        let counters = Arc::new(Counters::default());
+
+        let journal = match config.log {
+            Some(log_config) => Some(Journal::start(log_config, server_tx.clone())?),
+            None => None
+        };
+
+        tokio::spawn(clients_loop(config.name.clone(), server_rx, server_tx.downgrade(), routes.clone(), counters.clone(), heartbeat));
+
+        if let Some(heartbeat) = heartbeat {
+            let server_tx = server_tx.clone();
+
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(heartbeat.interval).await;
+
+                    if server_tx.send(ServerMsg::Heartbeat).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        info!("Namespace {} started", config.name);
+
+        Ok(Namespace {
+            name: config.name,
+            server_tx,
+            access_keys: config.access_keys,
+            routes,
+            journal,
+            counters,
+            flow_streams: Arc::new(Mutex::new(HashMap::new()))
+        })
+    }
+    pub fn check_access_key(&self, access_key: &str) -> Result<(), ProcessError> {
+        match &self.access_keys {
+            Some(access_keys) if !access_keys.iter().any(|a| a == access_key) => Err(ProcessError::NamespaceAccessDenied(self.name.clone())),
+            _ => Ok(())
+        }
+    }
+    pub fn get_addr_hash(&self, addr: &str) -> u64 {
+        self.routes.get_addr_hash(addr)
+    }
+}
+
+/// Routing tables of the server. Events and rpc requests are routed by full key from msg meta, so colliding key hashes can't mix traffic.
+/// Addrs are replaced with hashes calculated with server hash keys, they are not related to addr hashes used by clients in frames.
+#[derive(Clone)]
+pub(crate) struct Routes {
+    hash_keys: (u64, u64),
+    /// Subscribed addrs by addr hash, used for collision checks
+    addrs: HashMap<u64, String>,
+    event_subscribes: HashMap<Key, Vec<u64>>,
+    rpc_subscribes: HashMap<Key, Vec<u64>>
+}
+
+impl Routes {
+    /// Converts subscribes to routing tables. Random hash keys are used if not provided.
+    /// Fails if two subscribed addrs have the same hash.
+    pub fn new(subscribes: Subscribes, hash_keys: Option<(u64, u64)>) -> Result<Routes, ProcessError> {
+        let (event_subscribes, rpc_subscribes) = match subscribes {
+            Subscribes::ByAddr(_, _) => subscribes.traverse_to_keys(),
+            Subscribes::ByKey(event_subscribes, rpc_subscribes) => (event_subscribes, rpc_subscribes)
+        };
+
+        let mut routes = Routes {
+            hash_keys: hash_keys.unwrap_or_else(|| (random(), random())),
+            addrs: HashMap::new(),
+            event_subscribes: HashMap::new(),
+            rpc_subscribes: HashMap::new()
+        };
+
+        routes.event_subscribes = routes.hash_subscribes(event_subscribes)?;
+        routes.rpc_subscribes = routes.hash_subscribes(rpc_subscribes)?;
+
+        Ok(routes)
+    }
+    pub fn get_addr_hash(&self, addr: &str) -> u64 {
+        let mut hasher = SipHasher24::new_with_keys(self.hash_keys.0, self.hash_keys.1);
+
+        hasher.write(addr.as_bytes());
+
+        hasher.finish()
+    }
+    /// Checks that addr hash is not taken by other subscribed addr.
+    pub fn check_addr(&self, addr: &str, addr_hash: u64) -> Result<(), ProcessError> {
+        match self.addrs.get(&addr_hash) {
+            Some(registered) if registered != addr => Err(ProcessError::AddrHashCollision(registered.clone(), addr.to_owned())),
+            _ => Ok(())
+        }
+    }
+    fn hash_subscribes(&mut self, subscribes: HashMap<Key, Vec<String>>) -> Result<HashMap<Key, Vec<u64>>, ProcessError> {
+        let mut res = HashMap::new();
+
+        for (key, addrs) in subscribes {
+            let mut targets = vec![];
+
+            for addr in addrs {
+                let addr_hash = self.get_addr_hash(&addr);
+
+                self.check_addr(&addr, addr_hash)?;
+                targets.push(addr_hash);
+                self.addrs.insert(addr_hash, addr);
+            }
+
+            res.insert(key, targets);
+        }
+
+        Ok(res)
+    }
 }
 
 /// Starts the server based on provided ServerConfig struct. Creates new runtime and blocks.
@@ -30,124 +223,416 @@ pub fn start(config: ServerConfig, subscribes: Subscribes) {
 }
 
 /// Future for new server start based on provided ServerConfig struct, in case you want to create runtime by yourself.
+/// Subscribes are used for default namespace.
 pub async fn start_future(config: ServerConfig, subscribes: Subscribes) -> Result<(), ProcessError> {
+    let mut namespaces_subscribes = HashMap::new();
+
+    namespaces_subscribes.insert(DEFAULT_NAMESPACE.to_owned(), subscribes);
+
+    start_namespaces_future(config, namespaces_subscribes).await
+}
+
+/// Starts the server with subscribes for each namespace. Creates new runtime and blocks.
+pub fn start_with_namespaces(config: ServerConfig, subscribes: HashMap<String, Subscribes>) {
+    let rt = Runtime::new().expect("failed to create runtime"); 
+    let _ = rt.block_on(start_namespaces_future(config, subscribes));
+}
+
+/// Future for new server start with subscribes for each namespace. Namespaces from config without subscribes are started too.
+/// Clients select namespace on authorization, clients without selected namespace are connected to default namespace.
+pub async fn start_namespaces_future(config: ServerConfig, mut subscribes: HashMap<String, Subscribes>) -> Result<(), ProcessError> {
     let listener = TcpListener::bind(config.host.clone()).await?;
-    let (server_tx, mut server_rx) = mpsc::unbounded_channel();
-
-    tokio::spawn(async move {        
-        let mut clients = HashMap::new();
-
-        loop {
-            let msg = server_rx.recv().await.expect("ServerMsg receive failed");
-            match msg {
-                ServerMsg::AddClient(addr, net_addr, tx) => {
-                    let client = Client {
-                        addr,
-                        net_addr,
-                        tx
-                    };
-                    clients.insert(get_addr_hash(&client.addr), client);
+
+    for namespace_config in config.namespaces.iter().flatten() {
+        subscribes.entry(namespace_config.name.clone()).or_insert_with(|| Subscribes::ByKey(HashMap::new(), HashMap::new()));
+    }
+
+    let mut namespaces = HashMap::new();
+    let heartbeat = HeartbeatConfig::from_config(&config);
+
+    for (name, subscribes) in subscribes {
+        let namespace = Namespace::start(get_namespace_config(&config, &name), subscribes, config.hash_keys, heartbeat)?;
+
+        namespaces.insert(name, namespace);
+    }
+
+    let namespaces = Arc::new(namespaces);
+    let local_capabilities = match config.frame_checksums.unwrap_or(true) {
+        true => Capabilities::SUPPORTED,
+        false => Capabilities::SUPPORTED.difference(Capabilities::CHECKSUM)
+    };
+    let local_handshake = Handshake::new(config.max_frame_payload_size.unwrap_or(MAX_NEGOTIATED_FRAME_PAYLOAD_SIZE as u16)).with_capabilities(local_capabilities);
+
+    info!("Started on {}, protocol version {}", config.host, PROTOCOL_VERSION);
+
+    loop {                
+        let (stream, client_net_addr) = listener.accept().await?;
+
+        info!("New connection from {}", client_net_addr);
+
+        // connections are accepted concurrently, so peers which don't complete handshake don't block other clients
+        tokio::spawn(accept_connection(stream, client_net_addr, local_handshake.clone(), namespaces.clone(), heartbeat));
+    }
+}
+
+/// Makes handshake and authorization on new client connection, connection can be any stream (TCP, TLS, Unix socket).
+/// Clients connect twice, connection role from auth message tells if frames are written by the client or sent to the client.
+async fn accept_connection<S>(stream: S, client_net_addr: SocketAddr, local_handshake: Handshake, namespaces: Arc<HashMap<String, Namespace>>, heartbeat: Option<HeartbeatConfig>) where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    let AuthorizedConnection { mut frames, addr, namespace, role } = match timeout(Duration::from_millis(AUTH_TIMEOUT_MS), authorize_connection(stream, client_net_addr, &local_handshake, &namespaces)).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(e)) => {
+            error!("Failed to accept connection from {}, {:?}", client_net_addr, e);
+            return;
+        }
+        Err(_) => {
+            warn!("Connection from {} is not authorized in {} ms, closing", client_net_addr, AUTH_TIMEOUT_MS);
+            return;
+        }
+    };
+
+    info!("Stream from {} authorized as {} in namespace {}, connection role {:?}", client_net_addr, addr, namespace.name, role);
+
+    let server_tx = namespace.server_tx.clone();
+
+    match role {
+        ConnectionRole::Write => {
+            let router = Router::new(&addr, &namespace);
+            let idle_timeout = heartbeat.map(|heartbeat| heartbeat.timeout);
+
+            match process_write_stream(&mut frames, addr.clone(), router, client_net_addr, idle_timeout, server_tx).await {
+                Ok(()) => info!("Write process ended, client addr {}", addr),
+                Err(e) => {
+                    match e {
+                        ProcessError::StreamClosed => info!("Write process ended: stream closed, client addr {}", addr),
+                        _ => error!("Write process ended with error, client addr {}, {:?}", addr, e)
+                    }
                 }
-                ServerMsg::Send(addr_hash, frame) => {
-                    match clients.get_mut(&addr_hash) {
-                        Some(client) => {
-                            match client.tx.send(WriteMsg::Frame(frame)) {
-                                Ok(()) => {}                             
-                                Err(msg) => panic!("ServerMsg::Send processing failed - send error, client addr hash {}", addr_hash)
+            }
+        }
+        ConnectionRole::Read => {
+            let res = process_read_stream(addr.clone(), frames, client_net_addr, server_tx).await;
+            error!("{} read process ended, {:?}", addr, res);
+        }
+    }
+}
+
+/// Makes handshake and authorization of the connection.
+async fn authorize_connection<S>(mut stream: S, client_net_addr: SocketAddr, local_handshake: &Handshake, namespaces: &HashMap<String, Namespace>) -> Result<AuthorizedConnection<S>, ProcessError> where S: AsyncRead + AsyncWrite + Unpin {
+    // incompatible peers get server handshake too, so they are able to report the reason
+    let agreed = handshake(&mut stream, local_handshake).await?;
+
+    debug!("Handshake with {} completed, protocol version {}, capabilities {:?}", client_net_addr, agreed.version, agreed.capabilities);
+
+    // frames bigger than agreed payload size fail the connection
+    let mut frames = Framed::new(stream, FrameCodec::new(agreed.capabilities.contains(Capabilities::CHECKSUM), agreed.max_frame_payload_size as usize));
+    let (addr, namespace, role) = auth_stream(&mut frames, client_net_addr, namespaces).await?;
+
+    Ok(AuthorizedConnection {
+        frames,
+        addr,
+        namespace,
+        role
+    })
+}
+
+/// Keeps connected clients and delivers frames routed to them. Retained events are kept here too and delivered to subscribed clients on connect.
+/// Rpc requesters are registered by correlation id, so responses are delivered only to the client which sent the request.
+/// Requester gets timeout error if response is not started before request deadline (or rpc timeout if deadline is not set).
+/// Network clients are pinged on heartbeat and removed if they don't answer during heartbeat timeout.
+async fn clients_loop(namespace: String, mut server_rx: UnboundedReceiver<ServerMsg>, timer_tx: WeakUnboundedSender<ServerMsg>, routes: Routes, counters: Arc<Counters>, heartbeat_config: Option<HeartbeatConfig>) {
+    let mut clients: HashMap<u64, Client> = HashMap::new();
+    let mut retained: HashMap<Key, Vec<Frame>> = HashMap::new();
+    let mut rpcs: HashMap<Uuid, PendingRpc> = HashMap::new();
+    let heartbeat = Heartbeat::new();
+
+    loop {
+        let msg = match server_rx.recv().await {
+            Some(msg) => msg,
+            None => break
+        };
+        match msg {
+            ServerMsg::AddClient(addr, net_addr, tx) => {
+                let addr_hash = routes.get_addr_hash(&addr);
+
+                let check = match clients.get(&addr_hash) {
+                    Some(client) if client.addr != addr => Err(ProcessError::AddrHashCollision(client.addr.clone(), addr.clone())),
+                    _ => routes.check_addr(&addr, addr_hash)
+                };
+
+                if let Err(e) = check {
+                    error!("Client {} rejected, {:?}", addr, e);
+                    continue;
+                }
+
+                let client = Client {
+                    addr,
+                    net_addr,
+                    tx,
+                    last_pong: Instant::now(),
+                    rtt: None
+                };
+
+                for (key, frames) in &retained {
+                    match routes.event_subscribes.get(key) {
+                        Some(targets) if targets.contains(&addr_hash) => {
+                            debug!("Sending retained event to {}, key {:?}", client.addr, key);
+
+                            for frame in frames {
+                                if client.tx.send(WriteMsg::Frame(frame.clone())).is_err() {
+                                    error!("Retained event send failed, client addr {}, key {:?}", client.addr, key);
+                                    break;
+                                }
                             }
                         }
-                        None => error!("No client with addr hash {} for sending frame, stream id {}, key hash {}", addr_hash, frame.stream_id, frame.key_hash)
+                        _ => {}
                     }
-                }                
-                ServerMsg::RemoveClient(addr_hash) => {
-                    let _ = clients.remove(&addr_hash);
                 }
-            }     
-        }
-    });
 
-    let (event_subscribes, rpc_subscribes) = match subscribes {
-        Subscribes::ByAddr(_, _) => subscribes.traverse_to_keys(),
-        Subscribes::ByKey(event_subscribes, rpc_subscribes) => (event_subscribes, rpc_subscribes)
-    };
+                clients.insert(addr_hash, client);
+            }
+            ServerMsg::Send(addr_hash, frame) => {
+                match clients.get_mut(&addr_hash) {
+                    Some(client) => {
+                        match client.tx.send(WriteMsg::Frame(frame)) {
+                            Ok(()) => {}
+                            Err(_) => panic!("ServerMsg::Send processing failed - send error, client addr hash {}", addr_hash)
+                        }
+                    }
+                    None => error!("No client with addr hash {} for sending frame, stream id {}, key hash {}", addr_hash, frame.stream_id, frame.key_hash)
+                }
+            }
+            ServerMsg::GetStats(addr_hash, msg_meta) => {
+                let stats = NamespaceStats {
+                    namespace: namespace.clone(),
+                    clients: clients.len() as u64,
+                    messages: counters.messages.load(Ordering::Relaxed),
+                    dropped: counters.dropped.load(Ordering::Relaxed),
+                    frames: counters.frames.load(Ordering::Relaxed),
+                    bytes: counters.bytes.load(Ordering::Relaxed),
+                    retained: retained.len() as u64,
+                    pending_rpcs: rpcs.len() as u64,
+                    rtts: clients.values()
+                        .filter_map(|client| client.rtt.map(|rtt| (client.addr.clone(), rtt.as_micros() as u64)))
+                        .collect()
+                };
 
-    let mut client_states = HashMap::new();
-    let mut key_hasher = get_key_hasher();
+                let frames = serde_json::to_value(stats)
+                    .map_err(ProcessError::from)
+                    .and_then(|payload| get_rpc_response_frames(STATS_ADDR, &msg_meta, payload, vec![], vec![], RpcResult::Ok));
 
-    let event_subscribes = to_hashed_subscribes(&mut key_hasher, event_subscribes);
-    let rpc_subscribes = to_hashed_subscribes(&mut key_hasher, rpc_subscribes);
+                match (frames, clients.get(&addr_hash)) {
+                    (Ok(frames), Some(client)) => {
+                        for frame in frames {
+                            if client.tx.send(WriteMsg::Frame(frame)).is_err() {
+                                error!("Stats response send failed, client addr {}", client.addr);
+                                break;
+                            }
+                        }
+                    }
+                    (Err(e), _) => error!("Failed to create stats response, namespace {}, {:?}", namespace, e),
+                    (_, None) => error!("No client with addr hash {} for sending stats response", addr_hash)
+                }
+            }
+            ServerMsg::RemoveClient(addr_hash) => {
+                let _ = clients.remove(&addr_hash);
+                rpcs.retain(|_, rpc| rpc.addr_hash != addr_hash);
+            }
+            ServerMsg::AddRpc(addr_hash, msg_meta, targets) => {
+                let correlation_id = msg_meta.correlation_id;
+                let rpc_timeout = get_time_left(msg_meta.deadline).unwrap_or(Duration::from_millis(RPC_TIMEOUT_MS_AMOUNT));
+                let timer_tx = timer_tx.clone();
 
-    info!("Started on {}", config.host);
+                tokio::spawn(async move {
+                    tokio::time::sleep(rpc_timeout).await;
 
-    loop {                
-        let (mut stream, client_net_addr) = listener.accept().await?;
+                    if let Some(timer_tx) = timer_tx.upgrade() {
+                        let _ = timer_tx.send(ServerMsg::RpcTimeout(correlation_id));
+                    }
+                });
 
-        info!("New connection from {}", client_net_addr);
+                rpcs.insert(correlation_id, PendingRpc {
+                    addr_hash,
+                    msg_meta,
+                    targets,
+                    responding: false
+                });
+            }
+            ServerMsg::CancelRpc(addr_hash, correlation_id, frame) => {
+                // response is not started yet, rpc is removed if it is cancelled by its requester
+                let rpc = match rpcs.get(&correlation_id) {
+                    Some(rpc) if rpc.addr_hash == addr_hash && !rpc.responding => rpcs.remove(&correlation_id).expect("pending rpc is checked"),
+                    _ => {
+                        debug!("Rpc cancelled after response or timeout, correlation id {}", correlation_id);
+                        continue;
+                    }
+                };
+
+                debug!("Rpc cancelled by requester, {}", rpc.msg_meta.display());
+
+                send_rpc_cancel(&clients, &rpc.targets, frame);
+            }
+            ServerMsg::RpcTimeout(correlation_id) => {
+                // started response is delivered until its end
+                let rpc = match rpcs.get(&correlation_id) {
+                    Some(rpc) if !rpc.responding => rpcs.remove(&correlation_id).expect("pending rpc is checked"),
+                    _ => continue
+                };
 
-        let config = config.clone();
-        let server_tx = server_tx.clone();
-        let mut state = State::new();
+                warn!("Rpc timeout, {}", rpc.msg_meta.display());
 
-        match auth_tcp_stream(&mut stream, &mut state, client_net_addr, &config).await {
-            Ok(addr) => {
-                info!("Stream from {} authorized as {}", client_net_addr, addr);
-                
-                if !client_states.contains_key(&addr) {
-                    client_states.insert(addr.clone(), ClientState::new());
+                send_rpc_cancel(&clients, &rpc.targets, Frame::new_rpc_cancel(0, correlation_id));
+
+                let frames = get_rpc_error_frames(SERVER_ADDR, &rpc.msg_meta, RemoteError::new(ErrorCode::Timeout, "Rpc response is not received in time"));
+
+                match (frames, clients.get(&rpc.addr_hash)) {
+                    (Ok(frames), Some(client)) => {
+                        for frame in frames {
+                            if client.tx.send(WriteMsg::Frame(frame)).is_err() {
+                                error!("Rpc timeout response send failed, client addr {}", client.addr);
+                                break;
+                            }
+                        }
+                    }
+                    (Err(e), _) => error!("Failed to create rpc timeout response, correlation id {}, {:?}", correlation_id, e),
+                    (_, None) => debug!("Rpc requester is disconnected before timeout, correlation id {}", correlation_id)
                 }
+            }
+            ServerMsg::SendRpcResponse(correlation_id, frame) => {
+                let is_end = matches!(frame.get_frame_type(), Ok(FrameType::End) | Ok(FrameType::Cancel));
 
-                match client_states.get_mut(&addr) {
-                    Some(client_state) => {
-                        
-                        if !client_state.has_writer {
-                            client_state.has_writer = true;
-
-                            let event_subscribes = event_subscribes.clone();
-                            let rpc_subscribes = rpc_subscribes.clone();
-
-                            tokio::spawn(async move {                                
-                                match process_write_tcp_stream(&mut stream, &mut state, addr.clone(), event_subscribes, rpc_subscribes, client_net_addr, server_tx).await {
-									Ok(()) => info!("Write process ended, client addr {}", addr),
-									Err(e) => {
-										match e {
-											ProcessError::StreamClosed => info!("Write process ended: stream closed, client addr {}", addr),
-											_ => error!("Write process ended with error, client addr {}, {:?}", addr, e)
-										}
-									}
-								}                        
-                            });
-
-                        } else {
-                            client_state.has_writer = false;
-
-                            tokio::spawn(async move {            
-                                let res = process_read_tcp_stream(addr.clone(), stream, client_net_addr, server_tx).await;
-                                error!("{} read process ended, {:?}", addr, res);
-                            });
+                let addr_hash = match rpcs.get_mut(&correlation_id) {
+                    Some(rpc) => {
+                        rpc.responding = true;
+                        rpc.addr_hash
+                    }
+                    None => {
+                        warn!("No rpc requester for correlation id {}, dropping response frame, stream id {}", correlation_id, frame.stream_id);
+                        continue;
+                    }
+                };
+
+                if is_end {
+                    let _ = rpcs.remove(&correlation_id);
+                }
+
+                match clients.get_mut(&addr_hash) {
+                    Some(client) => {
+                        if client.tx.send(WriteMsg::Frame(frame)).is_err() {
+                            error!("Rpc response send failed, client addr {}, correlation id {}", client.addr, correlation_id);
                         }
-                        
                     }
-                    None => error!("failed to get client state for {} stream from {}", addr, client_net_addr)
-                }                
+                    None => error!("No client with addr hash {} for sending rpc response, correlation id {}", addr_hash, correlation_id)
+                }
+            }
+            ServerMsg::Retain(key, frames) => {
+                debug!("Retained event kept, key {:?}", key);
+                retained.insert(key, frames);
+            }
+            ServerMsg::ClearRetained(key) => {
+                debug!("Retained event cleared, key {:?}", key);
+                let _ = retained.remove(&key);
+            }
+            ServerMsg::Heartbeat => {
+                let heartbeat_timeout = match heartbeat_config {
+                    Some(heartbeat_config) => heartbeat_config.timeout,
+                    None => continue
+                };
+
+                // in-process clients are not pinged
+                let dead_clients: Vec<u64> = clients.iter()
+                    .filter(|(_, client)| client.net_addr.is_some() && client.last_pong.elapsed() > heartbeat_timeout)
+                    .map(|(addr_hash, _)| *addr_hash)
+                    .collect();
+
+                for addr_hash in dead_clients {
+                    if let Some(client) = clients.remove(&addr_hash) {
+                        warn!("Client {} didn't answer pings, disconnecting, namespace {}", client.addr, namespace);
+                    }
+
+                    rpcs.retain(|_, rpc| rpc.addr_hash != addr_hash);
+                }
+
+                for client in clients.values().filter(|client| client.net_addr.is_some()) {
+                    if client.tx.send(WriteMsg::Frame(Frame::new_ping(heartbeat.get_timestamp()))).is_err() {
+                        error!("Ping send failed, client addr {}", client.addr);
+                    }
+                }
             }
-            Err(e) => error!("failed to authorize stream from {}, {:?}", client_net_addr, e)
-        }        
+            ServerMsg::Pong(addr_hash, timestamp) => {
+                if let Some(client) = clients.get_mut(&addr_hash) {
+                    client.last_pong = Instant::now();
+                    client.rtt = Some(Duration::from_micros(heartbeat.get_timestamp().saturating_sub(timestamp)));
+                }
+            }
+        }
     }
-}
 
-struct ClientState {
-    has_writer: bool    
+    info!("Clients loop completed, namespace {}", namespace);
 }
 
-impl ClientState {
-    pub fn new() -> ClientState {
-        ClientState {
-            has_writer: false            
+/// Sends rpc cancel frame to callees of the rpc, so they stop processing it.
+fn send_rpc_cancel(clients: &HashMap<u64, Client>, targets: &[u64], frame: Frame) {
+    for client in targets.iter().filter_map(|target| clients.get(target)) {
+        if client.tx.send(WriteMsg::Frame(frame.clone())).is_err() {
+            error!("Rpc cancel send failed, client addr {}", client.addr);
         }
     }
 }
 
-async fn auth_tcp_stream(tcp_stream: &mut TcpStream, state: &mut State, client_net_addr: SocketAddr, _config: &ServerConfig) -> Result<String, ProcessError> {    
+struct PendingRpc {
+    /// Requester addr hash
+    addr_hash: u64,
+    /// Request msg meta, it is used for timeout response
+    msg_meta: Box<MsgMeta>,
+    /// Callee addr hashes, they get rpc cancel frame when requester doesn't wait for response anymore
+    targets: Vec<u64>,
+    /// Response stream is started, request is kept until response end
+    responding: bool
+}
+
+/// Settings of namespace from config, server log settings are used for default namespace without own log.
+fn get_namespace_config(config: &ServerConfig, name: &str) -> NamespaceConfig {
+    let mut res = config.namespaces.iter().flatten()
+        .find(|namespace_config| namespace_config.name == name)
+        .cloned()
+        .unwrap_or_else(|| NamespaceConfig {
+            name: name.to_owned(),
+            access_keys: None,
+            log: None
+        });
+
+    if name == DEFAULT_NAMESPACE && res.log.is_none() {
+        res.log = config.log.clone();
+    }
+
+    res
+}
+
+#[derive(Deserialize)]
+struct AuthPayload {
+    access_key: Option<String>,
+    namespace: Option<String>,
+    connection: ConnectionRole
+}
+
+/// Role of client connection, clients connect twice: for frames written by the client and for frames sent to the client.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ConnectionRole {
+    Write,
+    Read
+}
+
+/// Client connection with completed handshake and authorization, checksums and max frame payload size agreed on handshake are set in codec.
+struct AuthorizedConnection<S> {
+    frames: Framed<S, FrameCodec>,
+    addr: String,
+    namespace: Namespace,
+    role: ConnectionRole
+}
+
+/// Reads authorization message and selects namespace for the client, returns client addr, namespace and connection role.
+async fn auth_stream<S>(frames: &mut Framed<S, FrameCodec>, client_net_addr: SocketAddr, namespaces: &HashMap<String, Namespace>) -> Result<(String, Namespace, ConnectionRole), ProcessError> where S: AsyncRead + AsyncWrite + Unpin {
     let mut stream_layout = StreamLayout {
         id: 0,
         msg_meta: vec![],
@@ -156,148 +641,441 @@ async fn auth_tcp_stream(tcp_stream: &mut TcpStream, state: &mut State, client_n
     };
 
 	loop {		
-		match state.read_frame() {
-			ReadFrameResult::NotEnoughBytesForFrame => {
-				state.read_from_tcp_stream(tcp_stream).await?
-			}
-			ReadFrameResult::NextStep => {}
-			ReadFrameResult::Frame(frame) => {
-				debug!("Auth stream frame read, frame type {}, msg type {}, stream id {}", frame.frame_type, frame.msg_type, frame.stream_id);
-
-				match frame.get_frame_type() {
-					Ok(frame_type) => {
-						match frame_type {
-							FrameType::MsgMeta | FrameType::MsgMetaEnd => {
-                                match frame.payload {
-                                    Some(payload) => {
-                                        stream_layout.msg_meta.extend_from_slice(&payload[..frame.payload_size as usize]);
-                                    }
-                                    None => {}
-                                }								
-							}
-							FrameType::Payload | FrameType::PayloadEnd => {
-                                match frame.payload {
-                                    Some(payload) => {
-                                        stream_layout.payload.extend_from_slice(&payload[..frame.payload_size as usize]);
-                                    }
-                                    None => {}
-                                }								
-							}
-							FrameType::Attachment | FrameType::AttachmentEnd => {
-                                match frame.payload {
-                                    Some(payload) => {
-                                        stream_layout.attachments_data.extend_from_slice(&payload[..frame.payload_size as usize]);
-                                    }
-                                    None => {}
-                                }								
-							}
-							FrameType::End => {								
-								break;
-							}
-						}
+		let frame = read_frame(frames, None).await?;
+
+		debug!("Auth stream frame read, frame type {}, msg type {}, stream id {}", frame.frame_type, frame.msg_type, frame.stream_id);
 
+		match frame.get_frame_type() {
+			Ok(frame_type) => {
+				match frame_type {
+					FrameType::MsgMeta | FrameType::MsgMetaEnd => {
+                        match frame.payload {
+                            Some(payload) => {
+                                stream_layout.msg_meta.extend_from_slice(&payload[..frame.payload_size as usize]);
+                            }
+                            None => {}
+                        }								
+					}
+					FrameType::Payload | FrameType::PayloadEnd => {
+                        match frame.payload {
+                            Some(payload) => {
+                                stream_layout.payload.extend_from_slice(&payload[..frame.payload_size as usize]);
+                            }
+                            None => {}
+                        }								
+					}
+					FrameType::Attachment | FrameType::AttachmentEnd => {
+                        match frame.payload {
+                            Some(payload) => {
+                                stream_layout.attachments_data.extend_from_slice(&payload[..frame.payload_size as usize]);
+                            }
+                            None => {}
+                        }								
 					}
-					Err(e) => {
-						error!("Error on auth stream read for {:?}, get frame type failed, {:?}", client_net_addr, e);
+					FrameType::End => {								
+						break;
 					}
+					FrameType::Credit | FrameType::TransferAck | FrameType::Ping | FrameType::Pong | FrameType::RpcCancel => {}
+					FrameType::Cancel => return Err(ProcessError::StreamCancelled(frame.get_cancel_reason().unwrap_or(CancelReason::SenderFailed)))
 				}
+
+			}
+			Err(e) => {
+				error!("Error on auth stream read for {:?}, get frame type failed, {:?}", client_net_addr, e);
 			}
 		}
 	}
        
     let msg_meta: MsgMeta = from_slice(&stream_layout.msg_meta)?;
+    let auth_payload: AuthPayload = from_slice(&stream_layout.payload)?;
+    let name = auth_payload.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE);
+    let namespace = namespaces.get(name).ok_or_else(|| ProcessError::NamespaceNotFound(name.to_owned()))?;
+
+    namespace.check_access_key(auth_payload.access_key.as_deref().unwrap_or_default())?;
 
-    Ok(msg_meta.tx)
+    Ok((msg_meta.tx, namespace.clone(), auth_payload.connection))
 }
 
 
-async fn process_read_tcp_stream(addr: String, mut tcp_stream: TcpStream, client_net_addr: SocketAddr, server_tx: UnboundedSender<ServerMsg>) -> Result<(), ProcessError> {
-    let (client_tx, client_rx) = mpsc::unbounded_channel();
+/// Writes frames routed to the client, checksums are written if they are agreed for the connection.
+async fn process_read_stream<S>(addr: String, mut frames: Framed<S, FrameCodec>, client_net_addr: SocketAddr, server_tx: UnboundedSender<ServerMsg>) -> Result<(), ProcessError> where S: AsyncRead + AsyncWrite + Unpin {
+    let (client_tx, mut client_rx) = mpsc::unbounded_channel();
 
-    server_tx.send(ServerMsg::AddClient(addr, client_net_addr, client_tx))?;    
+    server_tx.send(ServerMsg::AddClient(addr, Some(client_net_addr), client_tx))?;    
 
-    write_loop(client_rx, &mut tcp_stream).await
+    write_loop(&mut client_rx, &mut frames).await
 }
 
-async fn process_write_tcp_stream(tcp_stream: &mut TcpStream, state: &mut State, addr: String, event_subscribes: HashMap<u64, Vec<u64>>, rpc_subscribes: HashMap<u64, Vec<u64>>, _client_net_addr: SocketAddr, server_tx: UnboundedSender<ServerMsg>) -> Result<(), ProcessError> {
+/// Routes frames written by the client. Connection is closed if idle timeout is set and nothing is read from the client during it.
+async fn process_write_stream<S>(frames: &mut Framed<S, FrameCodec>, _addr: String, mut router: Router, _client_net_addr: SocketAddr, idle_timeout: Option<Duration>, server_tx: UnboundedSender<ServerMsg>) -> Result<(), ProcessError> where S: AsyncRead + AsyncWrite + Unpin {
+	let res = route_stream(frames, &mut router, idle_timeout, &server_tx).await;
+
+	router.cancel_streams(CancelReason::SenderDisconnected, &server_tx);
+
+	res
+}
+
+async fn route_stream<S>(frames: &mut Framed<S, FrameCodec>, router: &mut Router, idle_timeout: Option<Duration>, server_tx: &UnboundedSender<ServerMsg>) -> Result<(), ProcessError> where S: AsyncRead + AsyncWrite + Unpin {
 	loop {
-		match state.read_frame() {
-			ReadFrameResult::NotEnoughBytesForFrame => {
-				state.read_from_tcp_stream(tcp_stream).await?;
-			}
-			ReadFrameResult::NextStep => {}
-			ReadFrameResult::Frame(frame) => {
-				debug!("Main stream frame read, frame type {}, msg type {}, stream id {}", frame.frame_type, frame.msg_type, frame.stream_id);
-
-				match frame.get_msg_type()? {
-					MsgType::Event => {
-                        match event_subscribes.get(&frame.key_hash) {
-                            Some(targets) => {
-                                match targets.len() {
-                                    0 => {
-                                        warn!("Subscribes empty for key hash {}, msg_type {:?}", frame.key_hash, frame.get_msg_type())
-                                    }
-                                    1 => {
-                                        let target = targets[0].clone();
-        
-                                        debug!("Sending frame to {}", target);
-                                        server_tx.send(ServerMsg::Send(target, frame))?;
-                                    }
-                                    _ => {
-                                        let index = targets.len() - 1;
-        
-                                        for target in targets.iter().take(index) {     
-                                            debug!("Sending frame to {}", target);
-                                            server_tx.send(ServerMsg::Send(target.clone(), frame.clone()))?;
-                                        }
-        
-                                        let target = &targets[index];
-        
-                                        debug!("Sending frame to {}", target);
-                                        server_tx.send(ServerMsg::Send(target.clone(), frame))?;
-                                    }
-                                }
-                            }
-                            None => warn!("No subscribes found for key hash {}, msg_type {:?}", frame.key_hash, frame.get_msg_type())
-                        }
+		let frame = read_frame(frames, idle_timeout).await?;
+
+		debug!("Main stream frame read, frame type {}, msg type {}, stream id {}", frame.frame_type, frame.msg_type, frame.stream_id);
+
+		router.route(frame, server_tx)?;
+	}
+}
+
+/// Routing state of single client connection. Frames are routed per stream: msg meta frames are kept until msg meta is complete,
+/// after that whole stream is either forwarded to subscribers of the key from msg meta or dropped (for example, if message deadline is expired).
+/// Streams of retained and logged events are also kept until stream end, journal rpc requests are processed by the server itself.
+pub(crate) struct Router {
+    /// Hash of the client addr, rpc responses for the client requests are routed to it
+    addr_hash: u64,
+    routes: Routes,
+    journal: Option<Journal>,
+    counters: Arc<Counters>,
+    streams: HashMap<u64, StreamRoute>,
+    flow_streams: FlowStreams
+}
+
+enum StreamRoute {
+    /// Msg meta is not complete yet, frames are waiting for routing decision
+    Pending(Vec<Frame>),
+    /// Frames are sent to target addr hashes
+    Forward(Vec<u64>),
+    /// Frames are sent to rpc requester with correlation id
+    Respond(Uuid),
+    /// Frames are kept until stream end
+    Keep(KeptStream),
+    Drop
+}
+
+struct KeptStream {
+    key: Key,
+    frames: Vec<Frame>,
+    /// Frames are forwarded to these addr hashes too
+    targets: Vec<u64>,
+    retain: bool,
+    append: bool,
+    journal_rpc: bool
+}
+
+impl Router {
+    pub fn new(addr: &str, namespace: &Namespace) -> Router {
+        Router {
+            addr_hash: namespace.get_addr_hash(addr),
+            routes: namespace.routes.clone(),
+            journal: namespace.journal.clone(),
+            counters: namespace.counters.clone(),
+            streams: HashMap::new(),
+            flow_streams: namespace.flow_streams.clone()
+        }
+    }
+    /// Routes frame written by client. Stream routing state is removed on stream end frame.
+    pub fn route(&mut self, frame: Frame, server_tx: &UnboundedSender<ServerMsg>) -> Result<(), ProcessError> {
+        let stream_id = frame.stream_id;
+        let frame_type = frame.get_frame_type()?;
+
+        // heartbeat frames are not counted as client traffic
+        match frame_type {
+            FrameType::Ping => return server_tx.send(ServerMsg::Send(self.addr_hash, frame.new_pong())).map_err(ProcessError::from),
+            FrameType::Pong => {
+                if let Some(timestamp) = frame.get_heartbeat_timestamp() {
+                    server_tx.send(ServerMsg::Pong(self.addr_hash, timestamp))?;
+                }
+
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        self.counters.frames.fetch_add(1, Ordering::Relaxed);
+        self.counters.bytes.fetch_add(frame.payload_size as u64, Ordering::Relaxed);
+
+        match frame_type {
+            FrameType::Credit => return self.route_to_stream_sender(frame, CREDIT_PAYLOAD_SIZE, server_tx),
+            FrameType::TransferAck => return self.route_to_stream_sender(frame, TRANSFER_ACK_PAYLOAD_SIZE, server_tx),
+            FrameType::RpcCancel => {
+                match frame.get_rpc_cancel_correlation_id() {
+                    Some(correlation_id) => server_tx.send(ServerMsg::CancelRpc(self.addr_hash, correlation_id, frame))?,
+                    None => {
+                        warn!("Malformed rpc cancel frame for stream {}, dropping", stream_id);
+                        self.counters.dropped.fetch_add(1, Ordering::Relaxed);
                     }
-					MsgType::RpcRequest => {
-                        match rpc_subscribes.get(&frame.key_hash) {
-                            Some(targets) => {
-                                match targets.len() {
-                                    0 => {
-                                        warn!("Subscribes empty for key hash {}, msg_type {:?}", frame.key_hash, frame.get_msg_type())
-                                    }
-                                    1 => {
-                                        let target = targets[0].clone();
-        
-                                        debug!("Sending frame to {}", target);
-                                        server_tx.send(ServerMsg::Send(target, frame))?;
-                                    }
-                                    _ => {
-                                        let index = targets.len() - 1;
-        
-                                        for target in targets.iter().take(index) {     
-                                            debug!("Sending frame to {}", target);
-                                            server_tx.send(ServerMsg::Send(target.clone(), frame.clone()))?;
-                                        }
-        
-                                        let target = &targets[index];
-        
-                                        debug!("Sending frame to {}", target);
-                                        server_tx.send(ServerMsg::Send(target.clone(), frame))?;
-                                    }
-                                }
+                }
+
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let stream_route = match self.streams.remove(&stream_id) {
+            Some(StreamRoute::Pending(mut frames)) => {
+                frames.push(frame);
+
+                match frame_type {
+                    FrameType::MsgMeta => StreamRoute::Pending(frames),
+                    // nothing is forwarded before msg meta is complete
+                    FrameType::Cancel => StreamRoute::Drop,
+                    _ => self.complete_msg_meta(frames, server_tx)?
+                }
+            }
+            Some(StreamRoute::Forward(targets)) => {
+                send_to_targets(frame, &targets, server_tx)?;
+
+                StreamRoute::Forward(targets)
+            }
+            Some(StreamRoute::Respond(correlation_id)) => {
+                server_tx.send(ServerMsg::SendRpcResponse(correlation_id, frame))?;
+
+                StreamRoute::Respond(correlation_id)
+            }
+            Some(StreamRoute::Keep(mut kept)) => {
+                send_to_targets(frame.clone(), &kept.targets, server_tx)?;
+                kept.frames.push(frame);
+
+                StreamRoute::Keep(kept)
+            }
+            Some(StreamRoute::Drop) => StreamRoute::Drop,
+            None => {
+                match frame_type {
+                    FrameType::MsgMeta => StreamRoute::Pending(vec![frame]),
+                    FrameType::MsgMetaEnd => self.complete_msg_meta(vec![frame], server_tx)?,
+                    FrameType::Cancel => StreamRoute::Drop,
+                    _ => {
+                        warn!("Stream {} started without msg meta, dropping", stream_id);
+                        self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+
+                        StreamRoute::Drop
+                    }
+                }
+            }
+        };
+
+        match (frame_type, stream_route) {
+            (FrameType::End, StreamRoute::Keep(kept)) => {
+                self.remove_flow_stream(stream_id);
+                self.complete_kept_stream(kept, server_tx)?
+            }
+            // cancelled stream is forwarded to the same targets as its other frames, kept frames are discarded
+            (FrameType::End, _) | (FrameType::Cancel, _) => self.remove_flow_stream(stream_id),
+            (_, stream_route) => {
+                self.streams.insert(stream_id, stream_route);
+            }
+        }
+
+        Ok(())
+    }
+    /// Sends cancel frames for streams which are not completed by the client, it is used when client is disconnected.
+    pub fn cancel_streams(&mut self, reason: CancelReason, server_tx: &UnboundedSender<ServerMsg>) {
+        let streams: Vec<_> = self.streams.drain().collect();
+
+        for (stream_id, stream_route) in streams {
+            let frame = Frame::new_cancel(0, 0, stream_id, self.addr_hash, reason);
+
+            self.remove_flow_stream(stream_id);
+
+            let res = match stream_route {
+                StreamRoute::Forward(targets) => send_to_targets(frame, &targets, server_tx),
+                StreamRoute::Keep(kept) => send_to_targets(frame, &kept.targets, server_tx),
+                StreamRoute::Respond(correlation_id) => server_tx.send(ServerMsg::SendRpcResponse(correlation_id, frame)).map_err(ProcessError::from),
+                StreamRoute::Pending(_) | StreamRoute::Drop => Ok(())
+            };
+
+            match res {
+                Ok(()) => debug!("Stream {} cancelled, {:?}", stream_id, reason),
+                Err(e) => warn!("Failed to cancel stream {}, {:?}", stream_id, e)
+            }
+        }
+    }
+    /// Credit and transfer ack frames are not part of the stream they are written for, they are sent to the stream sender addr carried in the payload after addr_offset.
+    /// Frames are routed only if the client is a receiver of the stream.
+    fn route_to_stream_sender(&self, frame: Frame, addr_offset: usize, server_tx: &UnboundedSender<ServerMsg>) -> Result<(), ProcessError> {
+        let addr = match &frame.payload {
+            Some(payload) if frame.payload_size as usize > addr_offset => String::from_utf8_lossy(&payload[addr_offset..frame.payload_size as usize]).into_owned(),
+            _ => {
+                warn!("Malformed frame of type {} for stream {}, dropping", frame.frame_type, frame.stream_id);
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+
+                return Ok(());
+            }
+        };
+
+        let sender_hash = self.routes.get_addr_hash(&addr);
+        let is_receiver = match self.flow_streams.lock().expect("Flow streams lock is poisoned").get(&(sender_hash, frame.stream_id)) {
+            Some(receivers) => receivers.contains(&self.addr_hash),
+            None => false
+        };
+
+        if !is_receiver {
+            warn!("Frame of type {} for stream {} of {} is not written by the stream receiver, dropping", frame.frame_type, frame.stream_id, addr);
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+
+            return Ok(());
+        }
+
+        server_tx.send(ServerMsg::Send(sender_hash, frame))?;
+
+        Ok(())
+    }
+    fn remove_flow_stream(&self, stream_id: u64) {
+        self.flow_streams.lock().expect("Flow streams lock is poisoned").remove(&(self.addr_hash, stream_id));
+    }
+    /// Makes routing decision for the stream based on msg meta and routes frames if stream is forwarded.
+    fn complete_msg_meta(&self, frames: Vec<Frame>, server_tx: &UnboundedSender<ServerMsg>) -> Result<StreamRoute, ProcessError> {
+        let mut msg_meta = vec![];
+
+        for frame in &frames {
+            match (frame.get_frame_type()?, &frame.payload) {
+                (FrameType::MsgMeta, Some(payload)) | (FrameType::MsgMetaEnd, Some(payload)) => msg_meta.extend_from_slice(&payload[..frame.payload_size as usize]),
+                _ => {}
+            }
+        }
+
+        let msg_meta = match from_slice::<MsgMeta>(&msg_meta) {
+            Ok(msg_meta) => msg_meta,
+            Err(e) => {
+                warn!("Failed to deserialize msg meta for stream {}, dropping, {:?}", frames[0].stream_id, e);
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+
+                return Ok(StreamRoute::Drop);
+            }
+        };
+
+        if deadline_expired(msg_meta.deadline) {
+            warn!("Deadline expired, dropping stream {}, {}", frames[0].stream_id, msg_meta.display());
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+
+            return Ok(StreamRoute::Drop);
+        }
+
+        self.counters.messages.fetch_add(1, Ordering::Relaxed);
+
+        let mut kept = KeptStream {
+            key: msg_meta.key.clone(),
+            frames: vec![],
+            targets: vec![],
+            retain: false,
+            append: false,
+            journal_rpc: false
+        };
+
+        match msg_meta.msg_type {
+            MsgType::Event => {
+                if msg_meta.retain && msg_meta.payload_size == 0 {
+                    // empty retained event only clears kept one, subscribers are not notified
+                    server_tx.send(ServerMsg::ClearRetained(msg_meta.key))?;
+
+                    return Ok(StreamRoute::Drop);
+                }
+
+                kept.targets = self.get_targets(&self.routes.event_subscribes, &msg_meta);
+                kept.retain = msg_meta.retain;
+                kept.append = matches!(&self.journal, Some(journal) if journal.is_logged(&msg_meta.key));
+            }
+            MsgType::RpcRequest if msg_meta.key == get_stats_key() => {
+                server_tx.send(ServerMsg::GetStats(self.addr_hash, Box::new(msg_meta)))?;
+
+                return Ok(StreamRoute::Drop);
+            }
+            MsgType::RpcRequest => {
+                match &self.journal {
+                    Some(journal) if journal.is_rpc(&msg_meta.key) => kept.journal_rpc = true,
+                    _ => {
+                        kept.targets = self.get_targets(&self.routes.rpc_subscribes, &msg_meta);
+
+                        if kept.targets.is_empty() {
+                            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+
+                            for frame in get_rpc_error_frames(SERVER_ADDR, &msg_meta, RemoteError::new(ErrorCode::NoRoute, format!("No subscribes found for key {:?}", msg_meta.key)))? {
+                                server_tx.send(ServerMsg::Send(self.addr_hash, frame))?;
                             }
-                            None => warn!("No subscribes found for key hash {}, msg_type {:?}", frame.key_hash, frame.get_msg_type())
+
+                            return Ok(StreamRoute::Drop);
                         }
+
+                        server_tx.send(ServerMsg::AddRpc(self.addr_hash, Box::new(msg_meta.clone()), kept.targets.clone()))?;
                     }
-					MsgType::RpcResponse(_) => {
-                        debug!("Sending frame to source, addr hash {}", frame.source_hash);
-                        server_tx.send(ServerMsg::Send(frame.source_hash, frame))?;
-                    }
-				}
-			}
-		}
-	}
+                }
+            }
+            MsgType::RpcResponse(_) => {
+                for frame in frames {
+                    server_tx.send(ServerMsg::SendRpcResponse(msg_meta.correlation_id, frame))?;
+                }
+
+                return Ok(StreamRoute::Respond(msg_meta.correlation_id));
+            }
+        }
+
+        if msg_meta.credits.is_some() || msg_meta.transfer.is_some() {
+            self.flow_streams.lock().expect("Flow streams lock is poisoned").insert((self.addr_hash, frames[0].stream_id), kept.targets.clone());
+        }
+
+        for frame in frames.iter().cloned() {
+            send_to_targets(frame, &kept.targets, server_tx)?;
+        }
+
+        match kept.retain || kept.append || kept.journal_rpc {
+            true => {
+                kept.frames = frames;
+
+                Ok(StreamRoute::Keep(kept))
+            }
+            false => Ok(StreamRoute::Forward(kept.targets))
+        }
+    }
+    fn get_targets(&self, subscribes: &HashMap<Key, Vec<u64>>, msg_meta: &MsgMeta) -> Vec<u64> {
+        match subscribes.get(&msg_meta.key) {
+            Some(targets) if !targets.is_empty() => targets.clone(),
+            _ => {
+                warn!("No subscribes found for key {:?}, msg_type {:?}", msg_meta.key, msg_meta.msg_type);
+
+                vec![]
+            }
+        }
+    }
+    fn complete_kept_stream(&self, kept: KeptStream, server_tx: &UnboundedSender<ServerMsg>) -> Result<(), ProcessError> {
+        if let Some(journal) = &self.journal {
+            if kept.append {
+                journal.send(JournalMsg::Append(kept.key.clone(), get_frames_data(&kept.frames)?))?;
+            }
+
+            if kept.journal_rpc {
+                journal.send(JournalMsg::Rpc(self.addr_hash, get_frames_data(&kept.frames)?))?;
+            }
+        }
+
+        if kept.retain {
+            server_tx.send(ServerMsg::Retain(kept.key, kept.frames))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn send_to_targets(frame: Frame, targets: &[u64], server_tx: &UnboundedSender<ServerMsg>) -> Result<(), ProcessError> {
+    match targets.len() {
+        0 => {}
+        1 => {
+            let target = targets[0];
+
+            debug!("Sending frame to {}", target);
+            server_tx.send(ServerMsg::Send(target, frame))?;
+        }
+        _ => {
+            let index = targets.len() - 1;
+
+            for target in targets.iter().take(index) {     
+                debug!("Sending frame to {}", target);
+                server_tx.send(ServerMsg::Send(*target, frame.clone()))?;
+            }
+
+            let target = targets[index];
+
+            debug!("Sending frame to {}", target);
+            server_tx.send(ServerMsg::Send(target, frame))?;
+        }
+    }
+
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,50 @@
+use std::error::Error;
+use std::future::Future;
+use log::*;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::sync::mpsc::UnboundedReceiver;
+use sp_dto::{Message, Response};
+use crate::proto::{ClientMsg, MagicBall};
+use crate::middleware::Layer;
+
+/// Client service run with client::run or client::run_stream. Service is shared by all handlers in Arc,
+/// so it can keep state and dependencies instead of passing them with every call.
+/// Handlers are written as async fns in the implementation, their futures must be Send.
+pub trait Service: Send + Sync + 'static {
+    /// Payload of events and rpcs processed by the service
+    type Payload: Serialize + DeserializeOwned + Send + 'static;
+
+    /// Called when client is started, target config is passed (it is received from config service if "cfg_host" is set).
+    /// Connection to the server may be not established yet, messages sent here are written after connect.
+    fn on_start(&self, _config: serde_json::Value, _mb: MagicBall) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+    /// Called for every received event with MagicBall carrying the event deadline.
+    fn on_event(&self, _mb: MagicBall, msg: Message<Self::Payload>) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send {
+        async move {
+            debug!("Event is not handled by the service, {}", msg.meta.display());
+
+            Ok(())
+        }
+    }
+    /// Called for every received rpc request with MagicBall carrying the request deadline, returned error is sent to the requester.
+    fn on_rpc(&self, _mb: MagicBall, msg: Message<Self::Payload>) -> impl Future<Output = Result<Response<Self::Payload>, Box<dyn Error>>> + Send {
+        async move {
+            Err(format!("Rpc {:?} is not handled by the service", msg.meta.key).into())
+        }
+    }
+    /// Called once for client run with client::run_stream, frames are received with rx as they are read.
+    fn on_stream(&self, _mb: MagicBall, mut rx: UnboundedReceiver<ClientMsg>) -> impl Future<Output = ()> + Send {
+        async move {
+            while rx.recv().await.is_some() {}
+        }
+    }
+    /// Called when client is completed or interrupted with ctrl-c.
+    fn on_stop(&self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+    /// Wraps the service with layer, for example service.layer(CatchPanicLayer).layer(LogLayer::default()).
+    fn layer<L>(self, layer: L) -> L::Service where L: Layer<Self>, Self: Sized {
+        layer.layer(self)
+    }
+}
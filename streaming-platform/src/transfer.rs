@@ -0,0 +1,257 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use serde_derive::{Serialize, Deserialize};
+use serde_json::{from_slice, to_vec};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use sp_dto::{Transfer, uuid::Uuid};
+use crate::proto::ProcessError;
+
+/// Amount of written bytes after which transfer progress is persisted, if other size is not set.
+pub const DEFAULT_TRANSFER_SYNC_SIZE: u64 = 1024 * 1024;
+
+const PROGRESS_EXTENSION: &str = "transfer";
+
+/// Persisted progress of the transfer, data up to offset is synced to the file.
+#[derive(Debug, Serialize, Deserialize)]
+struct TransferProgress {
+    id: Uuid,
+    offset: u64
+}
+
+/// File receiving data of resumable transfer. Progress is persisted next to the file with ".transfer" extension added,
+/// so file of transfer with the same id reopened after failure continues from persisted offset.
+/// Data written after the last sync is discarded on reopen.
+pub struct TransferFile {
+    file: File,
+    progress_path: PathBuf,
+    transfer: Transfer,
+    offset: u64,
+    synced_offset: u64,
+    sync_size: u64
+}
+
+impl TransferFile {
+    /// Opens file for the transfer. Data of the transfer with the same id is kept up to persisted offset, other data is truncated.
+    pub async fn open(path: impl AsRef<Path>, transfer: Transfer) -> Result<TransferFile, ProcessError> {
+        let path = path.as_ref();
+        let progress_path = get_progress_path(path);
+        let offset = fs::read(&progress_path).await.ok()
+            .and_then(|data| from_slice::<TransferProgress>(&data).ok())
+            .filter(|progress| progress.id == transfer.id && progress.offset <= transfer.size)
+            .map(|progress| progress.offset)
+            .unwrap_or_default();
+
+        let mut file = OpenOptions::new().create(true).truncate(false).write(true).open(path).await?;
+        let offset = offset.min(file.metadata().await?.len());
+
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        let transfer_file = TransferFile {
+            file,
+            progress_path,
+            transfer,
+            offset,
+            synced_offset: offset,
+            sync_size: DEFAULT_TRANSFER_SYNC_SIZE
+        };
+
+        transfer_file.persist_progress().await?;
+
+        Ok(transfer_file)
+    }
+    pub fn get_transfer(&self) -> Transfer {
+        self.transfer
+    }
+    /// Offset of the next written byte, it is acknowledged to the sender when transfer stream is started.
+    pub fn get_offset(&self) -> u64 {
+        self.offset
+    }
+    pub fn set_sync_size(&mut self, sync_size: u64) {
+        self.sync_size = sync_size;
+    }
+    pub fn is_complete(&self) -> bool {
+        self.offset == self.transfer.size
+    }
+    /// Writes transfer data. Progress is persisted when sync size is written after the last sync,
+    /// returned offset should be acknowledged to the sender then.
+    pub async fn write(&mut self, data: &[u8]) -> Result<Option<u64>, ProcessError> {
+        self.file.write_all(data).await?;
+        self.offset += data.len() as u64;
+
+        match self.offset - self.synced_offset >= self.sync_size {
+            true => Ok(Some(self.sync().await?)),
+            false => Ok(None)
+        }
+    }
+    /// Syncs written data to disk and persists progress, returns persisted offset.
+    pub async fn sync(&mut self) -> Result<u64, ProcessError> {
+        self.file.sync_data().await?;
+        self.synced_offset = self.offset;
+        self.persist_progress().await?;
+
+        Ok(self.synced_offset)
+    }
+    /// Syncs written data and removes persisted progress, the transfer can't be resumed after this.
+    pub async fn complete(self) -> Result<(), ProcessError> {
+        self.file.sync_data().await?;
+        fs::remove_file(&self.progress_path).await?;
+
+        Ok(())
+    }
+    async fn persist_progress(&self) -> Result<(), ProcessError> {
+        let progress = TransferProgress {
+            id: self.transfer.id,
+            offset: self.synced_offset
+        };
+
+        fs::write(&self.progress_path, to_vec(&progress)?).await?;
+
+        Ok(())
+    }
+}
+
+fn get_progress_path(path: &Path) -> PathBuf {
+    let mut progress_path = path.as_os_str().to_owned();
+
+    progress_path.push(".");
+    progress_path.push(PROGRESS_EXTENSION);
+
+    PathBuf::from(progress_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+    use sp_dto::{Key, MsgMeta, Transfer, bytes::Bytes, uuid::Uuid};
+    use crate::{CancelReason, ClientMsg, FrameType, MagicBall, RestreamMsg};
+    use crate::loopback::fixture::Fixture;
+    use super::{TransferFile, get_progress_path};
+
+    #[tokio::test]
+    async fn transfer_file_reopen() {
+        let path = std::env::temp_dir().join(format!("sp-transfer-{}", Uuid::new_v4()));
+        let transfer = Transfer::new(Uuid::new_v4(), 12);
+        let mut file = TransferFile::open(&path, transfer).await.expect("Failed to open transfer file");
+
+        file.set_sync_size(4);
+
+        assert_eq!(file.write(&[1; 4]).await.expect("Failed to write"), Some(4));
+        assert_eq!(file.write(&[2; 2]).await.expect("Failed to write"), None);
+
+        drop(file);
+
+        // data written after the last sync is discarded
+        let mut file = TransferFile::open(&path, transfer).await.expect("Failed to reopen transfer file");
+
+        assert_eq!(file.get_offset(), 4);
+
+        file.write(&[2; 8]).await.expect("Failed to write");
+
+        assert!(file.is_complete());
+
+        file.complete().await.expect("Failed to complete transfer");
+
+        let data = std::fs::read(&path).expect("Failed to read file");
+        let progress_exists = get_progress_path(&path).exists();
+
+        // file of other transfer starts from the beginning
+        let file = TransferFile::open(&path, Transfer::new(Uuid::new_v4(), 12)).await.expect("Failed to open transfer file");
+        let offset = file.get_offset();
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(get_progress_path(&path));
+
+        assert_eq!(data, [[1; 4], [2; 4], [2; 4]].concat());
+        assert!(!progress_exists);
+        assert_eq!(offset, 0);
+    }
+
+    /// Writes transfer streams to the file from config, progress is persisted and acknowledged after each 4 bytes.
+    async fn process_transfer_stream(config: Value, mb: MagicBall, mut rx: UnboundedReceiver<ClientMsg>, _: Option<UnboundedSender<RestreamMsg>>, _: Option<UnboundedReceiver<RestreamMsg>>, tx: UnboundedSender<Value>) {
+        let path = config["path"].as_str().expect("Path not set").to_owned();
+        let mut msg_meta = vec![];
+        let mut files = HashMap::new();
+
+        while let Some(ClientMsg::Frame(frame)) = rx.recv().await {
+            let payload = frame.payload.as_deref().unwrap_or_default();
+
+            match frame.get_frame_type().expect("Incorrect frame type") {
+                FrameType::MsgMeta => msg_meta.extend_from_slice(payload),
+                FrameType::MsgMetaEnd => {
+                    msg_meta.extend_from_slice(payload);
+
+                    let stream_msg_meta: MsgMeta = serde_json::from_slice(&msg_meta).expect("Failed to decode msg meta");
+                    let mut file = TransferFile::open(&path, stream_msg_meta.transfer.expect("Transfer not set")).await.expect("Failed to open transfer file");
+
+                    msg_meta.clear();
+                    file.set_sync_size(4);
+                    mb.ack_transfer(&stream_msg_meta.tx, frame.stream_id, file.get_offset()).expect("Failed to ack transfer");
+                    files.insert(frame.stream_id, (stream_msg_meta.tx, file));
+                }
+                FrameType::Attachment => {
+                    let (sender, file) = files.get_mut(&frame.stream_id).expect("Transfer not started");
+
+                    if let Some(offset) = file.write(payload).await.expect("Failed to write transfer data") {
+                        mb.ack_transfer(sender, frame.stream_id, offset).expect("Failed to ack transfer");
+                    }
+                }
+                FrameType::AttachmentEnd => {
+                    let (_, file) = files.remove(&frame.stream_id).expect("Transfer not started");
+
+                    assert!(file.is_complete());
+
+                    file.complete().await.expect("Failed to complete transfer");
+                    tx.send(json!({ "data": std::fs::read(&path).expect("Failed to read file") })).expect("Failed to send data");
+                }
+                FrameType::Cancel => {
+                    files.remove(&frame.stream_id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn startup_transfer_caller(_: Value, _: Value, mb: MagicBall, _: Option<Value>, tx: UnboundedSender<Value>) {
+        let data: Vec<u8> = (0..12).collect();
+        let mut mb = mb.with_transfer(Transfer::new(Uuid::new_v4(), data.len() as u64));
+
+        let mut stream = mb.start_event_stream(Key::simple("HiTransfer"), json!({})).await.expect("Failed to start stream");
+
+        let offset = stream.wait_transfer_offset().await.expect("Transfer offset not acknowledged");
+
+        // first attempt fails after 6 bytes, only 4 of them are persisted by the receiver
+        stream.write_payload(Bytes::copy_from_slice(&data[offset as usize..4])).await.expect("Failed to write frame");
+        stream.write_payload(Bytes::copy_from_slice(&data[4..6])).await.expect("Failed to write frame");
+        stream.abort(CancelReason::SenderFailed).expect("Failed to abort stream");
+
+        let mut stream = mb.start_event_stream(Key::simple("HiTransfer"), json!({})).await.expect("Failed to start stream");
+
+        let offset = stream.wait_transfer_offset().await.expect("Transfer offset not acknowledged");
+
+        tx.send(json!({ "offset": offset })).expect("Failed to send offset");
+
+        stream.write_attachment(&data[offset as usize..]).await.expect("Failed to write attachment");
+        stream.finish().expect("Failed to finish stream");
+    }
+
+    #[tokio::test]
+    async fn resumed_transfer() {
+        let mut fixture = Fixture::start(&[Key::simple("HiTransfer")], &[]);
+        let path = std::env::temp_dir().join(format!("sp-transfer-{}", Uuid::new_v4()));
+
+        fixture.spawn_stream_service(json!({ "addr": "Service", "path": path }), process_transfer_stream).await;
+        fixture.spawn_client(json!({ "addr": "Caller" }), startup_transfer_caller);
+
+        let offset = fixture.recv().await;
+        let data = fixture.recv().await;
+
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(offset, json!({ "offset": 4 }));
+        assert_eq!(data, json!({ "data": (0..12).collect::<Vec<u8>>() }));
+    }
+}